@@ -1,7 +1,10 @@
 mod helpers;
+mod retention;
 
 use std::convert::Infallible;
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::Mutex;
 
 use serde_derive::Serialize;
@@ -14,6 +17,7 @@ use warp::{http::Uri, reject, Filter, Rejection, Reply};
 use crossbeam::channel::unbounded;
 // use tokio::time::{sleep, Duration};
 
+use rooms::auth::AuthError;
 use structopt::StructOpt;
 use trace::{trace_err_ln, trace_good_ln, trace_info_ln};
 use urbit_api::api::Ship;
@@ -44,6 +48,46 @@ pub struct HolAPI {
     // ship code
     #[structopt(short = "code", long = "ship-code", default_value = "")]
     pub code: String,
+
+    /// where databases and logs are kept. defaults to $HOLIUM_DATA_DIR, then
+    /// `data_dir` in the config file, then ~/.local/share/holium
+    #[structopt(long = "data-dir", parse(from_os_str))]
+    pub data_dir: Option<PathBuf>,
+
+    /// expire logged packets of a source: <source>=<age|rows>[,...], e.g. ship=7d,100000.
+    /// source * applies to every source. packets are kept forever when not given
+    #[structopt(long = "packets-retention")]
+    pub packets_retention: Vec<bedrock_db::retention::RetentionRule>,
+
+    /// archive expired packets (gzipped JSONL) to <data root>/archive/<server id>
+    #[structopt(long = "packets-archive")]
+    pub packets_archive: bool,
+
+    /// off, full, incremental or incremental:<pages>
+    #[structopt(long = "packets-vacuum", default_value = "incremental")]
+    pub packets_vacuum: bedrock_db::retention::Vacuum,
+
+    /// seconds between retention runs
+    #[structopt(long = "retention-interval", default_value = "3600")]
+    pub retention_interval: u64,
+
+    /// seconds between vacuums
+    #[structopt(long = "vacuum-interval", default_value = "86400")]
+    pub vacuum_interval: u64,
+
+    /// size limit (MB) of the media cached for chat. the least recently used media is
+    /// evicted past it
+    #[structopt(long = "media-cache-size", default_value = "512")]
+    pub media_cache_size: u64,
+
+    /// media larger than this (MB) is not cached
+    #[structopt(long = "media-max-item-size", default_value = "25")]
+    pub media_max_item_size: u64,
+
+    /// seconds that the rooms open when the node stopped wait for their peers to come
+    /// back after a restart
+    #[structopt(long = "rooms-grace-period", default_value = "60")]
+    pub rooms_grace_period: u64,
 }
 
 #[tokio::main]
@@ -63,8 +107,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let _ = ship.scry("docket", "/our", "json").await?;
 
-    // create a new database file (bedrock.sqlite) in the ./src/lib/db/data folder
-    let db_pool = bedrock_db::initialize_pool("bedrock")?;
+    // each holon gets its own database file (<data root>/db/<server id>.sqlite)
+    let data = bedrock_db::DataRoot::resolve(opt.data_dir.as_deref())?;
+    data.ensure()?;
+    let db_pool = bedrock_db::DbPool::open(&data.database(&opt.server_id))?;
 
     let (sender, receiver) = unbounded::<JsonValue>();
 
@@ -72,23 +118,53 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let context: CallContext = NodeContext::to_call_context(NodeContext {
         db: Db { pool: db_pool },
         ship: Arc::new(Mutex::new(ship)),
+        sync_ship: Arc::new(Mutex::new(None)),
+        chat_events: urbit_api::chat::events::channel(),
         // used to send data from the EventSource (task/thread/loop) to the receiver
-        sender: sender,
+        sender,
         // threaded listener that waits for messages dispatched by the sender thread
         //  note: need to wrap in Arc::Mutex since will need a mutable reference from within
         //  the leveraging thread (see ws.rs)
         // receiver: Arc::new(Mutex::new(receiver)),
-        receiver: receiver,
+        receiver,
     });
 
+    // bring the database schema up to date before any module touches it
+    context.db.migrate()?;
+
     //
     // start each 'module'
     //  panic if any of these fail?
     //
 
+    if !opt.packets_retention.is_empty() {
+        retention::start(
+            context.db.pool.clone(),
+            bedrock_db::retention::RetentionPolicy {
+                rules: opt.packets_retention.clone(),
+                archive_dir: opt.packets_archive.then(|| data.archive(&opt.server_id)),
+                vacuum: opt.packets_vacuum,
+                interval: Duration::from_secs(opt.retention_interval),
+                vacuum_interval: Duration::from_secs(opt.vacuum_interval),
+            },
+        );
+    }
+
     // start the chat 'module'
     urbit_api::chat::core::start(&context).await?;
 
+    // fetch the media referenced by chat messages into <data root>/media/<server id>
+    let media_cache = urbit_api::media::MediaCache::new(
+        &data.media(&opt.server_id),
+        opt.media_cache_size * 1024 * 1024,
+        opt.media_max_item_size * 1024 * 1024,
+    )?;
+    urbit_api::media::start(context.clone(), media_cache.clone(), true);
+
+    // rooms are kept in the database, and the ones open when the node stopped come back
+    let rooms_store = rooms::store::Store::open(context.db.pool.clone())?;
+    rooms::store::recover(rooms_store, Duration::from_secs(opt.rooms_grace_period))?;
+
     //
     // note:
     // if websockets or ship subscription fails, the process should not start
@@ -110,9 +186,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     // setup_ctrlc_handler(&context).await?;
 
-    let rooms_route = rooms::api::rooms_route();
-    let signaling_route = rooms::socket::signaling_route();
+    let rooms_route = rooms::api::rooms_route(rooms_guard(context.clone()));
+    let signaling_route = rooms::socket::signaling_route(rooms_guard(context.clone()));
     let chat_route = urbit_api::chat::api::chat_router(context.clone());
+    let media_route = urbit_api::media::api::media_router(context.clone(), media_cache);
 
     let proxy = reverse_proxy_filter("".to_string(), http_server_url);
     let login_route = warp::path!("~" / "login" / ..).and(reverse_proxy_filter(
@@ -122,9 +199,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let routes = rooms_route
         .or(signaling_route)
-        .or(chat_route)
+        .or(under("/hol/chat/")
+            .and(check_cookie(context.clone()))
+            .and(chat_route))
         .or(ws_route)
         .or(login_route)
+        .or(under("/hol/media/")
+            .and(check_cookie(context.clone()))
+            .and(media_route))
         .or(check_cookie(context).and(proxy))
         .recover(handle_unauthorized)
         .recover(handle_rejection);
@@ -186,7 +268,7 @@ async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible> {
     if err.is_not_found() {
         code = StatusCode::NOT_FOUND;
         message = "NOT_FOUND";
-    } else if let Some(_) = err.find::<Unauthorized>() {
+    } else if err.find::<Unauthorized>().is_some() {
         code = StatusCode::FORBIDDEN;
         message = "FORBIDDEN";
     } else {
@@ -210,37 +292,41 @@ fn reject_on_path(path: &str) -> warp::Rejection {
     match path.starts_with("/~/scry/")
         || path.starts_with("/~/channel/")
         || path.starts_with("/spider/")
+        || path.starts_with("/hol/media/")
+        || path.starts_with("/hol/chat/")
     {
-        true => {
-            return reject::custom(Unauthorized);
-        }
-        false => {
-            return reject::custom(Redirect {
-                location: format!("{}", path.to_string()),
-            });
-        }
+        true => reject::custom(Unauthorized),
+        false => reject::custom(Redirect {
+            location: path.to_string(),
+        }),
     }
 }
 
-fn handle_response(path: &str, data: JsonValue) -> Result<(), warp::Rejection> {
-    let is_valid = data
-        .as_object()
-        .unwrap()
-        .get("is-valid")
-        .unwrap()
-        .as_bool()
-        .unwrap();
-    if is_valid {
-        if cfg!(feature = "trace") {
-            trace_info_ln!("cookie valid {}", path)
-        }
-        return Ok(());
-    } else {
-        if cfg!(feature = "trace") {
-            trace_err_ln!("cookie invalid {}", path)
-        }
-        return Err(reject_on_path(path));
-    }
+// the ship session in a cookie header: the urbauth cookie, or else the first one
+fn session_cookie(header: &str) -> String {
+    let mut cookies = header.split(';').map(str::trim);
+    let first = cookies.clone().next().unwrap_or_default();
+    cookies
+        .find(|cookie| cookie.starts_with("urbauth-"))
+        .unwrap_or(first)
+        .to_string()
+}
+
+// ask the ship whether a session cookie is one of its own. none if the ship cannot be
+//  asked
+async fn valid_cookie(context: &CallContext, cookie: &str) -> Option<bool> {
+    let res = context
+        .ship
+        .lock()
+        .await
+        .scry(
+            "holon",
+            format!("/valid-cookie/{}", cookie).as_str(),
+            "json",
+        )
+        .await
+        .ok()?;
+    res["is-valid"].as_bool()
 }
 
 fn check_cookie(ctx: CallContext) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
@@ -252,35 +338,290 @@ fn check_cookie(ctx: CallContext) -> impl Filter<Extract = (), Error = warp::Rej
             move |path: warp::path::FullPath,
                   context: CallContext,
                   headers: reqwest::header::HeaderMap| async move {
-                if !headers.contains_key("Cookie") {
+                let Some(cookie) = headers.get("Cookie").and_then(|c| c.to_str().ok()) else {
                     return Err(reject_on_path(path.as_str()));
-                }
-                let cookie = headers.get("Cookie").unwrap().to_str().unwrap();
+                };
                 if cfg!(feature = "trace") {
                     println!("path: {}, cookie: {}", path.as_str(), cookie);
                 }
-                let cookie = cookie.split(';').collect::<Vec<&str>>()[0].to_string();
-                let res = context
-                    .ship
-                    .lock()
-                    .await
-                    .scry(
-                        "holon",
-                        format!("/valid-cookie/{}", cookie).as_str(),
-                        "json",
-                    )
-                    .await;
-                if res.is_err() {
-                    return Err(reject::custom(ServerError));
+                match valid_cookie(&context, &session_cookie(cookie)).await {
+                    None => Err(reject::custom(ServerError)),
+                    Some(true) => {
+                        if cfg!(feature = "trace") {
+                            trace_info_ln!("cookie valid {}", path.as_str())
+                        }
+                        Ok(())
+                    }
+                    Some(false) => {
+                        if cfg!(feature = "trace") {
+                            trace_err_ln!("cookie invalid {}", path.as_str())
+                        }
+                        Err(reject_on_path(path.as_str()))
+                    }
                 }
-                return handle_response(path.as_str(), res.unwrap());
             },
         )
         .untuple_one()
 }
 
+// ask the ship whether `signature` is `ship`'s signature of `challenge`. none if the
+//  ship cannot be asked
+async fn valid_signature(
+    context: &CallContext,
+    ship: &str,
+    challenge: &str,
+    signature: &str,
+) -> Option<bool> {
+    let res = context
+        .ship
+        .lock()
+        .await
+        .scry(
+            "holon",
+            format!("/valid-signature/{}/{}/{}", ship, challenge, signature).as_str(),
+            "json",
+        )
+        .await
+        .ok()?;
+    res["is-valid"].as_bool()
+}
+
+// the characters of an @p, a challenge and a signature (@uw or @ux). anything else
+//  cannot be part of a scry path
+fn is_path_safe(s: &str) -> bool {
+    !s.is_empty()
+        && s.bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'~' || b == b'-' || b == b'.')
+}
+
+// rooms sessions are opened for the ship's own sessions, as the ship, and for other
+//  ships that signed a challenge of the node's, as themselves
+fn signaling_auth(ctx: CallContext) -> rooms::auth::Authenticate {
+    Arc::new(move |credentials: rooms::auth::Credentials| {
+        let context = ctx.clone();
+        Box::pin(async move {
+            if let Some(cookie) = &credentials.cookie {
+                match valid_cookie(&context, &session_cookie(cookie)).await {
+                    Some(true) => {
+                        let ship = context.ship.lock().await.ship_name.clone();
+                        return ship
+                            .map(|name| format!("~{}", name))
+                            .ok_or(AuthError::Unavailable);
+                    }
+                    // not the ship's, but the request may still prove another ship
+                    Some(false) => {}
+                    None => return Err(AuthError::Unavailable),
+                }
+            }
+            let rooms::auth::Credentials {
+                peer_id: Some(peer_id),
+                challenge: Some(challenge),
+                signature: Some(signature),
+                ..
+            } = credentials
+            else {
+                return Err(AuthError::Unauthorized);
+            };
+            let ship = format!("~{}", peer_id.trim_start_matches('~'));
+            if ![&ship, &challenge, &signature]
+                .iter()
+                .all(|part| is_path_safe(part))
+            {
+                return Err(AuthError::Unauthorized);
+            }
+            match valid_signature(&context, &ship, &challenge, &signature).await {
+                Some(true) => Ok(ship),
+                Some(false) => Err(AuthError::Unauthorized),
+                None => Err(AuthError::Unavailable),
+            }
+        })
+    })
+}
+
+// space rooms are for the members of the space, as far as the ship's chats know them
+fn space_member(ctx: CallContext) -> rooms::access::IsMember {
+    Arc::new(move |space: &str, ship: &str| {
+        let member = ctx
+            .db
+            .pool
+            .get_conn()
+            .and_then(|conn| urbit_api::chat::data::is_space_member(&conn, space, ship));
+        member.unwrap_or_else(|e| {
+            trace_err_ln!("cannot check membership of {} in {}. {}", ship, space, e);
+            false
+        })
+    })
+}
+
+fn rooms_guard(ctx: CallContext) -> rooms::auth::Guard {
+    rooms::auth::Guard {
+        authenticate: signaling_auth(ctx.clone()),
+        is_member: space_member(ctx),
+    }
+}
+
+// only requests for paths under `prefix` pass. lets a route be guarded without checking
+//  the cookie of requests that are not for it
+fn under(prefix: &'static str) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    warp::path::full()
+        .and_then(move |path: warp::path::FullPath| async move {
+            match path.as_str().starts_with(prefix) {
+                true => Ok(()),
+                false => Err(reject::not_found()),
+            }
+        })
+        .untuple_one()
+}
+
 fn with_call_context(
     context: CallContext,
 ) -> impl Filter<Extract = (CallContext,), Error = Infallible> + Clone {
     warp::any().map(move || context.clone())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use eyre_mock::MockEyre;
+    use serde_json::json;
+    use urbit_api::db::Db;
+
+    const CODE: &str = "lidlut-tabwed-pillex-ridrup";
+
+    async fn test_context(eyre: &MockEyre) -> CallContext {
+        let ship = Ship::new(eyre.url.as_str(), CODE).await.unwrap();
+        let (sender, receiver) = unbounded::<JsonValue>();
+        NodeContext::to_call_context(NodeContext {
+            db: Db {
+                pool: bedrock_db::DbPool::new(":memory:"),
+            },
+            ship: Arc::new(Mutex::new(ship)),
+            sync_ship: Arc::new(Mutex::new(None)),
+            chat_events: urbit_api::chat::events::channel(),
+            sender,
+            receiver,
+        })
+    }
+
+    fn guarded(
+        ctx: CallContext,
+    ) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone {
+        check_cookie(ctx)
+            .map(warp::reply)
+            .recover(handle_unauthorized)
+            .recover(handle_rejection)
+    }
+
+    #[tokio::test]
+    async fn check_cookie_accepts_ship_session() {
+        let eyre = MockEyre::start("zod", CODE);
+        let ctx = test_context(&eyre).await;
+
+        let res = warp::test::request()
+            .path("/apps/realm/")
+            .header("cookie", eyre.cookie().unwrap())
+            .reply(&guarded(ctx))
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn check_cookie_rejects_unknown_session() {
+        let eyre = MockEyre::start("zod", CODE);
+        let ctx = test_context(&eyre).await;
+        let cookie = "urbauth-~zod=0v1.abcde.fghij; Path=/; Max-Age=604800";
+
+        // api calls are rejected outright...
+        let res = warp::test::request()
+            .path("/~/scry/docket/our.json")
+            .header("cookie", cookie)
+            .reply(&guarded(ctx.clone()))
+            .await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        // ...while ui calls are redirected to the login page
+        let res = warp::test::request()
+            .path("/apps/realm/")
+            .header("cookie", cookie)
+            .reply(&guarded(ctx.clone()))
+            .await;
+        assert_eq!(res.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(res.headers()["location"], "/~/login?redirect=/apps/realm/");
+
+        let res = warp::test::request()
+            .path("/~/channel/1")
+            .reply(&guarded(ctx.clone()))
+            .await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        // cached media and the chat api are only for the ship's sessions
+        for path in ["/hol/media/00ff", "/hol/chat/messages"] {
+            let res = warp::test::request()
+                .path(path)
+                .header("cookie", cookie)
+                .reply(&guarded(ctx.clone()))
+                .await;
+            assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        }
+    }
+
+    #[tokio::test]
+    async fn signaling_sessions_are_proven_ships() {
+        let eyre = MockEyre::start("zod", CODE);
+        let ctx = test_context(&eyre).await;
+        let authenticate = signaling_auth(ctx);
+        let credentials = |cookie: Option<String>| rooms::auth::Credentials {
+            cookie,
+            ..Default::default()
+        };
+
+        // browsers send whatever other cookies they have along
+        let cookie = format!("theme=dark; {}", eyre.cookie().unwrap());
+        assert_eq!(
+            authenticate(credentials(Some(cookie))).await,
+            Ok("~zod".to_string())
+        );
+        assert_eq!(
+            authenticate(credentials(Some("urbauth-~zod=0v1.abcde".to_string()))).await,
+            Err(AuthError::Unauthorized)
+        );
+        assert_eq!(
+            authenticate(credentials(None)).await,
+            Err(AuthError::Unauthorized)
+        );
+
+        // another ship signs a challenge, which ~zod checks
+        eyre.add_scry(
+            "holon/valid-signature/~bus/c0ffee/0w1.sig.json",
+            json!({ "is-valid": true }),
+        );
+        eyre.add_scry(
+            "holon/valid-signature/~bus/c0ffee/0w1.forged.json",
+            json!({ "is-valid": false }),
+        );
+        let signed = |peer_id: &str, signature: &str| rooms::auth::Credentials {
+            peer_id: Some(peer_id.to_string()),
+            challenge: Some("c0ffee".to_string()),
+            signature: Some(signature.to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            authenticate(signed("bus", "0w1.sig")).await,
+            Ok("~bus".to_string())
+        );
+        assert_eq!(
+            authenticate(signed("~bus", "0w1.forged")).await,
+            Err(AuthError::Unauthorized)
+        );
+        assert_eq!(
+            authenticate(signed("~bus", "0w1.sig/../..")).await,
+            Err(AuthError::Unauthorized)
+        );
+        // the ship's cookie still makes ~zod, whoever the request claims to be
+        let both = rooms::auth::Credentials {
+            cookie: eyre.cookie(),
+            ..signed("~bus", "0w1.sig")
+        };
+        assert_eq!(authenticate(both).await, Ok("~zod".to_string()));
+    }
+}
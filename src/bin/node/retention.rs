@@ -0,0 +1,55 @@
+use std::time::{Instant, SystemTime};
+use tokio::time;
+
+use bedrock_db::retention::{apply_retention, vacuum, RetentionPolicy, Vacuum};
+use bedrock_db::DbPool;
+use trace::{trace_err_ln, trace_info_ln};
+
+/// run the packets retention policy every `policy.interval`. deletes and vacuums are
+///  blocking sqlite calls, so each run goes to the blocking pool
+pub fn start(pool: DbPool, policy: RetentionPolicy) {
+    tokio::spawn(async move {
+        let mut interval = time::interval(policy.interval);
+        let mut last_vacuum = Instant::now();
+        // rows were deleted since the last vacuum
+        let mut dirty = false;
+        loop {
+            interval.tick().await;
+
+            let vacuum_due =
+                policy.vacuum != Vacuum::Off && last_vacuum.elapsed() >= policy.vacuum_interval;
+            let (pool, policy) = (pool.clone(), policy.clone());
+            let res = tokio::task::spawn_blocking(
+                move || -> Result<(u64, bool), Box<dyn std::error::Error + Send + Sync>> {
+                    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
+                    let report = apply_retention(&pool, &policy, now.as_millis() as u64)?;
+                    if cfg!(feature = "trace") && report.deleted > 0 {
+                        trace_info_ln!(
+                            "retention: deleted {} packets, {} archive(s)",
+                            report.deleted,
+                            report.archives.len()
+                        );
+                    }
+                    let vacuumed = vacuum_due && (dirty || report.deleted > 0);
+                    if vacuumed {
+                        vacuum(&pool, policy.vacuum)?;
+                    }
+                    Ok((report.deleted, vacuumed))
+                },
+            )
+            .await;
+
+            match res {
+                Ok(Ok((deleted, vacuumed))) => {
+                    dirty |= deleted > 0;
+                    if vacuumed {
+                        dirty = false;
+                        last_vacuum = Instant::now();
+                    }
+                }
+                Ok(Err(e)) => trace_err_ln!("retention: {}", e),
+                Err(e) => trace_err_ln!("retention: job failed {}", e),
+            }
+        }
+    });
+}
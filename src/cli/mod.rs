@@ -10,8 +10,11 @@ pub mod printer;
 pub mod tmux;
 mod urbit;
 
+use std::io;
+use std::path::PathBuf;
 use std::process::exit;
 
+use bedrock_db::DataRoot;
 use structopt::StructOpt;
 
 #[derive(StructOpt)]
@@ -29,6 +32,11 @@ pub struct Hol {
     // the port for the Holium node
     #[structopt(long = "node-port", default_value = "3030")]
     pub node_port: u16,
+
+    /// where piers, databases and logs are kept. defaults to $HOLIUM_DATA_DIR, then
+    /// `data_dir` in the config file, then ~/.local/share/holium
+    #[structopt(long = "data-dir", parse(from_os_str))]
+    pub data_dir: Option<PathBuf>,
 }
 
 #[derive(StructOpt)]
@@ -105,10 +113,16 @@ pub enum Subcommand {
     /// Get the current instance access code
     #[structopt(name = "code")]
     Code,
+    /// Shows the progress of the node's chat history import
+    #[structopt(name = "chat-import")]
+    ChatImport,
 }
 
 pub fn start(opt: Hol) -> std::io::Result<()> {
-    let urbit = UrbitInstance;
+    let data = DataRoot::resolve(opt.data_dir.as_deref()).map_err(io::Error::other)?;
+    data.ensure().map_err(io::Error::other)?;
+    let urbit = UrbitInstance::new(data.clone());
+    let node = NodeRunner::new(data);
 
     match opt.command {
         Subcommand::Install => {
@@ -120,22 +134,20 @@ pub fn start(opt: Hol) -> std::io::Result<()> {
             urbit
                 .boot(&opt.server_id, fake, key, opt.urbit_port)
                 .unwrap();
-            NodeRunner
-                .start(&opt.server_id, opt.node_port, opt.urbit_port)
+            node.start(&opt.server_id, opt.node_port, opt.urbit_port)
                 .unwrap();
             exit(0);
         }
         Subcommand::Start {} => {
-            urbit.start(&opt.server_id, opt.urbit_port.clone()).unwrap();
-            NodeRunner
-                .start(&opt.server_id, opt.node_port, opt.urbit_port)
+            urbit.start(&opt.server_id, opt.urbit_port).unwrap();
+            node.start(&opt.server_id, opt.node_port, opt.urbit_port)
                 .unwrap();
             // RoomsRunner.start(&opt.server_id).unwrap();
             exit(0);
         }
         Subcommand::Stop {} => {
-            urbit.stop(&opt.server_id, opt.urbit_port.clone())?;
-            NodeRunner.stop(&opt.server_id).unwrap();
+            urbit.stop(&opt.server_id, opt.urbit_port)?;
+            node.stop(&opt.server_id).unwrap();
             // RoomsRunner.stop(&opt.server_id).unwrap();
             exit(0);
         }
@@ -163,9 +175,9 @@ pub fn start(opt: Hol) -> std::io::Result<()> {
                 .upgrade(
                     &opt.server_id,
                     UrbitUpdateOptions {
-                        update_urbit: update_urbit,
-                        update_vere: update_vere,
-                        update_all: update_all,
+                        update_urbit,
+                        update_vere,
+                        update_all,
                     },
                 )
                 .unwrap();
@@ -194,5 +206,9 @@ pub fn start(opt: Hol) -> std::io::Result<()> {
             println!("{}", access_code);
             exit(0);
         }
+        Subcommand::ChatImport => {
+            node.chat_import(opt.node_port)?;
+            exit(0);
+        }
     }
 }
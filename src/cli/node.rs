@@ -1,29 +1,49 @@
-use std::{io, process::Command};
+use std::{env, fs, io, process::Command};
+
+use bedrock_db::DataRoot;
+use urbit_api::chat::import::{ImportReport, ImportState};
 
 use crate::cli::{printer::print_to_cli, tmux::TmuxManager};
 
-pub struct NodeRunner;
+pub struct NodeRunner {
+    data: DataRoot,
+}
 
 impl NodeRunner {
+    pub fn new(data: DataRoot) -> NodeRunner {
+        NodeRunner { data }
+    }
+
     pub fn start(&self, server_id: &str, node_port: u16, urbit_port: u16) -> io::Result<()> {
         let session_name = format!("{}-api", server_id);
         let is_instance_running = TmuxManager::is_session_running(server_id);
         let is_node_running = TmuxManager::is_session_running(session_name.as_str());
         if !is_node_running & is_instance_running {
-            let mut command = Command::new("cargo");
+            // an installed `hol` runs the `node` binary installed next to it. otherwise
+            //  (e.g. in a checkout) build and run it with cargo
+            let installed = env::current_exe()?.with_file_name("node");
+            let mut command = if installed.exists() {
+                Command::new(installed)
+            } else {
+                let mut command = Command::new("cargo");
+                command.arg("run").arg("--bin").arg("node").arg("--");
+                command
+            };
 
             command
-                .arg("run")
-                .arg("--bin")
-                .arg("node")
-                .arg("--")
-                .arg(&server_id.to_string())
+                .arg(server_id)
                 .arg("--urbit-port")
-                .arg(&urbit_port.to_string())
+                .arg(urbit_port.to_string())
                 .arg("--node-port")
-                .arg(&node_port.to_string());
+                .arg(node_port.to_string())
+                .arg("--data-dir")
+                .arg(self.data.path());
 
             TmuxManager::create_session(session_name.as_str(), None)?;
+            // the node's output is kept under the data root
+            let logs = self.data.logs(server_id);
+            fs::create_dir_all(&logs)?;
+            TmuxManager::log_to_file(session_name.as_str(), &logs.join("node.log"))?;
             TmuxManager::send_command(session_name.as_str(), &command)?;
         } else {
             if !is_instance_running {
@@ -42,4 +62,43 @@ impl NodeRunner {
         TmuxManager::terminate_session(format!("{}-api", server_id).as_str())?;
         Ok(())
     }
+
+    /// print the progress of the running node's chat import
+    pub fn chat_import(&self, node_port: u16) -> io::Result<()> {
+        let url = format!("http://localhost:{}/hol/chat/import", node_port);
+        let report: ImportReport = reqwest::blocking::get(url)
+            .and_then(|response| response.error_for_status())
+            .and_then(|response| response.json())
+            .map_err(io::Error::other)?;
+
+        let status = &report.status;
+        match status.state {
+            ImportState::Idle => print_to_cli("chat import: not started"),
+            ImportState::Running => print_to_cli(format!(
+                "chat import: running, {:.1}% (at {} of {}..{})",
+                report.progress * 100.0,
+                status.checkpoint,
+                status.from,
+                status.until
+            )),
+            ImportState::Done => print_to_cli(format!(
+                "chat import: done (everything received before {})",
+                status.checkpoint
+            )),
+            ImportState::Failed => print_to_cli(format!(
+                "chat import: failed at {}, {:.1}% done. resumes with the next import. {}",
+                status.checkpoint,
+                report.progress * 100.0,
+                status.error.as_deref().unwrap_or_default()
+            )),
+        }
+        print_to_cli(format!(
+            "  {} rows imported, {} skipped",
+            status.imported, status.skipped
+        ));
+        for error in &report.errors {
+            print_to_cli(format!("  skipped {} row: {}", error.kind, error.error));
+        }
+        Ok(())
+    }
 }
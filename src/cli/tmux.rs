@@ -1,4 +1,5 @@
 use std::io;
+use std::path::Path;
 use std::process::Command;
 
 pub struct TmuxManager {}
@@ -36,6 +37,21 @@ impl TmuxManager {
         Ok(())
     }
 
+    // Append everything the session prints to `file`
+    pub fn log_to_file(session_name: &str, file: &Path) -> io::Result<()> {
+        // the file name is quoted for the shell that runs `cat`
+        let file = file.to_string_lossy().replace('\'', "'\\''");
+        let mut command_session = Command::new("tmux")
+            .arg("pipe-pane")
+            .arg("-o")
+            .arg("-t")
+            .arg(session_name)
+            .arg(format!("cat >> '{}'", file))
+            .spawn()?;
+        let _ = command_session.wait()?;
+        Ok(())
+    }
+
     pub fn send_dojo_command(session_name: &str, input_str: &str) -> io::Result<()> {
         let mut command_session = Command::new("tmux")
             .arg("send-keys")
@@ -1,8 +1,9 @@
 use std::fs;
 use std::io;
-use std::path::Path;
 use std::process::Command;
 
+use bedrock_db::DataRoot;
+
 use crate::cli::printer::print_to_cli;
 use crate::cli::tmux::TmuxManager;
 
@@ -16,11 +17,16 @@ const BINARY_URL: &str = if cfg!(target_os = "macos") {
     panic!("Unsupported platform");
 };
 
-pub fn symlink_urbit_binary(server_id: String) -> io::Result<String> {
-    let symlinked_urbit = format!("{}_urbit", server_id);
+// link <root>/<server_id>_urbit to the urbit binary in the data root. returns the link
+pub fn symlink_urbit_binary(data: &DataRoot, server_id: String) -> io::Result<String> {
+    let symlinked_urbit = data
+        .path()
+        .join(format!("{}_urbit", server_id))
+        .display()
+        .to_string();
     TmuxManager::send_command(
         &server_id,
-        &Command::new("ln")
+        Command::new("ln")
             .arg("-s")
             .arg("urbit")
             .arg(&symlinked_urbit),
@@ -49,7 +55,9 @@ pub trait Instance {
     fn version(&self) -> io::Result<()>;
 }
 
-pub struct UrbitInstance;
+pub struct UrbitInstance {
+    data: DataRoot,
+}
 
 pub struct UrbitUpdateOptions {
     pub update_vere: Option<bool>,
@@ -57,27 +65,31 @@ pub struct UrbitUpdateOptions {
     pub update_all: Option<bool>,
 }
 impl UrbitInstance {
+    pub fn new(data: DataRoot) -> UrbitInstance {
+        UrbitInstance { data }
+    }
+
     pub fn has_urbit_binary(&self) -> bool {
-        Path::new("./urbit").exists()
+        self.data.urbit_binary().exists()
     }
 
     pub fn args_to_file(&self, server_id: &str, args: &str) -> io::Result<()> {
-        fs::write(format!("ships/.{}.params", server_id), args)?;
+        fs::write(self.data.params_file(server_id), args)?;
         Ok(())
     }
 
     pub fn fake_to_file(&self, server_id: &str) -> io::Result<()> {
-        fs::write(format!("ships/.{}.fake", server_id), true.to_string())?;
+        fs::write(self.data.fake_file(server_id), true.to_string())?;
         Ok(())
     }
 
     pub fn clear_params_file(&self, server_id: &str) -> io::Result<()> {
-        fs::write(format!("ships/.{}.params", server_id), "")?;
+        fs::write(self.data.params_file(server_id), "")?;
         Ok(())
     }
 
     pub fn get_current_args(&self, server_id: &str) -> io::Result<Vec<String>> {
-        let args = fs::read_to_string(format!("ships/.{}.params", server_id))?;
+        let args = fs::read_to_string(self.data.params_file(server_id))?;
         let args = args.split("\n").map(|s| s.to_string()).collect();
         Ok(args)
     }
@@ -86,11 +98,14 @@ impl UrbitInstance {
 impl Instance for UrbitInstance {
     type UpdateOptions = UrbitUpdateOptions;
 
+    // the binary is installed in the data root
     fn download_and_setup(&self, binary_name: &str) -> io::Result<()> {
-        if !Path::new(binary_name).exists() {
+        let root = self.data.path();
+        if !root.join(binary_name).exists() {
             println!("Downloading Urbit binary...");
             // Download the latest Urbit binary
             Command::new("curl")
+                .current_dir(root)
                 .arg("-L")
                 .arg(BINARY_URL)
                 .arg("-o")
@@ -99,6 +114,7 @@ impl Instance for UrbitInstance {
 
             // Extract the file
             Command::new("tar")
+                .current_dir(root)
                 .arg("zxvf")
                 .arg("urbit.tar.gz")
                 .arg("-s")
@@ -107,60 +123,54 @@ impl Instance for UrbitInstance {
 
             // Make the binary executable
             Command::new("chmod")
+                .current_dir(root)
                 .arg("+x")
                 .arg(binary_name)
                 .output()
                 .expect("Failed to execute command");
 
             // Make ships folder
-            Command::new("mkdir")
-                .arg("ships")
-                .output()
-                .expect("Failed to execute command");
+            fs::create_dir_all(self.data.ships())?;
 
             // remove the tar file
-            fs::remove_file("urbit.tar.gz")?;
+            fs::remove_file(root.join("urbit.tar.gz"))?;
         }
         Ok(())
     }
 
     fn boot(&self, server_id: &str, fake: bool, key: Option<String>, port: u16) -> io::Result<()> {
-        if !Path::new(format!("ships").as_str()).exists() {
-            Command::new("mkdir")
-                .arg("ships")
-                .output()
-                .expect("Failed to execute command");
-        }
-        if !Path::new(format!("ships/{}", server_id).as_str()).exists() {
+        fs::create_dir_all(self.data.ships())?;
+        let pier = self.data.pier(server_id);
+        if !pier.exists() {
             // create screen session
-            TmuxManager::create_session(&server_id, None)?;
-            let symlinked_urbit = symlink_urbit_binary(server_id.to_string())?;
+            TmuxManager::create_session(server_id, None)?;
+            let symlinked_urbit = symlink_urbit_binary(&self.data, server_id.to_string())?;
             // smylink server_id_urbit to urbit
             TmuxManager::send_command(
-                &server_id,
-                &Command::new("ln")
+                server_id,
+                Command::new("ln")
                     .arg("-s")
                     .arg("urbit")
                     .arg(&symlinked_urbit),
             )?;
 
-            let mut command = Command::new(format!("./{}", symlinked_urbit));
+            let mut command = Command::new(&symlinked_urbit);
             // execute urbit in screen session
             if fake {
                 command.arg("-F");
-                command.arg(&server_id.to_string());
-                command.arg("-c").arg(format!("ships/{}", server_id));
+                command.arg(server_id);
+                command.arg("-c").arg(&pier);
             } else if let Some(key) = &key {
-                command.arg("-w").arg(&server_id);
+                command.arg("-w").arg(server_id);
                 command.arg("-G").arg(key);
-                command.arg("-c").arg(format!("ships/{}", server_id));
+                command.arg("-c").arg(&pier);
             }
-            command.arg("--http-port").arg(&port.to_string());
-            TmuxManager::send_command(&server_id, &command)?;
+            command.arg("--http-port").arg(port.to_string());
+            TmuxManager::send_command(server_id, &command)?;
             // save args to file
             let mut args = Vec::new();
-            args.push(format!("server_id: {}", server_id.to_string()));
-            args.push(format!("urbit_port: {}", port.to_string()));
+            args.push(format!("server_id: {}", server_id));
+            args.push(format!("urbit_port: {}", port));
             args.push(format!("all: {:?}", command.get_args().collect::<Vec<_>>()));
             if fake {
                 self.fake_to_file(server_id)?;
@@ -180,27 +190,28 @@ impl Instance for UrbitInstance {
         // Check if a session is running
         let is_running = TmuxManager::is_session_running(server_id);
         if !is_running {
-            TmuxManager::create_session(&server_id, None)?;
-            let symlinked_urbit = symlink_urbit_binary(server_id.to_string())?;
-            let mut command = Command::new(format!("./{}", symlinked_urbit));
+            TmuxManager::create_session(server_id, None)?;
+            let symlinked_urbit = symlink_urbit_binary(&self.data, server_id.to_string())?;
+            let mut command = Command::new(&symlinked_urbit);
 
             // check if a folder with the server ID exists
-            if !Path::new(format!("ships/{}", server_id).as_str()).exists() {
+            let pier = self.data.pier(server_id);
+            if !pier.exists() {
                 print_to_cli(format!("Identity {} is not booted", server_id));
             } else {
-                command.arg(format!("ships/{}", server_id));
+                command.arg(&pier);
             }
 
-            command.arg("--http-port").arg(&port.to_string());
-            TmuxManager::send_command(&server_id, &command)?;
+            command.arg("--http-port").arg(port.to_string());
+            TmuxManager::send_command(server_id, &command)?;
             print_to_cli(format!(
                 "Started urbit instance with args: {:?}",
                 command.get_args().collect::<Vec<_>>()
             ));
             // save args to file
             let mut args = Vec::new();
-            args.push(format!("server_id: {}", server_id.to_string()));
-            args.push(format!("urbit_port: {}", port.to_string()));
+            args.push(format!("server_id: {}", server_id));
+            args.push(format!("urbit_port: {}", port));
             args.push(format!("all: {:?}", command.get_args().collect::<Vec<_>>()));
             let args = args.join("\n");
             self.args_to_file(server_id, &args)?;
@@ -211,7 +222,7 @@ impl Instance for UrbitInstance {
     }
 
     fn stop(&self, server_id: &str, port: u16) -> io::Result<()> {
-        TmuxManager::terminate_session(&server_id)?;
+        TmuxManager::terminate_session(server_id)?;
         self.clear_params_file(server_id)?;
         print_to_cli(format!(
             "Stopped Urbit instance with server ID {} on port {}",
@@ -239,7 +250,10 @@ impl Instance for UrbitInstance {
     }
 
     fn upgrade(&self, server_id: &str, options: Self::UpdateOptions) -> std::io::Result<()> {
-        println!("{}, update_all={:?}", server_id, options.update_all);
+        println!(
+            "{}, update_all={:?}, update_urbit={:?}, update_vere={:?}",
+            server_id, options.update_all, options.update_urbit, options.update_vere
+        );
         Ok(())
     }
 
@@ -264,8 +278,9 @@ mod tests {
     use super::*;
 
     #[test]
+    #[ignore = "downloads and boots a real urbit binary"]
     fn test_urbit_instance() {
-        let urbit = UrbitInstance;
+        let urbit = UrbitInstance::new(DataRoot::new("."));
         let options = UrbitUpdateOptions {
             update_all: Some(false),
             update_urbit: Some(false),
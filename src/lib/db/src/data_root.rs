@@ -0,0 +1,223 @@
+//!
+//! the data root: where a holon keeps everything it writes to disk
+//!
+//! layout:
+//!   <root>/urbit                   urbit binary (installed by `hol install`)
+//!   <root>/ships/<id>              ship piers
+//!   <root>/ships/.<id>.params      args the instance was last started with
+//!   <root>/db/<name>.sqlite        sqlite databases
+//!   <root>/logs/<id>/node.log      output of the node (piped from its tmux session)
+//!   <root>/archive/<id>            packets expired by retention (gzipped JSONL)
+//!   <root>/media/<id>              media cached for chat (see urbit-api's `media`)
+//!
+//! the root is taken from (first match wins):
+//!   1) the `--data-dir` flag
+//!   2) the `HOLIUM_DATA_DIR` env var
+//!   3) `data_dir = <path>` in the config file. the config file is `HOLIUM_CONFIG`, or
+//!      `$XDG_CONFIG_HOME/holium/config` (`~/.config/holium/config`)
+//!   4) `$XDG_DATA_HOME/holium` (`~/.local/share/holium`)
+//!
+//! several holons can share a root (piers and databases are per id) or each use their own.
+//!
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const DATA_DIR_ENV: &str = "HOLIUM_DATA_DIR";
+pub const CONFIG_ENV: &str = "HOLIUM_CONFIG";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataRoot {
+    root: PathBuf,
+}
+
+impl DataRoot {
+    pub fn new(root: impl Into<PathBuf>) -> DataRoot {
+        DataRoot { root: root.into() }
+    }
+
+    /// resolve the root from the flag (if given), environment, config file or default
+    pub fn resolve(flag: Option<&Path>) -> Result<DataRoot> {
+        DataRoot::resolve_with(flag, |key| std::env::var(key).ok())
+    }
+
+    /// `resolve` with the environment supplied by `env`
+    pub fn resolve_with(
+        flag: Option<&Path>,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<DataRoot> {
+        if let Some(flag) = flag {
+            return Ok(DataRoot::new(flag));
+        }
+        if let Some(dir) = env(DATA_DIR_ENV).filter(|dir| !dir.is_empty()) {
+            return Ok(DataRoot::new(dir));
+        }
+
+        let home = env("HOME").filter(|home| !home.is_empty());
+        let xdg = |key: &str, fallback: &str| -> Option<PathBuf> {
+            env(key)
+                .filter(|dir| !dir.is_empty())
+                .map(PathBuf::from)
+                .or_else(|| home.as_ref().map(|home| Path::new(home).join(fallback)))
+        };
+
+        let config = env(CONFIG_ENV)
+            .filter(|file| !file.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| xdg("XDG_CONFIG_HOME", ".config").map(|dir| dir.join("holium/config")));
+        if let Some(config) = config.filter(|config| config.exists()) {
+            if let Some(dir) = read_config(&config)? {
+                return Ok(DataRoot::new(dir));
+            }
+        }
+
+        match xdg("XDG_DATA_HOME", ".local/share") {
+            Some(dir) => Ok(DataRoot::new(dir.join("holium"))),
+            None => bail!(
+                "libdb: [data root] no data directory. pass --data-dir or set {}",
+                DATA_DIR_ENV
+            ),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn urbit_binary(&self) -> PathBuf {
+        self.root.join("urbit")
+    }
+
+    pub fn ships(&self) -> PathBuf {
+        self.root.join("ships")
+    }
+
+    pub fn pier(&self, server_id: &str) -> PathBuf {
+        self.ships().join(server_id)
+    }
+
+    pub fn params_file(&self, server_id: &str) -> PathBuf {
+        self.ships().join(format!(".{}.params", server_id))
+    }
+
+    pub fn fake_file(&self, server_id: &str) -> PathBuf {
+        self.ships().join(format!(".{}.fake", server_id))
+    }
+
+    pub fn database(&self, name: &str) -> PathBuf {
+        self.root.join("db").join(format!("{}.sqlite", name))
+    }
+
+    pub fn logs(&self, server_id: &str) -> PathBuf {
+        self.root.join("logs").join(server_id)
+    }
+
+    pub fn archive(&self, server_id: &str) -> PathBuf {
+        self.root.join("archive").join(server_id)
+    }
+
+    pub fn media(&self, server_id: &str) -> PathBuf {
+        self.root.join("media").join(server_id)
+    }
+
+    /// create the root and its folders
+    pub fn ensure(&self) -> Result<()> {
+        for dir in [self.ships(), self.root.join("db"), self.root.join("logs")] {
+            fs::create_dir_all(&dir)
+                .with_context(|| format!("libdb: [data root] cannot create {}", dir.display()))?;
+        }
+        Ok(())
+    }
+}
+
+// the config file is `key = value` lines. blank lines and lines starting with # are ignored
+fn read_config(file: &Path) -> Result<Option<PathBuf>> {
+    let config = fs::read_to_string(file)
+        .with_context(|| format!("libdb: [data root] cannot read {}", file.display()))?;
+    for line in config.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            bail!(
+                "libdb: [data root] invalid line in {}: {}",
+                file.display(),
+                line
+            );
+        };
+        if key.trim() == "data_dir" {
+            let value = value.trim().trim_matches('"');
+            if !value.is_empty() {
+                return Ok(Some(PathBuf::from(value)));
+            }
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn resolve(flag: Option<&str>, env: &[(&str, &str)]) -> Result<DataRoot> {
+        let env: HashMap<String, String> = env
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        DataRoot::resolve_with(flag.map(Path::new), |key| env.get(key).cloned())
+    }
+
+    #[test]
+    fn root_is_resolved_in_order() {
+        let dir = std::env::temp_dir().join(format!("holium-data-root-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let config = dir.join("config");
+        fs::write(&config, "# holon settings\n\ndata_dir = \"/srv/holium\"\n").unwrap();
+        let config = config.to_str().unwrap();
+
+        let env = [
+            ("HOME", "/home/zod"),
+            (CONFIG_ENV, config),
+            (DATA_DIR_ENV, "/var/lib/holium"),
+        ];
+        assert_eq!(
+            resolve(Some("/tmp/flag"), &env).unwrap().path(),
+            Path::new("/tmp/flag")
+        );
+        assert_eq!(
+            resolve(None, &env).unwrap().path(),
+            Path::new("/var/lib/holium")
+        );
+        assert_eq!(
+            resolve(None, &env[..2]).unwrap().path(),
+            Path::new("/srv/holium")
+        );
+        assert_eq!(
+            resolve(None, &env[..1]).unwrap().path(),
+            Path::new("/home/zod/.local/share/holium")
+        );
+        assert_eq!(
+            resolve(None, &[("HOME", "/home/zod"), ("XDG_DATA_HOME", "/data")])
+                .unwrap()
+                .path(),
+            Path::new("/data/holium")
+        );
+        assert!(resolve(None, &[]).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn layout_is_under_the_root() {
+        let data = DataRoot::new("/srv/holium");
+        assert_eq!(data.pier("zod"), Path::new("/srv/holium/ships/zod"));
+        assert_eq!(
+            data.params_file("zod"),
+            Path::new("/srv/holium/ships/.zod.params")
+        );
+        assert_eq!(data.database("zod"), Path::new("/srv/holium/db/zod.sqlite"));
+        assert_eq!(data.logs("zod"), Path::new("/srv/holium/logs/zod"));
+        assert_eq!(data.media("zod"), Path::new("/srv/holium/media/zod"));
+    }
+}
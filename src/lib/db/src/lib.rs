@@ -1,43 +1,249 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
 
 use r2d2::{Pool, PooledConnection};
 use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::Connection;
+
+pub mod data_root;
+pub mod migrations;
+pub mod retention;
+
+pub use data_root::DataRoot;
+
+/// @see: https://www.sqlite.org/pragma.html#pragma_synchronous
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Synchronous {
+    Off,
+    Normal,
+    Full,
+    Extra,
+}
+
+impl Synchronous {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Synchronous::Off => "OFF",
+            Synchronous::Normal => "NORMAL",
+            Synchronous::Full => "FULL",
+            Synchronous::Extra => "EXTRA",
+        }
+    }
+}
+
+/// runs against every new connection, after the options below have been applied
+pub type InitHook = Arc<dyn Fn(&Connection) -> rusqlite::Result<()> + Send + Sync>;
+
+/// how pooled connections are set up. the defaults suit the node: WAL (readers do not
+///  block the writer), NORMAL sync (safe with WAL), a busy timeout so that concurrent
+///  writers wait instead of failing with SQLITE_BUSY, and enforced foreign keys
+#[derive(Clone)]
+pub struct DbOptions {
+    pub wal: bool,
+    pub synchronous: Synchronous,
+    pub busy_timeout: Duration,
+    pub foreign_keys: bool,
+    /// idle connections kept open. None keeps max_size connections
+    pub min_idle: Option<u32>,
+    pub max_size: u32,
+    pub init: Option<InitHook>,
+}
+
+impl Default for DbOptions {
+    fn default() -> DbOptions {
+        DbOptions {
+            wal: true,
+            synchronous: Synchronous::Normal,
+            busy_timeout: Duration::from_secs(5),
+            foreign_keys: true,
+            min_idle: None,
+            max_size: 10,
+            init: None,
+        }
+    }
+}
+
+impl DbOptions {
+    fn manager(&self, manager: SqliteConnectionManager) -> SqliteConnectionManager {
+        let options = self.clone();
+        manager.with_init(move |conn| {
+            conn.busy_timeout(options.busy_timeout)?;
+            if options.wal {
+                // answers with the resulting mode, so it cannot go thru execute
+                conn.query_row("PRAGMA journal_mode = WAL", [], |_| Ok(()))?;
+            }
+            conn.execute_batch(&format!(
+                "PRAGMA synchronous = {}; PRAGMA foreign_keys = {};",
+                options.synchronous.as_str(),
+                if options.foreign_keys { "ON" } else { "OFF" }
+            ))?;
+            if let Some(init) = &options.init {
+                init(conn)?;
+            }
+            Ok(())
+        })
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct DbPool {
     pub pool: Pool<SqliteConnectionManager>,
+    // a pool of one connection that write-heavy paths share, so that they queue up
+    //  here rather than contend for sqlite's write lock. None for in-memory databases,
+    //  where every connection is its own database
+    pub writer: Option<Pool<SqliteConnectionManager>>,
 }
 
 impl DbPool {
+    /// `db_name` is ":memory:" or the name of a database in the data root
     pub fn new(db_name: &str) -> DbPool {
-        let mut db_path = db_name.to_string();
-        if db_path != ":memory:" {
-            db_path = format!("src/lib/db/data/{}.sqlite", db_name);
-        }
-        let manager = SqliteConnectionManager::file(db_path.as_str());
-        let pool = Pool::new(manager);
+        let pool = initialize_pool(db_name);
         if pool.is_err() {
-            panic!("libdb: [new] Pool::new call failed");
+            panic!("libdb: [new] Pool::new call failed. {:?}", pool.err());
         }
-        DbPool {
-            pool: pool.unwrap(),
+        pool.unwrap()
+    }
+
+    /// open (creating if needed) the database file at `path` with the default options
+    pub fn open(path: &Path) -> Result<DbPool> {
+        DbPool::open_with(path, &DbOptions::default())
+    }
+
+    pub fn open_with(path: &Path, options: &DbOptions) -> Result<DbPool> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("libdb: [open] cannot create {}", dir.display()))?;
         }
+        let pool = Pool::builder()
+            .max_size(options.max_size)
+            .min_idle(options.min_idle)
+            .build(options.manager(SqliteConnectionManager::file(path)))?;
+        let writer = Pool::builder()
+            .max_size(1)
+            .build(options.manager(SqliteConnectionManager::file(path)))?;
+        Ok(DbPool {
+            pool,
+            writer: Some(writer),
+        })
     }
 
     pub fn get_conn(&self) -> Result<PooledConnection<SqliteConnectionManager>> {
         let pool = self.pool.get()?;
-        return Ok(pool);
+        Ok(pool)
+    }
+
+    /// the dedicated writer connection (or a pooled one if there is none). callers
+    ///  wait their turn for it
+    pub fn get_writer(&self) -> Result<PooledConnection<SqliteConnectionManager>> {
+        match &self.writer {
+            Some(writer) => Ok(writer.get()?),
+            None => self.get_conn(),
+        }
     }
 }
 
 // A function to establish a connection pool to the SQLite database.
+//  `db_name` is ":memory:" or the name of a database in the data root
 pub fn initialize_pool(db_name: &str) -> Result<DbPool> {
-    let mut db_path = db_name.to_string();
-    if db_path != ":memory:" {
-        db_path = format!("src/lib/db/data/{}.sqlite", db_name);
-    }
-    let manager = SqliteConnectionManager::file(db_path.as_str());
-    Ok(DbPool {
-        pool: Pool::new(manager)?,
-    })
+    if db_name == ":memory:" {
+        let options = DbOptions::default();
+        let manager = options.manager(SqliteConnectionManager::memory());
+        return Ok(DbPool {
+            pool: Pool::new(manager)?,
+            writer: None,
+        });
+    }
+    DbPool::open(&DataRoot::resolve(None)?.database(db_name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // a fresh database file under the system temp dir
+    fn temp_db(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("bedrock-db-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir.join("test.sqlite")
+    }
+
+    fn pragma<T: rusqlite::types::FromSql>(conn: &Connection, name: &str) -> T {
+        conn.query_row(&format!("PRAGMA {}", name), [], |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn connections_are_tuned_by_options() {
+        let path = temp_db("options");
+        let inits = Arc::new(AtomicUsize::new(0));
+        let counter = inits.clone();
+        let options = DbOptions {
+            synchronous: Synchronous::Full,
+            busy_timeout: Duration::from_millis(1234),
+            min_idle: Some(1),
+            max_size: 2,
+            init: Some(Arc::new(move |conn: &Connection| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                conn.execute_batch("PRAGMA cache_size = -4000")
+            })),
+            ..DbOptions::default()
+        };
+        let db = DbPool::open_with(&path, &options).unwrap();
+
+        for conn in [db.get_conn().unwrap(), db.get_writer().unwrap()] {
+            assert_eq!(pragma::<String>(&conn, "journal_mode"), "wal");
+            // 2 = FULL
+            assert_eq!(pragma::<i64>(&conn, "synchronous"), 2);
+            assert_eq!(pragma::<i64>(&conn, "busy_timeout"), 1234);
+            assert_eq!(pragma::<i64>(&conn, "foreign_keys"), 1);
+            assert_eq!(pragma::<i64>(&conn, "cache_size"), -4000);
+        }
+        assert!(inits.load(Ordering::SeqCst) >= 2);
+        assert_eq!(db.pool.max_size(), 2);
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn concurrent_writers_do_not_fail() {
+        let path = temp_db("writers");
+        let db = DbPool::open(&path).unwrap();
+        db.get_writer()
+            .unwrap()
+            .execute_batch("create table t (n INTEGER)")
+            .unwrap();
+
+        let threads: Vec<_> = (0..4)
+            .map(|i| {
+                let db = db.clone();
+                std::thread::spawn(move || {
+                    for n in 0..50 {
+                        // half the writers go thru the pool, relying on the busy timeout
+                        let conn = match i % 2 {
+                            0 => db.get_writer().unwrap(),
+                            _ => db.get_conn().unwrap(),
+                        };
+                        conn.execute("INSERT INTO t (n) VALUES (?1)", [n]).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let count: i64 = db
+            .get_conn()
+            .unwrap()
+            .query_row("SELECT COUNT(*) FROM t", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 200);
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
 }
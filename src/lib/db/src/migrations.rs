@@ -0,0 +1,207 @@
+//!
+//! versioned schema migrations
+//!
+//! each module that owns tables (bedrock, chat, ws, ...) registers a list of migrations
+//!  embedded in the binary with `include_str!`. the versions applied to a database are
+//!  tracked per module in the `schema_migrations` table. the pending migrations of a
+//!  module are applied in version order in one transaction, so a module's schema is
+//!  either fully migrated or left as it was.
+//!
+use anyhow::{bail, Context, Result};
+use std::time::SystemTime;
+
+use crate::DbPool;
+
+/// a single schema change. `version` is unique within its module and migrations are
+///  applied in ascending version order
+#[derive(Debug, Clone, Copy)]
+pub struct Migration {
+    pub version: u32,
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+/// the tables owned by bedrock-db itself
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial",
+        sql: include_str!("migrations/0001_initial.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "packets_source_index",
+        sql: include_str!("migrations/0002_packets_source_index.sql"),
+    },
+];
+
+const SCHEMA_MIGRATIONS: &str = "
+create table if not exists schema_migrations
+(
+    module       TEXT    NOT NULL,
+    version      INTEGER NOT NULL,
+    name         TEXT    NOT NULL,
+    /* when the migration was applied (ms since epoch) */
+    applied_at   INTEGER NOT NULL,
+    PRIMARY KEY (module, version)
+);";
+
+/// apply the migrations of `module` that have not been applied yet. returns the
+///  versions applied by this call (empty if the module was up to date)
+pub fn migrate(pool: &DbPool, module: &str, migrations: &[Migration]) -> Result<Vec<u32>> {
+    if migrations.windows(2).any(|w| w[0].version >= w[1].version) {
+        bail!("libdb: [migrate] '{}' versions must be ascending", module);
+    }
+
+    let mut conn = pool.get_conn()?;
+    conn.execute_batch(SCHEMA_MIGRATIONS)?;
+
+    let tx = conn.transaction()?;
+    let applied = query_versions(&tx, module)?;
+
+    // a database migrated by a newer build. running older code against it is unsafe
+    let latest = migrations.last().map_or(0, |m| m.version);
+    if let Some(version) = applied.iter().find(|v| **v > latest) {
+        bail!(
+            "libdb: [migrate] '{}' is at version {}, which this build does not know about",
+            module,
+            version
+        );
+    }
+
+    let ts = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
+    let mut pending = Vec::new();
+    for migration in migrations {
+        if applied.contains(&migration.version) {
+            continue;
+        }
+        tx.execute_batch(migration.sql).with_context(|| {
+            format!(
+                "libdb: [migrate] {} {:04}_{} failed",
+                module, migration.version, migration.name
+            )
+        })?;
+        tx.execute(
+            "INSERT INTO schema_migrations (
+              module,
+              version,
+              name,
+              applied_at
+            ) VALUES (
+              ?1,
+              ?2,
+              ?3,
+              ?4
+            )",
+            (
+                module,
+                migration.version,
+                migration.name,
+                ts.as_millis() as i64,
+            ),
+        )?;
+        pending.push(migration.version);
+    }
+    tx.commit()?;
+
+    Ok(pending)
+}
+
+/// versions of `module` applied to the database, in ascending order
+pub fn applied_versions(pool: &DbPool, module: &str) -> Result<Vec<u32>> {
+    let conn = pool.get_conn()?;
+    conn.execute_batch(SCHEMA_MIGRATIONS)?;
+    query_versions(&conn, module)
+}
+
+fn query_versions(conn: &rusqlite::Connection, module: &str) -> Result<Vec<u32>> {
+    let mut stmt =
+        conn.prepare("SELECT version FROM schema_migrations WHERE module = ?1 ORDER BY version")?;
+    let rows = stmt.query_map([module], |row| row.get::<_, u32>(0))?;
+    let mut versions = Vec::new();
+    for row in rows {
+        versions.push(row?);
+    }
+    Ok(versions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use r2d2::Pool;
+    use r2d2_sqlite::SqliteConnectionManager;
+
+    // a single connection, so every caller sees the same in-memory database
+    fn memory_pool() -> DbPool {
+        let pool = Pool::builder()
+            .max_size(1)
+            .build(SqliteConnectionManager::memory())
+            .unwrap();
+        DbPool { pool, writer: None }
+    }
+
+    const TEST_MIGRATIONS: &[Migration] = &[
+        Migration {
+            version: 1,
+            name: "notes",
+            sql: "create table notes (id INTEGER PRIMARY KEY, body TEXT);",
+        },
+        Migration {
+            version: 2,
+            name: "notes_author",
+            sql: "alter table notes add column author TEXT;",
+        },
+    ];
+
+    #[test]
+    fn pending_migrations_are_applied_once() {
+        let pool = memory_pool();
+        assert_eq!(migrate(&pool, "bedrock", MIGRATIONS).unwrap(), vec![1, 2]);
+        assert_eq!(
+            migrate(&pool, "test", &TEST_MIGRATIONS[..1]).unwrap(),
+            vec![1]
+        );
+        assert_eq!(migrate(&pool, "test", TEST_MIGRATIONS).unwrap(), vec![2]);
+        assert!(migrate(&pool, "test", TEST_MIGRATIONS).unwrap().is_empty());
+
+        assert_eq!(applied_versions(&pool, "test").unwrap(), vec![1, 2]);
+        assert_eq!(applied_versions(&pool, "bedrock").unwrap(), vec![1, 2]);
+        pool.get_conn()
+            .unwrap()
+            .execute("INSERT INTO notes (body, author) VALUES ('hi', '~zod')", [])
+            .unwrap();
+    }
+
+    #[test]
+    fn failed_migration_rolls_back_its_module() {
+        let pool = memory_pool();
+        let broken = [
+            TEST_MIGRATIONS[0],
+            Migration {
+                version: 2,
+                name: "broken",
+                sql: "alter table missing add column x TEXT;",
+            },
+        ];
+        let err = migrate(&pool, "test", &broken).unwrap_err();
+        assert!(err.to_string().contains("0002_broken"), "{}", err);
+
+        // the first migration was rolled back along with the broken one
+        assert!(applied_versions(&pool, "test").unwrap().is_empty());
+        assert!(pool
+            .get_conn()
+            .unwrap()
+            .execute("INSERT INTO notes (body) VALUES ('hi')", [])
+            .is_err());
+    }
+
+    #[test]
+    fn unknown_versions_are_rejected() {
+        let pool = memory_pool();
+        migrate(&pool, "test", TEST_MIGRATIONS).unwrap();
+        assert!(migrate(&pool, "test", &TEST_MIGRATIONS[..1]).is_err());
+
+        let unordered = [TEST_MIGRATIONS[1], TEST_MIGRATIONS[0]];
+        assert!(migrate(&memory_pool(), "test", &unordered).is_err());
+    }
+}
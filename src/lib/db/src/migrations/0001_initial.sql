@@ -0,0 +1,14 @@
+create table if not exists packets
+(
+    /* auto increment ROWID */
+    id           INTEGER PRIMARY KEY,
+    /* source of the packet: ws or ship */
+    source       TEXT,
+    /*
+        full action payload
+        @see: https://developers.urbit.org/reference/arvo/eyre/external-api-ref#actions
+    */
+    content      TEXT NOT NULL,
+    /* when the holon actually received the packet on the websocket channel */
+    received_at  INTEGER NOT NULL
+);
@@ -0,0 +1,2 @@
+/* retention expires packets per source, oldest first */
+create index if not exists packets_source on packets (source, received_at);
@@ -0,0 +1,464 @@
+//!
+//! retention for the packets table
+//!
+//! every websocket and ship packet is logged to `packets`. rules (per `source`) expire
+//!  rows by age and/or by keeping only the newest N rows. expired rows are optionally
+//!  archived to gzipped JSONL files before they are deleted, and the file is vacuumed
+//!  so that the space is given back.
+//!
+use anyhow::{bail, Context, Result};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::Serialize;
+use serde_json::value::RawValue;
+use std::fs;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::DbPool;
+
+// rows read (and archived to one file) at a time
+const BATCH_SIZE: i64 = 10_000;
+
+/// expire rows of one packet source. `source` "*" applies to every source
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetentionRule {
+    pub source: String,
+    /// rows received longer ago than this expire
+    pub max_age: Option<Duration>,
+    /// only the newest `max_rows` rows are kept
+    pub max_rows: Option<u64>,
+}
+
+/// parse `<source>=<spec>[,<spec>]` where a spec is an age (`30s`, `15m`, `12h`, `7d`)
+///  or a row count (`100000`). e.g. `ship=7d,1000000`
+impl FromStr for RetentionRule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<RetentionRule> {
+        let Some((source, specs)) = s.split_once('=') else {
+            bail!(
+                "libdb: [retention] expected <source>=<age|rows>, got '{}'",
+                s
+            );
+        };
+        let mut rule = RetentionRule {
+            source: source.trim().to_string(),
+            max_age: None,
+            max_rows: None,
+        };
+        if rule.source.is_empty() {
+            bail!("libdb: [retention] missing source in '{}'", s);
+        }
+        for spec in specs.split(',').map(str::trim) {
+            if let Ok(rows) = spec.parse::<u64>() {
+                rule.max_rows = Some(rows);
+                continue;
+            }
+            let unit = match spec.chars().last() {
+                Some('s') => 1,
+                Some('m') => 60,
+                Some('h') => 60 * 60,
+                Some('d') => 24 * 60 * 60,
+                _ => bail!("libdb: [retention] invalid age or row count '{}'", spec),
+            };
+            let n: u64 = spec[..spec.len() - 1]
+                .parse()
+                .with_context(|| format!("libdb: [retention] invalid age '{}'", spec))?;
+            rule.max_age = Some(Duration::from_secs(n * unit));
+        }
+        Ok(rule)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Vacuum {
+    Off,
+    /// rebuild the whole file. reclaims everything but rewrites the database
+    Full,
+    /// release up to this many free pages per run (0 = all). switches the database
+    ///  to auto_vacuum=INCREMENTAL on first use
+    Incremental(u32),
+}
+
+impl FromStr for Vacuum {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Vacuum> {
+        match s {
+            "off" => Ok(Vacuum::Off),
+            "full" => Ok(Vacuum::Full),
+            "incremental" => Ok(Vacuum::Incremental(0)),
+            _ => match s.strip_prefix("incremental:").map(str::parse) {
+                Some(Ok(pages)) => Ok(Vacuum::Incremental(pages)),
+                _ => bail!(
+                    "libdb: [retention] expected off, full, incremental or incremental:<pages>, got '{}'",
+                    s
+                ),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RetentionPolicy {
+    pub rules: Vec<RetentionRule>,
+    /// expired rows are written here (as gzipped JSONL) before they are deleted
+    pub archive_dir: Option<PathBuf>,
+    pub vacuum: Vacuum,
+    /// how often the retention job runs
+    pub interval: Duration,
+    /// minimum time between vacuums
+    pub vacuum_interval: Duration,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RetentionReport {
+    pub deleted: u64,
+    pub archives: Vec<PathBuf>,
+}
+
+/// delete (and archive) the rows expired by `policy` as of `now` (ms since epoch)
+pub fn apply_retention(
+    pool: &DbPool,
+    policy: &RetentionPolicy,
+    now: u64,
+) -> Result<RetentionReport> {
+    let mut report = RetentionReport::default();
+    for rule in &policy.rules {
+        let sources = match rule.source.as_str() {
+            "*" => packet_sources(pool)?,
+            source => vec![source.to_string()],
+        };
+        for source in sources {
+            expire(pool, policy, rule, &source, now, &mut report)?;
+        }
+    }
+    Ok(report)
+}
+
+/// give the space of deleted rows back to the file system
+pub fn vacuum(pool: &DbPool, vacuum: Vacuum) -> Result<()> {
+    let conn = pool.get_writer()?;
+    match vacuum {
+        Vacuum::Off => {}
+        Vacuum::Full => conn.execute_batch("VACUUM")?,
+        Vacuum::Incremental(pages) => {
+            // 2 = INCREMENTAL. changing auto_vacuum only takes effect after a full vacuum
+            let mode: i64 = conn.query_row("PRAGMA auto_vacuum", [], |row| row.get(0))?;
+            if mode != 2 {
+                conn.execute_batch("PRAGMA auto_vacuum = INCREMENTAL; VACUUM;")?;
+            }
+            let sql = match pages {
+                0 => "PRAGMA incremental_vacuum".to_string(),
+                pages => format!("PRAGMA incremental_vacuum({})", pages),
+            };
+            // the pragma returns a row per page freed. step thru them all
+            let mut stmt = conn.prepare(&sql)?;
+            let mut rows = stmt.query([])?;
+            while rows.next()?.is_some() {}
+        }
+    }
+    Ok(())
+}
+
+fn packet_sources(pool: &DbPool) -> Result<Vec<String>> {
+    let conn = pool.get_conn()?;
+    let mut stmt = conn.prepare("SELECT DISTINCT source FROM packets WHERE source IS NOT NULL")?;
+    let rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
+    let mut sources = Vec::new();
+    for row in rows {
+        sources.push(row?);
+    }
+    Ok(sources)
+}
+
+fn expire(
+    pool: &DbPool,
+    policy: &RetentionPolicy,
+    rule: &RetentionRule,
+    source: &str,
+    now: u64,
+    report: &mut RetentionReport,
+) -> Result<()> {
+    // rows older than the cutoff, or at/below the id of the newest row beyond max_rows
+    let cutoff = match rule.max_age {
+        Some(age) => now.saturating_sub(age.as_millis() as u64) as i64,
+        None => i64::MIN,
+    };
+    let last_id: i64 = match rule.max_rows {
+        Some(rows) => pool.get_conn()?.query_row(
+            "SELECT COALESCE((SELECT id FROM packets WHERE source = ?1
+                   ORDER BY id DESC LIMIT 1 OFFSET ?2), ?3)",
+            (source, rows as i64, i64::MIN),
+            |row| row.get(0),
+        )?,
+        None => i64::MIN,
+    };
+
+    loop {
+        let rows = expired_rows(pool, source, cutoff, last_id)?;
+        if rows.is_empty() {
+            return Ok(());
+        }
+
+        // archive first. if that fails the rows stay put and are retried next run
+        if let Some(dir) = &policy.archive_dir {
+            report.archives.push(archive(dir, source, &rows)?);
+        }
+
+        let mut conn = pool.get_writer()?;
+        let tx = conn.transaction()?;
+        let deleted = tx.execute(
+            "DELETE FROM packets WHERE source = ?1 AND id >= ?2 AND id <= ?3
+               AND (received_at < ?4 OR id <= ?5)",
+            (source, rows[0].id, rows[rows.len() - 1].id, cutoff, last_id),
+        )?;
+        tx.commit()?;
+        report.deleted += deleted as u64;
+
+        if (rows.len() as i64) < BATCH_SIZE {
+            return Ok(());
+        }
+    }
+}
+
+struct PacketRow {
+    id: i64,
+    source: String,
+    content: String,
+    received_at: i64,
+}
+
+fn expired_rows(pool: &DbPool, source: &str, cutoff: i64, last_id: i64) -> Result<Vec<PacketRow>> {
+    let conn = pool.get_conn()?;
+    let mut stmt = conn.prepare(
+        "SELECT id, source, content, received_at FROM packets
+           WHERE source = ?1 AND (received_at < ?2 OR id <= ?3)
+           ORDER BY id LIMIT ?4",
+    )?;
+    let rows = stmt.query_map((source, cutoff, last_id, BATCH_SIZE), |row| {
+        Ok(PacketRow {
+            id: row.get(0)?,
+            source: row.get(1)?,
+            content: row.get(2)?,
+            received_at: row.get(3)?,
+        })
+    })?;
+    let mut packets = Vec::new();
+    for row in rows {
+        packets.push(row?);
+    }
+    Ok(packets)
+}
+
+// one line of an archive
+#[derive(Serialize)]
+struct ArchivedRow<'a> {
+    id: i64,
+    source: &'a str,
+    received_at: i64,
+    content: ArchivedContent<'a>,
+}
+
+// content is json already, so it is embedded as is. anything else is kept as a string
+#[derive(Serialize)]
+#[serde(untagged)]
+enum ArchivedContent<'a> {
+    Json(&'a RawValue),
+    Text(&'a str),
+}
+
+// write rows to <dir>/packets-<source>-<first id>-<last id>.jsonl.gz
+fn archive(dir: &Path, source: &str, rows: &[PacketRow]) -> Result<PathBuf> {
+    fs::create_dir_all(dir)
+        .with_context(|| format!("libdb: [retention] cannot create {}", dir.display()))?;
+
+    let path = dir.join(format!(
+        "packets-{}-{}-{}.jsonl.gz",
+        source,
+        rows[0].id,
+        rows[rows.len() - 1].id
+    ));
+    let file = fs::File::create(&path)
+        .with_context(|| format!("libdb: [retention] cannot create {}", path.display()))?;
+    let mut gz = GzEncoder::new(BufWriter::new(file), Compression::default());
+    for row in rows {
+        let content = match serde_json::from_str(&row.content) {
+            Ok(json) => ArchivedContent::Json(json),
+            Err(_) => ArchivedContent::Text(&row.content),
+        };
+        let line = ArchivedRow {
+            id: row.id,
+            source: &row.source,
+            received_at: row.received_at,
+            content,
+        };
+        serde_json::to_writer(&mut gz, &line)?;
+        gz.write_all(b"\n")?;
+    }
+    let file = gz.finish()?.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use r2d2::Pool;
+    use r2d2_sqlite::SqliteConnectionManager;
+    use std::io::Read;
+
+    fn memory_pool() -> DbPool {
+        let pool = Pool::builder()
+            .max_size(1)
+            .build(SqliteConnectionManager::memory())
+            .unwrap();
+        let db = DbPool { pool, writer: None };
+        crate::migrations::migrate(&db, "bedrock", crate::migrations::MIGRATIONS).unwrap();
+        db
+    }
+
+    fn insert(pool: &DbPool, source: &str, received_at: i64, n: usize) {
+        let conn = pool.get_conn().unwrap();
+        for i in 0..n {
+            conn.execute(
+                "INSERT INTO packets (source, content, received_at) VALUES (?1, ?2, ?3)",
+                (source, format!("{{\"n\":{}}}", i), received_at),
+            )
+            .unwrap();
+        }
+    }
+
+    fn count(pool: &DbPool, source: &str) -> i64 {
+        pool.get_conn()
+            .unwrap()
+            .query_row(
+                "SELECT COUNT(*) FROM packets WHERE source = ?1",
+                [source],
+                |row| row.get(0),
+            )
+            .unwrap()
+    }
+
+    fn policy(rules: &[&str]) -> RetentionPolicy {
+        RetentionPolicy {
+            rules: rules.iter().map(|rule| rule.parse().unwrap()).collect(),
+            archive_dir: None,
+            vacuum: Vacuum::Off,
+            interval: Duration::from_secs(60),
+            vacuum_interval: Duration::from_secs(60),
+        }
+    }
+
+    #[test]
+    fn rules_parse() {
+        assert_eq!(
+            "ship=7d,1000".parse::<RetentionRule>().unwrap(),
+            RetentionRule {
+                source: "ship".to_string(),
+                max_age: Some(Duration::from_secs(7 * 24 * 60 * 60)),
+                max_rows: Some(1000),
+            }
+        );
+        assert_eq!(
+            "*=90s".parse::<RetentionRule>().unwrap().max_age,
+            Some(Duration::from_secs(90))
+        );
+        for bad in ["ship", "=7d", "ship=7w", "ship=d"] {
+            assert!(bad.parse::<RetentionRule>().is_err(), "{}", bad);
+        }
+        assert_eq!(
+            "incremental:100".parse::<Vacuum>().unwrap(),
+            Vacuum::Incremental(100)
+        );
+        assert!("sometimes".parse::<Vacuum>().is_err());
+    }
+
+    #[test]
+    fn rows_expire_by_age_and_count_per_source() {
+        let pool = memory_pool();
+        insert(&pool, "ship", 1_000, 5);
+        insert(&pool, "ship", 9_000, 5);
+        insert(&pool, "ws", 1_000, 5);
+
+        // ship: older than 5s as of 10s
+        let report = apply_retention(&pool, &policy(&["ship=5s"]), 10_000).unwrap();
+        assert_eq!(report.deleted, 5);
+        assert_eq!((count(&pool, "ship"), count(&pool, "ws")), (5, 5));
+
+        // ...then only the newest 2 of each source
+        let report = apply_retention(&pool, &policy(&["*=2"]), 10_000).unwrap();
+        assert_eq!(report.deleted, 6);
+        assert_eq!((count(&pool, "ship"), count(&pool, "ws")), (2, 2));
+        let newest: i64 = pool
+            .get_conn()
+            .unwrap()
+            .query_row(
+                "SELECT MIN(id) FROM packets WHERE source = 'ship'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(newest, 9);
+
+        // nothing left to expire
+        assert_eq!(
+            apply_retention(&pool, &policy(&["*=2"]), 10_000).unwrap(),
+            RetentionReport::default()
+        );
+    }
+
+    #[test]
+    fn expired_rows_are_archived() {
+        let pool = memory_pool();
+        insert(&pool, "ws", 1_000, 2);
+        // content that is not json is archived as a string
+        pool.get_conn()
+            .unwrap()
+            .execute(
+                "INSERT INTO packets (source, content, received_at) VALUES ('ws', ?1, 1000)",
+                ["a \"quoted\"\u{7f} line\n"],
+            )
+            .unwrap();
+        insert(&pool, "ws", 1_000, 1);
+        let dir = std::env::temp_dir().join(format!("bedrock-archive-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let mut policy = policy(&["ws=1"]);
+        policy.archive_dir = Some(dir.clone());
+        let report = apply_retention(&pool, &policy, 10_000).unwrap();
+        assert_eq!(report.deleted, 3);
+        assert_eq!(report.archives, vec![dir.join("packets-ws-1-3.jsonl.gz")]);
+
+        let mut jsonl = String::new();
+        flate2::read::GzDecoder::new(fs::File::open(&report.archives[0]).unwrap())
+            .read_to_string(&mut jsonl)
+            .unwrap();
+        assert_eq!(
+            jsonl,
+            "{\"id\":1,\"source\":\"ws\",\"received_at\":1000,\"content\":{\"n\":0}}\n\
+             {\"id\":2,\"source\":\"ws\",\"received_at\":1000,\"content\":{\"n\":1}}\n\
+             {\"id\":3,\"source\":\"ws\",\"received_at\":1000,\"content\":\"a \\\"quoted\\\"\u{7f} line\\n\"}\n"
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn incremental_vacuum_switches_mode() {
+        let pool = memory_pool();
+        insert(&pool, "ws", 1_000, 100);
+        apply_retention(&pool, &policy(&["ws=0"]), 10_000).unwrap();
+        vacuum(&pool, Vacuum::Incremental(0)).unwrap();
+        let mode: i64 = pool
+            .get_conn()
+            .unwrap()
+            .query_row("PRAGMA auto_vacuum", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(mode, 2);
+        vacuum(&pool, Vacuum::Full).unwrap();
+    }
+}
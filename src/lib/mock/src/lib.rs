@@ -0,0 +1,331 @@
+//! # eyre-mock
+//!
+//! a local stand-in for a ship's Eyre HTTP interface so that `urbit-api` and the
+//! node can be tested without booting a ship.
+//!
+//! supports:
+//!   - `POST /~/login` - issues `urbauth-~<ship>` session cookies for the ship's +code
+//!   - `PUT|POST /~/channel/{uid}` - channel actions (poke, subscribe, unsubscribe, ack, delete)
+//!   - `GET /~/channel/{uid}` - SSE event stream with `Last-Event-ID` replay of un-acked events
+//!   - `GET /~/scry/...` - json fixtures registered with [`MockEyre::add_scry`], plus
+//!     `%holon`'s `/valid-cookie/{cookie}` check
+//!
+//! faults (403s, expired sessions, arbitrary status codes) can be injected at any time.
+//!
+//! the server runs on its own thread and runtime, so blocking calls made by the code
+//! under test (e.g. `EventSource` receivers) cannot starve it.
+
+mod routes;
+mod state;
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+use serde_json::Value as JsonValue;
+use tokio::sync::oneshot;
+
+use crate::routes::SharedState;
+use crate::state::{Fault, State};
+
+/// default reconnect delay (ms) advertised to SSE clients. short so that tests
+///  exercising reconnects do not wait on the 5 second EventSource default.
+const DEFAULT_SSE_RETRY: u64 = 100;
+
+pub struct MockEyre {
+    /// base url of the mock ship, e.g. `http://127.0.0.1:53211`
+    pub url: String,
+    pub addr: SocketAddr,
+    state: SharedState,
+    shutdown: Option<oneshot::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MockEyre {
+    /// start a mock ship named `ship` (with or without ~) that accepts `code` at
+    ///  `/~/login`. the server listens on an ephemeral port on 127.0.0.1
+    pub fn start(ship: &str, code: &str) -> MockEyre {
+        let mut state = State::new(ship, code);
+        state.sse_retry = Some(DEFAULT_SSE_RETRY);
+        let state: SharedState = Arc::new(Mutex::new(state));
+
+        let (addr_tx, addr_rx) = std::sync::mpsc::channel();
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let server_state = state.clone();
+
+        let thread = std::thread::spawn(move || {
+            let rt = tokio::runtime::Builder::new_multi_thread()
+                .worker_threads(2)
+                .enable_all()
+                .build()
+                .expect("eyre-mock: failed to build runtime");
+            rt.block_on(async move {
+                let (addr, server) =
+                    warp::serve(routes::routes(server_state)).bind_ephemeral(([127, 0, 0, 1], 0));
+                let _ = addr_tx.send(addr);
+                tokio::select! {
+                    _ = server => {},
+                    _ = shutdown_rx => {},
+                }
+            });
+        });
+
+        let addr = addr_rx.recv().expect("eyre-mock: server failed to start");
+
+        MockEyre {
+            url: format!("http://{}", addr),
+            addr,
+            state,
+            shutdown: Some(shutdown_tx),
+            thread: Some(thread),
+        }
+    }
+
+    /// the ship's name without the leading ~
+    pub fn ship(&self) -> String {
+        self.state.lock().unwrap().ship.clone()
+    }
+
+    /// the most recent set-cookie value issued by `/~/login`
+    pub fn cookie(&self) -> Option<String> {
+        self.state.lock().unwrap().last_cookie.clone()
+    }
+
+    /// number of successful logins since the mock started
+    pub fn logins(&self) -> usize {
+        self.state.lock().unwrap().logins
+    }
+
+    /// register a scry fixture. `path` is everything after `/~/scry/`,
+    ///  e.g. `docket/our.json`
+    pub fn add_scry(&self, path: &str, data: JsonValue) {
+        self.state
+            .lock()
+            .unwrap()
+            .scries
+            .insert(path.trim_start_matches('/').to_string(), data);
+    }
+
+    /// invalidate every issued cookie and close all open event streams. the next
+    ///  authenticated request fails with 403 until the client logs in again
+    pub fn expire_sessions(&self) {
+        self.state.lock().unwrap().expire_sessions();
+    }
+
+    /// answer the next request whose path starts with `prefix` (e.g. `/~/scry`)
+    ///  with `status`. faults queue up and are consumed in order
+    pub fn fail_next(&self, prefix: &str, status: u16) {
+        self.state.lock().unwrap().faults.push(Fault {
+            prefix: prefix.to_string(),
+            status,
+        });
+    }
+
+    /// nack every subsequent poke to `app` with `err`
+    pub fn nack_pokes(&self, app: &str, err: &str) {
+        self.state
+            .lock()
+            .unwrap()
+            .nacks
+            .insert(app.to_string(), err.to_string());
+    }
+
+    /// uids of all open channels
+    pub fn channels(&self) -> Vec<String> {
+        self.state
+            .lock()
+            .unwrap()
+            .channels
+            .keys()
+            .cloned()
+            .collect()
+    }
+
+    /// every action received on channel `uid`, in order of arrival
+    pub fn actions(&self, uid: &str) -> Vec<JsonValue> {
+        self.state
+            .lock()
+            .unwrap()
+            .channels
+            .get(uid)
+            .map(|channel| channel.actions.clone())
+            .unwrap_or_default()
+    }
+
+    /// highest event id acked on channel `uid`
+    pub fn last_ack(&self, uid: &str) -> Option<u64> {
+        self.state
+            .lock()
+            .unwrap()
+            .channels
+            .get(uid)
+            .and_then(|channel| channel.last_ack)
+    }
+
+    /// number of events on channel `uid` still waiting for an ack
+    pub fn unacked(&self, uid: &str) -> usize {
+        self.state
+            .lock()
+            .unwrap()
+            .channels
+            .get(uid)
+            .map_or(0, |channel| channel.events.len())
+    }
+
+    /// active subscriptions on channel `uid` as (subscription id, app, path)
+    pub fn subscriptions(&self, uid: &str) -> Vec<(u64, String, String)> {
+        let state = self.state.lock().unwrap();
+        let mut subs: Vec<(u64, String, String)> = state
+            .channels
+            .get(uid)
+            .map(|channel| {
+                channel
+                    .subscriptions
+                    .iter()
+                    .map(|(id, (app, path))| (*id, app.clone(), path.clone()))
+                    .collect()
+            })
+            .unwrap_or_default();
+        subs.sort();
+        subs
+    }
+
+    /// push a raw event onto channel `uid`. returns the SSE event id
+    pub fn push_event(&self, uid: &str, data: JsonValue) -> Option<u64> {
+        self.state
+            .lock()
+            .unwrap()
+            .channels
+            .get_mut(uid)
+            .map(|channel| channel.push(data))
+    }
+
+    /// send a fact to every subscription on app/path. returns the number of
+    ///  subscriptions it was delivered to
+    pub fn push_fact(&self, app: &str, path: &str, data: JsonValue) -> usize {
+        self.state.lock().unwrap().push_fact(app, path, &data)
+    }
+
+    /// kick every subscription on app/path
+    pub fn kick(&self, app: &str, path: &str) -> usize {
+        self.state.lock().unwrap().kick(app, path)
+    }
+}
+
+impl Drop for MockEyre {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn login_issues_cookie_for_code() {
+        let eyre = MockEyre::start("~zod", "lidlut-tabwed-pillex-ridrup");
+        let client = reqwest::blocking::Client::new();
+
+        let res = client
+            .post(format!("{}/~/login", eyre.url))
+            .body("password=wrong")
+            .send()
+            .unwrap();
+        assert_eq!(res.status().as_u16(), 400);
+
+        let res = client
+            .post(format!("{}/~/login", eyre.url))
+            .body("password=lidlut-tabwed-pillex-ridrup")
+            .send()
+            .unwrap();
+        assert_eq!(res.status().as_u16(), 204);
+        let cookie = res.headers().get("set-cookie").unwrap().to_str().unwrap();
+        assert!(cookie.starts_with("urbauth-~zod=0v"));
+        assert_eq!(eyre.cookie().as_deref(), Some(cookie));
+    }
+
+    #[test]
+    fn channel_actions_require_session() {
+        let eyre = MockEyre::start("zod", "code");
+        let client = reqwest::blocking::Client::new();
+        let body = json!([{"id": 1, "action": "poke", "ship": "zod", "app": "hood", "mark": "helm-hi", "json": "hi"}]);
+
+        let res = client
+            .put(format!("{}/~/channel/1", eyre.url))
+            .json(&body)
+            .send()
+            .unwrap();
+        assert_eq!(res.status().as_u16(), 403);
+
+        client
+            .post(format!("{}/~/login", eyre.url))
+            .body("password=code")
+            .send()
+            .unwrap();
+        let res = client
+            .put(format!("{}/~/channel/1", eyre.url))
+            .header("cookie", eyre.cookie().unwrap())
+            .json(&body)
+            .send()
+            .unwrap();
+        assert_eq!(res.status().as_u16(), 204);
+        assert_eq!(eyre.actions("1").len(), 1);
+        assert_eq!(eyre.unacked("1"), 1);
+
+        eyre.expire_sessions();
+        let res = client
+            .put(format!("{}/~/channel/1", eyre.url))
+            .header("cookie", eyre.cookie().unwrap())
+            .json(&body)
+            .send()
+            .unwrap();
+        assert_eq!(res.status().as_u16(), 403);
+    }
+
+    #[test]
+    fn faults_are_consumed_in_order() {
+        let eyre = MockEyre::start("zod", "code");
+        let client = reqwest::blocking::Client::new();
+        eyre.fail_next("/~/login", 500);
+
+        let login = || {
+            client
+                .post(format!("{}/~/login", eyre.url))
+                .body("password=code")
+                .send()
+                .unwrap()
+                .status()
+                .as_u16()
+        };
+        assert_eq!(login(), 500);
+        assert_eq!(login(), 204);
+    }
+
+    #[test]
+    fn events_are_replayed_until_acked() {
+        let mut state = State::new("zod", "code");
+        let subscribe =
+            json!({"id": 2, "action": "subscribe", "ship": "zod", "app": "chat-db", "path": "/db"});
+        state.apply_actions("1", vec![subscribe]);
+        assert_eq!(state.push_fact("chat-db", "/db", &json!({"a": 1})), 1);
+        assert_eq!(state.push_fact("chat-db", "/other", &json!({"a": 1})), 0);
+        assert_eq!(state.channels["1"].events.len(), 2);
+
+        state.apply_actions("1", vec![json!({"id": 3, "action": "ack", "event-id": 0})]);
+        assert_eq!(state.channels["1"].events.len(), 1);
+        assert_eq!(state.channels["1"].last_ack, Some(0));
+
+        assert_eq!(state.kick("chat-db", "/db"), 1);
+        assert!(state.channels["1"].subscriptions.is_empty());
+
+        state.apply_actions("1", vec![json!({"id": 4, "action": "delete"})]);
+        assert!(state.channels.is_empty());
+    }
+}
@@ -0,0 +1,203 @@
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures_util::StreamExt;
+use serde_json::{json, Value as JsonValue};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use warp::http::StatusCode;
+use warp::reply::Response;
+use warp::{reject, Filter, Rejection, Reply};
+
+use crate::state::State;
+
+pub type SharedState = Arc<Mutex<State>>;
+
+// raised when a fault has been injected for the request path
+#[derive(Debug)]
+struct InjectedFault(u16);
+impl reject::Reject for InjectedFault {}
+
+pub fn routes(
+    state: SharedState,
+) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone {
+    let with_state = warp::any().map(move || state.clone());
+
+    // POST /~/login
+    let login = warp::path!("~" / "login")
+        .and(warp::post())
+        .and(warp::body::bytes())
+        .and(with_state.clone())
+        .map(|body: bytes::Bytes, state: SharedState| handle_login(&body, &state));
+
+    // PUT|POST /~/channel/{uid}
+    let actions = warp::path!("~" / "channel" / String)
+        .and(warp::put().or(warp::post()).unify())
+        .and(warp::header::optional::<String>("cookie"))
+        .and(warp::body::bytes())
+        .and(with_state.clone())
+        .map(
+            |uid: String, cookie: Option<String>, body: bytes::Bytes, state: SharedState| {
+                handle_actions(&uid, cookie.as_deref(), &body, &state)
+            },
+        );
+
+    // GET /~/channel/{uid} -> SSE
+    let events = warp::path!("~" / "channel" / String)
+        .and(warp::get())
+        .and(warp::header::optional::<String>("cookie"))
+        .and(warp::header::optional::<u64>("last-event-id"))
+        .and(with_state.clone())
+        .map(
+            |uid: String,
+             cookie: Option<String>,
+             last_event_id: Option<u64>,
+             state: SharedState| {
+                handle_events(&uid, cookie.as_deref(), last_event_id, &state)
+            },
+        );
+
+    // GET /~/scry/{app}/{path}.{mark}
+    let scry = warp::path!("~" / "scry" / ..)
+        .and(warp::path::tail())
+        .and(warp::get())
+        .and(warp::header::optional::<String>("cookie"))
+        .and(with_state.clone())
+        .map(
+            |tail: warp::path::Tail, cookie: Option<String>, state: SharedState| {
+                handle_scry(tail.as_str(), cookie.as_deref(), &state)
+            },
+        );
+
+    // faults are checked before any of the routes are considered
+    let faults = warp::path::full()
+        .and(with_state)
+        .and_then(
+            |path: warp::path::FullPath, state: SharedState| async move {
+                match state.lock().unwrap().take_fault(path.as_str()) {
+                    Some(status) => Err(reject::custom(InjectedFault(status))),
+                    None => Ok(()),
+                }
+            },
+        )
+        .untuple_one();
+
+    faults
+        .and(
+            login
+                .or(actions)
+                .unify()
+                .or(events)
+                .unify()
+                .or(scry)
+                .unify(),
+        )
+        .recover(handle_rejection)
+}
+
+fn status(code: u16) -> Response {
+    warp::reply::with_status(
+        warp::reply(),
+        StatusCode::from_u16(code).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
+    )
+    .into_response()
+}
+
+async fn handle_rejection(err: Rejection) -> Result<Response, Infallible> {
+    if let Some(InjectedFault(code)) = err.find::<InjectedFault>() {
+        Ok(status(*code))
+    } else if err.is_not_found() {
+        Ok(status(404))
+    } else if err.find::<reject::MethodNotAllowed>().is_some() {
+        Ok(status(405))
+    } else {
+        Ok(status(400))
+    }
+}
+
+fn handle_login(body: &[u8], state: &SharedState) -> Response {
+    let body = String::from_utf8_lossy(body);
+    let password = body
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("password="))
+        .unwrap_or("");
+    let mut state = state.lock().unwrap();
+    if password != state.code {
+        return status(400);
+    }
+    let cookie = state.login();
+    warp::reply::with_header(status(204), "set-cookie", cookie).into_response()
+}
+
+fn handle_actions(uid: &str, cookie: Option<&str>, body: &[u8], state: &SharedState) -> Response {
+    let mut state = state.lock().unwrap();
+    if !state.is_authenticated(cookie) {
+        return status(403);
+    }
+    let actions = match serde_json::from_slice::<Vec<JsonValue>>(body) {
+        Ok(actions) => actions,
+        Err(_) => return status(400),
+    };
+    state.apply_actions(uid, actions);
+    status(204)
+}
+
+fn handle_events(
+    uid: &str,
+    cookie: Option<&str>,
+    last_event_id: Option<u64>,
+    state: &SharedState,
+) -> Response {
+    let mut state = state.lock().unwrap();
+    if !state.is_authenticated(cookie) {
+        return status(403);
+    }
+    let retry = state.sse_retry;
+    let channel = match state.channels.get_mut(uid) {
+        Some(channel) => channel,
+        None => return status(404),
+    };
+    // eyre treats Last-Event-ID as an implicit ack of everything up to that id
+    if let Some(event_id) = last_event_id {
+        channel.ack(event_id);
+    }
+    let replay = channel.events.clone();
+    let (tx, rx) = mpsc::unbounded_channel();
+    channel.listeners.push(tx);
+
+    let stream = tokio_stream::iter(replay)
+        .chain(UnboundedReceiverStream::new(rx))
+        .enumerate()
+        .map(move |(n, (id, data))| {
+            let event = warp::sse::Event::default()
+                .id(id.to_string())
+                .data(data.to_string());
+            let event = match retry {
+                Some(ms) if n == 0 => event.retry(Duration::from_millis(ms)),
+                _ => event,
+            };
+            Ok::<_, Infallible>(event)
+        });
+
+    warp::sse::reply(warp::sse::keep_alive().stream(stream)).into_response()
+}
+
+fn handle_scry(path: &str, cookie: Option<&str>, state: &SharedState) -> Response {
+    let state = state.lock().unwrap();
+    if !state.is_authenticated(cookie) {
+        return status(403);
+    }
+    // %holon's cookie check is answered from the mock's own session list
+    if let Some(cookie) = path
+        .strip_prefix("holon/valid-cookie/")
+        .and_then(|rest| rest.strip_suffix(".json"))
+    {
+        let valid = state.is_authenticated(Some(cookie));
+        return warp::reply::json(&json!({ "is-valid": valid })).into_response();
+    }
+    match state.scries.get(path) {
+        Some(data) => warp::reply::json(data).into_response(),
+        None => status(404),
+    }
+}
@@ -0,0 +1,215 @@
+use rand::Rng;
+use serde_json::{json, Value as JsonValue};
+use std::collections::{HashMap, HashSet};
+use tokio::sync::mpsc::UnboundedSender;
+
+/// a single SSE event as eyre would deliver it: (event-id, payload)
+pub type SseEvent = (u64, JsonValue);
+
+/// an eyre channel, created by the first PUT/POST to `/~/channel/{uid}`
+#[derive(Debug, Default)]
+pub struct Channel {
+    // events that have been produced but not yet acked by the client
+    pub events: Vec<SseEvent>,
+    // the id given to the next event pushed onto this channel
+    pub next_event_id: u64,
+    // highest event id acknowledged by the client (ack action or Last-Event-ID header)
+    pub last_ack: Option<u64>,
+    // every action received on this channel, in order of arrival
+    pub actions: Vec<JsonValue>,
+    // subscription id -> (app, path)
+    pub subscriptions: HashMap<u64, (String, String)>,
+    // senders for each open GET (SSE) stream on this channel
+    pub listeners: Vec<UnboundedSender<SseEvent>>,
+}
+
+impl Channel {
+    /// append an event to the channel and deliver it to any open streams
+    pub fn push(&mut self, data: JsonValue) -> u64 {
+        let id = self.next_event_id;
+        self.next_event_id += 1;
+        self.events.push((id, data.clone()));
+        self.listeners
+            .retain(|listener| listener.send((id, data.clone())).is_ok());
+        id
+    }
+
+    /// acknowledge all events up to and including `event_id`. eyre drops acked
+    ///  events from its buffer, so they will not be replayed on reconnect
+    pub fn ack(&mut self, event_id: u64) {
+        if self.last_ack.is_none_or(|last| event_id > last) {
+            self.last_ack = Some(event_id);
+        }
+        self.events.retain(|(id, _)| *id > event_id);
+    }
+}
+
+/// injected failure: the next request whose path starts with `prefix` is
+///  answered with `status` instead of being handled
+#[derive(Debug, Clone)]
+pub struct Fault {
+    pub prefix: String,
+    pub status: u16,
+}
+
+#[derive(Debug, Default)]
+pub struct State {
+    // ship name without the leading ~
+    pub ship: String,
+    // +code accepted by /~/login
+    pub code: String,
+    // session tokens that are currently considered valid
+    pub sessions: HashSet<String>,
+    // the most recent set-cookie value handed out by /~/login
+    pub last_cookie: Option<String>,
+    // number of successful logins
+    pub logins: usize,
+    pub channels: HashMap<String, Channel>,
+    // scry fixtures keyed by "{app}{path}.{mark}" (e.g. "docket/our.json")
+    pub scries: HashMap<String, JsonValue>,
+    pub faults: Vec<Fault>,
+    // apps whose pokes are nacked, with the error returned to the client
+    pub nacks: HashMap<String, String>,
+    // reconnect delay (ms) advertised to SSE clients with the first event of a stream
+    pub sse_retry: Option<u64>,
+}
+
+impl State {
+    pub fn new(ship: &str, code: &str) -> State {
+        State {
+            ship: ship.trim_start_matches('~').to_string(),
+            code: code.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn cookie_key(&self) -> String {
+        format!("urbauth-~{}", self.ship)
+    }
+
+    /// issue a new session token and return the full set-cookie value
+    pub fn login(&mut self) -> String {
+        let mut rng = rand::thread_rng();
+        let token = format!(
+            "0v{}.{:05x}.{:05x}",
+            self.logins,
+            rng.gen::<u32>() & 0xfffff,
+            rng.gen::<u32>() & 0xfffff
+        );
+        self.sessions.insert(token.clone());
+        self.logins += 1;
+        let cookie = format!("{}={}; Path=/; Max-Age=604800", self.cookie_key(), token);
+        self.last_cookie.replace(cookie.clone());
+        cookie
+    }
+
+    /// true if the cookie header contains a session token issued by this ship
+    pub fn is_authenticated(&self, cookie: Option<&str>) -> bool {
+        let key = self.cookie_key();
+        let token = cookie.and_then(|cookie| {
+            cookie.split(';').find_map(|part| {
+                let (name, value) = part.trim().split_once('=')?;
+                (name == key).then(|| value.to_string())
+            })
+        });
+        token.is_some_and(|token| self.sessions.contains(&token))
+    }
+
+    /// invalidate every session and close all open event streams
+    pub fn expire_sessions(&mut self) {
+        self.sessions.clear();
+        for channel in self.channels.values_mut() {
+            channel.listeners.clear();
+        }
+    }
+
+    /// remove and return the first fault matching the request path
+    pub fn take_fault(&mut self, path: &str) -> Option<u16> {
+        let pos = self
+            .faults
+            .iter()
+            .position(|fault| path.starts_with(&fault.prefix))?;
+        Some(self.faults.remove(pos).status)
+    }
+
+    /// apply a list of channel actions the same way eyre does, creating the
+    ///  channel if needed
+    pub fn apply_actions(&mut self, uid: &str, actions: Vec<JsonValue>) {
+        let mut channel = self.channels.remove(uid).unwrap_or_default();
+        let mut deleted = false;
+        for action in actions {
+            channel.actions.push(action.clone());
+            let id = action["id"].as_u64().unwrap_or(0);
+            match action["action"].as_str().unwrap_or("") {
+                "poke" => {
+                    let app = action["app"].as_str().unwrap_or("");
+                    match self.nacks.get(app) {
+                        Some(err) => {
+                            channel.push(json!({"id": id, "response": "poke", "err": err}))
+                        }
+                        None => channel.push(json!({"id": id, "response": "poke", "ok": "ok"})),
+                    };
+                }
+                "subscribe" => {
+                    let app = action["app"].as_str().unwrap_or("").to_string();
+                    let path = action["path"].as_str().unwrap_or("").to_string();
+                    channel.subscriptions.insert(id, (app, path));
+                    channel.push(json!({"id": id, "response": "subscribe", "ok": "ok"}));
+                }
+                "unsubscribe" => {
+                    if let Some(sub_id) = action["subscription"].as_u64() {
+                        channel.subscriptions.remove(&sub_id);
+                    }
+                }
+                "ack" => {
+                    if let Some(event_id) = action["event-id"].as_u64() {
+                        channel.ack(event_id);
+                    }
+                }
+                "delete" => deleted = true,
+                _ => {}
+            }
+        }
+        if !deleted {
+            self.channels.insert(uid.to_string(), channel);
+        }
+    }
+
+    /// deliver a fact to every channel subscribed to app/path. returns the
+    ///  number of subscriptions that received it
+    pub fn push_fact(&mut self, app: &str, path: &str, data: &JsonValue) -> usize {
+        let mut count = 0;
+        for channel in self.channels.values_mut() {
+            let ids: Vec<u64> = channel
+                .subscriptions
+                .iter()
+                .filter(|(_, (a, p))| a == app && p == path)
+                .map(|(id, _)| *id)
+                .collect();
+            for id in ids {
+                channel.push(json!({"id": id, "response": "diff", "json": data}));
+                count += 1;
+            }
+        }
+        count
+    }
+
+    /// end every subscription to app/path with a %kick ("quit" response)
+    pub fn kick(&mut self, app: &str, path: &str) -> usize {
+        let mut count = 0;
+        for channel in self.channels.values_mut() {
+            let ids: Vec<u64> = channel
+                .subscriptions
+                .iter()
+                .filter(|(_, (a, p))| a == app && p == path)
+                .map(|(id, _)| *id)
+                .collect();
+            for id in ids {
+                channel.subscriptions.remove(&id);
+                channel.push(json!({"id": id, "response": "quit"}));
+                count += 1;
+            }
+        }
+        count
+    }
+}
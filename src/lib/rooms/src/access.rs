@@ -0,0 +1,102 @@
+//!
+//! who can see and enter a room
+//!
+//! public rooms are open to everyone, private rooms to the peers on their whitelist,
+//!  and space rooms to the members of the space at their `path` (and their whitelist).
+//!  a creator can always see and enter their own rooms. rooms are only listed to the
+//!  peers who could enter them, and a room never holds more peers than its capacity.
+//!
+//! a peer who is not let in can knock. the creator is told, and either approves (the
+//!  peer goes on the whitelist) or denies.
+//!
+use std::sync::Arc;
+
+use crate::protocol::ErrorCode;
+use crate::types::{Access, Room};
+
+/// whether a peer (2nd) belongs to a space (1st, a room `path`). supplied by the node
+pub type IsMember = Arc<dyn Fn(&str, &str) -> bool + Send + Sync>;
+
+/// whether `peer_id` may see `room` (in room lists and updates)
+pub fn can_see(room: &Room, peer_id: &str, is_member: &IsMember) -> bool {
+    admitted(room, peer_id, is_member).is_ok()
+}
+
+/// whether `peer_id` may enter `room`, and if not, why
+pub fn can_enter(room: &Room, peer_id: &str, is_member: &IsMember) -> Result<(), ErrorCode> {
+    admitted(room, peer_id, is_member)?;
+    let present = room.present.iter().any(|id| id == peer_id);
+    if !present && room.present.len() >= room.capacity as usize {
+        return Err(ErrorCode::RoomFull);
+    }
+    Ok(())
+}
+
+fn admitted(room: &Room, peer_id: &str, is_member: &IsMember) -> Result<(), ErrorCode> {
+    if room.creator == peer_id || room.whitelist.iter().any(|id| id == peer_id) {
+        return Ok(());
+    }
+    match room.access {
+        Access::Public => Ok(()),
+        Access::Private => Err(ErrorCode::NotWhitelisted),
+        Access::Space => match &room.path {
+            Some(path) if is_member(path, peer_id) => Ok(()),
+            _ => Err(ErrorCode::NotMember),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn room(access: Access) -> Room {
+        Room {
+            rid: "r1".to_string(),
+            rtype: "media".to_string(),
+            title: "standup".to_string(),
+            creator: "~zod".to_string(),
+            origin: String::new(),
+            provider: "default".to_string(),
+            access,
+            present: vec!["~zod".to_string()],
+            whitelist: vec!["~bus".to_string()],
+            capacity: 2,
+            path: Some("/spaces/~zod/our".to_string()),
+            sessions: HashMap::new(),
+            knocks: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn access_follows_the_room_policy() {
+        // ~nec is in ~zod's space
+        let is_member: IsMember =
+            Arc::new(|path, peer| path == "/spaces/~zod/our" && peer == "~nec");
+        let enter = |room: &Room, peer: &str| can_enter(room, peer, &is_member);
+
+        let public = room(Access::Public);
+        assert_eq!(enter(&public, "~fed"), Ok(()));
+
+        let private = room(Access::Private);
+        assert_eq!(enter(&private, "~bus"), Ok(()));
+        assert_eq!(enter(&private, "~nec"), Err(ErrorCode::NotWhitelisted));
+        assert!(!can_see(&private, "~nec", &is_member));
+        assert!(can_see(&private, "~zod", &is_member));
+
+        let mut space = room(Access::Space);
+        assert_eq!(enter(&space, "~nec"), Ok(()));
+        assert_eq!(enter(&space, "~bus"), Ok(()));
+        assert_eq!(enter(&space, "~fed"), Err(ErrorCode::NotMember));
+        space.path = None;
+        assert_eq!(enter(&space, "~nec"), Err(ErrorCode::NotMember));
+
+        // full rooms take no one new
+        let mut full = room(Access::Public);
+        full.present.push("~bus".to_string());
+        assert_eq!(enter(&full, "~fed"), Err(ErrorCode::RoomFull));
+        assert_eq!(enter(&full, "~bus"), Ok(()));
+        assert!(can_see(&full, "~fed", &is_member));
+    }
+}
@@ -1,15 +1,27 @@
 // use serde_json::{json, Value as JsonValue};
-use warp::Filter;
+use std::collections::HashSet;
+use warp::{Filter, Reply};
 
-use crate::types::{Room, Session, ROOM_MAP, SESSION_MAP};
+use crate::access::{self, IsMember};
+use crate::auth::{AuthError, Credentials, Guard};
+use crate::socket::refused;
+use crate::types::{Access, PeerId, Room, Session, ROOM_MAP, SESSION_MAP};
 
-pub fn rooms_route() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone
-{
+/// `/hol/rooms` lists the rooms the requester (by its cookie) can see: only the public
+///  ones for a request that proves nothing
+///
+/// `/hol/sessions` lists the requester's own sessions, and those of the peers in the
+///  rooms it can see (without their ip). it is refused to a request that proves nothing
+pub fn rooms_route(
+    guard: Guard,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
     let cors = warp::cors()
         .allow_any_origin()
         .allow_headers(vec!["content-type"])
         .allow_methods(vec!["GET", "POST", "DELETE"]);
 
+    let peers_guard = guard.clone();
+
     let get_rooms = warp::path!("hol" / "rooms" / ..)
         .and(warp::get())
         .and(
@@ -17,7 +29,25 @@ pub fn rooms_route() -> impl Filter<Extract = (impl warp::Reply,), Error = warp:
                 .map(Some)
                 .or_else(|_| async { Ok::<(Option<String>,), std::convert::Infallible>((None,)) }),
         )
-        .and_then(|arg: Option<String>| async { handle_get_session(arg).await });
+        .and(warp::header::optional::<String>("cookie"))
+        .and_then(move |arg: Option<String>, cookie: Option<String>| {
+            let Guard {
+                authenticate,
+                is_member,
+            } = guard.clone();
+            async move {
+                let viewer = match cookie {
+                    Some(cookie) => authenticate(Credentials {
+                        cookie: Some(cookie),
+                        ..Default::default()
+                    })
+                    .await
+                    .ok(),
+                    None => None,
+                };
+                handle_get_session(arg, viewer, is_member).await
+            }
+        });
 
     let get_peers = warp::path!("hol" / "sessions" / ..)
         .and(warp::get())
@@ -26,12 +56,35 @@ pub fn rooms_route() -> impl Filter<Extract = (impl warp::Reply,), Error = warp:
                 .map(Some)
                 .or_else(|_| async { Ok::<(Option<String>,), std::convert::Infallible>((None,)) }),
         )
-        .and_then(|arg: Option<String>| async { handle_get_peers(arg).await });
+        .and(warp::header::optional::<String>("cookie"))
+        .and_then(move |arg: Option<String>, cookie: Option<String>| {
+            let Guard {
+                authenticate,
+                is_member,
+            } = peers_guard.clone();
+            async move {
+                let viewer = match cookie {
+                    Some(cookie) => {
+                        authenticate(Credentials {
+                            cookie: Some(cookie),
+                            ..Default::default()
+                        })
+                        .await
+                    }
+                    None => Err(AuthError::Unauthorized),
+                };
+                handle_get_peers(arg, viewer, is_member).await
+            }
+        });
 
     get_rooms.or(get_peers).with(cors)
 }
 
-pub async fn handle_get_session(arg: Option<String>) -> Result<impl warp::Reply, warp::Rejection> {
+pub async fn handle_get_session(
+    arg: Option<String>,
+    viewer: Option<PeerId>,
+    is_member: IsMember,
+) -> Result<impl warp::Reply, warp::Rejection> {
     let arg = arg.as_ref();
     let rooms_list = {
         let rooms_state = ROOM_MAP.read().unwrap();
@@ -42,10 +95,17 @@ pub async fn handle_get_session(arg: Option<String>) -> Result<impl warp::Reply,
             let (_, room_data) = room;
 
             // by default, only return "room" rooms; otherwise allow additional types
-            if arg.is_none() || (arg.is_some() && arg.unwrap() == "all") {
-                rooms.push(room_data.write().unwrap().clone());
-            } else if &room.1.read().unwrap().rtype == arg.unwrap() {
-                rooms.push(room_data.write().unwrap().clone());
+            let room_data = room_data.read().unwrap();
+            let include = match arg {
+                None => true,
+                Some(rtype) => rtype == "all" || &room_data.rtype == rtype,
+            };
+            let visible = match &viewer {
+                Some(peer_id) => access::can_see(&room_data, peer_id, &is_member),
+                None => room_data.access == Access::Public,
+            };
+            if include && visible {
+                rooms.push(room_data.clone());
             }
         }
         rooms
@@ -53,15 +113,150 @@ pub async fn handle_get_session(arg: Option<String>) -> Result<impl warp::Reply,
     Ok(warp::reply::json(&rooms_list))
 }
 
-pub async fn handle_get_peers(arg: Option<String>) -> Result<impl warp::Reply, warp::Rejection> {
+pub async fn handle_get_peers(
+    arg: Option<String>,
+    viewer: Result<PeerId, AuthError>,
+    is_member: IsMember,
+) -> Result<warp::reply::Response, warp::Rejection> {
     let _arg = arg.as_ref();
 
+    let viewer = match viewer {
+        Ok(viewer) => viewer,
+        Err(e) => return Ok(refused(e)),
+    };
+
+    // the peers the viewer can see in rooms
+    let visible: HashSet<String> = ROOM_MAP
+        .read()
+        .unwrap()
+        .values()
+        .map(|room| room.read().unwrap())
+        .filter(|room| access::can_see(room, &viewer, &is_member))
+        .flat_map(|room| room.present.clone())
+        .collect();
+
     let mut result: Vec<Session> = Vec::new();
-    // send update to all known peers
     let sessions = SESSION_MAP.read().unwrap();
     for (_, value) in sessions.iter() {
-        result.push(value.0.clone());
+        let session = &value.0;
+        if session.peer_id == viewer {
+            result.push(session.clone());
+        } else if visible.contains(&session.peer_id) {
+            // where other peers connect from is theirs to tell
+            result.push(Session {
+                peer_ip: String::new(),
+                ..session.clone()
+            });
+        }
+    }
+
+    Ok(warp::reply::json(&result).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+    use std::collections::HashMap;
+    use std::sync::{Arc, RwLock};
+    use uuid::Uuid;
+
+    use crate::types::Rid;
+
+    // the cookie is the @p it proves
+    fn guard() -> Guard {
+        Guard {
+            authenticate: Arc::new(|credentials: Credentials| {
+                async move { credentials.cookie.ok_or(AuthError::Unauthorized) }.boxed()
+            }),
+            is_member: Arc::new(|_, _| false),
+        }
     }
 
-    Ok(warp::reply::json(&result))
+    fn session(peer_id: &str) -> String {
+        let session = Session {
+            id: Uuid::new_v4().to_string(),
+            peer_id: peer_id.to_string(),
+            peer_ip: "10.0.0.1".to_string(),
+            resume_token: String::new(),
+        };
+        let (sender, _) = tokio::sync::mpsc::unbounded_channel();
+        let id = session.id.clone();
+        SESSION_MAP
+            .write()
+            .unwrap()
+            .insert(id.clone(), (session, sender));
+        id
+    }
+
+    fn room(creator: &str, access: Access, present: &[&str]) -> Rid {
+        let rid = Uuid::new_v4().to_string();
+        let room = Room {
+            rid: rid.clone(),
+            rtype: "media".to_string(),
+            title: "standup".to_string(),
+            creator: creator.to_string(),
+            origin: String::new(),
+            provider: "default".to_string(),
+            access,
+            present: present.iter().map(|peer| peer.to_string()).collect(),
+            whitelist: Vec::new(),
+            capacity: 6,
+            path: None,
+            sessions: HashMap::new(),
+            knocks: Vec::new(),
+        };
+        ROOM_MAP
+            .write()
+            .unwrap()
+            .insert(rid.clone(), Arc::new(RwLock::new(room)));
+        rid
+    }
+
+    #[tokio::test]
+    async fn sessions_are_only_listed_to_peers_that_can_see_them() {
+        let route = rooms_route(guard());
+        // peers of this test only: other tests share the maps
+        let peer = |name: &str| format!("~{}-{}", name, Uuid::new_v4().simple());
+        let (me, mate, hidden) = (peer("me"), peer("mate"), peer("hidden"));
+        let own = session(&me);
+        let mates = session(&mate);
+        let hiddens = session(&hidden);
+        let public = room(&mate, Access::Public, &[&mate]);
+        let private = room(&hidden, Access::Private, &[&hidden]);
+
+        let res = warp::test::request()
+            .path("/hol/sessions")
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), 403);
+
+        let res = warp::test::request()
+            .path("/hol/sessions")
+            .header("cookie", me.as_str())
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), 200);
+        let listed: Vec<serde_json::Value> = serde_json::from_slice(res.body()).unwrap();
+        let listed: HashMap<&str, &serde_json::Value> = listed
+            .iter()
+            .map(|session| (session["session_id"].as_str().unwrap(), session))
+            .collect();
+        // its own session, with its ip...
+        assert_eq!(listed[own.as_str()]["peer_ip"], "10.0.0.1");
+        // ...the one of a peer in a room it can see, without...
+        assert_eq!(listed[mates.as_str()]["peer_id"], mate.as_str());
+        assert_eq!(listed[mates.as_str()]["peer_ip"], "");
+        // ...and nothing of a peer in a private room
+        assert!(!listed.contains_key(hiddens.as_str()));
+
+        ROOM_MAP
+            .write()
+            .unwrap()
+            .retain(|rid, _| *rid != public && *rid != private);
+        SESSION_MAP
+            .write()
+            .unwrap()
+            .retain(|id, _| ![&own, &mates, &hiddens].contains(&id));
+    }
 }
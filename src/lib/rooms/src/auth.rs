@@ -0,0 +1,108 @@
+//!
+//! who is on the other end of a signaling socket
+//!
+//! rooms does not know ships: the node hands the routes a `Guard`, whose `Authenticate`
+//!  turns the upgrade request's credentials into the @p they prove. the socket is only
+//!  opened for a proven @p, which becomes the session's peer id. rooms trust nothing else
+//!  a client says about itself.
+//!
+//! for the node, there are two proofs:
+//!
+//! - a session cookie of the node's own ship, checked with the ship. it proves the
+//!   ship's @p.
+//! - a challenge of the node's, signed by any ship. the client gets a challenge from
+//!   `/signaling/challenge`, has its ship sign it, and sends the challenge, the
+//!   signature and its @p (`serverId`) along with the upgrade request. the node's ship
+//!   checks the signature against the signer's keys. a challenge is only good once, and
+//!   only for `CHALLENGE_TTL`.
+//!
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::future::BoxFuture;
+use lazy_static::lazy_static;
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::access::IsMember;
+use crate::types::PeerId;
+
+/// how long a challenge can be answered
+const CHALLENGE_TTL: Duration = Duration::from_secs(60);
+
+lazy_static! {
+    // the challenges handed out and not answered yet, with when they were
+    static ref CHALLENGES: Mutex<HashMap<String, Instant>> = Mutex::new(HashMap::new());
+}
+
+/// what the upgrade request carries
+#[derive(Debug, Clone, Default)]
+pub struct Credentials {
+    /// the `cookie` header
+    pub cookie: Option<String>,
+    /// the @p the client says it is (the `serverId` query parameter)
+    pub peer_id: Option<PeerId>,
+    /// a challenge handed out by `challenge`, already redeemed (the `challenge` query
+    ///  parameter)
+    pub challenge: Option<String>,
+    /// `peer_id`'s signature of `challenge` (the `signature` query parameter)
+    pub signature: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuthError {
+    /// no credentials, or ones that prove nothing (403)
+    Unauthorized,
+    /// the credentials could not be checked (503)
+    Unavailable,
+}
+
+/// prove who sent the credentials
+pub type Authenticate =
+    Arc<dyn Fn(Credentials) -> BoxFuture<'static, Result<PeerId, AuthError>> + Send + Sync>;
+
+/// what the rooms routes need from the node
+#[derive(Clone)]
+pub struct Guard {
+    pub authenticate: Authenticate,
+    pub is_member: IsMember,
+}
+
+/// a new challenge for a ship to sign
+pub fn challenge() -> String {
+    let challenge = Uuid::new_v4().simple().to_string();
+    let mut challenges = CHALLENGES.lock().unwrap();
+    challenges.retain(|_, issued| issued.elapsed() < CHALLENGE_TTL);
+    challenges.insert(challenge.clone(), Instant::now());
+    challenge
+}
+
+/// use up `challenge`. false if it was not handed out, was used already or is too old
+pub(crate) fn redeem(challenge: &str) -> bool {
+    let mut challenges = CHALLENGES.lock().unwrap();
+    challenges
+        .remove(challenge)
+        .is_some_and(|issued| issued.elapsed() < CHALLENGE_TTL)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn challenges_are_good_once() {
+        let issued = challenge();
+        assert!(redeem(&issued));
+        assert!(!redeem(&issued));
+        assert!(!redeem("0123456789abcdef0123456789abcdef"));
+
+        let stale = challenge();
+        CHALLENGES
+            .lock()
+            .unwrap()
+            .insert(stale.clone(), Instant::now() - CHALLENGE_TTL);
+        assert!(!redeem(&stale));
+    }
+}
@@ -1,4 +1,9 @@
+pub mod access;
+pub mod auth;
+pub mod protocol;
+mod router;
 pub mod socket;
+pub mod store;
 pub mod types;
 
 pub mod api;
@@ -0,0 +1,527 @@
+//!
+//! the signaling protocol: the frames a client sends on `/signaling`, and those it gets back
+//!
+//! every frame is a json object tagged by its `type`. a frame that cannot be read, or
+//!  that asks for something that cannot be done, is answered with an `error` frame that
+//!  carries a `code` (see `ErrorCode`) and the type of the frame it answers. the
+//!  connection stays open.
+//!
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::types::{Access, PeerId, Rid, Room, SessionId};
+
+/// what a client asks for
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum ClientMessage {
+    /// register the session and get the room list (`rooms`, then `connected`). send it
+    ///  once the socket is open. a client coming back after the node restarted sends
+    ///  the resume token it was given, to take back its seats (see `store`)
+    Connect {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        resume_token: Option<String>,
+    },
+    /// open a room, with this session in it. the creator gets `room-created`, and the
+    ///  lobby of the room's path a `lobby` diff
+    CreateRoom {
+        rid: Rid,
+        /// "media" (the default) or "background"
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        rtype: Option<String>,
+        title: String,
+        /// where the room belongs, e.g. a space path
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        path: Option<String>,
+        /// who can see and enter the room. defaults to private if there is a whitelist,
+        ///  public otherwise
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        access: Option<Access>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        whitelist: Vec<PeerId>,
+        /// most peers in the room at once. defaults to 10
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        capacity: Option<u32>,
+    },
+    /// change a room's settings (creator only). fields left out are kept. answered with
+    ///  `edit-room` to the sessions in the room
+    EditRoom {
+        rid: Rid,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        title: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        access: Option<Access>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        capacity: Option<u32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        whitelist: Option<Vec<PeerId>>,
+    },
+    /// close a room (creator only). answered with `room-deleted` to the sessions in it
+    DeleteRoom { rid: Rid },
+    /// join a room the peer is allowed in (see `access`). answered with `room-entered`
+    ///  to the sessions in the room, unless the session is in the room already
+    EnterRoom { rid: Rid },
+    /// ask the creator of a room the peer is not allowed in to let them in. the creator
+    ///  gets `knocked`
+    Knock { rid: Rid },
+    /// let a peer who knocked in (creator only): they go on the whitelist, and get
+    ///  `knock-approved`
+    ApproveKnock { rid: Rid, peer_id: PeerId },
+    /// turn a peer who knocked away (creator only). they get `knock-denied`
+    DenyKnock { rid: Rid, peer_id: PeerId },
+    /// leave a room. answered with `room-left` to the sessions in the room, or
+    ///  `room-deleted` if the room closes with it (the creator left, or no one is left)
+    LeaveRoom { rid: Rid },
+    /// pass webrtc (or realm) signal data to another peer in the same room
+    Signal {
+        /// the session's own peer id
+        from: PeerId,
+        to: PeerId,
+        rid: Rid,
+        /// forwarded as is
+        signal: Value,
+    },
+    /// follow the rooms at `path` (the rooms without a path if left out): a `lobby` with
+    ///  the ones the peer can see, then a `lobby` diff whenever they change
+    SubscribeLobby {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        path: Option<String>,
+    },
+    UnsubscribeLobby {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        path: Option<String>,
+    },
+    /// leave every room and drop the session
+    Disconnect,
+}
+
+impl ClientMessage {
+    /// read a frame. the error says what is wrong with it
+    pub fn parse(frame: &str) -> Result<ClientMessage, ProtocolError> {
+        let value: Value = serde_json::from_str(frame)
+            .map_err(|e| ProtocolError::new(ErrorCode::InvalidMessage, e.to_string()))?;
+        let Some(kind) = value.get("type").and_then(Value::as_str) else {
+            return Err(ProtocolError::new(
+                ErrorCode::InvalidMessage,
+                "a message is a json object with a type",
+            ));
+        };
+        if !CLIENT_TYPES.contains(&kind) {
+            return Err(ProtocolError::new(
+                ErrorCode::UnknownType,
+                format!("unknown message type '{}'", kind),
+            )
+            .answering(kind));
+        }
+        let kind = kind.to_string();
+        serde_json::from_value(value).map_err(|e| {
+            ProtocolError::new(ErrorCode::InvalidMessage, e.to_string()).answering(&kind)
+        })
+    }
+
+    /// the frame's `type`
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ClientMessage::Connect { .. } => "connect",
+            ClientMessage::CreateRoom { .. } => "create-room",
+            ClientMessage::EditRoom { .. } => "edit-room",
+            ClientMessage::DeleteRoom { .. } => "delete-room",
+            ClientMessage::EnterRoom { .. } => "enter-room",
+            ClientMessage::Knock { .. } => "knock",
+            ClientMessage::ApproveKnock { .. } => "approve-knock",
+            ClientMessage::DenyKnock { .. } => "deny-knock",
+            ClientMessage::LeaveRoom { .. } => "leave-room",
+            ClientMessage::Signal { .. } => "signal",
+            ClientMessage::SubscribeLobby { .. } => "subscribe-lobby",
+            ClientMessage::UnsubscribeLobby { .. } => "unsubscribe-lobby",
+            ClientMessage::Disconnect => "disconnect",
+        }
+    }
+}
+
+const CLIENT_TYPES: &[&str] = &[
+    "connect",
+    "create-room",
+    "edit-room",
+    "delete-room",
+    "enter-room",
+    "knock",
+    "approve-knock",
+    "deny-knock",
+    "leave-room",
+    "signal",
+    "subscribe-lobby",
+    "unsubscribe-lobby",
+    "disconnect",
+];
+
+/// what the node tells clients
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum ServerMessage {
+    /// every room the peer can see
+    Rooms {
+        rooms: Vec<Room>,
+    },
+    /// the rooms of a lobby the session subscribed to that it can see: all of them right
+    ///  after subscribing, then the ones that appeared or changed. `removed` are the ones
+    ///  that went away (or that the peer can no longer see)
+    Lobby {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        path: Option<String>,
+        rooms: Vec<Room>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        removed: Vec<Rid>,
+    },
+    /// the session is registered. the client keeps the resume token for reconnecting
+    Connected {
+        session_id: SessionId,
+        resume_token: String,
+    },
+    /// the room the session just created
+    RoomCreated {
+        room: Room,
+    },
+    /// a room's settings changed
+    #[serde(rename = "edit-room")]
+    RoomEdited {
+        rid: Rid,
+        title: String,
+        access: Access,
+        capacity: u32,
+    },
+    RoomDeleted {
+        rid: Rid,
+    },
+    /// `peer_id` joined the room
+    RoomEntered {
+        rid: Rid,
+        peer_id: PeerId,
+        room: Room,
+    },
+    /// `peer_id` left the room
+    RoomLeft {
+        rid: Rid,
+        peer_id: PeerId,
+        room: Room,
+    },
+    /// `peer_id` would like to enter the creator's room
+    Knocked {
+        rid: Rid,
+        peer_id: PeerId,
+    },
+    /// the creator let the peer in: it can enter the room now
+    KnockApproved {
+        rid: Rid,
+    },
+    KnockDenied {
+        rid: Rid,
+    },
+    /// signal data from `from`
+    Signal {
+        rid: Rid,
+        from: PeerId,
+        signal: Value,
+    },
+    /// a frame could not be handled
+    Error {
+        code: ErrorCode,
+        message: String,
+        /// the type of the frame, when it could be read
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        request: Option<String>,
+    },
+}
+
+impl ServerMessage {
+    pub fn to_json(&self) -> String {
+        // the messages are plain data: serializing cannot fail
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorCode {
+    /// not json, no `type`, or missing / mistyped fields
+    InvalidMessage,
+    UnknownType,
+    RoomNotFound,
+    /// `create-room` with the id of an open room
+    RoomExists,
+    /// only the room's creator may do this
+    NotCreator,
+    /// a signal between peers that are not both in the room
+    NotInRoom,
+    /// the room is private, and the peer is not on its whitelist
+    NotWhitelisted,
+    /// the room is for the members of a space, and the peer is not one
+    NotMember,
+    /// the room is at capacity
+    RoomFull,
+    /// an approval or denial for a peer who did not knock
+    NoKnock,
+    /// a signal `from` another peer than the session's
+    WrongPeer,
+}
+
+/// why a frame was refused. becomes an `error` frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProtocolError {
+    pub code: ErrorCode,
+    pub message: String,
+    pub request: Option<String>,
+}
+
+impl ProtocolError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> ProtocolError {
+        ProtocolError {
+            code,
+            message: message.into(),
+            request: None,
+        }
+    }
+
+    /// note the type of the frame the error answers
+    pub fn answering(mut self, kind: &str) -> ProtocolError {
+        self.request = Some(kind.to_string());
+        self
+    }
+}
+
+impl From<ProtocolError> for ServerMessage {
+    fn from(e: ProtocolError) -> ServerMessage {
+        ServerMessage::Error {
+            code: e.code,
+            message: e.message,
+            request: e.request,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    fn room() -> Room {
+        Room {
+            rid: "r1".to_string(),
+            rtype: "media".to_string(),
+            title: "standup".to_string(),
+            creator: "~zod".to_string(),
+            origin: String::new(),
+            provider: "default".to_string(),
+            access: Access::Public,
+            present: vec!["~zod".to_string()],
+            whitelist: Vec::new(),
+            capacity: 10,
+            path: Some("/spaces/~zod/our".to_string()),
+            sessions: HashMap::new(),
+            knocks: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn client_messages_round_trip() {
+        let messages = vec![
+            ClientMessage::Connect { resume_token: None },
+            ClientMessage::Connect {
+                resume_token: Some("t1".to_string()),
+            },
+            ClientMessage::CreateRoom {
+                rid: "r1".to_string(),
+                rtype: Some("background".to_string()),
+                title: "standup".to_string(),
+                path: None,
+                access: Some(Access::Space),
+                whitelist: vec!["~bus".to_string()],
+                capacity: Some(4),
+            },
+            ClientMessage::EditRoom {
+                rid: "r1".to_string(),
+                title: None,
+                access: Some(Access::Private),
+                capacity: Some(4),
+                whitelist: None,
+            },
+            ClientMessage::DeleteRoom {
+                rid: "r1".to_string(),
+            },
+            ClientMessage::EnterRoom {
+                rid: "r1".to_string(),
+            },
+            ClientMessage::Knock {
+                rid: "r1".to_string(),
+            },
+            ClientMessage::ApproveKnock {
+                rid: "r1".to_string(),
+                peer_id: "~bus".to_string(),
+            },
+            ClientMessage::DenyKnock {
+                rid: "r1".to_string(),
+                peer_id: "~bus".to_string(),
+            },
+            ClientMessage::LeaveRoom {
+                rid: "r1".to_string(),
+            },
+            ClientMessage::Signal {
+                from: "~zod".to_string(),
+                to: "~bus".to_string(),
+                rid: "r1".to_string(),
+                signal: json!({"type": "offer", "sdp": "v=0"}),
+            },
+            ClientMessage::SubscribeLobby {
+                path: Some("/spaces/~zod/our".to_string()),
+            },
+            ClientMessage::UnsubscribeLobby { path: None },
+            ClientMessage::Disconnect,
+        ];
+        for message in messages {
+            let frame = serde_json::to_string(&message).unwrap();
+            let value: Value = serde_json::from_str(&frame).unwrap();
+            assert_eq!(value["type"], message.kind());
+            assert_eq!(ClientMessage::parse(&frame), Ok(message));
+        }
+    }
+
+    #[test]
+    fn server_messages_round_trip() {
+        let messages = vec![
+            ServerMessage::Rooms {
+                rooms: vec![room()],
+            },
+            ServerMessage::Lobby {
+                path: Some("/spaces/~zod/our".to_string()),
+                rooms: vec![room()],
+                removed: vec!["r2".to_string()],
+            },
+            ServerMessage::Connected {
+                session_id: "s1".to_string(),
+                resume_token: "t1".to_string(),
+            },
+            ServerMessage::RoomCreated { room: room() },
+            ServerMessage::RoomEdited {
+                rid: "r1".to_string(),
+                title: "standup".to_string(),
+                access: Access::Public,
+                capacity: 10,
+            },
+            ServerMessage::RoomDeleted {
+                rid: "r1".to_string(),
+            },
+            ServerMessage::RoomEntered {
+                rid: "r1".to_string(),
+                peer_id: "~bus".to_string(),
+                room: room(),
+            },
+            ServerMessage::RoomLeft {
+                rid: "r1".to_string(),
+                peer_id: "~bus".to_string(),
+                room: room(),
+            },
+            ServerMessage::Knocked {
+                rid: "r1".to_string(),
+                peer_id: "~bus".to_string(),
+            },
+            ServerMessage::KnockApproved {
+                rid: "r1".to_string(),
+            },
+            ServerMessage::KnockDenied {
+                rid: "r1".to_string(),
+            },
+            ServerMessage::Signal {
+                rid: "r1".to_string(),
+                from: "~zod".to_string(),
+                signal: json!({"type": "candidate"}),
+            },
+            ProtocolError::new(ErrorCode::RoomNotFound, "no room r2")
+                .answering("enter-room")
+                .into(),
+        ];
+        for message in messages {
+            let frame = message.to_json();
+            assert_eq!(
+                serde_json::from_str::<ServerMessage>(&frame).unwrap(),
+                message
+            );
+        }
+    }
+
+    #[test]
+    fn frames_keep_their_wire_format() {
+        // what clients have been sending all along
+        let parsed = ClientMessage::parse(
+            r#"{"type": "create-room", "rid": "r1", "title": "standup", "path": "/spaces/~zod/our"}"#,
+        );
+        assert_eq!(
+            parsed,
+            Ok(ClientMessage::CreateRoom {
+                rid: "r1".to_string(),
+                rtype: None,
+                title: "standup".to_string(),
+                path: Some("/spaces/~zod/our".to_string()),
+                access: None,
+                whitelist: Vec::new(),
+                capacity: None,
+            })
+        );
+        assert_eq!(
+            ClientMessage::parse(r#"{"type": "connect"}"#),
+            Ok(ClientMessage::Connect { resume_token: None })
+        );
+        // extra fields are ignored
+        assert_eq!(
+            ClientMessage::parse(r#"{"type": "disconnect", "rid": "r1"}"#),
+            Ok(ClientMessage::Disconnect)
+        );
+
+        let entered = ServerMessage::RoomEntered {
+            rid: "r1".to_string(),
+            peer_id: "~bus".to_string(),
+            room: room(),
+        };
+        let value: Value = serde_json::from_str(&entered.to_json()).unwrap();
+        assert_eq!(value["type"], "room-entered");
+        assert_eq!(value["peer_id"], "~bus");
+        assert_eq!(value["room"]["present"], json!(["~zod"]));
+        let edited: Value = serde_json::from_str(
+            &ServerMessage::RoomEdited {
+                rid: "r1".to_string(),
+                title: "t".to_string(),
+                access: Access::Public,
+                capacity: 2,
+            }
+            .to_json(),
+        )
+        .unwrap();
+        assert_eq!(edited["type"], "edit-room");
+    }
+
+    #[test]
+    fn invalid_frames_are_explained() {
+        let error = |frame: &str| ClientMessage::parse(frame).unwrap_err();
+
+        assert_eq!(error("{not json").code, ErrorCode::InvalidMessage);
+        assert_eq!(error("[1, 2]").code, ErrorCode::InvalidMessage);
+        assert_eq!(error(r#"{"rid": "r1"}"#).request, None);
+
+        let unknown = error(r#"{"type": "dance"}"#);
+        assert_eq!(unknown.code, ErrorCode::UnknownType);
+        assert_eq!(unknown.request.as_deref(), Some("dance"));
+
+        let missing = error(r#"{"type": "enter-room"}"#);
+        assert_eq!(missing.code, ErrorCode::InvalidMessage);
+        assert_eq!(missing.request.as_deref(), Some("enter-room"));
+        assert!(missing.message.contains("rid"), "{}", missing.message);
+
+        let mistyped = error(r#"{"type": "edit-room", "rid": "r1", "capacity": "ten"}"#);
+        assert_eq!(mistyped.code, ErrorCode::InvalidMessage);
+
+        let reply: Value = serde_json::from_str(&ServerMessage::from(mistyped).to_json()).unwrap();
+        assert_eq!(reply["type"], "error");
+        assert_eq!(reply["code"], "invalid-message");
+        assert_eq!(reply["request"], "edit-room");
+    }
+}
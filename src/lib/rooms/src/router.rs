@@ -0,0 +1,375 @@
+//!
+//! who hears about a room
+//!
+//! room events (`room-entered`, `room-left`, `edit-room`, `room-deleted`) go to the
+//!  sessions in the room, and nobody else. the other sessions learn about rooms from
+//!  lobbies: a session subscribes to the lobby of a `path` (`subscribe-lobby`), is sent
+//!  the rooms at that path it can see, and from then on a `lobby` diff whenever one of
+//!  them changes, appears or goes away. the router remembers which rooms it has shown
+//!  each subscriber, so a diff only names rooms the subscriber can see, or saw before.
+//!
+//! what an event costs is bounded by the room's sessions and its lobby's subscribers,
+//!  not by every session the node has.
+//!
+use lazy_static::lazy_static;
+use std::collections::{HashMap, HashSet};
+use std::sync::RwLock;
+use tokio::sync::mpsc::UnboundedSender;
+use warp::ws::Message;
+
+use crate::access::{self, IsMember};
+use crate::protocol::ServerMessage;
+use crate::types::{Rid, Room, SessionId, SESSION_MAP};
+
+type Path = Option<String>;
+
+lazy_static! {
+    // the subscribers of the lobby of each path (None: the rooms without one), with the
+    //  rooms each was shown
+    static ref LOBBIES: RwLock<HashMap<Path, HashMap<SessionId, HashSet<Rid>>>> =
+        RwLock::new(HashMap::new());
+}
+
+/// subscribe `session_id` to the lobby of `path`, and send it the rooms there it can see
+pub(crate) fn subscribe(
+    sender: &UnboundedSender<Message>,
+    session_id: &str,
+    peer_id: &str,
+    path: Path,
+    rooms: &[Room],
+    is_member: &IsMember,
+) {
+    let rooms: Vec<Room> = rooms
+        .iter()
+        .filter(|room| room.path == path && access::can_see(room, peer_id, is_member))
+        .cloned()
+        .collect();
+    let shown = rooms.iter().map(|room| room.rid.clone()).collect();
+    LOBBIES
+        .write()
+        .unwrap()
+        .entry(path.clone())
+        .or_default()
+        .insert(session_id.to_string(), shown);
+    let message = ServerMessage::Lobby {
+        path,
+        rooms,
+        removed: Vec::new(),
+    };
+    let _ = sender.send(Message::text(message.to_json()));
+}
+
+pub(crate) fn unsubscribe(session_id: &str, path: &Path) {
+    let mut lobbies = LOBBIES.write().unwrap();
+    if let Some(subscribers) = lobbies.get_mut(path) {
+        subscribers.remove(session_id);
+        if subscribers.is_empty() {
+            lobbies.remove(path);
+        }
+    }
+}
+
+/// drop the subscriptions of a session that is gone
+pub(crate) fn forget(session_id: &str) {
+    let mut lobbies = LOBBIES.write().unwrap();
+    lobbies.retain(|_, subscribers| {
+        subscribers.remove(session_id);
+        !subscribers.is_empty()
+    });
+}
+
+/// send `message` to the sessions in `room`
+pub(crate) fn to_members(room: &Room, message: &ServerMessage) {
+    let message = message.to_json();
+    let sessions = SESSION_MAP.read().unwrap();
+    for session_id in room.sessions.keys() {
+        if let Some((_, sender)) = sessions.get(session_id) {
+            let _ = sender.send(Message::text(message.clone()));
+        }
+    }
+}
+
+/// send `message` to the sessions of `peer_id` in `room`
+pub(crate) fn to_member(room: &Room, peer_id: &str, message: &ServerMessage) {
+    let message = message.to_json();
+    let sessions = SESSION_MAP.read().unwrap();
+    for session in room.sessions.values() {
+        if session.peer_id == peer_id {
+            if let Some((_, sender)) = sessions.get(&session.id) {
+                let _ = sender.send(Message::text(message.clone()));
+            }
+        }
+    }
+}
+
+/// `room` is new, or changed in a way that may change who can see it (its access or
+///  whitelist). tell the subscribers of its lobby who can see it about it, and the ones
+///  who could but no longer can that it is gone
+pub(crate) fn room_changed(room: &Room, is_member: &IsMember) {
+    diff(room, |peer_id| access::can_see(room, peer_id, is_member));
+}
+
+/// `room` changed, but not who can see it (e.g. who is present): tell the subscribers
+///  of its lobby that were shown it
+pub(crate) fn room_updated(room: &Room) {
+    let rid = &room.rid;
+    let lobbies = LOBBIES.read().unwrap();
+    let subscribers = match lobbies.get(&room.path) {
+        Some(subscribers) => subscribers,
+        None => return,
+    };
+    let message = lobby(room.path.clone(), vec![room.clone()], Vec::new());
+    let sessions = SESSION_MAP.read().unwrap();
+    for (session_id, shown) in subscribers {
+        if shown.contains(rid) {
+            if let Some((_, sender)) = sessions.get(session_id) {
+                let _ = sender.send(message.clone());
+            }
+        }
+    }
+}
+
+/// `room` was deleted: tell the subscribers of its lobby that were shown it
+pub(crate) fn room_gone(room: &Room) {
+    let mut lobbies = LOBBIES.write().unwrap();
+    let subscribers = match lobbies.get_mut(&room.path) {
+        Some(subscribers) => subscribers,
+        None => return,
+    };
+    let message = lobby(room.path.clone(), Vec::new(), vec![room.rid.clone()]);
+    let sessions = SESSION_MAP.read().unwrap();
+    for (session_id, shown) in subscribers.iter_mut() {
+        if shown.remove(&room.rid) {
+            if let Some((_, sender)) = sessions.get(session_id) {
+                let _ = sender.send(message.clone());
+            }
+        }
+    }
+}
+
+fn diff(room: &Room, can_see: impl Fn(&str) -> bool) {
+    let mut lobbies = LOBBIES.write().unwrap();
+    let subscribers = match lobbies.get_mut(&room.path) {
+        Some(subscribers) => subscribers,
+        None => return,
+    };
+    let sessions = SESSION_MAP.read().unwrap();
+    for (session_id, shown) in subscribers.iter_mut() {
+        let (session, sender) = match sessions.get(session_id) {
+            Some(entry) => entry,
+            None => continue,
+        };
+        let message = if can_see(&session.peer_id) {
+            shown.insert(room.rid.clone());
+            lobby(room.path.clone(), vec![room.clone()], Vec::new())
+        } else if shown.remove(&room.rid) {
+            lobby(room.path.clone(), Vec::new(), vec![room.rid.clone()])
+        } else {
+            continue;
+        };
+        let _ = sender.send(message);
+    }
+}
+
+fn lobby(path: Path, rooms: Vec<Room>, removed: Vec<Rid>) -> Message {
+    Message::text(
+        ServerMessage::Lobby {
+            path,
+            rooms,
+            removed,
+        }
+        .to_json(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::socket::handle_message;
+    use crate::types::ROOM_MAP;
+    use futures::executor::block_on;
+    use std::sync::Arc;
+    use tokio::sync::mpsc::{self, UnboundedReceiver};
+    use uuid::Uuid;
+
+    struct Client {
+        session_id: String,
+        peer_id: String,
+        sender: UnboundedSender<Message>,
+        receiver: UnboundedReceiver<Message>,
+    }
+
+    impl Client {
+        fn connect(peer_id: &str) -> Client {
+            let (sender, receiver) = mpsc::unbounded_channel();
+            let mut client = Client {
+                session_id: Uuid::new_v4().to_string(),
+                peer_id: peer_id.to_string(),
+                sender,
+                receiver,
+            };
+            client.send(r#"{"type": "connect"}"#.to_string());
+            client.drain();
+            client
+        }
+
+        fn send(&self, frame: String) {
+            let is_member: IsMember = Arc::new(|_, _| false);
+            block_on(handle_message(
+                self.sender.clone(),
+                &self.session_id,
+                &"127.0.0.1".to_string(),
+                &self.peer_id,
+                &is_member,
+                &frame,
+            ));
+        }
+
+        // the number of frames waiting
+        fn drain(&mut self) -> usize {
+            let mut count = 0;
+            while self.receiver.try_recv().is_ok() {
+                count += 1;
+            }
+            count
+        }
+
+        fn disconnect(&self) {
+            SESSION_MAP.write().unwrap().remove(&self.session_id);
+            forget(&self.session_id);
+        }
+    }
+
+    // the frames a room's life sends to its members, the subscribers of its lobby, the
+    //  subscriber of another lobby and `idle` other sessions
+    fn room_traffic(idle: usize) -> (usize, usize, usize, usize) {
+        let rid = Uuid::new_v4().to_string();
+        let path = format!("/spaces/~zod/{}", rid);
+        let mut members: Vec<Client> = ["~zod", "~bus", "~nec"]
+            .into_iter()
+            .map(Client::connect)
+            .collect();
+        let mut lobby: Vec<Client> = ["~fed", "~wes"].into_iter().map(Client::connect).collect();
+        let mut other = Client::connect("~sev");
+        let mut idle: Vec<Client> = (0..idle).map(|_| Client::connect("~ten")).collect();
+        for client in lobby.iter_mut() {
+            client.send(format!(
+                r#"{{"type": "subscribe-lobby", "path": "{}"}}"#,
+                path
+            ));
+            client.drain();
+        }
+        other.send(r#"{"type": "subscribe-lobby", "path": "/spaces/~zod/other"}"#.to_string());
+        other.drain();
+
+        members[0].send(format!(
+            r#"{{"type": "create-room", "rid": "{}", "title": "t", "path": "{}"}}"#,
+            rid, path
+        ));
+        for member in &members[1..] {
+            member.send(format!(r#"{{"type": "enter-room", "rid": "{}"}}"#, rid));
+        }
+        members[2].send(format!(r#"{{"type": "leave-room", "rid": "{}"}}"#, rid));
+        members[0].send(format!(r#"{{"type": "delete-room", "rid": "{}"}}"#, rid));
+
+        let traffic = (
+            members.iter_mut().map(Client::drain).sum(),
+            lobby.iter_mut().map(Client::drain).sum(),
+            other.drain(),
+            idle.iter_mut().map(Client::drain).sum(),
+        );
+        for client in members.iter().chain(&lobby).chain(&idle) {
+            client.disconnect();
+        }
+        other.disconnect();
+        traffic
+    }
+
+    #[test]
+    fn room_events_reach_the_room_and_its_lobby_only() {
+        let few = room_traffic(10);
+        // created (1), entered (2 + 3), left (3), deleted (2)
+        assert_eq!(few.0, 11);
+        // created, 2 entered, left and deleted, to each subscriber
+        assert_eq!(few.1, 10);
+        assert_eq!((few.2, few.3), (0, 0));
+        // what a room costs does not depend on how many sessions there are
+        assert_eq!(room_traffic(500), few);
+    }
+
+    #[test]
+    fn sessions_outside_a_room_come_and_go_unnoticed() {
+        let rid = Uuid::new_v4().to_string();
+        let path = format!("/spaces/~zod/{}", rid);
+        let zod = Client::connect("~zod");
+        let mut bus = Client::connect("~bus");
+        bus.send(format!(
+            r#"{{"type": "subscribe-lobby", "path": "{}"}}"#,
+            path
+        ));
+        zod.send(format!(
+            r#"{{"type": "create-room", "rid": "{}", "title": "t", "path": "{}"}}"#,
+            rid, path
+        ));
+        bus.drain();
+
+        let nec = Client::connect("~nec");
+        nec.send(r#"{"type": "disconnect"}"#.to_string());
+        assert_eq!(bus.drain(), 0);
+        assert!(ROOM_MAP.read().unwrap().contains_key(&rid));
+
+        zod.send(format!(r#"{{"type": "delete-room", "rid": "{}"}}"#, rid));
+        zod.disconnect();
+        bus.disconnect();
+    }
+
+    #[test]
+    fn lobbies_get_diffs_of_what_their_subscribers_can_see() {
+        let rid = Uuid::new_v4().to_string();
+        let path = format!("/spaces/~zod/{}", rid);
+        let zod = Client::connect("~zod");
+        let mut bus = Client::connect("~bus");
+        bus.send(format!(
+            r#"{{"type": "subscribe-lobby", "path": "{}"}}"#,
+            path
+        ));
+        let mut lobby = || {
+            let mut frames = Vec::new();
+            while let Ok(message) = bus.receiver.try_recv() {
+                match serde_json::from_str(message.to_str().unwrap()).unwrap() {
+                    ServerMessage::Lobby { rooms, removed, .. } => frames.push((
+                        rooms.into_iter().map(|room| room.rid).collect::<Vec<_>>(),
+                        removed,
+                    )),
+                    message => panic!("{:?}", message),
+                }
+            }
+            frames
+        };
+        // nothing there yet
+        assert_eq!(lobby(), vec![(vec![], vec![])]);
+
+        // a private room is not ~bus's business
+        zod.send(format!(
+            r#"{{"type": "create-room", "rid": "{}", "title": "t", "path": "{}", "access": "private"}}"#,
+            rid, path
+        ));
+        assert!(lobby().is_empty());
+        let edit = |access: &str| {
+            format!(
+                r#"{{"type": "edit-room", "rid": "{}", "access": "{}"}}"#,
+                rid, access
+            )
+        };
+        zod.send(edit("public"));
+        assert_eq!(lobby(), vec![(vec![rid.clone()], vec![])]);
+        zod.send(edit("private"));
+        assert_eq!(lobby(), vec![(vec![], vec![rid.clone()])]);
+        // ~bus saw the room go: it does not hear about it going again
+        zod.send(format!(r#"{{"type": "delete-room", "rid": "{}"}}"#, rid));
+        assert!(lobby().is_empty());
+
+        zod.disconnect();
+        bus.disconnect();
+    }
+}
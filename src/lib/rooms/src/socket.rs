@@ -1,75 +1,159 @@
 use futures_util::{SinkExt, StreamExt};
-use serde_json::{json, Value};
+use serde_json::json;
 use std::collections::HashMap;
+use std::convert::Infallible;
 use std::net::{IpAddr, SocketAddr};
 use std::sync::{Arc, RwLock};
 use tokio::sync::mpsc::{self, UnboundedSender};
 use tokio_stream::wrappers::UnboundedReceiverStream;
 use uuid::Uuid;
+use warp::http::StatusCode;
 use warp::ws::{Message, WebSocket};
-use warp::Filter;
+use warp::{Filter, Reply};
 use warp_real_ip::get_forwarded_for;
 
-use trace::{trace_err_ln, trace_good_ln, trace_info_ln, trace_json_ln, trace_warn_ln};
+use trace::{trace_err_ln, trace_good_ln, trace_info_ln, trace_warn_ln};
 
-use crate::types::{PeerId, PeerIp, Room, Session, ROOM_MAP, SESSION_MAP};
-
-// InvalidArgs is the rejection that is raised when the serverId and/or deviceId
-//   arguments are missing from the url query string
-#[derive(Debug)]
-struct InvalidArgs;
-impl warp::reject::Reject for InvalidArgs {}
+use crate::access::{self, IsMember};
+use crate::auth::{self, AuthError, Credentials, Guard};
+use crate::protocol::{ClientMessage, ErrorCode, ProtocolError, ServerMessage};
+use crate::router;
+use crate::store;
+use crate::types::{Access, PeerId, PeerIp, Rid, Room, RoomLock, Session, ROOM_MAP, SESSION_MAP};
 
+/// `/signaling`: the rooms socket. it is only opened for requests whose credentials
+///  the guard's `authenticate` accepts, and the @p they prove becomes the session's peer
+///  id. the `serverId` query parameter, which older clients send, has to agree with it.
+///  the ship's cookie is only taken from upgrades whose `Origin` is the node itself.
+///
+/// `/signaling/challenge` hands out challenges for ships to sign (see `auth`)
 pub fn signaling_route(
+    guard: Guard,
 ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
-    let signaling = warp::path("signaling")
-        .and(warp::header("user-agent"))
+    let Guard {
+        authenticate,
+        is_member,
+    } = guard;
+
+    // the challenge is no secret: any page may ask for one
+    let cors = warp::cors().allow_any_origin().allow_methods(vec!["GET"]);
+    let challenge_route = warp::path!("signaling" / "challenge")
+        .and(warp::get())
+        .map(|| warp::reply::json(&json!({ "challenge": auth::challenge() })))
+        .with(cors);
+
+    let socket_route = warp::path("signaling")
+        .and(warp::ws())
+        .and(warp::header::optional::<String>("cookie"))
+        .and(warp::header::optional::<String>("origin"))
+        .and(warp::header::optional::<String>("host"))
         .and(warp::query::<HashMap<String, String>>())
         .and_then(
-            /*
-              ensure that the url contains the serverId and deviceId as a query string parameter
-            */
-            |user_agent: String, query_params: HashMap<String, String>| async move {
-                trace_info_ln!("user agent: {}", user_agent);
-
-                // deviceId parameter is now required
-                let server_id = query_params.get("serverId");
-
-                if server_id.is_none() {
-                    trace_err_ln!("invalid args. serverId and deviceId required");
-                    return Err(warp::reject::custom(InvalidArgs));
+            move |ws: warp::ws::Ws,
+                  cookie: Option<String>,
+                  origin: Option<String>,
+                  host: Option<String>,
+                  query: HashMap<String, String>| {
+                let authenticate = authenticate.clone();
+                async move {
+                    // upgrades are not covered by CORS, and the browser sends the ship's
+                    //  cookie along from any page. it only counts from the node's own
+                    let cookie = cookie.filter(|_| own_origin(origin.as_deref(), host.as_deref()));
+                    let credentials = Credentials {
+                        cookie,
+                        peer_id: query.get("serverId").cloned(),
+                        challenge: query.get("challenge").cloned(),
+                        signature: query.get("signature").cloned(),
+                    };
+                    let proven = match &credentials.challenge {
+                        Some(challenge) if !auth::redeem(challenge) => {
+                            trace_err_ln!("challenge {} was not handed out", challenge);
+                            Err(AuthError::Unauthorized)
+                        }
+                        _ => authenticate(credentials).await,
+                    };
+                    let proven = proven.and_then(|peer_id| match query.get("serverId") {
+                        Some(claimed)
+                            if claimed.trim_start_matches('~')
+                                != peer_id.trim_start_matches('~') =>
+                        {
+                            trace_err_ln!("serverId {} is not {}", claimed, peer_id);
+                            Err(AuthError::Unauthorized)
+                        }
+                        _ => Ok(peer_id),
+                    });
+                    Ok::<_, Infallible>((ws, proven))
                 }
-
-                let session_id = Uuid::new_v4();
-
-                Ok((
-                    String::from(session_id.to_string()),
-                    String::from(server_id.unwrap()),
-                ))
             },
         )
-        .and(warp::ws())
+        .untuple_one()
         .and(warp::addr::remote())
         .and(get_forwarded_for())
         .map(
-            |args: (String, String),
-             ws: warp::ws::Ws,
-             remote_ip: Option<SocketAddr>,
-             peer_ips: Vec<IpAddr>| {
+            move |ws: warp::ws::Ws,
+                  proven: Result<PeerId, AuthError>,
+                  remote_ip: Option<SocketAddr>,
+                  peer_ips: Vec<IpAddr>| {
+                let peer_id = match proven {
+                    Ok(peer_id) => peer_id,
+                    Err(e) => return refused(e),
+                };
                 let peer_ip = match peer_ips.first() {
                     Some(ip) => ip.to_string(),
-                    None => remote_ip.unwrap().ip().to_string(),
+                    None => remote_ip
+                        .map(|addr| addr.ip().to_string())
+                        .unwrap_or_default(),
                 };
+                let session_id = Uuid::new_v4().to_string();
 
-                trace_info_ln!("upgrading to ws: [{}, {}, {}]", args.0, peer_ip, args.1);
+                trace_info_ln!(
+                    "upgrading to ws: [{}, {}, {}]",
+                    session_id,
+                    peer_ip,
+                    peer_id
+                );
 
-                ws.on_upgrade(move |socket| handle_signaling(socket, peer_ip, args.0, args.1))
+                let is_member = is_member.clone();
+                ws.on_upgrade(move |socket| {
+                    handle_signaling(socket, peer_ip, session_id, peer_id, is_member)
+                })
+                .into_response()
             },
         );
-    signaling
+
+    challenge_route.or(socket_route)
+}
+
+// true if `origin` is the node's, i.e. names the host the request was sent to.
+//  the port counts: another service on the same host is another origin
+fn own_origin(origin: Option<&str>, host: Option<&str>) -> bool {
+    let origin_host = origin.and_then(|origin| {
+        origin
+            .strip_prefix("https://")
+            .or_else(|| origin.strip_prefix("http://"))
+    });
+    match (origin_host, host) {
+        (Some(origin_host), Some(host)) => origin_host.eq_ignore_ascii_case(host),
+        _ => false,
+    }
 }
 
-pub async fn handle_signaling(ws: WebSocket, peer_ip: PeerIp, session_id: String, peer_id: String) {
+// the answer to an upgrade request that proved nothing
+pub(crate) fn refused(e: AuthError) -> warp::reply::Response {
+    let status = match e {
+        AuthError::Unauthorized => StatusCode::FORBIDDEN,
+        AuthError::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    warp::reply::with_status(warp::reply::json(&json!({ "error": e })), status).into_response()
+}
+
+pub async fn handle_signaling(
+    ws: WebSocket,
+    peer_ip: PeerIp,
+    session_id: String,
+    peer_id: String,
+    is_member: IsMember,
+) {
     trace_good_ln!("ws connected: [{}, {}, {}]", session_id, peer_id, peer_ip);
 
     let (mut ws_sender, mut ws_receiver) = ws.split();
@@ -82,7 +166,7 @@ pub async fn handle_signaling(ws: WebSocket, peer_ip: PeerIp, session_id: String
 
     tokio::task::spawn(async move {
         while let Some(message) = receiver.next().await {
-            let msg: Message = Message::from(message);
+            let msg: Message = message;
             let result = ws_sender.send(msg.clone()).await;
 
             if result.is_err() {
@@ -103,14 +187,24 @@ pub async fn handle_signaling(ws: WebSocket, peer_ip: PeerIp, session_id: String
             Ok(message) => message,
             Err(e) => {
                 trace_err_ln!("websocket error: {}", e);
-                disconnect(session_id.as_str(), peer_id.as_str(), peer_ip.as_str());
                 break;
             }
         };
         if let Ok(message) = message.to_str() {
-            handle_message(sender.clone(), &session_id, &peer_ip, &peer_id, message).await;
+            handle_message(
+                sender.clone(),
+                &session_id,
+                &peer_ip,
+                &peer_id,
+                &is_member,
+                message,
+            )
+            .await;
         };
     }
+
+    // the socket is gone, whether it failed or was closed: so is the session
+    disconnect(session_id.as_str(), peer_id.as_str(), peer_ip.as_str());
 }
 
 pub async fn handle_message(
@@ -118,227 +212,242 @@ pub async fn handle_message(
     session_id: &String,
     peer_ip: &PeerIp,
     peer_id: &PeerId,
+    is_member: &IsMember,
     message: &str,
 ) {
-    let message: Value = serde_json::from_str(message).expect("Error parsing message");
-    match message["type"].as_str().unwrap() {
-        // Receive peer info from the client
-        "create-room" => {
-            print!("create-room: [{}, {}, {}]", session_id, peer_id, peer_ip);
-            let rid = message["rid"].as_str().unwrap().to_string();
-            let mut rtype = String::from("media");
-            if !message["rtype"].is_null() {
-                rtype = message["rtype"].as_str().unwrap().to_string();
-            }
-            // let rtype = message["rtype"].as_str().unwrap().to_string();
-            let title = message["title"].as_str().unwrap().to_string();
-            println!(". room: '{}'", title);
-
-            // path is optional
-            let path = match message["path"].as_str() {
-                Some(path) => Some(path.to_string()),
-                None => None,
-            };
+    let result = ClientMessage::parse(message).and_then(|message| {
+        let kind = message.kind();
+        handle(&sender, session_id, peer_ip, peer_id, is_member, message)
+            .map_err(|e| e.answering(kind))
+    });
+    if let Err(e) = result {
+        trace_warn_ln!("[{}, {}] {:?}: {}", session_id, peer_id, e.code, e.message);
+        send(&sender, &e.into());
+    }
+}
 
+fn handle(
+    sender: &UnboundedSender<Message>,
+    session_id: &String,
+    peer_ip: &PeerIp,
+    peer_id: &PeerId,
+    is_member: &IsMember,
+    message: ClientMessage,
+) -> Result<(), ProtocolError> {
+    match message {
+        // Receive peer info from the client
+        ClientMessage::CreateRoom {
+            rid,
+            rtype,
+            title,
+            path,
+            access,
+            whitelist,
+            capacity,
+        } => {
+            trace_info_ln!(
+                "create-room: [{}, {}, {}]. room: '{}'",
+                session_id,
+                peer_id,
+                peer_ip,
+                title
+            );
             let new_room = Room {
-                rid: rid,
-                rtype: rtype,
-                title: title,
+                rid,
+                rtype: rtype.unwrap_or_else(|| String::from("media")),
+                title,
                 creator: peer_id.clone(),
                 provider: "default".to_string(),
-                access: "public".to_string(),
+                // a whitelist only means something for a private room
+                access: access.unwrap_or(if whitelist.is_empty() {
+                    Access::Public
+                } else {
+                    Access::Private
+                }),
                 present: vec![peer_id.clone()],
-                whitelist: Vec::new(),
-                capacity: 10,
-                path: path,
+                whitelist,
+                capacity: capacity.unwrap_or(10),
+                path,
                 origin: session_id.to_string(),
                 sessions: HashMap::from([(
                     session_id.to_string(),
-                    Session {
-                        id: session_id.to_string(),
-                        peer_id: peer_id.to_string(),
-                        peer_ip: peer_ip.to_string(),
-                    },
+                    session(session_id, peer_id, peer_ip),
                 )]),
+                knocks: Vec::new(),
             };
 
             let rid = new_room.rid.clone();
 
             // Add room to ROOM_MAP
-            let new_room_lock = Arc::new(RwLock::new(new_room));
             {
                 let mut rooms = ROOM_MAP.write().unwrap();
-                rooms.insert(rid.clone(), new_room_lock);
-            }
-
-            // Prepare message
-            let rooms_message = {
-                let rooms = ROOM_MAP.read().unwrap();
-                rooms
-                    .iter()
-                    .map(|(_, room)| {
-                        let room = room.read().unwrap();
-                        room.clone()
-                    })
-                    .collect::<Vec<Room>>()
-            };
-
-            let message = json!({
-                "type": "rooms",
-                "rooms": rooms_message,
-            });
-
-            trace_info_ln!("create-room...");
-            trace_json_ln!(&message);
-
-            // send update to all known peers
-            let sessions = SESSION_MAP.read().unwrap();
-            for (_, value) in sessions.iter() {
-                value.1.send(Message::text(message.to_string())).unwrap()
+                if rooms.contains_key(&rid) {
+                    return Err(ProtocolError::new(
+                        ErrorCode::RoomExists,
+                        format!("room {} exists", rid),
+                    ));
+                }
+                store::save(&new_room);
+                router::room_changed(&new_room, is_member);
+                rooms.insert(rid.clone(), Arc::new(RwLock::new(new_room)));
             }
 
             // send self a room-created message
-            let rooms = ROOM_MAP.read().unwrap();
-            let room = match rooms.get(&rid) {
-                Some(room) => room,
-                None => return,
+            let room = match find_room(&rid) {
+                Ok(room) => room.read().unwrap().clone(),
+                // deleted in the meantime
+                Err(_) => return Ok(()),
             };
-            let room = room.read().unwrap();
-            let message = json!({
-                "type": "room-created",
-                "room": room.clone(),
-            });
 
             // @patrick
             //  this is a change based on a request for mobile support. originally
             //  we only sent out the room-created event to the creator of the room
             //  with this change; however, we are going to send the room-created event
             //  to ALL known peers
-            // send update to all known peers
-            // let sessions = SESSION_MAP.read().unwrap();
-            // for (_, value) in sessions.iter() {
-            //     value.1.send(Message::text(message.to_string())).unwrap()
-            // }
-            sender.send(Message::text(message.to_string())).unwrap()
+            // since then, the other peers hear about new rooms from their lobbies
+            send(sender, &ServerMessage::RoomCreated { room });
         }
 
-        "edit-room" => {
-            println!("edit-room: [{}, {}, {}]", session_id, peer_id, peer_ip);
-            let rid = message["rid"].as_str().unwrap().to_string();
-            let rooms = ROOM_MAP.read().unwrap();
-            let room = match rooms.get(&rid) {
-                Some(room) => room,
-                None => {
-                    println!(". room not found {}", rid);
-                    return;
-                }
-            };
+        ClientMessage::EditRoom {
+            rid,
+            title,
+            access,
+            capacity,
+            whitelist,
+        } => {
+            trace_info_ln!("edit-room: [{}, {}, {}]", session_id, peer_id, peer_ip);
+            let room = find_room(&rid)?;
             let mut room = room.write().unwrap();
-            if room.creator != peer_id.clone() {
-                return;
+            only_creator(&room, peer_id)?;
+            if let Some(title) = title {
+                room.title = title
             }
-            match message["title"].as_str() {
-                Some(title) => room.title = title.to_string(),
-                None => (),
-            };
-            match message["access"].as_str() {
-                Some(access) => room.access = access.to_string(),
-                None => (),
-            };
-            match message["capacity"].as_u64() {
-                Some(capacity) => room.capacity = capacity as u32,
-                None => (),
-            };
-
-            let message = json!({
-                "type": "edit-room",
-                "title": room.title.clone(),
-                "access": room.access.clone(),
-                "capacity": room.capacity,
-            });
-
-            // send update to all known peers
-            let sessions = SESSION_MAP.read().unwrap();
-            for (_, value) in sessions.iter() {
-                value.1.send(Message::text(message.to_string())).unwrap()
+            if let Some(access) = access {
+                room.access = access
+            }
+            if let Some(capacity) = capacity {
+                room.capacity = capacity
+            }
+            if let Some(whitelist) = whitelist {
+                room.whitelist = whitelist
             }
+            store::save(&room);
+
+            // send update to the room, and its lobby: who can see it may have changed
+            router::to_members(
+                &room,
+                &ServerMessage::RoomEdited {
+                    rid,
+                    title: room.title.clone(),
+                    access: room.access,
+                    capacity: room.capacity,
+                },
+            );
+            router::room_changed(&room, is_member);
         }
-        "delete-room" => {
-            print!("delete-room: [{}, {}, {}]", session_id, peer_id, peer_ip);
-            let rid = message["rid"].as_str().unwrap().to_string();
+        ClientMessage::DeleteRoom { rid } => {
+            trace_info_ln!("delete-room: [{}, {}, {}]", session_id, peer_id, peer_ip);
+            only_creator(&find_room(&rid)?.read().unwrap(), peer_id)?;
             delete_room(session_id, &rid);
         }
-        "enter-room" => {
-            print!("enter-room: [{}, {}, {}]", session_id, peer_id, peer_ip);
-            let rid = message["rid"].as_str().unwrap().to_string();
-
-            // Retrieve the room
-            let rooms = ROOM_MAP.read().unwrap();
-            let room = match rooms.get(&rid) {
-                Some(room) => room,
-                None => {
-                    println!(". room not found {}", rid);
-                    return;
-                }
-            };
-
+        ClientMessage::EnterRoom { rid } => {
+            trace_info_ln!("enter-room: [{}, {}, {}]", session_id, peer_id, peer_ip);
+            let room = find_room(&rid)?;
             let mut room = room.write().unwrap();
-            println!(". room: '{}'", room.title);
+            trace_info_ln!(". room: '{}'", room.title);
 
             if room.sessions.contains_key(session_id) {
-                println!("{}/{} already in room", session_id, peer_id);
-                return;
+                trace_info_ln!("{}/{} already in room", session_id, peer_id);
+                return Ok(());
             }
+            access::can_enter(&room, peer_id, is_member).map_err(|code| {
+                let message = match code {
+                    ErrorCode::RoomFull => format!("room {} is full", rid),
+                    _ => format!("{} may not enter room {}", peer_id, rid),
+                };
+                ProtocolError::new(code, message)
+            })?;
 
-            room.sessions.insert(session_id.to_string(), Session {
-              id: session_id.to_string(),
-              peer_id: peer_id.to_string(),
-              peer_ip: peer_ip.to_string()
-            });
+            room.sessions.insert(
+                session_id.to_string(),
+                session(session_id, peer_id, peer_ip),
+            );
 
             if !room.present.contains(peer_id) {
                 room.present.push(peer_id.clone());
             }
+            room.knocks.retain(|id| id != peer_id);
+            store::save(&room);
 
-            // Create the message
-            let message = json!({
-                "type": "room-entered",
-                "rid": rid.clone(),
-                "peer_id": peer_id.clone(),
-                "room": &room.clone(),
-            });
-
-            // send update to all known peers
-            let sessions = SESSION_MAP.read().unwrap();
-            for (_, value) in sessions.iter() {
-                value.1.send(Message::text(message.to_string())).unwrap()
+            // send update to the room, and its lobby
+            router::to_members(
+                &room,
+                &ServerMessage::RoomEntered {
+                    rid,
+                    peer_id: peer_id.clone(),
+                    room: room.clone(),
+                },
+            );
+            router::room_updated(&room);
+        }
+        ClientMessage::Knock { rid } => {
+            trace_info_ln!("knock: [{}, {}, {}]", session_id, peer_id, peer_ip);
+            let room = find_room(&rid)?;
+            let mut room = room.write().unwrap();
+            // nothing to ask for
+            if access::can_see(&room, peer_id, is_member) {
+                send(sender, &ServerMessage::KnockApproved { rid });
+                return Ok(());
             }
+            if !room.knocks.contains(peer_id) {
+                room.knocks.push(peer_id.clone());
+            }
+            send_to_peer(
+                &room.creator,
+                &ServerMessage::Knocked {
+                    rid,
+                    peer_id: peer_id.clone(),
+                },
+            );
         }
-        "leave-room" => {
-            print!("leave-room: [{}, {}, {}]", session_id, peer_id, peer_ip);
-            let rid = message["rid"].as_str().unwrap().to_string();
+        ClientMessage::ApproveKnock {
+            rid,
+            peer_id: knocker,
+        } => {
+            trace_info_ln!("approve-knock: [{}, {}, {}]", session_id, peer_id, peer_ip);
+            answer_knock(&rid, peer_id, &knocker, true, is_member)?;
+            send_to_peer(&knocker, &ServerMessage::KnockApproved { rid });
+        }
+        ClientMessage::DenyKnock {
+            rid,
+            peer_id: knocker,
+        } => {
+            trace_info_ln!("deny-knock: [{}, {}, {}]", session_id, peer_id, peer_ip);
+            answer_knock(&rid, peer_id, &knocker, false, is_member)?;
+            send_to_peer(&knocker, &ServerMessage::KnockDenied { rid });
+        }
+        ClientMessage::LeaveRoom { rid } => {
+            trace_info_ln!("leave-room: [{}, {}, {}]", session_id, peer_id, peer_ip);
             let rooms = ROOM_MAP.read().unwrap();
-            let room = match rooms.get(&rid) {
-                Some(room) => room,
-                None => {
-                    println!(". room not found {}", rid);
-                    return;
-                }
-            };
+            let room = rooms.get(&rid).ok_or_else(|| room_not_found(&rid))?;
             let mut room = room.write().unwrap();
-            println!(". room: '{}'", room.title);
+            trace_info_ln!(". room: '{}'", room.title);
 
-            if !room
-                .sessions
-                .iter()
-                .any(|(_sid, session)| &session.peer_id == peer_id)
-            {
-                if let Some(index) = room.present.iter().position(|id| id == peer_id) {
-                    room.present.remove(index);
+            // the peer is still present while another session of theirs is in the room
+            if room.sessions.remove(session_id).is_some() {
+                if !room
+                    .sessions
+                    .values()
+                    .any(|session| &session.peer_id == peer_id)
+                {
+                    room.present.retain(|id| id != peer_id);
                 }
+                let mut sessions = SESSION_MAP.write().unwrap();
+                sessions.remove(session_id);
+                drop(sessions);
             }
 
-            if room.present.len() == 0 || &room.origin == session_id {
+            if room.present.is_empty() || &room.origin == session_id {
                 trace_warn_ln!(
                     "{}/{} is last one in room or the creator. deleting room {}...",
                     session_id,
@@ -347,117 +456,270 @@ pub async fn handle_message(
                 );
                 drop(room);
                 drop(rooms);
+                send(sender, &ServerMessage::RoomDeleted { rid: rid.clone() });
                 delete_room(session_id, &rid);
-                return;
+                return Ok(());
             }
 
-            if room.sessions.contains_key(session_id) {
-              room.sessions.remove(session_id);
-              let mut sessions = SESSION_MAP.write().unwrap();
-              sessions.remove(session_id);
-              drop(sessions);
-          }
-
-            // Create the message
-            let message = json!({
-                "type": "room-left",
-                "rid": rid.clone(),
-                "peer_id": peer_id.clone(),
-                "room": room.clone(),
-            });
-
-            // send update to all known peers
-            let sessions = SESSION_MAP.read().unwrap();
-            for (_, value) in sessions.iter() {
-                value.1.send(Message::text(message.to_string())).unwrap()
-            }
+            store::save(&room);
+
+            // send update to the room (this session is out of it by now), and its lobby
+            let left = ServerMessage::RoomLeft {
+                rid,
+                peer_id: peer_id.clone(),
+                room: room.clone(),
+            };
+            router::to_members(&room, &left);
+            send(sender, &left);
+            router::room_updated(&room);
         }
-        "signal" => {
+        ClientMessage::Signal {
+            from,
+            to,
+            rid,
+            signal,
+        } => {
             // signal_type - webrtc: offer, answer, candidate, renegotiate, transceiverRequest, transceiverAnswer, transceiverIce, transceiverClose
             // signal_type - realm: cursor, chat, file, video, audio, screen
-            let from = message["from"].as_str().unwrap().to_string();
-            let to = message["to"].as_str().unwrap().to_string();
-            let rid = message["rid"].as_str().unwrap().to_string();
-            // println!(
-            //     "{} - signal - {}, {}, {}, {}",
-            //     peer_id, peer_ip, from, to, rid
-            // );
+            if &from != peer_id {
+                return Err(ProtocolError::new(
+                    ErrorCode::WrongPeer,
+                    format!("{} cannot signal as {}", peer_id, from),
+                ));
+            }
             // check if they are in the same room first
-            let rooms = ROOM_MAP.read().unwrap();
-            let room = match rooms.get(&rid) {
-                Some(room) => room,
-                None => {
-                    println!("room not found {}", rid);
-                    return;
-                }
-            };
+            let room = find_room(&rid)?;
             let room = room.read().unwrap();
             if !room.present.contains(&from) || !room.present.contains(&to) {
-                println!("both peers not in room {}", rid);
-                return;
+                return Err(ProtocolError::new(
+                    ErrorCode::NotInRoom,
+                    format!("{} and {} are not both in room {}", from, to, rid),
+                ));
             }
-            let signal = message["signal"].clone();
-            println!("signal_type: {}", signal["type"]);
-
-            let message = json!({
-                "type": "signal",
-                "rid": rid.clone(),
-                "from": from,
-                "signal": signal,
-            });
-
-            // send update to all known peers
-            let sessions = SESSION_MAP.read().unwrap();
-            for (_, value) in sessions.iter() {
-                if value.0.peer_id == to {
-                    value.1.send(Message::text(message.to_string())).unwrap()
-                }
+
+            router::to_member(&room, &to, &ServerMessage::Signal { rid, from, signal });
+        }
+        ClientMessage::Connect { resume_token } => {
+            trace_info_ln!("connect: [{}, {}, {}]", session_id, peer_id, peer_ip);
+            // a token is only good for the seats of the peer it was given to
+            let seats: Vec<Rid> = match &resume_token {
+                Some(token) => store::seats(token)
+                    .into_iter()
+                    .filter(|(_, seated)| seated == peer_id)
+                    .map(|(rid, _)| rid)
+                    .collect(),
+                None => Vec::new(),
+            };
+            let resume_token = match resume_token {
+                Some(token) if !seats.is_empty() => token,
+                _ => Uuid::new_v4().to_string(),
+            };
+            let session = Session {
+                id: session_id.to_string(),
+                peer_id: peer_id.to_string(),
+                peer_ip: peer_ip.to_string(),
+                resume_token: resume_token.clone(),
+            };
+            SESSION_MAP
+                .write()
+                .unwrap()
+                .insert(session_id.to_string(), (session.clone(), sender.clone()));
+            for rid in seats {
+                reclaim(&rid, &session);
             }
+
+            send(
+                sender,
+                &ServerMessage::Rooms {
+                    rooms: visible_rooms(&all_rooms(), peer_id, is_member),
+                },
+            );
+            send(
+                sender,
+                &ServerMessage::Connected {
+                    session_id: session_id.to_string(),
+                    resume_token,
+                },
+            );
         }
-        "connect" => {
-            println!("connect: [{}, {}, {}]", session_id, peer_id, peer_ip);
-            let rooms = ROOM_MAP.read().unwrap();
-            let rooms: Vec<Room> = rooms
-                .iter()
-                // .filter(|&(_k, v)| v.read().unwrap().rtype == "interactive")
-                .map(|(_, room)| room.read().unwrap().clone())
-                .collect();
-            let message = json!({
-                "type": "rooms",
-                "rooms": rooms,
-            });
+        ClientMessage::SubscribeLobby { path } => {
+            trace_info_ln!(
+                "subscribe-lobby: [{}, {}, {}]",
+                session_id,
+                peer_id,
+                peer_ip
+            );
+            router::subscribe(sender, session_id, peer_id, path, &all_rooms(), is_member);
+        }
+        ClientMessage::UnsubscribeLobby { path } => {
+            trace_info_ln!(
+                "unsubscribe-lobby: [{}, {}, {}]",
+                session_id,
+                peer_id,
+                peer_ip
+            );
+            router::unsubscribe(session_id, &path);
+        }
+        ClientMessage::Disconnect => disconnect(session_id, peer_id, peer_ip),
+    };
+    Ok(())
+}
 
-            trace_json_ln!(&message);
+// some changes are for the room's creator only
+fn only_creator(room: &Room, peer_id: &PeerId) -> Result<(), ProtocolError> {
+    if &room.creator != peer_id {
+        return Err(ProtocolError::new(
+            ErrorCode::NotCreator,
+            format!("room {} belongs to {}", room.rid, room.creator),
+        ));
+    }
+    Ok(())
+}
 
-            sender.send(Message::text(message.to_string())).unwrap();
+// take a knock off the room, and put the knocker on its whitelist if approved
+fn answer_knock(
+    rid: &str,
+    peer_id: &PeerId,
+    knocker: &PeerId,
+    approve: bool,
+    is_member: &IsMember,
+) -> Result<(), ProtocolError> {
+    let room = find_room(rid)?;
+    let mut room = room.write().unwrap();
+    only_creator(&room, peer_id)?;
+    let index = room
+        .knocks
+        .iter()
+        .position(|id| id == knocker)
+        .ok_or_else(|| {
+            ProtocolError::new(
+                ErrorCode::NoKnock,
+                format!("{} did not knock on room {}", knocker, rid),
+            )
+        })?;
+    room.knocks.remove(index);
+    if approve && !room.whitelist.contains(knocker) {
+        room.whitelist.push(knocker.clone());
+        store::save(&room);
+        // the knocker's lobby shows the room now
+        router::room_changed(&room, is_member);
+    }
+    Ok(())
+}
 
-            let message = json!({
-                "type": "connected",
-                "session_id": session_id,
-            });
+// the session of a peer, with the resume token it connected with
+fn session(session_id: &str, peer_id: &str, peer_ip: &str) -> Session {
+    let sessions = SESSION_MAP.read().unwrap();
+    Session {
+        id: session_id.to_string(),
+        peer_id: peer_id.to_string(),
+        peer_ip: peer_ip.to_string(),
+        resume_token: sessions
+            .get(session_id)
+            .map(|(session, _)| session.resume_token.clone())
+            .unwrap_or_default(),
+    }
+}
 
-            sender.send(Message::text(message.to_string())).unwrap();
+// put a returning session back in the seat its peer held in room `rid`. the seat may
+//  have been given up in the meantime
+fn reclaim(rid: &str, session: &Session) {
+    let room = match find_room(rid) {
+        Ok(room) => room,
+        Err(_) => return,
+    };
+    let mut room = room.write().unwrap();
+    if !room.present.contains(&session.peer_id) {
+        return;
+    }
+    trace_info_ln!(
+        "{}/{} reclaims a seat in {}",
+        session.id,
+        session.peer_id,
+        rid
+    );
+    room.sessions.insert(session.id.clone(), session.clone());
+    // the room is the creator's again: it ends when they leave
+    if room.creator == session.peer_id && room.origin.is_empty() {
+        room.origin = session.id.clone();
+    }
+    store::save(&room);
+    router::to_members(
+        &room,
+        &ServerMessage::RoomEntered {
+            rid: rid.to_string(),
+            peer_id: session.peer_id.clone(),
+            room: room.clone(),
+        },
+    );
+    router::room_updated(&room);
+}
 
-            let mut sessions = SESSION_MAP.write().unwrap();
-            sessions.insert(
-                session_id.to_string(),
-                (
-                    Session {
-                        id: session_id.to_string(),
-                        peer_id: peer_id.to_string(),
-                        peer_ip: peer_ip.to_string(),
-                    },
-                    sender.clone(),
-                ),
-            );
+/// the grace period of the rooms in `rids`, put back after a restart, is over: give up
+///  the seats nobody reclaimed, and delete the rooms whose creator did not come back
+pub(crate) fn end_grace(rids: &[Rid]) {
+    for rid in rids {
+        let room = match find_room(rid) {
+            Ok(room) => room,
+            Err(_) => continue,
+        };
+        let mut room = room.write().unwrap();
+        let seated: Vec<PeerId> = room
+            .sessions
+            .values()
+            .map(|session| session.peer_id.clone())
+            .collect();
+        room.present.retain(|peer_id| seated.contains(peer_id));
+        if room.present.is_empty() || room.origin.is_empty() {
+            trace_warn_ln!("room {} was not reclaimed. deleting...", rid);
+            drop(room);
+            delete_room("", rid);
+        } else {
+            store::save(&room);
+            router::room_updated(&room);
         }
-        "disconnect" => disconnect(session_id, peer_id, peer_ip),
-        _ => unknown(),
-    };
+    }
+}
+
+fn room_not_found(rid: &str) -> ProtocolError {
+    ProtocolError::new(ErrorCode::RoomNotFound, format!("room {} not found", rid))
+}
+
+fn find_room(rid: &str) -> Result<RoomLock, ProtocolError> {
+    let rooms = ROOM_MAP.read().unwrap();
+    rooms.get(rid).cloned().ok_or_else(|| room_not_found(rid))
+}
+
+fn all_rooms() -> Vec<Room> {
+    let rooms = ROOM_MAP.read().unwrap();
+    rooms
+        .values()
+        .map(|room| room.read().unwrap().clone())
+        .collect()
+}
+
+// a closed session is dropped on disconnect: failing to reach it is not an error
+fn send(sender: &UnboundedSender<Message>, message: &ServerMessage) {
+    let _ = sender.send(Message::text(message.to_json()));
+}
+
+fn visible_rooms(rooms: &[Room], peer_id: &str, is_member: &IsMember) -> Vec<Room> {
+    rooms
+        .iter()
+        .filter(|room| access::can_see(room, peer_id, is_member))
+        .cloned()
+        .collect()
 }
 
-fn unknown() {
-    trace_warn_ln!("unknown message type")
+// send to every session of `peer_id`
+fn send_to_peer(peer_id: &str, message: &ServerMessage) {
+    let message = message.to_json();
+    let sessions = SESSION_MAP.read().unwrap();
+    for (_, value) in sessions.iter() {
+        if value.0.peer_id == peer_id {
+            let _ = value.1.send(Message::text(message.clone()));
+        }
+    }
 }
 
 fn delete_room(_session_id: &str, room_id: &str) {
@@ -465,35 +727,33 @@ fn delete_room(_session_id: &str, room_id: &str) {
     let room = match rooms.remove(room_id) {
         Some(room) => room,
         None => {
-            println!(". room not found {}", room_id);
+            trace_warn_ln!(". room not found {}", room_id);
             return;
         }
     };
 
+    store::forget(room_id);
     let room = room.read().unwrap();
-    println!(". room: '{}'", room.title);
+    trace_info_ln!(". room: '{}'", room.title);
+
+    // send update to the room, and its lobby
+    router::to_members(
+        &room,
+        &ServerMessage::RoomDeleted {
+            rid: room_id.to_string(),
+        },
+    );
+    router::room_gone(&room);
 
     let mut sessions = SESSION_MAP.write().unwrap();
     for (sid, _) in room.sessions.iter() {
-      trace_warn_ln!("removing session {}...", sid);
-      sessions.remove(sid);
-    }
-    drop(sessions);
-
-    let message = json!({
-        "type": "room-deleted",
-        "rid": room_id.clone(),
-    });
-
-    // send update to all known peers
-    let sessions = SESSION_MAP.read().unwrap();
-    for (_, value) in sessions.iter() {
-        value.1.send(Message::text(message.to_string())).unwrap()
+        trace_warn_ln!("removing session {}...", sid);
+        sessions.remove(sid);
     }
 }
 
 fn disconnect(session_id: &str, peer_id: &str, peer_ip: &str) {
-    println!("disconnect: [{}, {}, {}]", session_id, peer_id, peer_ip);
+    trace_info_ln!("disconnect: [{}, {}, {}]", session_id, peer_id, peer_ip);
     let mut room_ids_to_remove = Vec::new();
     let mut session_ids_to_remove = Vec::new();
 
@@ -501,36 +761,47 @@ fn disconnect(session_id: &str, peer_id: &str, peer_ip: &str) {
         let rooms = ROOM_MAP.read().unwrap();
         for (rid, room) in rooms.iter() {
             let mut room = room.write().unwrap();
-            if let Some(index) = room
+            // the other rooms, and their lobbies, are none of this session's business
+            if room.sessions.remove(session_id).is_none() {
+                continue;
+            }
+            // the peer is still present while another session of theirs is in the room
+            if !room
                 .sessions
-                .iter()
-                .position(|(sid, _session)| sid == session_id)
+                .values()
+                .any(|session| session.peer_id == peer_id)
             {
-                room.present.remove(index);
+                room.present.retain(|id| id != peer_id);
             }
             // if the peer was the last one in the room or the owner of the room, mark the room for removal
-            if room.present.is_empty() || &room.origin == session_id {
+            if room.present.is_empty() || room.origin == session_id {
                 room_ids_to_remove.push(rid.clone());
                 // queue up all sessions in this room. they will need to be removed
                 for (sid, _) in room.sessions.iter() {
-                  session_ids_to_remove.push(sid.clone());
+                    session_ids_to_remove.push(sid.clone());
                 }
+                // send update to the room (while its sessions are still known), and its
+                //  lobby
+                router::to_members(&room, &ServerMessage::RoomDeleted { rid: rid.clone() });
+                router::room_gone(&room);
+            } else {
+                store::save(&room);
+                router::room_updated(&room);
             }
         }
     }
 
+    router::forget(session_id);
+
     // for each room that is being deleted, remove all the associated connections/sessions
     {
-      let mut sessions = SESSION_MAP.write().unwrap();
-      for sid in session_ids_to_remove {
-        sessions.remove(&sid);
-      }
-
-      // print current peer ids
-      println!("Current peers: {:?}", sessions.keys());
-      for (_, value) in sessions.iter() {
-          println!("[{}, {}, {}]", value.0.id, value.0.peer_id, value.0.peer_ip);
-      }
+        let mut sessions = SESSION_MAP.write().unwrap();
+        sessions.remove(session_id);
+        for sid in session_ids_to_remove {
+            sessions.remove(&sid);
+        }
+
+        trace_info_ln!("{} session(s) left", sessions.len());
     }
 
     // Remove rooms in a separate pass to avoid the mutable borrow issue
@@ -538,17 +809,719 @@ fn disconnect(session_id: &str, peer_id: &str, peer_ip: &str) {
         let mut rooms = ROOM_MAP.write().unwrap();
         for rid in room_ids_to_remove {
             rooms.remove(&rid);
+            store::forget(&rid);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Authenticate;
+    use futures::executor::block_on;
+    use futures::FutureExt;
+    use tokio::sync::mpsc::UnboundedReceiver;
+    use warp::test::WsClient;
+
+    const COOKIE: &str = "urbauth-~zod=0v1.abcde";
+    // where the node serves its pages
+    const HOST: &str = "localhost:3030";
+    const ORIGIN: &str = "http://localhost:3030";
 
-            let message = json!({
-                "type": "room-deleted",
-                "rid": rid.clone(),
-            });
+    // COOKIE is ~zod's, and a ship signs a challenge with `signature`
+    fn authenticate() -> Authenticate {
+        Arc::new(|credentials: Credentials| {
+            async move {
+                if credentials.cookie.as_deref() == Some(COOKIE) {
+                    return Ok("~zod".to_string());
+                }
+                match credentials {
+                    Credentials {
+                        peer_id: Some(peer_id),
+                        challenge: Some(challenge),
+                        signature: Some(signed),
+                        ..
+                    } if signed == signature(&peer_id, &challenge) => Ok(peer_id),
+                    _ => Err(AuthError::Unauthorized),
+                }
+            }
+            .boxed()
+        })
+    }
+
+    fn signature(peer_id: &str, challenge: &str) -> String {
+        format!("{}.{}", peer_id, challenge)
+    }
+
+    // ~nec is in ~zod's spaces
+    fn is_member() -> IsMember {
+        Arc::new(|space, peer| space.starts_with("/spaces/~zod/") && peer == "~nec")
+    }
+
+    fn guard() -> Guard {
+        Guard {
+            authenticate: authenticate(),
+            is_member: is_member(),
+        }
+    }
+
+    fn upgrade(path: &str) -> warp::test::RequestBuilder {
+        warp::test::request()
+            .path(path)
+            .header("connection", "upgrade")
+            .header("upgrade", "websocket")
+            .header("sec-websocket-version", "13")
+            .header("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ==")
+    }
+
+    #[tokio::test]
+    async fn signaling_needs_a_proven_identity() {
+        let route = signaling_route(guard());
+
+        let res = upgrade("/signaling?serverId=~zod").reply(&route).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        let res = upgrade("/signaling")
+            .header("cookie", "urbauth-~zod=0v9.forged")
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        // a cookie of ~zod's does not make anyone ~bus
+        let res = upgrade("/signaling?serverId=~bus")
+            .header("cookie", COOKIE)
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        let mut client = warp::test::ws()
+            .path("/signaling?serverId=zod")
+            .header("cookie", COOKIE)
+            .header("host", HOST)
+            .header("origin", ORIGIN)
+            .handshake(route)
+            .await
+            .unwrap();
+        client.send_text(r#"{"type": "connect"}"#).await;
+        // the room list comes first
+        let session_id = loop {
+            let frame = client.recv().await.unwrap();
+            if let Ok(ServerMessage::Connected { session_id, .. }) =
+                serde_json::from_str(frame.to_str().unwrap())
+            {
+                break session_id;
+            }
+        };
+        // the session is ~zod's
+        let session = SESSION_MAP.read().unwrap()[&session_id].0.clone();
+        assert_eq!(session.peer_id, "~zod");
+        SESSION_MAP.write().unwrap().remove(&session_id);
+    }
+
+    #[tokio::test]
+    async fn cookies_only_count_from_the_nodes_own_pages() {
+        let route = signaling_route(guard());
+
+        // another site, another port on the same host, or no origin at all
+        for origin in [
+            Some("https://evil.example"),
+            Some("http://localhost:8080"),
+            Some("null"),
+            None,
+        ] {
+            let mut request = upgrade("/signaling")
+                .header("cookie", COOKIE)
+                .header("host", HOST);
+            if let Some(origin) = origin {
+                request = request.header("origin", origin);
+            }
+            let res = request.reply(&route).await;
+            assert_eq!(res.status(), StatusCode::FORBIDDEN, "{:?}", origin);
+        }
+
+        let (_client, session_id) = connect(route, "/signaling", Some(COOKIE)).await;
+        assert!(SESSION_MAP.read().unwrap().contains_key(&session_id));
+        SESSION_MAP.write().unwrap().remove(&session_id);
+    }
+
+    // a connected session over `route` at `path`, with its id
+    async fn connect<F>(route: F, path: &str, cookie: Option<&str>) -> (WsClient, String)
+    where
+        F: Filter + Clone + Send + Sync + 'static,
+        F::Extract: Reply + Send,
+    {
+        let mut request = warp::test::ws().path(path);
+        if let Some(cookie) = cookie {
+            request = request
+                .header("cookie", cookie)
+                .header("host", HOST)
+                .header("origin", ORIGIN);
+        }
+        let mut client = request.handshake(route).await.unwrap();
+        client.send_text(r#"{"type": "connect"}"#).await;
+        // the room list comes first
+        let session_id = loop {
+            let frame = client.recv().await.unwrap();
+            if let Ok(ServerMessage::Connected { session_id, .. }) =
+                serde_json::from_str(frame.to_str().unwrap())
+            {
+                break session_id;
+            }
+        };
+        (client, session_id)
+    }
+
+    #[tokio::test]
+    async fn other_ships_sign_a_challenge() {
+        let route = signaling_route(guard());
+        let challenge = || async {
+            let res = warp::test::request()
+                .path("/signaling/challenge")
+                .reply(&signaling_route(guard()))
+                .await;
+            assert_eq!(res.status(), StatusCode::OK);
+            let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+            body["challenge"].as_str().unwrap().to_string()
+        };
+        let signed = |peer_id: &str, challenge: &str| {
+            format!(
+                "/signaling?serverId={}&challenge={}&signature={}",
+                peer_id,
+                challenge,
+                signature(peer_id, challenge)
+            )
+        };
+
+        // a challenge that was not handed out, or is signed by someone else
+        let res = upgrade(&signed("~bus", "0123456789abcdef0123456789abcdef"))
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        let issued = challenge().await;
+        let forged = format!(
+            "/signaling?serverId=~bus&challenge={}&signature={}",
+            issued,
+            signature("~nec", &issued)
+        );
+        let res = upgrade(&forged).reply(&route).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        // a challenge is only good once
+        let issued = challenge().await;
+        let (_bus, bus) = connect(route.clone(), &signed("~bus", &issued), None).await;
+        let res = upgrade(&signed("~bus", &issued)).reply(&route).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        // ~zod, on its own node, and ~bus, who signed, are in a room together
+        let (mut zod_client, zod) = connect(route.clone(), "/signaling", Some(COOKIE)).await;
+        let rid = Uuid::new_v4().to_string();
+        let peer = |session_id: &str| SESSION_MAP.read().unwrap()[session_id].0.peer_id.clone();
+        assert_eq!(peer(&zod), "~zod");
+        assert_eq!(peer(&bus), "~bus");
+        let (sender, _receiver) = mpsc::unbounded_channel();
+        let frame = |session_id: &String, frame: String| {
+            block_on(handle_message(
+                sender.clone(),
+                session_id,
+                &"127.0.0.1".to_string(),
+                &peer(session_id),
+                &is_member(),
+                &frame,
+            ))
+        };
+        frame(
+            &zod,
+            format!(
+                r#"{{"type": "create-room", "rid": "{}", "title": "t", "whitelist": ["~bus"]}}"#,
+                rid
+            ),
+        );
+        frame(
+            &bus,
+            format!(r#"{{"type": "enter-room", "rid": "{}"}}"#, rid),
+        );
+        let entered = loop {
+            let frame = zod_client.recv().await.unwrap();
+            if let Ok(ServerMessage::RoomEntered { peer_id, .. }) =
+                serde_json::from_str(frame.to_str().unwrap())
+            {
+                break peer_id;
+            }
+        };
+        assert_eq!(entered, "~bus");
+        {
+            let rooms = ROOM_MAP.read().unwrap();
+            assert_eq!(rooms[&rid].read().unwrap().present, vec!["~zod", "~bus"]);
+        }
+
+        frame(
+            &zod,
+            format!(r#"{{"type": "delete-room", "rid": "{}"}}"#, rid),
+        );
+        let mut sessions = SESSION_MAP.write().unwrap();
+        sessions.remove(&zod);
+        sessions.remove(&bus);
+    }
+
+    #[tokio::test]
+    async fn closed_sockets_give_up_their_seats() {
+        let route = signaling_route(guard());
+        let (host_client, host) = connect(route.clone(), "/signaling", Some(COOKIE)).await;
+        let (guest_client, guest) = connect(route.clone(), "/signaling", Some(COOKIE)).await;
+        let rid = Uuid::new_v4().to_string();
+        let (sender, _receiver) = mpsc::unbounded_channel();
+        let frame = |session_id: &String, frame: String| {
+            block_on(handle_message(
+                sender.clone(),
+                session_id,
+                &"127.0.0.1".to_string(),
+                &"~zod".to_string(),
+                &is_member(),
+                &frame,
+            ))
+        };
+        frame(
+            &host,
+            format!(
+                r#"{{"type": "create-room", "rid": "{}", "title": "t"}}"#,
+                rid
+            ),
+        );
+        frame(
+            &guest,
+            format!(r#"{{"type": "enter-room", "rid": "{}"}}"#, rid),
+        );
+        let seated = |session_id: &str| {
+            let rooms = ROOM_MAP.read().unwrap();
+            rooms
+                .get(&rid)
+                .is_some_and(|room| room.read().unwrap().sessions.contains_key(session_id))
+        };
+        assert!(seated(&guest));
+
+        // closing the socket, without saying goodbye, frees the seat...
+        drop(guest_client);
+        for _ in 0..100 {
+            if !seated(&guest) {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert!(!seated(&guest));
+        assert!(!SESSION_MAP.read().unwrap().contains_key(&guest));
+        assert!(seated(&host));
+
+        // ...and the room goes once its creator's socket closes
+        drop(host_client);
+        for _ in 0..100 {
+            if !ROOM_MAP.read().unwrap().contains_key(&rid) {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert!(!ROOM_MAP.read().unwrap().contains_key(&rid));
+        assert!(!SESSION_MAP.read().unwrap().contains_key(&host));
+    }
+
+    #[test]
+    fn rooms_answer_to_their_creator_and_whitelist() {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let rid = Uuid::new_v4().to_string();
+        let ip = "127.0.0.1".to_string();
+        let frame = |peer_id: &str, frame: String| {
+            let session = format!("{}-{}", rid, peer_id);
+            let peer_id = peer_id.to_string();
+            block_on(handle_message(
+                sender.clone(),
+                &session,
+                &ip,
+                &peer_id,
+                &is_member(),
+                &frame,
+            ))
+        };
+
+        frame(
+            "~zod",
+            format!(
+                r#"{{"type": "create-room", "rid": "{}", "title": "t", "whitelist": ["~bus"]}}"#,
+                rid
+            ),
+        );
+        replies(&mut receiver);
+
+        frame(
+            "~nec",
+            format!(r#"{{"type": "enter-room", "rid": "{}"}}"#, rid),
+        );
+        assert_eq!(
+            error_code(&replies(&mut receiver)),
+            Some((ErrorCode::NotWhitelisted, Some("enter-room")))
+        );
+        frame(
+            "~bus",
+            format!(r#"{{"type": "enter-room", "rid": "{}"}}"#, rid),
+        );
+        assert!(replies(&mut receiver).is_empty());
+        {
+            let rooms = ROOM_MAP.read().unwrap();
+            assert_eq!(rooms[&rid].read().unwrap().present, vec!["~zod", "~bus"]);
+        }
+
+        // ~bus can neither speak for ~zod nor close ~zod's room
+        frame(
+            "~bus",
+            format!(
+                r#"{{"type": "signal", "rid": "{}", "from": "~zod", "to": "~bus", "signal": {{}}}}"#,
+                rid
+            ),
+        );
+        assert_eq!(
+            error_code(&replies(&mut receiver)),
+            Some((ErrorCode::WrongPeer, Some("signal")))
+        );
+        frame(
+            "~bus",
+            format!(r#"{{"type": "delete-room", "rid": "{}"}}"#, rid),
+        );
+        assert_eq!(
+            error_code(&replies(&mut receiver)),
+            Some((ErrorCode::NotCreator, Some("delete-room")))
+        );
+
+        frame(
+            "~zod",
+            format!(r#"{{"type": "delete-room", "rid": "{}"}}"#, rid),
+        );
+        assert!(!ROOM_MAP.read().unwrap().contains_key(&rid));
+    }
 
-            // send update to all known peers
-            let sessions = SESSION_MAP.read().unwrap();
-            for (_, value) in sessions.iter() {
-                value.1.send(Message::text(message.to_string())).unwrap()
+    #[test]
+    fn private_rooms_are_hidden_and_knocked_on() {
+        let rid = Uuid::new_v4().to_string();
+        let space = format!("/spaces/~zod/{}", rid);
+        let ip = "127.0.0.1".to_string();
+        // a connected session per peer, following the space's lobby
+        let mut receivers = HashMap::new();
+        for peer in ["~zod", "~bus", "~nec"] {
+            let (sender, receiver) = mpsc::unbounded_channel();
+            let session = format!("{}-{}", rid, peer);
+            for frame in [
+                r#"{"type": "connect"}"#.to_string(),
+                format!(r#"{{"type": "subscribe-lobby", "path": "{}"}}"#, space),
+            ] {
+                block_on(handle_message(
+                    sender.clone(),
+                    &session,
+                    &ip,
+                    &peer.to_string(),
+                    &is_member(),
+                    &frame,
+                ));
             }
+            receivers.insert(peer, (sender, receiver));
         }
+        let mut frame = |peer: &'static str, frame: String| {
+            let (sender, _) = &receivers[peer];
+            block_on(handle_message(
+                sender.clone(),
+                &format!("{}-{}", rid, peer),
+                &ip,
+                &peer.to_string(),
+                &is_member(),
+                &frame,
+            ));
+            // the other tests' sessions come and go meanwhile: keep to this room
+            let mut replies = HashMap::new();
+            for (peer, (_, receiver)) in receivers.iter_mut() {
+                let mut mine = super::tests::replies(receiver);
+                mine.retain(|reply| about(reply, &rid));
+                replies.insert(*peer, mine);
+            }
+            replies
+        };
+        let listed = |replies: &[ServerMessage]| {
+            replies.iter().any(|reply| match reply {
+                ServerMessage::Lobby { rooms, .. } => rooms.iter().any(|room| room.rid == rid),
+                _ => false,
+            })
+        };
+        let room = |rid: &str| ROOM_MAP.read().unwrap()[rid].read().unwrap().clone();
+
+        // only ~zod sees a private room of ~zod's
+        let replies = frame(
+            "~zod",
+            format!(
+                r#"{{"type": "create-room", "rid": "{}", "title": "t", "access": "private", "path": "{}", "capacity": 2}}"#,
+                rid, space
+            ),
+        );
+        assert!(listed(&replies["~zod"]));
+        assert!(!listed(&replies["~bus"]));
+        let replies = frame(
+            "~bus",
+            format!(r#"{{"type": "enter-room", "rid": "{}"}}"#, rid),
+        );
+        assert_eq!(
+            error_code(&replies["~bus"]),
+            Some((ErrorCode::NotWhitelisted, Some("enter-room")))
+        );
+
+        // ~bus knocks, and only the creator hears it
+        let replies = frame("~bus", format!(r#"{{"type": "knock", "rid": "{}"}}"#, rid));
+        assert!(replies["~bus"].is_empty());
+        assert!(replies["~nec"].is_empty());
+        assert_eq!(
+            replies["~zod"],
+            vec![ServerMessage::Knocked {
+                rid: rid.clone(),
+                peer_id: "~bus".to_string(),
+            }]
+        );
+        let approve = |peer: &str| {
+            format!(
+                r#"{{"type": "approve-knock", "rid": "{}", "peer_id": "{}"}}"#,
+                rid, peer
+            )
+        };
+        let replies = frame("~bus", approve("~bus"));
+        assert_eq!(
+            error_code(&replies["~bus"]),
+            Some((ErrorCode::NotCreator, Some("approve-knock")))
+        );
+        let replies = frame("~zod", approve("~nec"));
+        assert_eq!(
+            error_code(&replies["~zod"]),
+            Some((ErrorCode::NoKnock, Some("approve-knock")))
+        );
+        let replies = frame("~zod", approve("~bus"));
+        assert!(listed(&replies["~bus"]));
+        assert_eq!(
+            replies["~bus"].last(),
+            Some(&ServerMessage::KnockApproved { rid: rid.clone() })
+        );
+        assert_eq!(room(&rid).whitelist, vec!["~bus"]);
+        let replies = frame(
+            "~bus",
+            format!(r#"{{"type": "enter-room", "rid": "{}"}}"#, rid),
+        );
+        assert!(matches!(
+            replies["~zod"].as_slice(),
+            [
+                ServerMessage::RoomEntered { .. },
+                ServerMessage::Lobby { .. }
+            ]
+        ));
+        assert!(replies["~nec"].is_empty());
+
+        // a space room is open to the space, up to its capacity
+        let replies = frame(
+            "~zod",
+            format!(
+                r#"{{"type": "edit-room", "rid": "{}", "access": "space"}}"#,
+                rid
+            ),
+        );
+        assert!(listed(&replies["~nec"]));
+        let replies = frame(
+            "~nec",
+            format!(r#"{{"type": "enter-room", "rid": "{}"}}"#, rid),
+        );
+        assert_eq!(
+            error_code(&replies["~nec"]),
+            Some((ErrorCode::RoomFull, Some("enter-room")))
+        );
+        assert_eq!(room(&rid).present, vec!["~zod", "~bus"]);
+
+        // a peer who leaves gives up its seat
+        let leave = format!(r#"{{"type": "leave-room", "rid": "{}"}}"#, rid);
+        let enter = format!(r#"{{"type": "enter-room", "rid": "{}"}}"#, rid);
+        let replies = frame("~bus", leave.clone());
+        match replies["~zod"].as_slice() {
+            [ServerMessage::RoomLeft { peer_id, room, .. }, ServerMessage::Lobby { .. }] => {
+                assert_eq!(peer_id, "~bus");
+                assert_eq!(room.present, vec!["~zod"]);
+            }
+            replies => panic!("{:?}", replies),
+        }
+        assert_eq!(room(&rid).present, vec!["~zod"]);
+        let replies = frame("~nec", enter.clone());
+        assert_eq!(error_code(&replies["~nec"]), None);
+        assert_eq!(room(&rid).present, vec!["~zod", "~nec"]);
+        frame("~nec", leave);
+        let replies = frame("~bus", enter);
+        assert_eq!(error_code(&replies["~bus"]), None);
+        assert_eq!(room(&rid).present, vec!["~zod", "~bus"]);
+
+        frame(
+            "~zod",
+            format!(r#"{{"type": "delete-room", "rid": "{}"}}"#, rid),
+        );
+        let mut sessions = SESSION_MAP.write().unwrap();
+        sessions.retain(|session_id, _| !session_id.starts_with(&rid));
+    }
+
+    #[test]
+    fn a_peer_stays_present_while_one_of_its_sessions_is_in_the_room() {
+        let rid = Uuid::new_v4().to_string();
+        let ip = "127.0.0.1".to_string();
+        let (sender, _receiver) = mpsc::unbounded_channel();
+        let frame = |session: &str, peer: &str, frame: String| {
+            block_on(handle_message(
+                sender.clone(),
+                &format!("{}-{}", rid, session),
+                &ip,
+                &peer.to_string(),
+                &is_member(),
+                &frame,
+            ));
+        };
+        let room = |rid: &str| ROOM_MAP.read().unwrap()[rid].read().unwrap().clone();
+
+        frame(
+            "bus",
+            "~bus",
+            format!(
+                r#"{{"type": "create-room", "rid": "{}", "title": "t"}}"#,
+                rid
+            ),
+        );
+        // ~zod, in two tabs
+        for session in ["zod-1", "zod-2"] {
+            frame(session, "~zod", r#"{"type": "connect"}"#.to_string());
+            frame(
+                session,
+                "~zod",
+                format!(r#"{{"type": "enter-room", "rid": "{}"}}"#, rid),
+            );
+        }
+        assert_eq!(room(&rid).present, vec!["~bus", "~zod"]);
+
+        frame("zod-1", "~zod", r#"{"type": "disconnect"}"#.to_string());
+        let left = room(&rid);
+        assert!(!left.sessions.contains_key(&format!("{}-zod-1", rid)));
+        assert_eq!(left.present, vec!["~bus", "~zod"]);
+        frame("zod-2", "~zod", r#"{"type": "disconnect"}"#.to_string());
+        let left = room(&rid);
+        assert_eq!(left.sessions.len(), 1);
+        assert_eq!(left.present, vec!["~bus"]);
+
+        frame(
+            "bus",
+            "~bus",
+            format!(r#"{{"type": "delete-room", "rid": "{}"}}"#, rid),
+        );
+        let mut sessions = SESSION_MAP.write().unwrap();
+        sessions.retain(|session_id, _| !session_id.starts_with(&rid));
+    }
+
+    fn about(reply: &ServerMessage, rid: &str) -> bool {
+        match reply {
+            ServerMessage::Rooms { rooms } => rooms.iter().any(|room| room.rid == rid),
+            ServerMessage::Lobby { rooms, removed, .. } => {
+                rooms.iter().any(|room| room.rid == rid) || removed.iter().any(|id| id == rid)
+            }
+            ServerMessage::Error { .. } => true,
+            reply => {
+                let json = serde_json::to_value(reply).unwrap();
+                json["rid"] == rid || json["room"]["rid"] == rid
+            }
+        }
+    }
+
+    // the frames waiting for a session
+    fn replies(receiver: &mut UnboundedReceiver<Message>) -> Vec<ServerMessage> {
+        let mut replies = Vec::new();
+        while let Ok(message) = receiver.try_recv() {
+            replies.push(serde_json::from_str(message.to_str().unwrap()).unwrap());
+        }
+        replies
+    }
+
+    fn error_code(replies: &[ServerMessage]) -> Option<(ErrorCode, Option<&str>)> {
+        match replies {
+            [ServerMessage::Error { code, request, .. }] => Some((*code, request.as_deref())),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn bad_frames_are_answered_with_errors() {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let session = Uuid::new_v4().to_string();
+        let rid = Uuid::new_v4().to_string();
+        let zod = "~zod".to_string();
+        let ip = "127.0.0.1".to_string();
+        let frame = |peer_id: &String, frame: String| {
+            block_on(handle_message(
+                sender.clone(),
+                &session,
+                &ip,
+                peer_id,
+                &is_member(),
+                &frame,
+            ))
+        };
+
+        frame(&zod, "{oops".to_string());
+        assert_eq!(
+            error_code(&replies(&mut receiver)),
+            Some((ErrorCode::InvalidMessage, None))
+        );
+        frame(&zod, r#"{"type": "create-room", "rid": 1}"#.to_string());
+        assert_eq!(
+            error_code(&replies(&mut receiver)),
+            Some((ErrorCode::InvalidMessage, Some("create-room")))
+        );
+        frame(
+            &zod,
+            format!(r#"{{"type": "enter-room", "rid": "{}"}}"#, rid),
+        );
+        assert_eq!(
+            error_code(&replies(&mut receiver)),
+            Some((ErrorCode::RoomNotFound, Some("enter-room")))
+        );
+
+        // the socket is still usable
+        let create = format!(
+            r#"{{"type": "create-room", "rid": "{}", "title": "t"}}"#,
+            rid
+        );
+        frame(&zod, create.clone());
+        match replies(&mut receiver).as_slice() {
+            [ServerMessage::RoomCreated { room }] => assert_eq!(room.creator, zod),
+            replies => panic!("{:?}", replies),
+        }
+        frame(&zod, create);
+        assert_eq!(
+            error_code(&replies(&mut receiver)),
+            Some((ErrorCode::RoomExists, Some("create-room")))
+        );
+        frame(
+            &"~bus".to_string(),
+            format!(
+                r#"{{"type": "edit-room", "rid": "{}", "title": "mine"}}"#,
+                rid
+            ),
+        );
+        assert_eq!(
+            error_code(&replies(&mut receiver)),
+            Some((ErrorCode::NotCreator, Some("edit-room")))
+        );
+        frame(
+            &zod,
+            format!(
+                r#"{{"type": "signal", "rid": "{}", "from": "~zod", "to": "~bus", "signal": {{}}}}"#,
+                rid
+            ),
+        );
+        assert_eq!(
+            error_code(&replies(&mut receiver)),
+            Some((ErrorCode::NotInRoom, Some("signal")))
+        );
+
+        frame(
+            &zod,
+            format!(r#"{{"type": "delete-room", "rid": "{}"}}"#, rid),
+        );
+        assert!(replies(&mut receiver).is_empty());
+        assert!(!ROOM_MAP.read().unwrap().contains_key(&rid));
     }
 }
@@ -0,0 +1,31 @@
+/*
+    rooms, kept so that they outlive a restart of the node (see `store`)
+*/
+create table if not exists rooms
+(
+    rid          TEXT    NOT NULL PRIMARY KEY,
+    rtype        TEXT    NOT NULL,
+    title        TEXT    NOT NULL,
+    creator      TEXT    NOT NULL,
+    provider     TEXT    NOT NULL,
+    /* public, private or space */
+    access       TEXT    NOT NULL,
+    /* json array of peer ids */
+    whitelist    TEXT    NOT NULL,
+    capacity     INTEGER NOT NULL,
+    path         TEXT,
+    updated_at   INTEGER NOT NULL
+);
+
+/*
+    the peers present in a room, in the order they entered. a seat is reclaimed after
+    a restart with the resume token of the session that held it
+*/
+create table if not exists room_seats
+(
+    rid           TEXT    NOT NULL REFERENCES rooms (rid) ON DELETE CASCADE,
+    peer_id       TEXT    NOT NULL,
+    resume_token  TEXT,
+    PRIMARY KEY (rid, peer_id)
+);
+create index if not exists room_seats_resume_token_index on room_seats (resume_token);
@@ -0,0 +1,396 @@
+//!
+//! rooms that outlive a restart of the node
+//!
+//! rooms, and the seats of the peers present in them, are kept in the `rooms` and
+//!  `room_seats` tables and saved as they change. a seat remembers the resume token of
+//!  the session holding it: every session is given one when it connects.
+//!
+//! when the node starts, `recover` puts the saved rooms back with their seats held, but
+//!  with no sessions in them. for a grace period, a client that comes back connects
+//!  with its resume token and takes its seats back. once the grace period is over, the
+//!  seats nobody reclaimed are given up, and the rooms whose creator did not come back
+//!  are deleted, as if the creator had left.
+//!
+use anyhow::Result;
+use lazy_static::lazy_static;
+use rusqlite::{params, Connection};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use bedrock_db::migrations::{migrate, Migration};
+use bedrock_db::DbPool;
+
+use trace::{trace_err_ln, trace_info_ln};
+
+use crate::types::{Access, PeerId, Rid, Room, ROOM_MAP};
+
+/// the rooms tables. applied by `Store::open`
+pub const MIGRATIONS: &[Migration] = &[Migration {
+    version: 1,
+    name: "rooms",
+    sql: include_str!("sql/0001_rooms.sql"),
+}];
+
+lazy_static! {
+    // where rooms are saved. None (e.g. in tests) keeps them in memory only
+    static ref STORE: RwLock<Option<Store>> = RwLock::new(None);
+}
+
+#[derive(Debug, Clone)]
+pub struct Store {
+    pool: DbPool,
+}
+
+impl Store {
+    /// rooms kept in `pool`, which is migrated to the current schema
+    pub fn open(pool: DbPool) -> Result<Store> {
+        migrate(&pool, "rooms", MIGRATIONS)?;
+        Ok(Store { pool })
+    }
+
+    /// save `room` and its seats. the seats of peers that are present without a session
+    ///  (not reclaimed yet) keep their resume token
+    pub fn save(&self, room: &Room) -> Result<()> {
+        let mut conn = self.pool.get_writer()?;
+        let tx = conn.transaction()?;
+        tx.execute(
+            "INSERT INTO rooms (
+                rid, rtype, title, creator, provider, access, whitelist, capacity, path,
+                updated_at
+            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
+            ON CONFLICT (rid) DO UPDATE SET
+                title = excluded.title,
+                access = excluded.access,
+                whitelist = excluded.whitelist,
+                capacity = excluded.capacity,
+                updated_at = excluded.updated_at",
+            params![
+                room.rid,
+                room.rtype,
+                room.title,
+                room.creator,
+                room.provider,
+                room.access.as_str(),
+                serde_json::to_string(&room.whitelist)?,
+                room.capacity,
+                room.path,
+                now(),
+            ],
+        )?;
+        let seated = seated(&tx, &room.rid)?;
+        for peer_id in seated.iter().filter(|id| !room.present.contains(id)) {
+            tx.execute(
+                "DELETE FROM room_seats WHERE rid = ?1 AND peer_id = ?2",
+                [&room.rid, peer_id],
+            )?;
+        }
+        for peer_id in &room.present {
+            let token = room
+                .sessions
+                .values()
+                .find(|session| &session.peer_id == peer_id && !session.resume_token.is_empty())
+                .map(|session| session.resume_token.as_str());
+            tx.execute(
+                "INSERT INTO room_seats (rid, peer_id, resume_token) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (rid, peer_id) DO UPDATE SET
+                    resume_token = COALESCE(excluded.resume_token, resume_token)",
+                params![room.rid, peer_id, token],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn delete(&self, rid: &str) -> Result<()> {
+        let conn = self.pool.get_writer()?;
+        conn.execute("DELETE FROM rooms WHERE rid = ?1", [rid])?;
+        Ok(())
+    }
+
+    /// every saved room, with the peers holding seats in it as `present`
+    pub fn load(&self) -> Result<Vec<Room>> {
+        let conn = self.pool.get_conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT rid, rtype, title, creator, provider, access, whitelist, capacity, path
+               FROM rooms
+              ORDER BY updated_at",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, String>(3)?,
+                row.get::<_, String>(4)?,
+                row.get::<_, String>(5)?,
+                row.get::<_, String>(6)?,
+                row.get::<_, u32>(7)?,
+                row.get::<_, Option<String>>(8)?,
+            ))
+        })?;
+        let mut seats =
+            conn.prepare("SELECT peer_id FROM room_seats WHERE rid = ?1 ORDER BY rowid")?;
+        let mut rooms = Vec::new();
+        for row in rows {
+            let (rid, rtype, title, creator, provider, access, whitelist, capacity, path) = row?;
+            let present = seats
+                .query_map([&rid], |row| row.get(0))?
+                .collect::<rusqlite::Result<Vec<PeerId>>>()?;
+            rooms.push(Room {
+                rid,
+                rtype,
+                title,
+                creator,
+                // no session holds the room until its creator comes back
+                origin: String::new(),
+                provider,
+                access: Access::parse(&access).unwrap_or_default(),
+                present,
+                whitelist: serde_json::from_str(&whitelist)?,
+                capacity,
+                path,
+                sessions: HashMap::new(),
+                knocks: Vec::new(),
+            });
+        }
+        Ok(rooms)
+    }
+
+    /// the seats (room, peer) held with `resume_token`
+    pub fn seats(&self, resume_token: &str) -> Result<Vec<(Rid, PeerId)>> {
+        let conn = self.pool.get_conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT rid, peer_id FROM room_seats WHERE resume_token = ?1 ORDER BY rowid",
+        )?;
+        let rows = stmt.query_map([resume_token], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(rows.collect::<rusqlite::Result<Vec<(Rid, PeerId)>>>()?)
+    }
+}
+
+fn seated(conn: &Connection, rid: &str) -> Result<Vec<PeerId>> {
+    let mut stmt = conn.prepare_cached("SELECT peer_id FROM room_seats WHERE rid = ?1")?;
+    let rows = stmt.query_map([rid], |row| row.get(0))?;
+    Ok(rows.collect::<rusqlite::Result<Vec<PeerId>>>()?)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// keep rooms in `store` from now on, and put back the rooms saved in it before the node
+///  started. their seats are held for `grace`, after which whatever was not reclaimed
+///  is swept. answers the number of rooms put back
+pub fn recover(store: Store, grace: Duration) -> Result<usize> {
+    let rooms = store.load()?;
+    let rids: Vec<Rid> = rooms.iter().map(|room| room.rid.clone()).collect();
+    {
+        let mut map = ROOM_MAP.write().unwrap();
+        for room in rooms {
+            map.entry(room.rid.clone())
+                .or_insert_with(|| Arc::new(RwLock::new(room)));
+        }
+    }
+    *STORE.write().unwrap() = Some(store);
+    trace_info_ln!("recovered {} rooms, held for {:?}", rids.len(), grace);
+
+    let recovered = rids.len();
+    tokio::spawn(async move {
+        tokio::time::sleep(grace).await;
+        crate::socket::end_grace(&rids);
+    });
+    Ok(recovered)
+}
+
+/// save `room`, if rooms are kept. the rooms in memory are what counts: failing to
+///  save one is only logged
+pub(crate) fn save(room: &Room) {
+    if let Some(store) = STORE.read().unwrap().as_ref() {
+        if let Err(e) = store.save(room) {
+            trace_err_ln!("cannot save room {}. {}", room.rid, e);
+        }
+    }
+}
+
+pub(crate) fn forget(rid: &str) {
+    if let Some(store) = STORE.read().unwrap().as_ref() {
+        if let Err(e) = store.delete(rid) {
+            trace_err_ln!("cannot delete room {}. {}", rid, e);
+        }
+    }
+}
+
+/// the seats held with `resume_token`, if rooms are kept
+pub(crate) fn seats(resume_token: &str) -> Vec<(Rid, PeerId)> {
+    match STORE.read().unwrap().as_ref() {
+        Some(store) => store.seats(resume_token).unwrap_or_else(|e| {
+            trace_err_ln!("cannot look up seats. {}", e);
+            Vec::new()
+        }),
+        None => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access::IsMember;
+    use crate::protocol::ServerMessage;
+    use crate::socket::handle_message;
+    use crate::types::{Session, SESSION_MAP};
+    use tokio::sync::mpsc;
+    use uuid::Uuid;
+
+    // a store in a fresh database file under the temp dir
+    fn temp_store() -> (Store, std::path::PathBuf) {
+        let dir = std::env::temp_dir().join(format!("rooms-{}", Uuid::new_v4()));
+        let pool = DbPool::open(&dir.join("rooms.sqlite")).unwrap();
+        (Store::open(pool).unwrap(), dir)
+    }
+
+    fn room(rid: &str, creator: &str, seats: &[(&str, &str)]) -> Room {
+        Room {
+            rid: rid.to_string(),
+            rtype: "media".to_string(),
+            title: "standup".to_string(),
+            creator: creator.to_string(),
+            origin: format!("s-{}", creator),
+            provider: "default".to_string(),
+            access: Access::Private,
+            present: seats.iter().map(|(peer, _)| peer.to_string()).collect(),
+            whitelist: vec!["~bus".to_string()],
+            capacity: 4,
+            path: Some("/spaces/~zod".to_string()),
+            sessions: seats
+                .iter()
+                .map(|(peer, token)| {
+                    let session = Session {
+                        id: format!("s-{}", peer),
+                        peer_id: peer.to_string(),
+                        peer_ip: "127.0.0.1".to_string(),
+                        resume_token: token.to_string(),
+                    };
+                    (session.id.clone(), session)
+                })
+                .collect(),
+            knocks: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn rooms_are_saved_with_their_seats() {
+        let (store, dir) = temp_store();
+        let mut saved = room("r1", "~zod", &[("~zod", "tz"), ("~bus", "tb")]);
+        store.save(&saved).unwrap();
+
+        let loaded = store.load().unwrap();
+        assert_eq!(loaded.len(), 1);
+        let restored = &loaded[0];
+        assert_eq!(restored.present, vec!["~zod", "~bus"]);
+        assert_eq!(restored.access, Access::Private);
+        assert_eq!(restored.whitelist, vec!["~bus"]);
+        assert_eq!(restored.path, saved.path);
+        // nobody is in it until they come back
+        assert!(restored.sessions.is_empty());
+        assert!(restored.origin.is_empty());
+        assert_eq!(
+            store.seats("tb").unwrap(),
+            vec![("r1".into(), "~bus".into())]
+        );
+
+        // a seat held without a session keeps its token
+        saved.sessions.remove("s-~bus");
+        saved.title = "retro".to_string();
+        store.save(&saved).unwrap();
+        assert_eq!(store.seats("tb").unwrap().len(), 1);
+        assert_eq!(store.load().unwrap()[0].title, "retro");
+        // and goes once the peer is gone
+        saved.present.retain(|peer| peer != "~bus");
+        store.save(&saved).unwrap();
+        assert!(store.seats("tb").unwrap().is_empty());
+
+        store.delete("r1").unwrap();
+        assert!(store.load().unwrap().is_empty());
+        assert!(store.seats("tz").unwrap().is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn seats_are_reclaimed_within_the_grace_period() {
+        let (store, dir) = temp_store();
+        let kept = Uuid::new_v4().to_string();
+        let abandoned = Uuid::new_v4().to_string();
+        let zod = Uuid::new_v4().to_string();
+        let bus = Uuid::new_v4().to_string();
+        store
+            .save(&room(&kept, "~zod", &[("~zod", &zod), ("~bus", &bus)]))
+            .unwrap();
+        store
+            .save(&room(&abandoned, "~nec", &[("~nec", "tn"), ("~zod", &zod)]))
+            .unwrap();
+
+        // the node restarts
+        assert_eq!(
+            recover(store.clone(), Duration::from_secs(3600)).unwrap(),
+            2
+        );
+        let room = |rid: &str| ROOM_MAP.read().unwrap()[rid].read().unwrap().clone();
+        assert_eq!(room(&kept).present, vec!["~zod", "~bus"]);
+
+        let is_member: IsMember = Arc::new(|_, _| false);
+        let connect = |peer: &str, token: &str| {
+            let (sender, mut receiver) = mpsc::unbounded_channel();
+            let session = format!("{}-{}", kept, peer);
+            futures::executor::block_on(handle_message(
+                sender,
+                &session,
+                &"127.0.0.1".to_string(),
+                &peer.to_string(),
+                &is_member,
+                &format!(r#"{{"type": "connect", "resume_token": "{}"}}"#, token),
+            ));
+            let mut replies = Vec::new();
+            while let Ok(message) = receiver.try_recv() {
+                let reply: ServerMessage = serde_json::from_str(message.to_str().unwrap()).unwrap();
+                replies.push(reply);
+            }
+            replies
+        };
+        let resume_token = |replies: &[ServerMessage]| {
+            replies.iter().find_map(|reply| match reply {
+                ServerMessage::Connected { resume_token, .. } => Some(resume_token.clone()),
+                _ => None,
+            })
+        };
+
+        // ~zod comes back, and is in both rooms again
+        let replies = connect("~zod", &zod);
+        assert_eq!(resume_token(&replies), Some(zod.clone()));
+        let kept_room = room(&kept);
+        assert!(kept_room.sessions.contains_key(&format!("{}-~zod", kept)));
+        assert_eq!(kept_room.origin, format!("{}-~zod", kept));
+        assert_eq!(room(&abandoned).sessions.len(), 1);
+        // a token of someone else's reclaims nothing
+        let replies = connect("~bus", &zod);
+        assert_ne!(resume_token(&replies), Some(zod.clone()));
+        assert!(room(&kept).sessions.len() == 1);
+
+        // ~bus and ~nec never came back
+        crate::socket::end_grace(&[kept.clone(), abandoned.clone()]);
+        assert_eq!(room(&kept).present, vec!["~zod"]);
+        assert!(!ROOM_MAP.read().unwrap().contains_key(&abandoned));
+        let saved: Vec<Rid> = store.load().unwrap().into_iter().map(|r| r.rid).collect();
+        assert!(saved.contains(&kept));
+        assert!(!saved.contains(&abandoned));
+
+        ROOM_MAP.write().unwrap().remove(&kept);
+        SESSION_MAP
+            .write()
+            .unwrap()
+            .retain(|session_id, _| !session_id.starts_with(&kept));
+        *STORE.write().unwrap() = None;
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -38,7 +38,39 @@ impl RoomType {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// who can see and enter a room (see `access`)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Access {
+    /// everyone
+    #[default]
+    Public,
+    /// the peers on the whitelist
+    Private,
+    /// the members of the space at the room's `path` (and the whitelist)
+    Space,
+}
+
+impl Access {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Access::Public => "public",
+            Access::Private => "private",
+            Access::Space => "space",
+        }
+    }
+
+    pub fn parse(access: &str) -> Option<Access> {
+        match access {
+            "public" => Some(Access::Public),
+            "private" => Some(Access::Private),
+            "space" => Some(Access::Space),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Session {
     #[serde(rename = "session_id")]
     pub id: SessionId,
@@ -48,6 +80,10 @@ pub struct Session {
     pub peer_id: String,
     #[serde(skip_deserializing)]
     pub peer_ip: String,
+    // reclaims the session's seats after a restart (see `store`). a secret of the
+    //  client's, unlike the session id
+    #[serde(skip)]
+    pub resume_token: String,
     // pub rooms: Arc<RwLock<[Option<()>; 2]>>,
 }
 
@@ -57,7 +93,7 @@ pub struct Peer {
     // pub rooms: Arc<RwLock<[Option<()>; 2]>>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Room {
     pub rid: String,
     // room type:
@@ -69,13 +105,16 @@ pub struct Room {
     #[serde(skip)]
     pub origin: String,
     pub provider: String,
-    pub access: String,
+    pub access: Access,
     pub present: Vec<String>,
     pub whitelist: Vec<String>,
     pub capacity: u32,
     pub path: Option<String>,
     #[serde(skip)]
     pub sessions: HashMap<String, Session>,
+    // peers waiting for the creator to let them in. only the creator is told about them
+    #[serde(skip)]
+    pub knocks: Vec<PeerId>,
 }
 pub type RoomLock = Arc<RwLock<Room>>;
 
@@ -91,28 +130,3 @@ lazy_static! {
     //   for electron clients), or simply allow the same IP to connect multiple times (if needed)
     pub static ref SESSION_MAP: RwLock<HashMap<SessionId, SocketSession>> = RwLock::new(HashMap::new());
 }
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(untagged)]
-pub enum Response {
-    Ok {
-        rtype: String,
-    },
-    Error {
-        rtype: String,
-        message: String,
-    },
-    RoomUpdate {
-        rtype: String,
-        room: String,
-        peers: Vec<Peer>,
-    },
-    PeerJoined {
-        rtype: String,
-        peer_id: PeerId,
-    },
-    PeerLeft {
-        rtype: String,
-        peer_id: PeerId,
-    },
-}
@@ -89,12 +89,8 @@ pub fn trace(
     // let _ = write!(&mut buffer, "[{}]", buf2.);
     let _ = buffer.reset();
 
-    if clr.is_some() {
-        let _ = buffer.set_color(
-            ColorSpec::new()
-                .set_intense(true)
-                .set_fg(Some(clr.unwrap())),
-        );
+    if let Some(clr) = clr {
+        let _ = buffer.set_color(ColorSpec::new().set_intense(true).set_fg(Some(clr)));
     }
     let _ = write!(&mut buffer, " {}", msg);
     let _ = buffer.reset();
@@ -116,12 +112,8 @@ pub fn etrace(
     let bufwtr = BufferWriter::stderr(ColorChoice::Always);
     let mut buffer = bufwtr.buffer();
 
-    if clr.is_some() {
-        let _ = buffer.set_color(
-            ColorSpec::new()
-                .set_intense(true)
-                .set_fg(Some(clr.unwrap())),
-        );
+    if let Some(clr) = clr {
+        let _ = buffer.set_color(ColorSpec::new().set_intense(true).set_fg(Some(clr)));
     }
     let _ = write!(&mut buffer, "{}", msg);
 
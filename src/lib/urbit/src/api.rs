@@ -9,12 +9,14 @@ use reqwest::{Client, Response, StatusCode};
 use eventsource_threaded::{EventSource, ReceiverSource};
 
 use crate::error::{Result as UrbitResult, UrbitAPIError};
+use crate::eyre::{Action, Event};
 use rand::Rng;
 
 use trace::{trace_err_ln, trace_good_ln, trace_info_ln, trace_json_ln, trace_warn_ln};
 
 pub static CHANNEL_OPEN_MSG_ID: u64 = u64::MAX - 1;
 pub static CHANNEL_DELETE_MSG_ID: u64 = u64::MAX - 2;
+pub static CHANNEL_ACK_MSG_ID: u64 = u64::MAX - 3;
 
 #[derive(Debug, Clone)]
 pub struct Ship {
@@ -45,9 +47,7 @@ impl Ship {
         };
         match result.login().await {
             Ok(_) => Ok(result),
-            Err(e) => {
-                return Err(e);
-            }
+            Err(e) => Err(e),
         }
     }
 
@@ -93,9 +93,6 @@ impl Ship {
 
         let ship_name = &session_auth[9..end_pos];
 
-        let ship_name = ship_name;
-        let session_auth = session_auth;
-
         self.ship_name.replace(ship_name.to_string());
         self.session_auth.replace(session_auth.to_string());
 
@@ -110,12 +107,13 @@ impl Ship {
     // then, and only then, do you return success from this method
     pub async fn open_channel(&mut self) -> Result<ReceiverSource> {
         let mut rng: rand::rngs::StdRng = rand::SeedableRng::from_entropy();
-        // Defining the uid as UNIX time, or random if error
+        // Defining the uid as UNIX time, or random if error. a random suffix keeps
+        //  channels opened in the same millisecond (e.g. the node's and chat sync's) apart
         let uid = match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
             Ok(n) => n.as_millis(),
             Err(_) => rng.gen(),
-        }
-        .to_string();
+        };
+        let uid = format!("{}-{:06x}", uid, rng.gen::<u32>() & 0xff_ffff);
 
         // Channel url
         let channel_url = format!("{}/~/channel/{}", &self.url, uid);
@@ -135,14 +133,13 @@ impl Ship {
         let session_auth = self.session_auth.as_ref().unwrap().to_string();
 
         // Opening channel request json
-        let body = json!([{
-                "id": CHANNEL_OPEN_MSG_ID,
-                "action": "poke",
-                "ship": ship_name,
-                "app": "hood",
-                "mark": "helm-hi",
-                "json": "Opening channel",
-        }]);
+        let body = [Action::Poke {
+            id: CHANNEL_OPEN_MSG_ID,
+            ship: ship_name,
+            app: "hood".to_string(),
+            mark: "helm-hi".to_string(),
+            json: json!("Opening channel"),
+        }];
 
         // Make the put request to create the channel.
         let resp = self
@@ -171,7 +168,7 @@ impl Ship {
         let receiver = EventSource::new(url_structured, headers);
         trace_info_ln!("waiting for open channel confirmation event...");
 
-        let msg = receiver.recv();
+        let msg = tokio::task::block_in_place(|| receiver.recv());
 
         if msg.is_err() {
             bail!("api: [open_channel] event receive error. msg => {:?}", msg);
@@ -188,7 +185,7 @@ impl Ship {
 
         trace_good_ln!("api: [open_channel] received event:");
 
-        let data = serde_json::from_str::<JsonValue>(&event.data);
+        let data = serde_json::from_str::<Event>(&event.data);
 
         if data.is_err() {
             bail!(
@@ -199,56 +196,13 @@ impl Ship {
 
         let data = data.unwrap();
 
-        if !data.is_object() {
-            bail!(
-                "api: [open_channel] invalid event stream message. must be json object {}",
-                event.data
-            );
-        }
-
-        trace_json_ln!(&data);
-
-        let id = {
-            let id = data.get("id");
-            if id.is_none() {
-                bail!(
-                    "api: [open_channel] invalid event stream message. must be json object {}",
-                    event.data
-                )
-            }
-            let id = id.unwrap().as_u64();
-            if id.is_none() {
-                bail!(
-                    "api: [open_channel] SSE event id not a valid number {}",
-                    event.data
-                )
-            }
-            id.unwrap()
-        };
-
-        let ok = {
-            let ok = data.get("ok");
-            if ok.is_none() {
-                bail!(
-                    "api: [open_channel] unexpected SSE event. missing ok field. {}",
-                    event.data
-                )
-            }
-            ok.unwrap()
-        };
-
-        let response = {
-            let response = data.get("response");
-            if response.is_none() {
-                bail!(
-                    "api: [open_channel] unexpected SSE event. missing response field. {}",
-                    event.data
-                )
-            }
-            response.unwrap()
-        };
+        trace_info_ln!("{:?}", data);
 
-        if !(data.is_object() && id == CHANNEL_OPEN_MSG_ID && ok == "ok" && response == "poke") {
+        if data
+            != (Event::PokeAck {
+                id: CHANNEL_OPEN_MSG_ID,
+            })
+        {
             bail!(
                 "api: [open_channel] failed to valid SSE handshake {}",
                 event.data
@@ -263,16 +217,15 @@ impl Ship {
     pub async fn discard_channel(&mut self) -> Result<()> {
         let session_auth = self.session_auth.as_ref().unwrap().to_string();
 
-        // Opening channel request json
-        let body = json!([{
-                "id": CHANNEL_DELETE_MSG_ID,
-                "action": "delete",
-        }]);
+        // Deleting channel request json
+        let body = [Action::Delete {
+            id: CHANNEL_DELETE_MSG_ID,
+        }];
 
         // Make the put request to create the channel.
         let resp = self
             .send_put_request(
-                self.channel_url.as_ref().clone().unwrap().as_str(),
+                self.channel_url.as_ref().unwrap().as_str(),
                 session_auth.as_str(),
                 &body,
             )
@@ -294,12 +247,50 @@ impl Ship {
         Ok(())
     }
 
+    // reconnect to the event stream of the channel opened by `open_channel`. eyre replays
+    //  any un-acked events that came after `last_event_id`, so nothing that arrived while
+    //  the stream was down is lost
+    pub fn resume_channel(&self, last_event_id: Option<u64>) -> Result<ReceiverSource> {
+        if self.channel_url.is_none() {
+            bail!("ship: [resume_channel] no channel to resume");
+        }
+
+        let channel_url = self.channel_url.as_ref().unwrap();
+        let session_auth = self.session_auth.as_ref().unwrap();
+
+        let url_structured = Url::parse(channel_url)?;
+
+        let mut headers = HeaderMap::new();
+        headers.append(COOKIE, HeaderValue::from_str(session_auth)?);
+        if let Some(event_id) = last_event_id {
+            headers.append("Last-Event-ID", HeaderValue::from(event_id));
+        }
+
+        trace_info_ln!(
+            "resuming channel {} after {:?}...",
+            channel_url,
+            last_event_id
+        );
+
+        Ok(EventSource::new(url_structured, headers))
+    }
+
+    // acknowledge every channel event up to and including `event_id`. eyre drops acked
+    //  events from the channel's buffer
+    pub async fn ack(&mut self, event_id: u64) -> Result<()> {
+        self.post(&[Action::Ack {
+            id: CHANNEL_ACK_MSG_ID,
+            event_id,
+        }])
+        .await
+    }
+
     // Send a put request using the `ShipInterface`
     pub async fn send_put_request(
         &self,
         url: &str,
         session_auth: &str,
-        body: &JsonValue,
+        body: &[Action],
     ) -> Result<Response> {
         // let json = body.to_string();
         let json = serde_json::to_string(body)?;
@@ -357,6 +348,8 @@ impl Ship {
                     trace_err_ln!("login failed");
                     return Err(UrbitAPIError::FailedToLogin);
                 }
+                // retry with the session cookie issued by the login above
+                let session_auth = self.session_auth.as_ref().unwrap().to_string();
                 let resp = self
                     .req_client
                     .get(&scry_url)
@@ -382,7 +375,7 @@ impl Ship {
     //   originating from connected devices
     // this method will attempt to refresh the urbit auth cookie if the
     //   request fails with a 403 (forbidden).
-    pub async fn post(&mut self, payload: &JsonValue) -> Result<()> {
+    pub async fn post(&mut self, payload: &[Action]) -> Result<()> {
         let session_auth = self.session_auth.as_ref().unwrap().to_string();
         let channel_url = self.channel_url.as_ref().unwrap().to_string();
         let post_result: () = 'result: {
@@ -409,6 +402,8 @@ impl Ship {
                 if result.is_err() {
                     bail!("ship: [post] login failed")
                 }
+                // retry with the session cookie issued by the login above
+                let session_auth = self.session_auth.as_ref().unwrap().to_string();
                 let res = self
                     .req_client
                     .post(channel_url.to_string())
@@ -420,7 +415,7 @@ impl Ship {
                 if res.status().as_u16() != 204 {
                     bail!("ship: [post] retry failed. {}", res.status().as_u16());
                 }
-                break 'result ();
+                break 'result;
             }
             if res.status().as_u16() != 204 {
                 bail!(
@@ -428,9 +423,160 @@ impl Ship {
                     res.status().as_u16()
                 )
             }
-            trace_good_ln!("ship: [post] success {}", payload.to_string());
-            ()
+            trace_good_ln!("ship: [post] success {:?}", payload);
         };
         Ok(post_result)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{run, test_ship, CODE, SHIP};
+    use eyre_mock::MockEyre;
+
+    fn channel_uid(ship: &Ship) -> String {
+        let url = ship.channel_url.as_ref().unwrap();
+        url.rsplit('/').next().unwrap().to_string()
+    }
+
+    #[test]
+    fn login_acquires_session() {
+        run(async {
+            let (eyre, mut ship) = test_ship().await;
+            assert_eq!(ship.ship_name.as_deref(), Some(SHIP));
+            assert_eq!(ship.session_auth, eyre.cookie());
+
+            let (ship_name, session_auth) = ship.login().await.unwrap();
+            assert_eq!(ship_name, SHIP);
+            assert_eq!(Some(session_auth), eyre.cookie());
+            assert_eq!(eyre.logins(), 2);
+        });
+    }
+
+    #[test]
+    fn login_rejects_bad_code() {
+        run(async {
+            let eyre = MockEyre::start(SHIP, CODE);
+            assert!(Ship::new(eyre.url.as_str(), "wrong-code").await.is_err());
+
+            eyre.fail_next("/~/login", 500);
+            assert!(Ship::new(eyre.url.as_str(), CODE).await.is_err());
+        });
+    }
+
+    #[test]
+    fn open_channel_validates_handshake() {
+        run(async {
+            let (eyre, mut ship) = test_ship().await;
+            let _receiver = ship.open_channel().await.unwrap();
+
+            let uid = channel_uid(&ship);
+            assert_eq!(eyre.channels(), vec![uid.clone()]);
+            let actions = eyre.actions(&uid);
+            assert_eq!(actions.len(), 1);
+            assert_eq!(actions[0]["id"], CHANNEL_OPEN_MSG_ID);
+            assert_eq!(actions[0]["action"], "poke");
+
+            // a nacked handshake poke must not produce a channel receiver
+            eyre.nack_pokes("hood", "not today");
+            assert!(ship.open_channel().await.is_err());
+
+            eyre.fail_next("/~/channel", 403);
+            assert!(ship.open_channel().await.is_err());
+        });
+    }
+
+    #[test]
+    fn discard_channel_deletes_channel() {
+        run(async {
+            let (eyre, mut ship) = test_ship().await;
+            let _receiver = ship.open_channel().await.unwrap();
+            ship.discard_channel().await.unwrap();
+            assert!(eyre.channels().is_empty());
+        });
+    }
+
+    #[test]
+    fn resume_channel_replays_unacked_events() {
+        run(async {
+            let (eyre, mut ship) = test_ship().await;
+            let receiver = ship.open_channel().await.unwrap();
+            let uid = channel_uid(&ship);
+            drop(receiver);
+
+            let first = eyre.push_event(&uid, json!({"id": 1, "response": "quit"}));
+            let second = eyre.push_event(&uid, json!({"id": 2, "response": "quit"}));
+
+            // everything up to and including `first` has been seen
+            let receiver = ship.resume_channel(first).unwrap();
+            let event = receiver
+                .recv_timeout(std::time::Duration::from_secs(10))
+                .unwrap()
+                .unwrap();
+            assert_eq!(event.id, second.map(|id| id.to_string()));
+
+            ship.ack(second.unwrap()).await.unwrap();
+            assert_eq!(eyre.last_ack(&uid), second);
+            assert_eq!(eyre.unacked(&uid), 0);
+        });
+    }
+
+    #[test]
+    fn scry_returns_fixture() {
+        run(async {
+            let (eyre, mut ship) = test_ship().await;
+            eyre.add_scry("docket/our.json", json!({"title": "zod"}));
+
+            let data = ship.scry("docket", "/our", "json").await.unwrap();
+            assert_eq!(data, json!({"title": "zod"}));
+
+            let err = ship.scry("docket", "/missing", "json").await.unwrap_err();
+            assert!(matches!(err, UrbitAPIError::StatusCode(404)));
+        });
+    }
+
+    #[test]
+    fn scry_logs_in_again_after_expiry() {
+        run(async {
+            let (eyre, mut ship) = test_ship().await;
+            eyre.add_scry("docket/our.json", json!({"title": "zod"}));
+            eyre.expire_sessions();
+
+            let data = ship.scry("docket", "/our", "json").await.unwrap();
+            assert_eq!(data, json!({"title": "zod"}));
+            assert_eq!(eyre.logins(), 2);
+            assert_eq!(ship.session_auth, eyre.cookie());
+        });
+    }
+
+    #[test]
+    fn post_delivers_actions() {
+        run(async {
+            let (eyre, mut ship) = test_ship().await;
+            let _receiver = ship.open_channel().await.unwrap();
+            let uid = channel_uid(&ship);
+
+            let poke = [Action::Poke {
+                id: 1,
+                ship: SHIP.to_string(),
+                app: "hood".to_string(),
+                mark: "helm-hi".to_string(),
+                json: json!("hello"),
+            }];
+            ship.post(&poke).await.unwrap();
+            assert_eq!(eyre.actions(&uid)[1]["json"], "hello");
+
+            // an expired session is refreshed and the post retried once
+            eyre.expire_sessions();
+            ship.post(&poke).await.unwrap();
+            assert_eq!(eyre.actions(&uid).len(), 3);
+            assert_eq!(eyre.logins(), 2);
+
+            // ...but a second failure is reported
+            eyre.fail_next("/~/channel", 403);
+            eyre.fail_next("/~/channel", 403);
+            assert!(ship.post(&poke).await.is_err());
+        });
+    }
+}
@@ -1,11 +1,12 @@
 use crate::error::{Result, UrbitAPIError};
+use crate::eyre::Action;
 use eventsource_threaded::{EventSource, ReceiverSource};
 
 use rand::Rng;
 use reqwest::header::HeaderMap;
 use reqwest::Response;
 use reqwest::Url;
-use serde_json::{from_str, json, Value};
+use serde_json::{json, Value};
 use std::time::SystemTime;
 
 use crate::{subscription::CreationID, ShipInterface, Subscription};
@@ -44,15 +45,13 @@ impl Channel {
         // Channel url
         let channel_url = format!("{}/~/channel/{}", &ship_interface.url, uid);
         // Opening channel request json
-        let mut body = from_str::<Value>(r#"[]"#).unwrap();
-        body[0] = json!({
-                "id": 1,
-                "action": "poke",
-                "ship": ship_interface.ship_name.clone(),
-                "app": "hood",
-                "mark": "helm-hi",
-                "json": "Opening channel",
-        });
+        let body = json!([Action::Poke {
+            id: 1,
+            ship: ship_interface.ship_name.clone(),
+            app: "hood".to_string(),
+            mark: "helm-hi".to_string(),
+            json: json!("Opening channel"),
+        }]);
 
         // Make the put request to create the channel.
         let resp = ship_interface.send_put_request(&channel_url, &body).await?;
@@ -81,15 +80,13 @@ impl Channel {
 
     /// Sends a poke over the channel
     pub async fn poke(&mut self, app: &str, mark: &str, json: &Value) -> Result<Response> {
-        let mut body = from_str::<Value>(r#"[]"#).unwrap();
-        body[0] = json!({
-                "id": self.get_and_raise_message_id_count(),
-                "action": "poke",
-                "ship": self.ship_interface.ship_name.clone(),
-                "app": app,
-                "mark": mark,
-                "json": json.clone(),
-        });
+        let body = json!([Action::Poke {
+            id: self.get_and_raise_message_id_count(),
+            ship: self.ship_interface.ship_name.clone(),
+            app: app.to_string(),
+            mark: mark.to_string(),
+            json: json.clone(),
+        }]);
 
         // Make the put request for the poke
         self.ship_interface.send_put_request(&self.url, &body).await
@@ -119,14 +116,12 @@ impl Channel {
         // Saves the message id to be reused
         let creation_id = self.get_and_raise_message_id_count();
         // Create the json body
-        let mut body = from_str::<Value>(r#"[]"#).unwrap();
-        body[0] = json!({
-                "id": creation_id,
-                "action": "subscribe",
-                "ship": self.ship_interface.ship_name.clone(),
-                "app": app.to_string(),
-                "path": path.to_string(),
-        });
+        let body = json!([Action::Subscribe {
+            id: creation_id,
+            ship: self.ship_interface.ship_name.clone(),
+            app: app.to_string(),
+            path: path.to_string(),
+        }]);
 
         // Make the put request to create the channel.
         let resp = self
@@ -173,12 +168,10 @@ impl Channel {
                             // Using unwrap because `add_to_message_list`
                             // already does error checking.
                             let eid: u64 = event.id.unwrap().parse().unwrap();
-                            let mut json = from_str::<Value>(r#"[]"#).unwrap();
-                            json[0] = json!({
-                                "id": self.message_id_count,
-                                "action": "ack",
-                                "event-id": eid,
-                            });
+                            let json = json!([Action::Ack {
+                                id: self.message_id_count,
+                                event_id: eid,
+                            }]);
                             self.message_id_count += 1;
                             let _ack_res = self.ship_interface.send_put_request(&self.url, &json);
                             break;
@@ -217,11 +210,9 @@ impl Channel {
 
     /// Deletes the channel
     pub fn delete_channel(&self) {
-        let mut json = from_str::<Value>(r#"[]"#).unwrap();
-        json[0] = json!({
-            "id": self.message_id_count,
-            "action": "delete",
-        });
+        let json = json!([Action::Delete {
+            id: self.message_id_count,
+        }]);
         let _res = self.ship_interface.send_put_request(&self.url, &json);
         std::mem::drop(self);
     }
@@ -1,15 +1,30 @@
 use std::convert::Infallible;
 
 use crate::context::CallContext;
+use serde::Deserialize;
+use serde_json::json;
+
+use super::data::{MessageQuery, SearchQuery};
+use super::types::{EditMessage, MessageCursor, NewMessage, NewReaction};
+use super::write::{self, WriteError};
+use warp::hyper::body::Bytes;
 use warp::{http::StatusCode, reject, reply, Filter, Rejection, Reply};
 
 #[derive(Debug)]
 struct InvalidParameter;
 #[derive(Debug)]
 struct DbError;
+/// a write a browser says comes from another site (403)
+#[derive(Debug)]
+struct CrossOrigin;
+/// a write whose body is not sent as json (415)
+#[derive(Debug)]
+struct NotJson;
 
 impl reject::Reject for InvalidParameter {}
 impl reject::Reject for DbError {}
+impl reject::Reject for CrossOrigin {}
+impl reject::Reject for NotJson {}
 
 use trace::trace_err_ln;
 
@@ -17,7 +32,7 @@ use trace::trace_err_ln;
 async fn _handle_rejection(err: Rejection) -> Result<impl Reply, std::convert::Infallible> {
     if err.is_not_found() {
         Ok(reply::with_status("NOT_FOUND", StatusCode::NOT_FOUND))
-    } else if let Some(_) = err.find::<InvalidParameter>() {
+    } else if err.find::<InvalidParameter>().is_some() {
         Ok(reply::with_status("BAD_REQUEST", StatusCode::BAD_REQUEST))
     } else {
         trace_err_ln!("unhandled rejection: {:?}", err);
@@ -31,22 +46,208 @@ async fn _handle_rejection(err: Rejection) -> Result<impl Reply, std::convert::I
 pub fn chat_router(
     ctx: CallContext,
 ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    // reads may come from any origin. writes act as the ship, so they are same-origin
+    //  only: no cors headers are sent for them, bodies must be json (which other origins
+    //  cannot send without a preflight), and a cross-origin `origin` or `sec-fetch-site`
+    //  is refused
     let cors = warp::cors()
         .allow_any_origin()
         .allow_headers(vec!["content-type"])
-        .allow_methods(vec!["GET"]); // , "POST", "DELETE"]);
+        .allow_methods(vec!["GET"]);
 
     // /db/messages/start-ms/{}
     let chat_routes = warp::path!("hol" / "chat" / "messages" / "start-ms" / String)
         .and(warp::get())
         // .and(warp::path::param())
-        .and(with_context(ctx))
+        .and(with_context(ctx.clone()))
         .and_then(|param: String, context: CallContext| async {
             handle_chat_messages(context, param).await
         });
     // .recover(handle_rejection);
 
-    chat_routes.with(cors)
+    // /hol/chat/messages?limit=&before=&after=&path=&sender=&content_type=&reply_to=&view=
+    let page_routes = warp::path!("hol" / "chat" / "messages")
+        .and(warp::get())
+        .and(warp::query::<MessagesQuery>())
+        .and(with_context(ctx.clone()))
+        .and_then(|query: MessagesQuery, context: CallContext| async move {
+            handle_chat_message_page(context, query).await
+        });
+
+    // POST /hol/chat/messages
+    let send_routes = warp::path!("hol" / "chat" / "messages")
+        .and(warp::post())
+        .and(same_origin())
+        .and(json_body())
+        .and(with_context(ctx.clone()))
+        .and_then(|body: Bytes, context: CallContext| async move {
+            handle_chat_send(context, body).await
+        });
+
+    // PATCH /hol/chat/messages?path=&msg_id=
+    let edit_routes = warp::path!("hol" / "chat" / "messages")
+        .and(warp::patch())
+        .and(same_origin())
+        .and(warp::query::<MessageKey>())
+        .and(json_body())
+        .and(with_context(ctx.clone()))
+        .and_then(
+            |key: MessageKey, body: Bytes, context: CallContext| async move {
+                handle_chat_edit(context, key, body).await
+            },
+        );
+
+    // DELETE /hol/chat/messages?path=&msg_id=
+    let delete_routes = warp::path!("hol" / "chat" / "messages")
+        .and(warp::delete())
+        .and(same_origin())
+        .and(warp::query::<MessageKey>())
+        .and(with_context(ctx.clone()))
+        .and_then(|key: MessageKey, context: CallContext| async move {
+            handle_chat_delete(context, key).await
+        });
+
+    // POST /hol/chat/reactions
+    let react_routes = warp::path!("hol" / "chat" / "reactions")
+        .and(warp::post())
+        .and(same_origin())
+        .and(json_body())
+        .and(with_context(ctx.clone()))
+        .and_then(|body: Bytes, context: CallContext| async move {
+            handle_chat_react(context, body).await
+        });
+
+    // DELETE /hol/chat/reactions?path=&msg_id=&emoji=
+    let unreact_routes = warp::path!("hol" / "chat" / "reactions")
+        .and(warp::delete())
+        .and(same_origin())
+        .and(warp::query::<ReactionKey>())
+        .and(with_context(ctx.clone()))
+        .and_then(|key: ReactionKey, context: CallContext| async move {
+            handle_chat_unreact(context, key).await
+        });
+
+    // /hol/chat/thread?path=&msg_id=
+    let thread_routes = warp::path!("hol" / "chat" / "thread")
+        .and(warp::get())
+        .and(warp::query::<MessageKey>())
+        .and(with_context(ctx.clone()))
+        .and_then(|query: MessageKey, context: CallContext| async move {
+            handle_chat_thread(context, query).await
+        });
+
+    // /hol/chat/search?q=&path=&sender=&limit=&offset=
+    let search_routes = warp::path!("hol" / "chat" / "search")
+        .and(warp::get())
+        .and(warp::query::<SearchParams>())
+        .and(with_context(ctx.clone()))
+        .and_then(|query: SearchParams, context: CallContext| async move {
+            handle_chat_search(context, query).await
+        });
+
+    // /hol/chat/paths
+    let path_routes = warp::path!("hol" / "chat" / "paths")
+        .and(warp::get())
+        .and(with_context(ctx.clone()))
+        .and_then(|context: CallContext| async move { handle_chat_paths(context).await });
+
+    // /hol/chat/peers?path={path}
+    let peer_routes = warp::path!("hol" / "chat" / "peers")
+        .and(warp::get())
+        .and(warp::query::<PeersQuery>())
+        .and(with_context(ctx.clone()))
+        .and_then(|query: PeersQuery, context: CallContext| async move {
+            handle_chat_peers(context, query).await
+        });
+
+    // /hol/chat/import
+    let import_routes = warp::path!("hol" / "chat" / "import")
+        .and(warp::get())
+        .and(with_context(ctx))
+        .and_then(|context: CallContext| async move { handle_chat_import(context).await });
+
+    let reads = chat_routes
+        .or(page_routes)
+        .or(thread_routes)
+        .or(search_routes)
+        .or(path_routes)
+        .or(peer_routes)
+        .or(import_routes)
+        .with(cors);
+
+    let writes = send_routes
+        .or(edit_routes)
+        .or(delete_routes)
+        .or(react_routes)
+        .or(unreact_routes)
+        .recover(refuse_write);
+
+    reads.or(writes)
+}
+
+// turn the refusals of writes into responses. anything else is passed on
+async fn refuse_write(err: Rejection) -> Result<reply::Response, Rejection> {
+    let (status, message) = if err.find::<CrossOrigin>().is_some() {
+        (StatusCode::FORBIDDEN, "cross-origin writes are not allowed")
+    } else if err.find::<NotJson>().is_some() {
+        (
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "the body must be sent as application/json",
+        )
+    } else {
+        return Err(err);
+    };
+    Ok(reply::with_status(reply::json(&json!({ "error": message })), status).into_response())
+}
+
+/// messages returned when `limit` is not given
+const DEFAULT_PAGE_SIZE: u32 = 50;
+const MAX_PAGE_SIZE: u32 = 500;
+
+/// search results returned when `limit` is not given
+const DEFAULT_SEARCH_SIZE: u32 = 20;
+
+#[derive(Debug, Default, Deserialize)]
+struct SearchParams {
+    q: Option<String>,
+    path: Option<String>,
+    sender: Option<String>,
+    limit: Option<u32>,
+    offset: Option<u32>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct MessagesQuery {
+    limit: Option<u32>,
+    before: Option<String>,
+    after: Option<String>,
+    path: Option<String>,
+    sender: Option<String>,
+    content_type: Option<String>,
+    reply_to: Option<String>,
+    /// `parts` (the default): raw chat-db rows. `messages`: reassembled messages
+    view: Option<String>,
+}
+
+/// largest accepted write body (bytes)
+const MAX_BODY_SIZE: u64 = 64 * 1024;
+
+#[derive(Debug, Deserialize)]
+struct MessageKey {
+    path: String,
+    msg_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ReactionKey {
+    path: String,
+    msg_id: String,
+    emoji: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct PeersQuery {
+    path: Option<String>,
 }
 
 pub async fn handle_chat_messages(
@@ -54,7 +255,7 @@ pub async fn handle_chat_messages(
     param: String,
 ) -> Result<impl warp::Reply, warp::Rejection> {
     let timestamp = {
-        let ts = i64::from_str_radix(&param, 10);
+        let ts = param.parse::<i64>();
         if ts.is_err() {
             trace_err_ln!("invalid start-ms parameter {}", param);
             return Err(reject::custom(InvalidParameter));
@@ -72,8 +273,940 @@ pub async fn handle_chat_messages(
     Ok(warp::reply::json(&data))
 }
 
+async fn handle_chat_message_page(
+    context: CallContext,
+    query: MessagesQuery,
+) -> Result<reply::Response, Infallible> {
+    if query.before.is_some() && query.after.is_some() {
+        return Ok(bad_request("before and after cannot be combined"));
+    }
+    let cursor = |token: &Option<String>| match token {
+        Some(token) => MessageCursor::decode(token).ok_or(()).map(Some),
+        None => Ok(None),
+    };
+    let (Ok(before), Ok(after)) = (cursor(&query.before), cursor(&query.after)) else {
+        return Ok(bad_request("invalid cursor"));
+    };
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+    if limit == 0 || limit > MAX_PAGE_SIZE {
+        return Ok(bad_request("limit must be between 1 and 500"));
+    }
+
+    let view = query.view;
+    let query = MessageQuery {
+        limit,
+        before,
+        after,
+        path: query.path,
+        sender: query.sender,
+        content_type: query.content_type,
+        reply_to: query.reply_to,
+    };
+    let page = match view.as_deref() {
+        None | Some("parts") => super::data::query_message_page(&context.db, &query)
+            .await
+            .map(|page| reply::json(&page)),
+        Some("messages") => super::data::query_message_list(&context.db, &query)
+            .await
+            .map(|list| reply::json(&list)),
+        Some(_) => return Ok(bad_request("view must be parts or messages")),
+    };
+    match page {
+        Ok(page) => Ok(page.into_response()),
+        Err(e) => {
+            trace_err_ln!("query_message_page failed. {}", e);
+            Ok(server_error())
+        }
+    }
+}
+
+async fn handle_chat_thread(
+    context: CallContext,
+    query: MessageKey,
+) -> Result<reply::Response, Infallible> {
+    match super::data::query_thread(&context.db, &query.path, &query.msg_id).await {
+        Ok(Some(thread)) => Ok(reply::json(&thread).into_response()),
+        Ok(None) => Ok(reply::with_status(
+            reply::json(&json!({ "error": "message not found" })),
+            StatusCode::NOT_FOUND,
+        )
+        .into_response()),
+        Err(e) => {
+            trace_err_ln!("query_thread failed. {}", e);
+            Ok(server_error())
+        }
+    }
+}
+
+async fn handle_chat_search(
+    context: CallContext,
+    query: SearchParams,
+) -> Result<reply::Response, Infallible> {
+    let terms = query.q.unwrap_or_default();
+    if terms.trim().is_empty() {
+        return Ok(bad_request("q is required"));
+    }
+    let limit = query.limit.unwrap_or(DEFAULT_SEARCH_SIZE);
+    if limit == 0 || limit > MAX_PAGE_SIZE {
+        return Ok(bad_request("limit must be between 1 and 500"));
+    }
+
+    let query = SearchQuery {
+        terms,
+        path: query.path,
+        sender: query.sender,
+        limit,
+        offset: query.offset.unwrap_or(0),
+    };
+    match super::data::search_messages(&context.db, &query).await {
+        Ok(results) => Ok(reply::json(&json!({ "results": results })).into_response()),
+        Err(e) => {
+            trace_err_ln!("search_messages failed. {}", e);
+            Ok(server_error())
+        }
+    }
+}
+
+pub async fn handle_chat_paths(context: CallContext) -> Result<impl warp::Reply, warp::Rejection> {
+    match super::data::query_paths(&context.db).await {
+        Ok(data) => Ok(warp::reply::json(&data)),
+        Err(e) => {
+            trace_err_ln!("query_paths failed. {}", e);
+            Err(reject::custom(DbError))
+        }
+    }
+}
+
+async fn handle_chat_import(context: CallContext) -> Result<impl warp::Reply, warp::Rejection> {
+    match super::import::status(&context.db) {
+        Ok(report) => Ok(warp::reply::json(&report)),
+        Err(e) => {
+            trace_err_ln!("import status failed. {}", e);
+            Err(reject::custom(DbError))
+        }
+    }
+}
+
+async fn handle_chat_peers(
+    context: CallContext,
+    query: PeersQuery,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    match super::data::query_peers(&context.db, query.path.as_deref()).await {
+        Ok(data) => Ok(warp::reply::json(&data)),
+        Err(e) => {
+            trace_err_ln!("query_peers failed. {}", e);
+            Err(reject::custom(DbError))
+        }
+    }
+}
+
+async fn handle_chat_send(
+    context: CallContext,
+    body: Bytes,
+) -> Result<reply::Response, Infallible> {
+    let msg: NewMessage = match serde_json::from_slice(&body) {
+        Ok(msg) => msg,
+        Err(e) => return Ok(bad_request(&format!("invalid message. {}", e))),
+    };
+    if msg.path.is_empty() || msg.parts.is_empty() {
+        return Ok(bad_request("a message needs a path and at least one part"));
+    }
+    match write::send(&context, &msg).await {
+        Ok(message) => Ok(accepted(&message)),
+        Err(e) => Ok(write_error(e)),
+    }
+}
+
+async fn handle_chat_edit(
+    context: CallContext,
+    key: MessageKey,
+    body: Bytes,
+) -> Result<reply::Response, Infallible> {
+    let edit: EditMessage = match serde_json::from_slice(&body) {
+        Ok(edit) => edit,
+        Err(e) => return Ok(bad_request(&format!("invalid edit. {}", e))),
+    };
+    if edit.parts.is_empty() {
+        return Ok(bad_request("an edit needs at least one part"));
+    }
+    match write::edit(&context, &key.path, &key.msg_id, &edit.parts).await {
+        Ok(message) => Ok(accepted(&message)),
+        Err(e) => Ok(write_error(e)),
+    }
+}
+
+async fn handle_chat_delete(
+    context: CallContext,
+    key: MessageKey,
+) -> Result<reply::Response, Infallible> {
+    match write::delete(&context, &key.path, &key.msg_id).await {
+        Ok(()) => Ok(accepted(&json!({ "path": key.path, "msg-id": key.msg_id }))),
+        Err(e) => Ok(write_error(e)),
+    }
+}
+
+async fn handle_chat_react(
+    context: CallContext,
+    body: Bytes,
+) -> Result<reply::Response, Infallible> {
+    let reaction: NewReaction = match serde_json::from_slice(&body) {
+        Ok(reaction) => reaction,
+        Err(e) => return Ok(bad_request(&format!("invalid reaction. {}", e))),
+    };
+    if reaction.emoji.is_empty() {
+        return Ok(bad_request("emoji is required"));
+    }
+    match write::react(&context, &reaction).await {
+        Ok(message) => Ok(accepted(&message)),
+        Err(e) => Ok(write_error(e)),
+    }
+}
+
+async fn handle_chat_unreact(
+    context: CallContext,
+    key: ReactionKey,
+) -> Result<reply::Response, Infallible> {
+    match write::unreact(&context, &key.path, &key.msg_id, &key.emoji).await {
+        Ok(()) => Ok(accepted(
+            &json!({ "path": key.path, "msg-id": key.msg_id, "emoji": key.emoji }),
+        )),
+        Err(e) => Ok(write_error(e)),
+    }
+}
+
+// the write was applied locally and sent to the ship, which has yet to confirm it
+fn accepted<T: serde::Serialize>(body: &T) -> reply::Response {
+    reply::with_status(reply::json(body), StatusCode::ACCEPTED).into_response()
+}
+
+fn write_error(e: WriteError) -> reply::Response {
+    let (status, message) = match e {
+        WriteError::NotConnected => (StatusCode::SERVICE_UNAVAILABLE, "not connected to the ship"),
+        WriteError::NotFound => (StatusCode::NOT_FOUND, "message not found"),
+        WriteError::Provisional => (StatusCode::CONFLICT, "message is not confirmed yet"),
+        WriteError::Ship(e) => {
+            trace_err_ln!("chat write failed. {}", e);
+            (StatusCode::BAD_GATEWAY, "the ship could not be reached")
+        }
+        WriteError::Db(e) => {
+            trace_err_ln!("chat write failed. {}", e);
+            return server_error();
+        }
+    };
+    reply::with_status(reply::json(&json!({ "error": message })), status).into_response()
+}
+
+fn bad_request(message: &str) -> reply::Response {
+    trace_err_ln!("invalid chat query. {}", message);
+    reply::with_status(
+        reply::json(&json!({ "error": message })),
+        StatusCode::BAD_REQUEST,
+    )
+    .into_response()
+}
+
+fn server_error() -> reply::Response {
+    reply::with_status(
+        reply::json(&json!({ "error": "database error" })),
+        StatusCode::INTERNAL_SERVER_ERROR,
+    )
+    .into_response()
+}
+
+fn json_body() -> impl Filter<Extract = (Bytes,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("content-type")
+        .and_then(|content_type: Option<String>| async move {
+            let media_type = content_type.as_deref().and_then(|t| t.split(';').next());
+            match media_type.map(str::trim) {
+                Some(t) if t.eq_ignore_ascii_case("application/json") => Ok(()),
+                _ => Err(reject::custom(NotJson)),
+            }
+        })
+        .untuple_one()
+        .and(warp::body::content_length_limit(MAX_BODY_SIZE))
+        .and(warp::body::bytes())
+}
+
+// refuse a request a browser sent on behalf of another origin: its `sec-fetch-site`
+//  is not same-origin, or its `origin` is not the host it was sent to. requests without
+//  either (non-browser clients) pass
+fn same_origin() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("sec-fetch-site")
+        .and(warp::header::optional::<String>("origin"))
+        .and(warp::header::optional::<String>("host"))
+        .and_then(
+            |site: Option<String>, origin: Option<String>, host: Option<String>| async move {
+                let cross_site = site.is_some_and(|site| site != "same-origin" && site != "none");
+                let cross_origin = origin.is_some_and(|origin| {
+                    let origin_host = origin
+                        .strip_prefix("https://")
+                        .or_else(|| origin.strip_prefix("http://"));
+                    match (origin_host, host.as_deref()) {
+                        (Some(origin_host), Some(host)) => !origin_host.eq_ignore_ascii_case(host),
+                        _ => true,
+                    }
+                });
+                match cross_site || cross_origin {
+                    true => Err(reject::custom(CrossOrigin)),
+                    false => Ok(()),
+                }
+            },
+        )
+        .untuple_one()
+}
+
 fn with_context(
     ctx: CallContext,
 ) -> impl Filter<Extract = (CallContext,), Error = Infallible> + Clone {
     warp::any().map(move || ctx.clone())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{run, test_context, test_ship};
+    use serde_json::Value as JsonValue;
+
+    // seven message parts on two paths. the two parts of the last message share a
+    //  created-at, so they are ordered by part id
+    fn seed(context: &CallContext) {
+        let mut changes = Vec::new();
+        for n in 0..6u64 {
+            let path = match n % 2 {
+                0 => "/spaces/~zod/chats/0v1",
+                _ => "/spaces/~zod/chats/0v2",
+            };
+            let reply_to = match n {
+                3 | 5 => json!({"path": path, "msg-id": "/~2023.7.7..19.57.11..0001/~zod"}),
+                _ => JsonValue::Null,
+            };
+            for part in 0..=(n / 5) {
+                changes.push(json!({"type": "add-row", "table": "messages", "row": {
+                    "path": path,
+                    "msg-id": format!("/~2023.7.7..19.57.1{}..000{}/~zod", n, n),
+                    "msg-part-id": part,
+                    "content-type": if part == 0 { "plain" } else { "image" },
+                    "content-data": format!("message {}.{}", n, part),
+                    "reply-to": reply_to,
+                    "metadata": {},
+                    "sender": if n < 4 { "~zod" } else { "~bus" },
+                    "created-at": 1000 + n,
+                    "updated-at": 1000 + n,
+                    "received-at": 1000 + n,
+                    "expires-at": null,
+                }}));
+            }
+        }
+        super::super::sync::apply(&context.db, &JsonValue::Array(changes)).unwrap();
+    }
+
+    async fn get(context: &CallContext, path: &str) -> (StatusCode, JsonValue) {
+        let res = warp::test::request()
+            .path(path)
+            .reply(&chat_router(context.clone()))
+            .await;
+        (res.status(), serde_json::from_slice(res.body()).unwrap())
+    }
+
+    fn data(page: &JsonValue) -> Vec<String> {
+        page["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|msg| msg["content-data"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn messages_are_paged_with_cursors() {
+        run(async {
+            let (_eyre, ship) = test_ship().await;
+            let context = test_context(ship);
+            seed(&context);
+
+            // newest first, following `next` until it runs out
+            let mut seen = Vec::new();
+            let (_, mut page) = get(&context, "/hol/chat/messages?limit=3").await;
+            loop {
+                assert!(page["messages"].as_array().unwrap().len() <= 3);
+                seen.extend(data(&page));
+                let Some(next) = page["next"].as_str() else {
+                    break;
+                };
+                page = get(
+                    &context,
+                    &format!("/hol/chat/messages?limit=3&before={}", next),
+                )
+                .await
+                .1;
+            }
+            assert_eq!(
+                seen,
+                vec![
+                    "message 5.1",
+                    "message 5.0",
+                    "message 4.0",
+                    "message 3.0",
+                    "message 2.0",
+                    "message 1.0",
+                    "message 0.0"
+                ]
+            );
+
+            // ...or forward from a cursor, oldest first
+            let (_, page) = get(&context, "/hol/chat/messages?limit=2").await;
+            let (_, page) = get(
+                &context,
+                &format!(
+                    "/hol/chat/messages?after={}",
+                    page["next"].as_str().unwrap()
+                ),
+            )
+            .await;
+            assert_eq!(data(&page), vec!["message 5.1"]);
+            assert!(page["next"].is_null());
+        });
+    }
+
+    #[test]
+    fn messages_are_filtered() {
+        run(async {
+            let (_eyre, ship) = test_ship().await;
+            let context = test_context(ship);
+            seed(&context);
+
+            let (_, page) = get(&context, "/hol/chat/messages?path=/spaces/~zod/chats/0v2").await;
+            assert_eq!(
+                data(&page),
+                vec!["message 5.1", "message 5.0", "message 3.0", "message 1.0"]
+            );
+            let (_, page) = get(
+                &context,
+                "/hol/chat/messages?sender=~bus&content_type=plain",
+            )
+            .await;
+            assert_eq!(data(&page), vec!["message 5.0", "message 4.0"]);
+            let (_, page) = get(
+                &context,
+                "/hol/chat/messages?reply_to=/~2023.7.7..19.57.11..0001/~zod&limit=1",
+            )
+            .await;
+            assert_eq!(data(&page), vec!["message 5.1"]);
+            assert!(page["next"].is_string());
+
+            for query in ["limit=0", "limit=501", "before=zz", "before=00&after=00"] {
+                let (status, body) = get(&context, &format!("/hol/chat/messages?{}", query)).await;
+                assert_eq!(status, StatusCode::BAD_REQUEST, "{}", query);
+                assert!(body["error"].is_string());
+            }
+        });
+    }
+
+    #[test]
+    fn messages_and_threads_are_reassembled() {
+        run(async {
+            let (_eyre, ship) = test_ship().await;
+            let context = test_context(ship);
+            seed(&context);
+
+            // one entry per message, with its parts in order
+            let (_, page) = get(&context, "/hol/chat/messages?view=messages&limit=5").await;
+            let messages = page["messages"].as_array().unwrap();
+            assert_eq!(messages.len(), 5);
+            let parts: Vec<&str> = messages[0]["parts"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|part| part["content-data"].as_str().unwrap())
+                .collect();
+            assert_eq!(parts, vec!["message 5.0", "message 5.1"]);
+            assert!(page["next"].is_string());
+
+            let (status, thread) = get(
+                &context,
+                "/hol/chat/thread?path=/spaces/~zod/chats/0v2&msg_id=/~2023.7.7..19.57.11..0001/~zod",
+            )
+            .await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(
+                thread["replies"][0]["parts"][0]["content-data"],
+                "message 3.0"
+            );
+            assert_eq!(thread["replies"][1]["parts"].as_array().unwrap().len(), 2);
+
+            let (status, _) = get(
+                &context,
+                "/hol/chat/thread?path=/spaces/~zod/chats/0v2&msg_id=/~1/~nec",
+            )
+            .await;
+            assert_eq!(status, StatusCode::NOT_FOUND);
+            let (status, _) = get(&context, "/hol/chat/messages?view=tree").await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+        });
+    }
+
+    fn text(
+        path: &str,
+        msg_id: &str,
+        sender: &str,
+        content_type: &str,
+        data: &str,
+        at: u64,
+    ) -> JsonValue {
+        json!({
+            "path": path,
+            "msg-id": msg_id,
+            "msg-part-id": 0,
+            "content-type": content_type,
+            "content-data": data,
+            "reply-to": null,
+            "metadata": {},
+            "sender": sender,
+            "created-at": at,
+            "updated-at": at,
+            "received-at": at,
+            "expires-at": null,
+        })
+    }
+
+    #[test]
+    fn messages_are_searched() {
+        run(async {
+            let (_eyre, ship) = test_ship().await;
+            let context = test_context(ship);
+            let add = |row: JsonValue| json!({"type": "add-row", "table": "messages", "row": row});
+            let (p1, p2) = ("/spaces/~zod/chats/0v1", "/spaces/~zod/chats/0v2");
+            super::super::sync::apply(
+                &context.db,
+                &json!([
+                    add(text(
+                        p1,
+                        "/~1/~zod",
+                        "~zod",
+                        "plain",
+                        "hello from the holium node",
+                        1
+                    )),
+                    add(text(
+                        p1,
+                        "/~2/~bus",
+                        "~bus",
+                        "plain",
+                        "Héllo hello, anyone here?",
+                        2
+                    )),
+                    add(text(p2, "/~3/~bus", "~bus", "plain", "goodbye", 3)),
+                    add(text(p2, "/~4/~zod", "~zod", "react", "hello", 4)),
+                ]),
+            )
+            .unwrap();
+
+            let hits = |body: &JsonValue| -> Vec<String> {
+                body["results"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|hit| hit["message"]["msg-id"].as_str().unwrap().to_string())
+                    .collect()
+            };
+
+            // diacritics are folded, the closer match ranks first and reactions are not indexed
+            let (status, body) = get(&context, "/hol/chat/search?q=hello").await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(hits(&body), vec!["/~2/~bus", "/~1/~zod"]);
+            assert_eq!(
+                body["results"][1]["snippet"],
+                "<mark>hello</mark> from the holium node"
+            );
+
+            // every word must match; the last may be a prefix
+            let (_, body) = get(&context, "/hol/chat/search?q=hello%20hol").await;
+            assert_eq!(hits(&body), vec!["/~1/~zod"]);
+            let (_, body) = get(&context, "/hol/chat/search?q=hello&sender=~zod").await;
+            assert_eq!(hits(&body), vec!["/~1/~zod"]);
+            let (_, body) = get(
+                &context,
+                "/hol/chat/search?q=good&path=/spaces/~zod/chats/0v2",
+            )
+            .await;
+            assert_eq!(hits(&body), vec!["/~3/~bus"]);
+
+            // the index follows edits and deletes
+            let mut edited = text(p2, "/~3/~bus", "~bus", "plain", "hello again", 5);
+            edited["created-at"] = json!(3);
+            super::super::sync::apply(
+                &context.db,
+                &json!([
+                    {"type": "upd-messages", "msg-id": "/~3/~bus", "message": [edited]},
+                    {"type": "del-messages-row", "path": p1, "msg-id": "/~2/~bus", "timestamp": 6},
+                ]),
+            )
+            .unwrap();
+            let (_, body) = get(&context, "/hol/chat/search?q=good").await;
+            assert!(hits(&body).is_empty());
+            let (_, body) = get(&context, "/hol/chat/search?q=hello&limit=1&offset=1").await;
+            assert_eq!(body["results"].as_array().unwrap().len(), 1);
+            let (_, body) = get(&context, "/hol/chat/search?q=hello").await;
+            let mut found = hits(&body);
+            found.sort();
+            assert_eq!(found, vec!["/~1/~zod", "/~3/~bus"]);
+
+            // user input is never fts5 syntax
+            let (status, _) = get(&context, "/hol/chat/search?q=%22hello%20OR%20*").await;
+            assert_eq!(status, StatusCode::OK);
+            let (status, _) = get(&context, "/hol/chat/search?q=%20").await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+        });
+    }
+
+    const CHAT: &str = "/spaces/~zod/chats/0v1";
+
+    async fn send(
+        context: &CallContext,
+        method: &str,
+        path: &str,
+        body: JsonValue,
+    ) -> (StatusCode, JsonValue) {
+        let res = warp::test::request()
+            .method(method)
+            .path(path)
+            .json(&body)
+            .reply(&chat_router(context.clone()))
+            .await;
+        (res.status(), serde_json::from_slice(res.body()).unwrap())
+    }
+
+    // start the chat sync and wait for its channel. returns the channel's uid
+    async fn start_sync(eyre: &eyre_mock::MockEyre, context: &CallContext) -> String {
+        super::super::sync::start(context.clone()).await.unwrap();
+        for _ in 0..200 {
+            if let Some(uid) = eyre
+                .channels()
+                .into_iter()
+                .find(|uid| !eyre.subscriptions(uid).is_empty())
+            {
+                return uid;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+        panic!("the sync channel was not opened");
+    }
+
+    // ids and first parts of the reassembled messages, once `done` holds for them
+    async fn wait_for(
+        context: &CallContext,
+        done: impl Fn(&[(String, String)]) -> bool,
+    ) -> Vec<(String, String)> {
+        let mut messages = Vec::new();
+        for _ in 0..200 {
+            let (_, page) = get(context, "/hol/chat/messages?view=messages").await;
+            messages = page["messages"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|msg| {
+                    (
+                        msg["id"].as_str().unwrap().to_string(),
+                        msg["parts"][0]["content-data"]
+                            .as_str()
+                            .unwrap()
+                            .to_string(),
+                    )
+                })
+                .collect();
+            if done(&messages) {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+        messages
+    }
+
+    fn pending(context: &CallContext) -> u64 {
+        let conn = context.db.pool.get_conn().unwrap();
+        conn.query_row("SELECT COUNT(*) FROM chat_pending", [], |row| row.get(0))
+            .unwrap()
+    }
+
+    fn row(msg_id: &str, data: &str, updated_at: u64) -> JsonValue {
+        let mut row = text(CHAT, msg_id, "~zod", "plain", data, 1000);
+        row["updated-at"] = json!(updated_at);
+        row
+    }
+
+    #[test]
+    fn writes_are_applied_ahead_of_the_ship() {
+        run(async {
+            let (eyre, ship) = test_ship().await;
+            let context = test_context(ship);
+            let uid = start_sync(&eyre, &context).await;
+
+            let (status, message) = send(
+                &context,
+                "POST",
+                "/hol/chat/messages",
+                json!({"path": CHAT, "parts": [{"content-type": "plain", "content-data": "hello"}]}),
+            )
+            .await;
+            assert_eq!(status, StatusCode::ACCEPTED);
+            let provisional = message["id"].as_str().unwrap().to_string();
+            assert!(provisional.starts_with("/~pending."), "{}", provisional);
+            assert_eq!(message["sender"], "~zod");
+            let (_, page) = get(&context, "/hol/chat/messages?view=messages").await;
+            assert_eq!(page["messages"][0]["id"], provisional.as_str());
+
+            // the poke the ship got
+            let poke = eyre
+                .actions(&uid)
+                .into_iter()
+                .find(|action| action["app"] == "realm-chat")
+                .unwrap();
+            assert_eq!(poke["action"], "poke");
+            assert_eq!(poke["mark"], "chat-action");
+            assert_eq!(
+                poke["json"]["send-message"]["fragments"][0]["content"]["plain"],
+                "hello"
+            );
+
+            // the ship's copy replaces the provisional message
+            let msg_id = "/~2023.7.7..20.00.00..0000/~zod";
+            eyre.push_fact(
+                "chat-db",
+                "/db",
+                json!([{"type": "add-row", "table": "messages", "row": row(msg_id, "hello", 1000)}]),
+            );
+            let confirmed = vec![(msg_id.to_string(), "hello".to_string())];
+            assert_eq!(wait_for(&context, |m| m == confirmed).await, confirmed);
+            assert_eq!(pending(&context), 0);
+
+            // edits show up right away, and are settled by the ship's version
+            let key = format!("path={}&msg_id={}", CHAT, msg_id);
+            let (status, message) = send(
+                &context,
+                "PATCH",
+                &format!("/hol/chat/messages?{}", key),
+                json!({"parts": [{"content-type": "plain", "content-data": "hello!"}]}),
+            )
+            .await;
+            assert_eq!(status, StatusCode::ACCEPTED);
+            assert_eq!(message["parts"][0]["content-data"], "hello!");
+            assert_eq!(pending(&context), 1);
+            eyre.push_fact(
+                "chat-db",
+                "/db",
+                json!([{"type": "upd-messages", "msg-id": msg_id, "message": [row(msg_id, "hello!", 2000)]}]),
+            );
+            for _ in 0..200 {
+                if pending(&context) == 0 {
+                    break;
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            }
+            assert_eq!(pending(&context), 0);
+
+            // reactions are folded onto the message, and can be taken back
+            let (status, message) = send(
+                &context,
+                "POST",
+                "/hol/chat/reactions",
+                json!({"path": CHAT, "msg-id": msg_id, "emoji": "1f44d"}),
+            )
+            .await;
+            assert_eq!(status, StatusCode::ACCEPTED);
+            assert_eq!(message["reactions"][0]["emoji"], "1f44d");
+            assert_eq!(message["reactions"][0]["sender"], "~zod");
+            let unreact = format!("/hol/chat/reactions?{}&emoji=1f44d", key);
+            // ...once the ship has it
+            let (status, _) = send(&context, "DELETE", &unreact, json!({})).await;
+            assert_eq!(status, StatusCode::CONFLICT);
+            let mut react = text(
+                CHAT,
+                "/~2023.7.7..20.00.01..0000/~zod",
+                "~zod",
+                "react",
+                "1f44d",
+                1001,
+            );
+            react["reply-to"] = json!({"path": CHAT, "msg-id": msg_id});
+            eyre.push_fact(
+                "chat-db",
+                "/db",
+                json!([{"type": "add-row", "table": "messages", "row": react}]),
+            );
+            for _ in 0..200 {
+                if pending(&context) == 0 {
+                    break;
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            }
+            let (status, _) = send(&context, "DELETE", &unreact, json!({})).await;
+            assert_eq!(status, StatusCode::ACCEPTED);
+            let (_, thread) = get(&context, &format!("/hol/chat/thread?{}", key)).await;
+            assert!(thread["reactions"].as_array().unwrap().is_empty());
+
+            // deletes
+            let (status, _) = send(
+                &context,
+                "DELETE",
+                &format!("/hol/chat/messages?{}", key),
+                json!({}),
+            )
+            .await;
+            assert_eq!(status, StatusCode::ACCEPTED);
+            assert!(wait_for(&context, |m| m.is_empty()).await.is_empty());
+
+            let (status, _) = send(
+                &context,
+                "PATCH",
+                &format!("/hol/chat/messages?{}", key),
+                json!({"parts": [{"content-type": "plain", "content-data": "?"}]}),
+            )
+            .await;
+            assert_eq!(status, StatusCode::NOT_FOUND);
+            let (status, _) = send(
+                &context,
+                "POST",
+                "/hol/chat/messages",
+                json!({"path": CHAT, "parts": []}),
+            )
+            .await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+        });
+    }
+
+    #[test]
+    fn refused_writes_are_rolled_back() {
+        run(async {
+            let (eyre, ship) = test_ship().await;
+            let context = test_context(ship);
+
+            // nothing can be written before the sync channel is open
+            let new =
+                json!({"path": CHAT, "parts": [{"content-type": "plain", "content-data": "hi"}]});
+            let (status, _) = send(&context, "POST", "/hol/chat/messages", new.clone()).await;
+            assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+
+            start_sync(&eyre, &context).await;
+            let msg_id = "/~2023.7.7..20.00.00..0000/~zod";
+            super::super::sync::apply(
+                &context.db,
+                &json!([{"type": "add-row", "table": "messages", "row": row(msg_id, "hello", 1000)}]),
+            )
+            .unwrap();
+            let original = vec![(msg_id.to_string(), "hello".to_string())];
+
+            // the ship cannot be reached: nothing is kept
+            eyre.fail_next("/~/channel", 500);
+            let (status, _) = send(&context, "POST", "/hol/chat/messages", new.clone()).await;
+            assert_eq!(status, StatusCode::BAD_GATEWAY);
+            assert_eq!(wait_for(&context, |_| true).await, original);
+
+            // the ship refuses: the message goes away again...
+            eyre.nack_pokes("realm-chat", "not a member");
+            let (status, _) = send(&context, "POST", "/hol/chat/messages", new).await;
+            assert_eq!(status, StatusCode::ACCEPTED);
+            assert_eq!(wait_for(&context, |m| m == original).await, original);
+
+            // ...and a deleted (or edited) one comes back
+            let (status, _) = send(
+                &context,
+                "DELETE",
+                &format!("/hol/chat/messages?path={}&msg_id={}", CHAT, msg_id),
+                json!({}),
+            )
+            .await;
+            assert_eq!(status, StatusCode::ACCEPTED);
+            assert_eq!(wait_for(&context, |m| m == original).await, original);
+            let (status, _) = send(
+                &context,
+                "PATCH",
+                &format!("/hol/chat/messages?path={}&msg_id={}", CHAT, msg_id),
+                json!({"parts": [{"content-type": "plain", "content-data": "bye"}]}),
+            )
+            .await;
+            assert_eq!(status, StatusCode::ACCEPTED);
+            assert_eq!(wait_for(&context, |m| m == original).await, original);
+            assert_eq!(pending(&context), 0);
+        });
+    }
+
+    #[test]
+    fn cors_allows_reads_only() {
+        run(async {
+            let (_eyre, ship) = test_ship().await;
+            let context = test_context(ship);
+            let preflight = |method: &'static str| {
+                warp::test::request()
+                    .method("OPTIONS")
+                    .path("/hol/chat/messages")
+                    .header("origin", "https://realm.holium.com")
+                    .header("access-control-request-method", method)
+            };
+            let res = preflight("GET").reply(&chat_router(context.clone())).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            for method in ["POST", "PATCH", "DELETE"] {
+                let res = preflight(method).reply(&chat_router(context.clone())).await;
+                assert_ne!(res.status(), StatusCode::OK, "{}", method);
+                assert!(!res.headers().contains_key("access-control-allow-origin"));
+            }
+        });
+    }
+
+    #[test]
+    fn cross_origin_writes_are_refused() {
+        run(async {
+            let (_eyre, ship) = test_ship().await;
+            let context = test_context(ship);
+            let body =
+                json!({"path": CHAT, "parts": [{"content-type": "plain", "content-data": "hi"}]})
+                    .to_string();
+            let post = || {
+                warp::test::request()
+                    .method("POST")
+                    .path("/hol/chat/messages")
+                    .header("host", "localhost:3030")
+                    .body(body.clone())
+            };
+
+            // a no-cors fetch from another site sends json as text/plain
+            let res = post()
+                .header("content-type", "text/plain;charset=UTF-8")
+                .reply(&chat_router(context.clone()))
+                .await;
+            assert_eq!(res.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+            let res = post().reply(&chat_router(context.clone())).await;
+            assert_eq!(res.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+            for (header, value) in [
+                ("origin", "https://evil.example"),
+                ("origin", "http://localhost:8080"),
+                ("origin", "null"),
+                ("sec-fetch-site", "cross-site"),
+                ("sec-fetch-site", "same-site"),
+            ] {
+                let res = post()
+                    .header("content-type", "application/json")
+                    .header(header, value)
+                    .reply(&chat_router(context.clone()))
+                    .await;
+                assert_eq!(res.status(), StatusCode::FORBIDDEN, "{}: {}", header, value);
+            }
+            let res = warp::test::request()
+                .method("DELETE")
+                .path(&format!("/hol/chat/messages?path={}&msg_id=/~1/~zod", CHAT))
+                .header("sec-fetch-site", "cross-site")
+                .reply(&chat_router(context.clone()))
+                .await;
+            assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+            // the node's own pages get through (to the ship, which is not connected here)
+            let res = post()
+                .header("content-type", "application/json; charset=utf-8")
+                .header("origin", "http://localhost:3030")
+                .header("sec-fetch-site", "same-origin")
+                .reply(&chat_router(context.clone()))
+                .await;
+            assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        });
+    }
+}
@@ -1,12 +1,18 @@
 use crate::db::Db;
+use crate::helper::get_current_time;
 use anyhow::Result;
+use rusqlite::{Connection, OptionalExtension};
 
-use super::types::ChatMessage;
+use super::events::{Changes, ChatEvent};
+use super::types::{
+    ChatMessage, Message, MessageCursor, MessageList, MessagePage, MsgRef, Part, PathRow, PeerRow,
+    Reaction, SearchResult,
+};
 
 pub async fn query_messages(db: &Db, timestamp: i64) -> Result<Vec<ChatMessage>> {
     let conn = db.pool.get_conn()?;
 
-    let mut stmt = conn.prepare(
+    let mut stmt = conn.prepare(&format!(
         "SELECT path,
                 msg_id,
                 msg_part_id,
@@ -20,10 +26,12 @@ pub async fn query_messages(db: &Db, timestamp: i64) -> Result<Vec<ChatMessage>>
                 received_at,
                 expires_at
               FROM chat_messages
-              WHERE received_at >= ?1",
-    )?;
+              WHERE received_at >= ?1
+                AND {}",
+        unexpired("chat_messages", "?2")
+    ))?;
 
-    let msg_iter = stmt.query_map([timestamp], |row| {
+    let msg_iter = stmt.query_map((timestamp, get_current_time()), |row| {
         Ok(ChatMessage {
             path: row.get(0)?,
             msg_id: row.get(1)?,
@@ -47,3 +55,1228 @@ pub async fn query_messages(db: &Db, timestamp: i64) -> Result<Vec<ChatMessage>>
 
     Ok(records)
 }
+
+/// a page of messages. filters left as None match every message
+#[derive(Debug, Default)]
+pub struct MessageQuery {
+    pub limit: u32,
+    /// messages created before the cursor, newest first (the default, from the newest)
+    pub before: Option<MessageCursor>,
+    /// messages created after the cursor, oldest first
+    pub after: Option<MessageCursor>,
+    pub path: Option<String>,
+    pub sender: Option<String>,
+    pub content_type: Option<String>,
+    /// replies to this msg-id
+    pub reply_to: Option<String>,
+}
+
+pub async fn query_message_page(db: &Db, query: &MessageQuery) -> Result<MessagePage> {
+    let conn = db.pool.get_conn()?;
+    let (messages, next) = page_parts(&conn, query, "")?;
+    Ok(MessagePage { messages, next })
+}
+
+/// a page of reassembled messages, reactions folded in. filters apply to a message's
+///  first part; react messages are never listed themselves
+pub async fn query_message_list(db: &Db, query: &MessageQuery) -> Result<MessageList> {
+    let conn = db.pool.get_conn()?;
+    let now = get_current_time();
+    let (firsts, next) = page_parts(
+        &conn,
+        query,
+        "AND msg_part_id = 0 AND content_type IS NOT 'react'",
+    )?;
+    let mut messages = Vec::new();
+    for first in firsts {
+        if let Some(message) = load_live_message(&conn, &first.path, &first.msg_id, now)? {
+            messages.push(message);
+        }
+    }
+    Ok(MessageList { messages, next })
+}
+
+/// a message with its replies (and theirs) nested under it, oldest first
+pub async fn query_thread(db: &Db, path: &str, msg_id: &str) -> Result<Option<Message>> {
+    let conn = db.pool.get_conn()?;
+    let now = get_current_time();
+    let Some(mut root) = load_live_message(&conn, path, msg_id, now)? else {
+        return Ok(None);
+    };
+    root.replies = load_replies(&conn, path, msg_id, now, THREAD_DEPTH)?;
+    Ok(Some(root))
+}
+
+/// replies nested deeper than this are left out of thread views
+const THREAD_DEPTH: usize = 16;
+
+fn load_replies(
+    conn: &Connection,
+    path: &str,
+    msg_id: &str,
+    now: u64,
+    depth: usize,
+) -> Result<Vec<Message>> {
+    if depth == 0 {
+        return Ok(Vec::new());
+    }
+    let ids = {
+        let mut stmt = conn.prepare_cached(&format!(
+            "SELECT msg_id FROM chat_messages
+              WHERE json_extract(reply_to, '$.msg-id') = ?2 AND path = ?1
+                AND msg_part_id = 0 AND content_type IS NOT 'react'
+                AND {}
+              ORDER BY created_at, msg_id",
+            unexpired("chat_messages", "?3")
+        ))?;
+        let rows = stmt.query_map((path, msg_id, now), |row| row.get::<_, String>(0))?;
+        rows.collect::<rusqlite::Result<Vec<String>>>()?
+    };
+    let mut replies = Vec::new();
+    for id in ids {
+        if let Some(mut reply) = load_live_message(conn, path, &id, now)? {
+            reply.replies = load_replies(conn, path, &id, now, depth - 1)?;
+            replies.push(reply);
+        }
+    }
+    Ok(replies)
+}
+
+/// every part of a message, in part order
+pub fn message_parts(conn: &Connection, path: &str, msg_id: &str) -> Result<Vec<ChatMessage>> {
+    parts_at(conn, path, msg_id, None)
+}
+
+/// every part of a message, plus the reactions pointing at it. expired or not (see
+///  `load_live_message`)
+pub fn load_message(conn: &Connection, path: &str, msg_id: &str) -> Result<Option<Message>> {
+    message_at(conn, path, msg_id, None)
+}
+
+/// a message as clients see it at `now`: None once it has expired, and without
+///  expired reactions
+pub fn load_live_message(
+    conn: &Connection,
+    path: &str,
+    msg_id: &str,
+    now: u64,
+) -> Result<Option<Message>> {
+    message_at(conn, path, msg_id, Some(now))
+}
+
+// the parts of a message, leaving out expired ones if `now` is given
+fn parts_at(
+    conn: &Connection,
+    path: &str,
+    msg_id: &str,
+    now: Option<u64>,
+) -> Result<Vec<ChatMessage>> {
+    let mut stmt = conn.prepare_cached(&format!(
+        "SELECT {MESSAGE_COLUMNS} FROM chat_messages
+          WHERE path = ?1 AND msg_id = ?2
+            AND (?3 IS NULL OR {})
+          ORDER BY msg_part_id",
+        unexpired("chat_messages", "?3")
+    ))?;
+    let rows = stmt.query_map((path, msg_id, now), read_message)?;
+    Ok(rows.collect::<rusqlite::Result<Vec<ChatMessage>>>()?)
+}
+
+fn message_at(
+    conn: &Connection,
+    path: &str,
+    msg_id: &str,
+    now: Option<u64>,
+) -> Result<Option<Message>> {
+    let parts = parts_at(conn, path, msg_id, now)?;
+    let reactions = {
+        let mut stmt = conn.prepare_cached(&format!(
+            "SELECT {MESSAGE_COLUMNS} FROM chat_messages
+              WHERE json_extract(reply_to, '$.msg-id') = ?2 AND path = ?1
+                AND content_type = 'react'
+                AND (?3 IS NULL OR {})
+              ORDER BY created_at, msg_id",
+            unexpired("chat_messages", "?3")
+        ))?;
+        let rows = stmt.query_map((path, msg_id, now), read_message)?;
+        rows.collect::<rusqlite::Result<Vec<ChatMessage>>>()?
+    };
+    Ok(assemble(parts, reactions))
+}
+
+/// messages that have expired by `now`. see `unexpired`
+pub fn expired_messages(conn: &Connection, now: u64) -> Result<Vec<(String, String)>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT path, msg_id FROM chat_messages
+          WHERE expires_at <= ?1
+         UNION
+         SELECT m.path, m.msg_id FROM chat_messages m
+           JOIN chat_paths p ON p.path = m.path
+          WHERE m.created_at <= ?1 - p.max_expires_at_duration
+         ORDER BY 1, 2",
+    )?;
+    let rows = stmt.query_map([now], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    Ok(rows.collect::<rusqlite::Result<Vec<(String, String)>>>()?)
+}
+
+/// the urls of the newest `limit` image and link parts, newest first
+pub fn media_urls(conn: &Connection, limit: usize) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT content_data FROM chat_messages
+          WHERE content_type IN ('image', 'link')
+          GROUP BY content_data
+          ORDER BY MAX(created_at) DESC
+          LIMIT ?1",
+    )?;
+    let rows = stmt.query_map([limit], |row| row.get(0))?;
+    Ok(rows.collect::<rusqlite::Result<Vec<String>>>()?)
+}
+
+/// whether `ship` is a member of the space at `space` (e.g. /spaces/~zod/our): a peer
+///  of the space's chat, or of one under it
+pub fn is_space_member(conn: &Connection, space: &str, ship: &str) -> Result<bool> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT EXISTS(SELECT 1 FROM chat_peers
+          WHERE ship = ?2
+            AND (path = ?1 OR substr(path, 1, length(?1) + 1) = ?1 || '/'))",
+    )?;
+    Ok(stmt.query_row((space, ship), |row| row.get(0))?)
+}
+
+// a condition on `table` (chat_messages or an alias of it) that holds for message parts
+//  that have not expired at `now` (a query parameter, e.g. "?3"). a message expires at
+//  its expires-at, or once its path's max-expires-at-duration has passed since it was
+//  created, whichever comes first
+fn unexpired(table: &str, now: &str) -> String {
+    format!(
+        "({table}.expires_at IS NULL OR {table}.expires_at > {now})
+         AND NOT EXISTS (SELECT 1 FROM chat_paths
+              WHERE chat_paths.path = {table}.path
+                AND {table}.created_at <= {now} - chat_paths.max_expires_at_duration)"
+    )
+}
+
+/// group the parts of one message (in part order) into a `Message`
+pub fn assemble(parts: Vec<ChatMessage>, reactions: Vec<ChatMessage>) -> Option<Message> {
+    let first = parts.first()?;
+    Some(Message {
+        id: first.msg_id.clone(),
+        path: first.path.clone(),
+        sender: first.sender.clone(),
+        reply_to: serde_json::from_value::<MsgRef>(first.reply_to.clone()).ok(),
+        created_at: first.created_at,
+        updated_at: parts.iter().map(|part| part.updated_at).max().unwrap_or(0),
+        expires_at: first.expires_at.as_u64(),
+        reactions: reactions
+            .into_iter()
+            .map(|react| Reaction {
+                msg_id: react.msg_id,
+                sender: react.sender,
+                emoji: react.content_data,
+                created_at: react.created_at,
+            })
+            .collect(),
+        parts: parts
+            .into_iter()
+            .map(|part| Part {
+                id: part.msg_part_id,
+                content_type: part.content_type,
+                content_data: part.content_data,
+                metadata: part.metadata,
+            })
+            .collect(),
+        replies: Vec::new(),
+    })
+}
+
+// message parts in `created-at` order from the query's cursor. `restrict` is added to
+//  the WHERE clause. returns the page and the cursor of the next one
+fn page_parts(
+    conn: &Connection,
+    query: &MessageQuery,
+    restrict: &str,
+) -> Result<(Vec<ChatMessage>, Option<String>)> {
+    let (cursor, compare, order) = match (&query.before, &query.after) {
+        (_, Some(after)) => (Some(after), ">", "ASC"),
+        (before, None) => (before.as_ref(), "<", "DESC"),
+    };
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {MESSAGE_COLUMNS}
+              FROM chat_messages
+              WHERE (?1 IS NULL OR (created_at, path, msg_id, msg_part_id) {compare} (?1, ?2, ?3, ?4))
+                AND (?5 IS NULL OR path = ?5)
+                AND (?6 IS NULL OR sender = ?6)
+                AND (?7 IS NULL OR content_type = ?7)
+                AND (?8 IS NULL OR json_extract(reply_to, '$.msg-id') = ?8)
+                AND {live}
+                {restrict}
+              ORDER BY created_at {order}, path {order}, msg_id {order}, msg_part_id {order}
+              LIMIT ?9",
+        live = unexpired("chat_messages", "?10"),
+    ))?;
+
+    // one extra row tells whether there is a next page
+    let limit = query.limit.max(1);
+    let msg_iter = stmt.query_map(
+        rusqlite::params![
+            cursor.map(|c| c.created_at),
+            cursor.map(|c| &c.path),
+            cursor.map(|c| &c.msg_id),
+            cursor.map(|c| c.msg_part_id),
+            query.path,
+            query.sender,
+            query.content_type,
+            query.reply_to,
+            limit + 1,
+            get_current_time(),
+        ],
+        read_message,
+    )?;
+
+    let mut messages: Vec<ChatMessage> = Vec::new();
+    for msg in msg_iter {
+        messages.push(msg?);
+    }
+
+    let next = match messages.len() > limit as usize {
+        true => {
+            messages.truncate(limit as usize);
+            messages.last().map(|msg| MessageCursor::of(msg).encode())
+        }
+        false => None,
+    };
+
+    Ok((messages, next))
+}
+
+/// a full text search. filters left as None match every message
+#[derive(Debug, Default)]
+pub struct SearchQuery {
+    /// words to look for. the last one may be the start of a word
+    pub terms: String,
+    pub path: Option<String>,
+    pub sender: Option<String>,
+    pub limit: u32,
+    pub offset: u32,
+}
+
+/// marks placed around matched words in a snippet
+pub const HIGHLIGHT_OPEN: &str = "<mark>";
+pub const HIGHLIGHT_CLOSE: &str = "</mark>";
+
+/// best matches first
+pub async fn search_messages(db: &Db, query: &SearchQuery) -> Result<Vec<SearchResult>> {
+    let conn = db.pool.get_conn()?;
+
+    let mut stmt = conn.prepare(&format!(
+        "SELECT m.path,
+                m.msg_id,
+                m.msg_part_id,
+                m.content_type,
+                m.content_data,
+                m.reply_to,
+                m.metadata,
+                m.sender,
+                m.created_at,
+                m.updated_at,
+                m.received_at,
+                m.expires_at,
+                snippet(chat_messages_fts, 0, ?2, ?3, '…', 16),
+                bm25(chat_messages_fts)
+              FROM chat_messages_fts
+              JOIN chat_messages m ON m.id = chat_messages_fts.rowid
+              WHERE chat_messages_fts MATCH ?1
+                AND (?4 IS NULL OR m.path = ?4)
+                AND (?5 IS NULL OR m.sender = ?5)
+                AND {}
+              ORDER BY bm25(chat_messages_fts), m.created_at DESC
+              LIMIT ?6 OFFSET ?7",
+        unexpired("m", "?8")
+    ))?;
+
+    let result_iter = stmt.query_map(
+        rusqlite::params![
+            match_expression(&query.terms),
+            HIGHLIGHT_OPEN,
+            HIGHLIGHT_CLOSE,
+            query.path,
+            query.sender,
+            query.limit,
+            query.offset,
+            get_current_time(),
+        ],
+        |row| {
+            Ok(SearchResult {
+                message: read_message(row)?,
+                snippet: row.get(12)?,
+                rank: row.get(13)?,
+            })
+        },
+    )?;
+
+    let mut records: Vec<SearchResult> = Vec::new();
+    for result in result_iter {
+        records.push(result?);
+    }
+
+    Ok(records)
+}
+
+// quote each word so that user input is never read as fts5 query syntax. the words
+//  must all match (in any order); the last may be a prefix, to search as you type
+fn match_expression(terms: &str) -> String {
+    let words: Vec<String> = terms
+        .split_whitespace()
+        .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+        .collect();
+    match words.is_empty() {
+        true => String::new(),
+        false => format!("{}*", words.join(" ")),
+    }
+}
+
+const MESSAGE_COLUMNS: &str = "path, msg_id, msg_part_id, content_type, content_data, reply_to,
+    metadata, sender, created_at, updated_at, received_at, expires_at";
+
+// the MESSAGE_COLUMNS of a chat_messages row (columns 0..=11)
+fn read_message(row: &rusqlite::Row) -> rusqlite::Result<ChatMessage> {
+    Ok(ChatMessage {
+        path: row.get(0)?,
+        msg_id: row.get(1)?,
+        msg_part_id: row.get(2)?,
+        content_type: row.get(3)?,
+        content_data: row.get(4)?,
+        reply_to: row.get(5)?,
+        metadata: row.get(6)?,
+        sender: row.get(7)?,
+        created_at: row.get(8)?,
+        updated_at: row.get(9)?,
+        received_at: row.get(10)?,
+        expires_at: row.get(11)?,
+    })
+}
+
+pub async fn query_paths(db: &Db) -> Result<Vec<PathRow>> {
+    let conn = db.pool.get_conn()?;
+
+    let mut stmt = conn.prepare(
+        "SELECT path,
+                type,
+                metadata,
+                pins,
+                invites,
+                peers_get_backlog,
+                max_expires_at_duration,
+                created_at,
+                updated_at,
+                received_at
+              FROM chat_paths
+              ORDER BY path",
+    )?;
+
+    let path_iter = stmt.query_map([], |row| {
+        Ok(PathRow {
+            path: row.get(0)?,
+            path_type: row.get(1)?,
+            metadata: row.get(2)?,
+            pins: serde_json::from_value(row.get(3)?).unwrap_or_default(),
+            invites: row.get(4)?,
+            peers_get_backlog: row.get(5)?,
+            max_expires_at_duration: row.get(6)?,
+            created_at: row.get(7)?,
+            updated_at: row.get(8)?,
+            received_at: row.get(9)?,
+        })
+    })?;
+
+    let mut records: Vec<PathRow> = Vec::new();
+    for path in path_iter {
+        records.push(path?);
+    }
+
+    Ok(records)
+}
+
+/// peers of every path, or of one path
+pub async fn query_peers(db: &Db, path: Option<&str>) -> Result<Vec<PeerRow>> {
+    let conn = db.pool.get_conn()?;
+
+    let mut stmt = conn.prepare(
+        "SELECT path,
+                ship,
+                role,
+                created_at,
+                updated_at,
+                received_at
+              FROM chat_peers
+              WHERE ?1 IS NULL OR path = ?1
+              ORDER BY path, ship",
+    )?;
+
+    let peer_iter = stmt.query_map([path], |row| {
+        Ok(PeerRow {
+            path: row.get(0)?,
+            ship: row.get(1)?,
+            role: row.get(2)?,
+            created_at: row.get(3)?,
+            updated_at: row.get(4)?,
+            received_at: row.get(5)?,
+        })
+    })?;
+
+    let mut records: Vec<PeerRow> = Vec::new();
+    for peer in peer_iter {
+        records.push(peer?);
+    }
+
+    Ok(records)
+}
+
+/// insert or update a path. an existing path is only overwritten by a version at least
+///  as new as itself, and a path deleted since `updated_at` is not added back
+pub fn save_path(conn: &Connection, row: &PathRow) -> Result<()> {
+    if deleted_since(conn, &row.path, "del-paths-row", "", "", row.updated_at)? {
+        return Ok(());
+    }
+    let mut stmt = conn.prepare_cached(
+        "INSERT INTO chat_paths (
+                path,
+                type,
+                metadata,
+                pins,
+                invites,
+                peers_get_backlog,
+                max_expires_at_duration,
+                created_at,
+                updated_at,
+                received_at
+              ) VALUES (
+                ?1,
+                ?2,
+                ?3,
+                ?4,
+                ?5,
+                ?6,
+                ?7,
+                ?8,
+                ?9,
+                ?10
+              )
+              ON CONFLICT (path) DO UPDATE SET
+                type = excluded.type,
+                metadata = excluded.metadata,
+                pins = excluded.pins,
+                invites = excluded.invites,
+                peers_get_backlog = excluded.peers_get_backlog,
+                max_expires_at_duration = excluded.max_expires_at_duration,
+                created_at = excluded.created_at,
+                updated_at = excluded.updated_at,
+                received_at = excluded.received_at
+              WHERE excluded.updated_at >= chat_paths.updated_at",
+    )?;
+    stmt.execute((
+        &row.path,
+        &row.path_type,
+        &row.metadata,
+        serde_json::to_value(&row.pins)?,
+        &row.invites,
+        row.peers_get_backlog,
+        row.max_expires_at_duration,
+        row.created_at,
+        row.updated_at,
+        row.received_at,
+    ))?;
+    Ok(())
+}
+
+/// insert or update a peer. same rules as `save_path`
+pub fn save_peer(conn: &Connection, row: &PeerRow) -> Result<()> {
+    if deleted_since(
+        conn,
+        &row.path,
+        "del-peers-row",
+        &row.ship,
+        "",
+        row.updated_at,
+    )? {
+        return Ok(());
+    }
+    let mut stmt = conn.prepare_cached(
+        "INSERT INTO chat_peers (
+                path,
+                ship,
+                role,
+                created_at,
+                updated_at,
+                received_at
+              ) VALUES (
+                ?1,
+                ?2,
+                ?3,
+                ?4,
+                ?5,
+                ?6
+              )
+              ON CONFLICT (path, ship) DO UPDATE SET
+                role = excluded.role,
+                created_at = excluded.created_at,
+                updated_at = excluded.updated_at,
+                received_at = excluded.received_at
+              WHERE excluded.updated_at >= chat_peers.updated_at",
+    )?;
+    stmt.execute((
+        &row.path,
+        &row.ship,
+        &row.role,
+        row.created_at,
+        row.updated_at,
+        row.received_at,
+    ))?;
+    Ok(())
+}
+
+/// insert or update a message part. same rules as `save_path`
+pub fn save_message(conn: &Connection, msg: &ChatMessage) -> Result<()> {
+    if deleted_since(
+        conn,
+        &msg.path,
+        "del-messages-row",
+        "",
+        &msg.msg_id,
+        msg.updated_at,
+    )? {
+        return Ok(());
+    }
+    let mut stmt = conn.prepare_cached(
+        "INSERT INTO chat_messages (
+                path,
+                msg_id,
+                msg_part_id,
+                content_type,
+                content_data,
+                reply_to,
+                metadata,
+                sender,
+                created_at,
+                updated_at,
+                received_at,
+                expires_at
+              ) VALUES (
+                ?1,
+                ?2,
+                ?3,
+                ?4,
+                ?5,
+                ?6,
+                ?7,
+                ?8,
+                ?9,
+                ?10,
+                ?11,
+                ?12
+              )
+              ON CONFLICT (path, msg_id, msg_part_id) DO UPDATE SET
+                content_type = excluded.content_type,
+                content_data = excluded.content_data,
+                reply_to = excluded.reply_to,
+                metadata = excluded.metadata,
+                sender = excluded.sender,
+                created_at = excluded.created_at,
+                updated_at = excluded.updated_at,
+                received_at = excluded.received_at,
+                expires_at = excluded.expires_at
+              WHERE excluded.updated_at >= chat_messages.updated_at",
+    )?;
+    stmt.execute((
+        &msg.path,
+        &msg.msg_id,
+        msg.msg_part_id,
+        &msg.content_type,
+        &msg.content_data,
+        &msg.reply_to,
+        &msg.metadata,
+        &msg.sender,
+        msg.created_at,
+        msg.updated_at,
+        msg.received_at,
+        &msg.expires_at,
+    ))?;
+    Ok(())
+}
+
+/// replace all parts of an (edited) message. ignored if the stored message is newer
+pub fn replace_message(conn: &Connection, parts: &[ChatMessage]) -> Result<()> {
+    let Some(first) = parts.first() else {
+        return Ok(());
+    };
+    let updated_at = parts.iter().map(|part| part.updated_at).max().unwrap_or(0);
+    let stored: Option<u64> = conn.query_row(
+        "SELECT MAX(updated_at) FROM chat_messages WHERE path = ?1 AND msg_id = ?2",
+        (&first.path, &first.msg_id),
+        |row| row.get(0),
+    )?;
+    if stored.is_some_and(|stored| stored > updated_at) {
+        return Ok(());
+    }
+    conn.execute(
+        "DELETE FROM chat_messages WHERE path = ?1 AND msg_id = ?2",
+        (&first.path, &first.msg_id),
+    )?;
+    for part in parts {
+        save_message(conn, part)?;
+    }
+    Ok(())
+}
+
+/// delete every part of a message
+pub fn delete_message(
+    conn: &Connection,
+    path: &str,
+    msg_id: &str,
+    timestamp: u64,
+) -> Result<usize> {
+    log_delete(conn, "del-messages-row", path, "", msg_id, timestamp)?;
+    Ok(conn.execute(
+        "DELETE FROM chat_messages WHERE path = ?1 AND msg_id = ?2",
+        (path, msg_id),
+    )?)
+}
+
+/// remove a peer from a path
+pub fn delete_peer(conn: &Connection, path: &str, ship: &str, timestamp: u64) -> Result<usize> {
+    log_delete(conn, "del-peers-row", path, ship, "", timestamp)?;
+    Ok(conn.execute(
+        "DELETE FROM chat_peers WHERE path = ?1 AND ship = ?2",
+        (path, ship),
+    )?)
+}
+
+/// delete a path along with its peers and messages
+pub fn delete_path(conn: &Connection, path: &str, timestamp: u64) -> Result<usize> {
+    log_delete(conn, "del-paths-row", path, "", "", timestamp)?;
+    conn.execute("DELETE FROM chat_peers WHERE path = ?1", [path])?;
+    conn.execute("DELETE FROM chat_messages WHERE path = ?1", [path])?;
+    Ok(conn.execute("DELETE FROM chat_paths WHERE path = ?1", [path])?)
+}
+
+/// remove every part of a message without logging a delete. used for changes the ship
+///  has not confirmed (see `write`), which may need to be undone
+pub fn remove_message(conn: &Connection, path: &str, msg_id: &str) -> Result<usize> {
+    Ok(conn.execute(
+        "DELETE FROM chat_messages WHERE path = ?1 AND msg_id = ?2",
+        (path, msg_id),
+    )?)
+}
+
+/// id of the `sender`'s `emoji` reaction to a message
+pub fn find_reaction(
+    conn: &Connection,
+    path: &str,
+    msg_id: &str,
+    sender: &str,
+    emoji: &str,
+) -> Result<Option<String>> {
+    Ok(conn
+        .prepare_cached(
+            "SELECT msg_id FROM chat_messages
+              WHERE json_extract(reply_to, '$.msg-id') = ?2 AND path = ?1
+                AND content_type = 'react' AND sender = ?3 AND content_data = ?4
+              ORDER BY created_at DESC
+              LIMIT 1",
+        )?
+        .query_row((path, msg_id, sender, emoji), |row| row.get(0))
+        .optional()?)
+}
+
+/// what a pending write did, so that it can be undone
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PendingKind {
+    /// a provisional message was added
+    Send,
+    /// the parts of a message were replaced
+    Edit,
+    /// a message was removed
+    Delete,
+}
+
+impl PendingKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            PendingKind::Send => "send",
+            PendingKind::Edit => "edit",
+            PendingKind::Delete => "delete",
+        }
+    }
+}
+
+/// record a write that was applied ahead of the ship. `previous` holds the rows an edit
+///  or delete replaced. sends are matched to the ship's copy of the message by sender,
+///  reply-to and content, so the provisional message must be saved first
+pub fn save_pending(
+    conn: &Connection,
+    poke_id: u64,
+    kind: PendingKind,
+    path: &str,
+    msg_id: &str,
+    previous: &[ChatMessage],
+    now: u64,
+) -> Result<()> {
+    let fingerprint = match kind {
+        PendingKind::Send => fingerprint(conn, path, msg_id)?.unwrap_or_default(),
+        _ => String::new(),
+    };
+    conn.prepare_cached(
+        "INSERT INTO chat_pending (
+                poke_id,
+                kind,
+                path,
+                msg_id,
+                fingerprint,
+                previous,
+                created_at
+              ) VALUES (
+                ?1,
+                ?2,
+                ?3,
+                ?4,
+                ?5,
+                ?6,
+                ?7
+              )",
+    )?
+    .execute((
+        poke_id,
+        kind.as_str(),
+        path,
+        msg_id,
+        fingerprint,
+        serde_json::to_string(previous)?,
+        now,
+    ))?;
+    Ok(())
+}
+
+/// true if the message is a provisional one, not yet confirmed by the ship
+pub fn is_provisional(conn: &Connection, path: &str, msg_id: &str) -> Result<bool> {
+    Ok(conn
+        .prepare_cached(
+            "SELECT EXISTS (SELECT 1 FROM chat_pending
+              WHERE path = ?1 AND msg_id = ?2 AND kind = 'send')",
+        )?
+        .query_row((path, msg_id), |row| row.get(0))?)
+}
+
+/// undo a pending write (the ship refused it, or never confirmed it). false if there
+///  is no such write, e.g. because it was confirmed in the meantime
+pub fn rollback_pending(conn: &Connection, poke_id: u64, changes: &mut Changes) -> Result<bool> {
+    let pending: Option<(String, String, String, String)> = conn
+        .prepare_cached("SELECT kind, path, msg_id, previous FROM chat_pending WHERE poke_id = ?1")?
+        .query_row([poke_id], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+        })
+        .optional()?;
+    let Some((_kind, path, msg_id, previous)) = pending else {
+        return Ok(false);
+    };
+    // a send leaves nothing behind. edits and deletes get their old rows back
+    let previous = serde_json::from_str::<Vec<ChatMessage>>(&previous)?;
+    changes.touch(conn, &path, &msg_id)?;
+    if let Some(first) = previous.first() {
+        changes.touch_row(conn, first)?;
+    }
+    remove_message(conn, &path, &msg_id)?;
+    for part in &previous {
+        save_message(conn, part)?;
+    }
+    conn.execute("DELETE FROM chat_pending WHERE poke_id = ?1", [poke_id])?;
+    Ok(true)
+}
+
+/// the ship changed (edited or deleted) a message. pending edits and deletes of it are
+///  settled by the ship's version
+pub fn confirm_pending(conn: &Connection, path: &str, msg_id: &str) -> Result<usize> {
+    Ok(conn.execute(
+        "DELETE FROM chat_pending WHERE path = ?1 AND msg_id = ?2 AND kind != 'send'",
+        (path, msg_id),
+    )?)
+}
+
+/// the ship added a message. if it is the ship's copy of a pending send, the provisional
+///  message is dropped in its favour. true if one was
+pub fn reconcile_send(
+    conn: &Connection,
+    path: &str,
+    msg_id: &str,
+    changes: &mut Changes,
+) -> Result<bool> {
+    let Some(fingerprint) = fingerprint(conn, path, msg_id)? else {
+        return Ok(false);
+    };
+    let pending: Option<(u64, String)> = conn
+        .prepare_cached(
+            "SELECT poke_id, msg_id FROM chat_pending
+              WHERE path = ?1 AND kind = 'send' AND fingerprint = ?2 AND msg_id != ?3
+              ORDER BY poke_id
+              LIMIT 1",
+        )?
+        .query_row((path, &fingerprint, msg_id), |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })
+        .optional()?;
+    let Some((poke_id, provisional)) = pending else {
+        return Ok(false);
+    };
+    changes.touch(conn, path, &provisional)?;
+    remove_message(conn, path, &provisional)?;
+    conn.execute("DELETE FROM chat_pending WHERE poke_id = ?1", [poke_id])?;
+    Ok(true)
+}
+
+/// roll back the writes the ship has not confirmed since `before` (ms). returns how many
+///  there were, and the events for the changes undone
+pub fn expire_pending(db: &Db, before: u64) -> Result<(usize, Vec<ChatEvent>)> {
+    let mut conn = db.pool.get_writer()?;
+    let tx = conn.transaction()?;
+    let expired = {
+        let mut stmt = tx.prepare("SELECT poke_id FROM chat_pending WHERE created_at < ?1")?;
+        let rows = stmt.query_map([before], |row| row.get::<_, u64>(0))?;
+        rows.collect::<rusqlite::Result<Vec<u64>>>()?
+    };
+    let mut changes = Changes::new();
+    for poke_id in &expired {
+        rollback_pending(&tx, *poke_id, &mut changes)?;
+    }
+    let events = changes.events(&tx)?;
+    tx.commit()?;
+    Ok((expired.len(), events))
+}
+
+/// the highest poke id of a pending write (0 if there are none)
+pub fn max_pending_poke_id(db: &Db) -> Result<u64> {
+    let conn = db.pool.get_conn()?;
+    Ok(conn.query_row(
+        "SELECT COALESCE(MAX(poke_id), 0) FROM chat_pending",
+        [],
+        |row| row.get(0),
+    )?)
+}
+
+// sender, reply-to and content of a message: what the ship keeps of a message we send
+fn fingerprint(conn: &Connection, path: &str, msg_id: &str) -> Result<Option<String>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT sender, json_extract(reply_to, '$.msg-id'), content_type, content_data
+          FROM chat_messages
+          WHERE path = ?1 AND msg_id = ?2
+          ORDER BY msg_part_id",
+    )?;
+    let rows = stmt.query_map((path, msg_id), |row| {
+        Ok((
+            row.get::<_, String>(0)?,
+            row.get::<_, Option<String>>(1)?,
+            row.get::<_, String>(2)?,
+            row.get::<_, String>(3)?,
+        ))
+    })?;
+    let rows = rows.collect::<rusqlite::Result<Vec<_>>>()?;
+    let Some((sender, reply_to, _, _)) = rows.first() else {
+        return Ok(None);
+    };
+    let parts: Vec<(&String, &String)> = rows.iter().map(|row| (&row.2, &row.3)).collect();
+    Ok(Some(
+        serde_json::json!({ "sender": sender, "reply-to": reply_to, "parts": parts }).to_string(),
+    ))
+}
+
+fn log_delete(
+    conn: &Connection,
+    change: &str,
+    path: &str,
+    ship: &str,
+    msg_id: &str,
+    timestamp: u64,
+) -> Result<()> {
+    conn.prepare_cached(
+        "INSERT OR IGNORE INTO chat_delete_log (
+                type,
+                path,
+                ship,
+                msg_id,
+                timestamp
+              ) VALUES (
+                ?1,
+                ?2,
+                ?3,
+                ?4,
+                ?5
+              )",
+    )?
+    .execute((change, path, ship, msg_id, timestamp))?;
+    Ok(())
+}
+
+// true if the row (or its whole path) was deleted at or after `updated_at`
+fn deleted_since(
+    conn: &Connection,
+    path: &str,
+    change: &str,
+    ship: &str,
+    msg_id: &str,
+    updated_at: u64,
+) -> Result<bool> {
+    Ok(conn
+        .prepare_cached(
+            "SELECT EXISTS (SELECT 1 FROM chat_delete_log
+              WHERE path = ?1 AND timestamp >= ?5
+                AND (type = 'del-paths-row' OR (type = ?2 AND ship = ?3 AND msg_id = ?4)))",
+        )?
+        .query_row((path, change, ship, msg_id, updated_at), |row| row.get(0))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{run, test_db};
+    use serde_json::{json, Value as JsonValue};
+
+    const PATH: &str = "/spaces/~zod/chats/0v1";
+
+    fn row(
+        msg_id: &str,
+        part: u64,
+        content_type: &str,
+        data: &str,
+        reply_to: Option<&str>,
+        at: u64,
+    ) -> JsonValue {
+        json!({"type": "add-row", "table": "messages", "row": {
+            "path": PATH,
+            "msg-id": msg_id,
+            "msg-part-id": part,
+            "content-type": content_type,
+            "content-data": data,
+            "reply-to": reply_to.map(|id| json!({"path": PATH, "msg-id": id})),
+            "metadata": {},
+            "sender": msg_id.rsplit('/').next().unwrap(),
+            "created-at": at,
+            "updated-at": at + part,
+            "received-at": at,
+            "expires-at": null,
+        }})
+    }
+
+    // a (two parts) <- b <- c, two reactions on a, and d on its own
+    fn seed() -> Db {
+        let db = test_db();
+        super::super::sync::apply(
+            &db,
+            &json!([
+                row("/~1/~zod", 0, "plain", "look at this", None, 1),
+                row("/~1/~zod", 1, "image", "https://holium.com/a.png", None, 1),
+                row("/~2/~bus", 0, "plain", "nice", Some("/~1/~zod"), 2),
+                row("/~3/~zod", 0, "plain", "thanks", Some("/~2/~bus"), 3),
+                row("/~4/~bus", 0, "react", "2764-fe0f", Some("/~1/~zod"), 4),
+                row("/~5/~nec", 0, "react", "1f44d", Some("/~1/~zod"), 5),
+                row("/~6/~nec", 0, "plain", "hi all", None, 6),
+            ]),
+        )
+        .unwrap();
+        db
+    }
+
+    #[test]
+    fn parts_and_reactions_are_reassembled() {
+        run(async {
+            let db = seed();
+            let query = MessageQuery {
+                limit: 10,
+                ..Default::default()
+            };
+            let list = query_message_list(&db, &query).await.unwrap();
+            let ids: Vec<&str> = list.messages.iter().map(|msg| msg.id.as_str()).collect();
+            assert_eq!(ids, vec!["/~6/~nec", "/~3/~zod", "/~2/~bus", "/~1/~zod"]);
+            assert!(list.next.is_none());
+
+            let first = &list.messages[3];
+            assert_eq!(first.sender, "~zod");
+            assert_eq!(first.updated_at, 2);
+            let parts: Vec<(&str, &str)> = first
+                .parts
+                .iter()
+                .map(|part| (part.content_type.as_str(), part.content_data.as_str()))
+                .collect();
+            assert_eq!(
+                parts,
+                vec![
+                    ("plain", "look at this"),
+                    ("image", "https://holium.com/a.png")
+                ]
+            );
+            let reactions: Vec<(&str, &str)> = first
+                .reactions
+                .iter()
+                .map(|react| (react.sender.as_str(), react.emoji.as_str()))
+                .collect();
+            assert_eq!(reactions, vec![("~bus", "2764-fe0f"), ("~nec", "1f44d")]);
+            assert_eq!(first.reply_to, None);
+            assert_eq!(
+                list.messages[2].reply_to,
+                Some(MsgRef {
+                    msg_id: "/~1/~zod".to_string(),
+                    path: PATH.to_string()
+                })
+            );
+
+            // paged by message, not by part
+            let query = MessageQuery {
+                limit: 3,
+                ..Default::default()
+            };
+            let page = query_message_list(&db, &query).await.unwrap();
+            let query = MessageQuery {
+                limit: 3,
+                before: MessageCursor::decode(page.next.as_ref().unwrap()),
+                ..Default::default()
+            };
+            let rest = query_message_list(&db, &query).await.unwrap();
+            assert_eq!(rest.messages.len(), 1);
+            assert_eq!(rest.messages[0].parts.len(), 2);
+        });
+    }
+
+    #[test]
+    fn threads_nest_replies() {
+        run(async {
+            let db = seed();
+            let thread = query_thread(&db, PATH, "/~1/~zod").await.unwrap().unwrap();
+            assert_eq!(thread.replies.len(), 1);
+            assert_eq!(thread.replies[0].id, "/~2/~bus");
+            assert_eq!(thread.replies[0].replies[0].id, "/~3/~zod");
+            assert!(thread.replies[0].replies[0].replies.is_empty());
+
+            // replies are only nested in thread views
+            let json = serde_json::to_value(&thread.replies[0].replies[0]).unwrap();
+            assert!(json.get("replies").is_none());
+            assert_eq!(json["reply-to"]["msg-id"], "/~2/~bus");
+
+            assert!(query_thread(&db, PATH, "/~9/~zod").await.unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn space_members_are_peers_of_its_chats() {
+        let db = test_db();
+        {
+            let conn = db.pool.get_writer().unwrap();
+            for (path, ship) in [(PATH, "~bus"), ("/spaces/~zod-other/chats/0v2", "~nec")] {
+                let peer = PeerRow {
+                    path: path.to_string(),
+                    ship: ship.to_string(),
+                    role: "member".to_string(),
+                    created_at: 1,
+                    updated_at: 1,
+                    received_at: 1,
+                };
+                save_peer(&conn, &peer).unwrap();
+            }
+        }
+        let conn = db.pool.get_conn().unwrap();
+        let member = |space: &str, ship: &str| is_space_member(&conn, space, ship).unwrap();
+        assert!(member("/spaces/~zod", "~bus"));
+        assert!(member(PATH, "~bus"));
+        // a space is not any path that starts the same
+        assert!(!member("/spaces/~zod", "~nec"));
+        assert!(!member("/spaces/~zod", "~fed"));
+    }
+
+    fn expiring(mut row: JsonValue, expires_at: u64) -> JsonValue {
+        row["row"]["expires-at"] = json!(expires_at);
+        row
+    }
+
+    #[test]
+    fn expired_messages_are_left_out() {
+        run(async {
+            let db = test_db();
+            // far in the future
+            let later = get_current_time() + 3_600_000;
+            super::super::sync::apply(
+                &db,
+                &json!([
+                    expiring(row("/~1/~zod", 0, "plain", "gone soon", None, 1), 2),
+                    row("/~2/~bus", 0, "plain", "still here", Some("/~1/~zod"), 2),
+                    expiring(row("/~3/~nec", 0, "react", "1f44d", Some("/~2/~bus"), 3), 4),
+                    expiring(row("/~4/~zod", 0, "plain", "here for now", None, 4), later),
+                ]),
+            )
+            .unwrap();
+            let expired = || {
+                let conn = db.pool.get_conn().unwrap();
+                expired_messages(&conn, get_current_time()).unwrap()
+            };
+            let save = |path: &JsonValue| {
+                let conn = db.pool.get_writer().unwrap();
+                save_path(&conn, &serde_json::from_value(path.clone()).unwrap()).unwrap();
+            };
+            assert_eq!(
+                expired(),
+                vec![
+                    (PATH.to_string(), "/~1/~zod".to_string()),
+                    (PATH.to_string(), "/~3/~nec".to_string()),
+                ]
+            );
+
+            let query = MessageQuery {
+                limit: 10,
+                ..Default::default()
+            };
+            let list = query_message_list(&db, &query).await.unwrap();
+            let ids: Vec<&str> = list.messages.iter().map(|msg| msg.id.as_str()).collect();
+            assert_eq!(ids, vec!["/~4/~zod", "/~2/~bus"]);
+            assert!(list.messages[1].reactions.is_empty());
+            let page = query_message_page(&db, &query).await.unwrap();
+            assert_eq!(page.messages.len(), 2);
+
+            assert!(query_thread(&db, PATH, "/~1/~zod").await.unwrap().is_none());
+            let thread = query_thread(&db, PATH, "/~2/~bus").await.unwrap().unwrap();
+            assert!(thread.reactions.is_empty());
+            let search = |terms: &str| SearchQuery {
+                terms: terms.to_string(),
+                limit: 10,
+                ..Default::default()
+            };
+            assert!(search_messages(&db, &search("gone"))
+                .await
+                .unwrap()
+                .is_empty());
+            assert_eq!(
+                search_messages(&db, &search("here")).await.unwrap().len(),
+                2
+            );
+
+            // the path caps how long its messages live: everything above is older than
+            //  a minute
+            let mut path = json!({
+                "path": PATH,
+                "type": "dm",
+                "metadata": {},
+                "pins": [],
+                "invites": "host",
+                "peers-get-backlog": true,
+                "max-expires-at-duration": 60_000,
+                "created-at": 1,
+                "updated-at": 1,
+                "received-at": 1,
+            });
+            save(&path);
+            let list = query_message_list(&db, &query).await.unwrap();
+            assert!(list.messages.is_empty());
+            assert_eq!(expired().len(), 4);
+
+            path["max-expires-at-duration"] = json!(null);
+            path["updated-at"] = json!(2);
+            save(&path);
+            let list = query_message_list(&db, &query).await.unwrap();
+            assert_eq!(list.messages.len(), 2);
+        });
+    }
+}
@@ -0,0 +1,222 @@
+//!
+//! changes to the chat tables as events for connected clients
+//!
+//! whatever changes the tables (the chat-db watch, REST writes and their rollbacks)
+//!  snapshots the messages it is about to touch with a `Changes`, and turns the
+//!  difference into `ChatEvent`s once it is done. events go out on the node's chat event
+//!  channel (`NodeContext::chat_events`), which the websocket hub forwards to the devices
+//!  that asked for them.
+//!
+//! reactions are not messages of their own here: a reaction being added or removed is
+//!  an update of the message it points at.
+//!
+use std::collections::HashSet;
+
+use anyhow::Result;
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+use crate::context::CallContext;
+
+use super::data;
+use super::types::{ChatMessage, Message};
+
+/// events buffered for each receiver. a receiver that falls further behind skips ahead
+const EVENT_BUFFER: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "event")]
+pub enum ChatEvent {
+    #[serde(rename = "chat.message.added")]
+    MessageAdded { path: String, message: Message },
+    /// edited, or a reaction was added to / removed from it
+    #[serde(rename = "chat.message.updated")]
+    MessageUpdated { path: String, message: Message },
+    #[serde(rename = "chat.message.deleted")]
+    MessageDeleted {
+        path: String,
+        #[serde(rename = "msg-id")]
+        msg_id: String,
+    },
+}
+
+impl ChatEvent {
+    pub fn path(&self) -> &str {
+        match self {
+            ChatEvent::MessageAdded { path, .. }
+            | ChatEvent::MessageUpdated { path, .. }
+            | ChatEvent::MessageDeleted { path, .. } => path,
+        }
+    }
+}
+
+/// the sending end of a node's chat event channel
+pub fn channel() -> broadcast::Sender<ChatEvent> {
+    broadcast::channel(EVENT_BUFFER).0
+}
+
+/// send events to whoever is listening (possibly no one)
+pub fn publish(ctx: &CallContext, events: Vec<ChatEvent>) {
+    for event in events {
+        let _ = ctx.chat_events.send(event);
+    }
+}
+
+/// the messages touched by a change, as they were before it
+#[derive(Debug, Default)]
+pub struct Changes {
+    before: Vec<((String, String), Option<Message>)>,
+    seen: HashSet<(String, String)>,
+}
+
+impl Changes {
+    pub fn new() -> Changes {
+        Changes::default()
+    }
+
+    /// note a message that is about to change. a reaction stands for its target
+    pub fn touch(&mut self, conn: &Connection, path: &str, msg_id: &str) -> Result<()> {
+        let key = (path.to_string(), msg_id.to_string());
+        if !self.seen.insert(key.clone()) {
+            return Ok(());
+        }
+        let message = data::load_message(conn, path, msg_id)?;
+        if let Some(target) = message.as_ref().and_then(react_target) {
+            return self.touch(conn, path, &target);
+        }
+        self.before.push((key, message));
+        Ok(())
+    }
+
+    /// note the message a row is about to be saved to
+    pub fn touch_row(&mut self, conn: &Connection, row: &ChatMessage) -> Result<()> {
+        if row.content_type == "react" {
+            if let Some(target) = row.reply_to["msg-id"].as_str() {
+                return self.touch(conn, &row.path, target);
+            }
+        }
+        self.touch(conn, &row.path, &row.msg_id)
+    }
+
+    /// note every message on a path
+    pub fn touch_path(&mut self, conn: &Connection, path: &str) -> Result<()> {
+        let msg_ids = {
+            let mut stmt = conn.prepare_cached(
+                "SELECT DISTINCT msg_id FROM chat_messages WHERE path = ?1 AND msg_part_id = 0",
+            )?;
+            let rows = stmt.query_map([path], |row| row.get::<_, String>(0))?;
+            rows.collect::<rusqlite::Result<Vec<String>>>()?
+        };
+        for msg_id in msg_ids {
+            self.touch(conn, path, &msg_id)?;
+        }
+        Ok(())
+    }
+
+    /// what became of the touched messages
+    pub fn events(&self, conn: &Connection) -> Result<Vec<ChatEvent>> {
+        let mut events = Vec::new();
+        for ((path, msg_id), before) in &self.before {
+            // a reaction touched before it was stored (see `touch`) is skipped here
+            let before = before.as_ref().filter(|msg| react_target(msg).is_none());
+            let after =
+                data::load_message(conn, path, msg_id)?.filter(|msg| react_target(msg).is_none());
+            let path = path.clone();
+            match (before, after) {
+                (None, Some(message)) => events.push(ChatEvent::MessageAdded { path, message }),
+                (Some(before), Some(message)) if *before != message => {
+                    events.push(ChatEvent::MessageUpdated { path, message })
+                }
+                (Some(_), None) => events.push(ChatEvent::MessageDeleted {
+                    path,
+                    msg_id: msg_id.clone(),
+                }),
+                _ => {}
+            }
+        }
+        Ok(events)
+    }
+}
+
+// the message a reaction points at
+fn react_target(message: &Message) -> Option<String> {
+    let part = message.parts.first()?;
+    if part.content_type != "react" {
+        return None;
+    }
+    Some(message.reply_to.as_ref()?.msg_id.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::sync::apply;
+    use crate::testing::test_db;
+    use serde_json::{json, Value as JsonValue};
+
+    const RECORDED: &str = include_str!("fixtures/db_changes.json");
+
+    // (event, msg-id, first part's content) of each event
+    fn summary(events: &[ChatEvent]) -> Vec<(&'static str, String, String)> {
+        events
+            .iter()
+            .map(|event| match event {
+                ChatEvent::MessageAdded { message, .. } => (
+                    "added",
+                    message.id.clone(),
+                    message.parts[0].content_data.clone(),
+                ),
+                ChatEvent::MessageUpdated { message, .. } => (
+                    "updated",
+                    message.id.clone(),
+                    message.parts[0].content_data.clone(),
+                ),
+                ChatEvent::MessageDeleted { msg_id, .. } => {
+                    ("deleted", msg_id.clone(), String::new())
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn recorded_changes_become_events() {
+        let db = test_db();
+        let facts: Vec<JsonValue> = serde_json::from_str(RECORDED).unwrap();
+        let events: Vec<Vec<ChatEvent>> = facts.iter().map(|f| apply(&db, f).unwrap()).collect();
+
+        let first = "/~2023.7.7..19.57.10..a94a/~fasnut-famden".to_string();
+        let reply = "/~2023.7.7..19.58.02..b1c0/~tolwer-mogmer".to_string();
+        // posted (both parts in one fact: one event)
+        assert_eq!(
+            summary(&events[0]),
+            vec![("added", first.clone(), "hello".to_string())]
+        );
+        assert_eq!(summary(&events[1])[0].0, "added");
+        assert_eq!(
+            summary(&events[2]),
+            vec![("updated", first.clone(), "hello (edited)".to_string())]
+        );
+        assert_eq!(summary(&events[3]), vec![("deleted", reply, String::new())]);
+        // a reaction updates the message it points at
+        let ChatEvent::MessageUpdated { message, .. } = &events[4][0] else {
+            panic!("{:?}", events[4]);
+        };
+        assert_eq!(events[4].len(), 1);
+        assert_eq!(message.id, first);
+        assert_eq!(message.reactions.len(), 1);
+
+        // replays change nothing, so there is nothing to tell
+        for fact in &facts {
+            assert!(apply(&db, fact).unwrap().is_empty());
+        }
+
+        // deleting the path deletes its messages
+        let events = apply(
+            &db,
+            &json!([{"type": "del-paths-row", "path": "/spaces/~zod/chats/0v1", "timestamp": 1}]),
+        )
+        .unwrap();
+        assert_eq!(summary(&events), vec![("deleted", first, String::new())]);
+    }
+}
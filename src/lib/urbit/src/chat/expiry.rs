@@ -0,0 +1,134 @@
+//!
+//! disappearing messages
+//!
+//! a message expires at its `expires-at`, or once its path's `max-expires-at-duration`
+//!  has passed since it was created, whichever comes first. queries leave expired
+//!  messages out from that moment (see `data::unexpired`). the sweeper then deletes them
+//!  for good: the delete is logged like one made on the ship, so that a stale copy (e.g.
+//!  from a catch up import) is not added back, and clients get the usual deletion events.
+//!
+use anyhow::Result;
+use tokio::time::{self, Duration};
+
+use crate::context::CallContext;
+use crate::db::Db;
+use crate::helper::get_current_time;
+
+use super::data;
+use super::events::{self, Changes, ChatEvent};
+
+use trace::{trace_err_ln, trace_info_ln};
+
+/// how often expired messages are deleted (ms)
+const SWEEP_MS: u64 = 5_000;
+
+/// delete expired messages every `SWEEP_MS` until the node stops
+pub fn start(ctx: CallContext) {
+    tokio::spawn(async move {
+        let mut interval = time::interval(Duration::from_millis(SWEEP_MS));
+        loop {
+            interval.tick().await;
+            match sweep(&ctx.db, get_current_time()) {
+                Ok(events) if events.is_empty() => {}
+                Ok(events) => {
+                    trace_info_ln!("chat: [expiry] {} message(s) changed", events.len());
+                    events::publish(&ctx, events);
+                }
+                Err(e) => trace_err_ln!("chat: [expiry] sweep failed. {}", e),
+            }
+        }
+    });
+}
+
+/// delete the messages that have expired by `now`. returns the events for them (and
+///  for the messages that lost an expired reaction)
+pub fn sweep(db: &Db, now: u64) -> Result<Vec<ChatEvent>> {
+    let mut conn = db.pool.get_writer()?;
+    let tx = conn.transaction()?;
+    let expired = data::expired_messages(&tx, now)?;
+    if expired.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut changes = Changes::new();
+    for (path, msg_id) in &expired {
+        changes.touch(&tx, path, msg_id)?;
+    }
+    for (path, msg_id) in &expired {
+        data::delete_message(&tx, path, msg_id, now)?;
+    }
+    let events = changes.events(&tx)?;
+    tx.commit()?;
+    Ok(events)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::sync::apply;
+    use crate::testing::test_db;
+    use serde_json::{json, Value as JsonValue};
+
+    const PATH: &str = "/spaces/~zod/chats/0v1";
+
+    fn row(msg_id: &str, content_type: &str, reply_to: Option<&str>, expires_at: u64) -> JsonValue {
+        json!({"type": "add-row", "table": "messages", "row": {
+            "path": PATH,
+            "msg-id": msg_id,
+            "msg-part-id": 0,
+            "content-type": content_type,
+            "content-data": "2764-fe0f",
+            "reply-to": reply_to.map(|id| json!({"path": PATH, "msg-id": id})),
+            "metadata": {},
+            "sender": "~zod",
+            "created-at": 1,
+            "updated-at": 1,
+            "received-at": 1,
+            "expires-at": expires_at,
+        }})
+    }
+
+    fn ids(db: &Db) -> Vec<String> {
+        let conn = db.pool.get_conn().unwrap();
+        let mut stmt = conn
+            .prepare("SELECT msg_id FROM chat_messages ORDER BY msg_id")
+            .unwrap();
+        let rows = stmt.query_map([], |row| row.get(0)).unwrap();
+        rows.map(|row| row.unwrap()).collect()
+    }
+
+    #[test]
+    fn expired_messages_are_swept() {
+        let db = test_db();
+        let facts = json!([
+            row("/~1/~zod", "plain", None, 100),
+            row("/~2/~zod", "plain", None, 300),
+            row("/~3/~zod", "react", Some("/~2/~zod"), 100),
+        ]);
+        apply(&db, &facts).unwrap();
+
+        assert!(sweep(&db, 50).unwrap().is_empty());
+        let events = sweep(&db, 200).unwrap();
+        assert_eq!(ids(&db), vec!["/~2/~zod"]);
+        assert_eq!(events.len(), 2);
+        assert_eq!(
+            events[0],
+            ChatEvent::MessageDeleted {
+                path: PATH.to_string(),
+                msg_id: "/~1/~zod".to_string()
+            }
+        );
+        // the reaction is gone from the message it pointed at
+        let ChatEvent::MessageUpdated { message, .. } = &events[1] else {
+            panic!("{:?}", events[1]);
+        };
+        assert_eq!(message.id, "/~2/~zod");
+        assert!(message.reactions.is_empty());
+
+        // a stale copy of the swept messages is not added back
+        assert!(apply(&db, &facts).unwrap().is_empty());
+        assert_eq!(ids(&db), vec!["/~2/~zod"]);
+
+        assert_eq!(sweep(&db, 300).unwrap().len(), 1);
+        assert!(ids(&db).is_empty());
+    }
+}
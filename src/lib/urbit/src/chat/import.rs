@@ -0,0 +1,589 @@
+//!
+//! chunked, resumable import of chat-db history
+//!
+//! a ship may hold years of chat history: too much for one scry, or one transaction. the
+//!  import pages through it by the time rows were received on the ship. each window is
+//!  one scry (`/db/start-ms/<start>/end-ms/<end>`), applied in one transaction along
+//!  with the checkpoint that says how far the import has got. a node stopped midway
+//!  resumes from the checkpoint; once an import is done, the next one (e.g. the catch up
+//!  after a reconnect) only covers what was received since.
+//!
+//! windows grow while they come back small (e.g. the years before the ship had any
+//!  chats) and shrink when they come back large. rows that cannot be read are skipped
+//!  and recorded in `chat_import_errors`, rather than failing their window.
+//!
+//! progress is served at `GET /hol/chat/import` and shown by `hol <id> chat-import`.
+//!
+use anyhow::{bail, Result};
+use rusqlite::{Connection, OptionalExtension};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+
+use crate::context::CallContext;
+use crate::db::Db;
+use crate::helper::get_current_time;
+
+use super::events;
+use super::sync;
+use super::types::{ChatTable, ChatTables};
+
+use trace::{trace_err_ln, trace_info_ln, trace_warn_ln};
+
+const DAY_MS: u64 = 24 * 60 * 60 * 1000;
+/// length of the first window scried (ms)
+const FIRST_WINDOW_MS: u64 = DAY_MS;
+/// windows do not grow past this (ms)
+const MAX_WINDOW_MS: u64 = 365 * DAY_MS;
+/// rows a window should hold. the next window is halved above this, and doubled below
+///  a quarter of it
+const CHUNK_ROWS: usize = 5_000;
+/// row errors listed by `status`
+const ERRORS_SHOWN: u32 = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ImportState {
+    /// nothing imported yet
+    Idle,
+    Running,
+    Done,
+    /// stopped on an error. the next import resumes from the checkpoint
+    Failed,
+}
+
+impl ImportState {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ImportState::Idle => "idle",
+            ImportState::Running => "running",
+            ImportState::Done => "done",
+            ImportState::Failed => "failed",
+        }
+    }
+
+    fn parse(state: &str) -> ImportState {
+        match state {
+            "running" => ImportState::Running,
+            "done" => ImportState::Done,
+            "failed" => ImportState::Failed,
+            _ => ImportState::Idle,
+        }
+    }
+}
+
+/// the import in progress, or the last one
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ImportStatus {
+    pub state: ImportState,
+    /// rows received on the ship from `from` up to `until` (ms) are imported
+    pub from: u64,
+    pub until: u64,
+    /// everything received before this is in
+    pub checkpoint: u64,
+    /// length of the next window (ms)
+    pub window_ms: u64,
+    /// rows imported so far
+    pub imported: u64,
+    /// rows that could not be read (see `RowError`)
+    pub skipped: u64,
+    pub error: Option<String>,
+    pub started_at: u64,
+    pub updated_at: u64,
+}
+
+impl ImportStatus {
+    fn idle() -> ImportStatus {
+        ImportStatus {
+            state: ImportState::Idle,
+            from: 0,
+            until: 0,
+            checkpoint: 0,
+            window_ms: FIRST_WINDOW_MS,
+            imported: 0,
+            skipped: 0,
+            error: None,
+            started_at: 0,
+            updated_at: 0,
+        }
+    }
+
+    /// share of the time range imported, from 0 to 1
+    pub fn progress(&self) -> f64 {
+        match self.state {
+            ImportState::Done => 1.0,
+            _ if self.until <= self.from => 0.0,
+            _ => {
+                let done = self.checkpoint.saturating_sub(self.from) as f64;
+                (done / (self.until - self.from) as f64).min(1.0)
+            }
+        }
+    }
+}
+
+/// a row of a window that could not be read
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct RowError {
+    /// paths, peers, messages or del-log
+    pub kind: String,
+    pub row: JsonValue,
+    pub error: String,
+    pub window_start: u64,
+    pub window_end: u64,
+}
+
+/// what `GET /hol/chat/import` returns
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ImportReport {
+    #[serde(flatten)]
+    pub status: ImportStatus,
+    pub progress: f64,
+    /// the latest rows that could not be read
+    pub errors: Vec<RowError>,
+}
+
+/// import what the ship received since the last import, or finish the one that was
+///  interrupted. `now` is where this import ends
+pub async fn run(ctx: &CallContext, now: u64) -> Result<ImportStatus> {
+    let mut status = begin(&ctx.db, now)?;
+    if status.state == ImportState::Running {
+        trace_info_ln!(
+            "chat: [import] importing rows received from {} to {} (from {})...",
+            status.checkpoint,
+            status.until,
+            status.from
+        );
+    }
+    while status.state == ImportState::Running {
+        if let Err(e) = import_window(ctx, &status).await {
+            trace_err_ln!("chat: [import] stopped at {}. {}", status.checkpoint, e);
+            fail(&ctx.db, &e.to_string(), get_current_time())?;
+            return Err(e);
+        }
+        let conn = ctx.db.pool.get_conn()?;
+        status = load(&conn)?.unwrap_or_else(ImportStatus::idle);
+    }
+    if status.skipped > 0 {
+        trace_warn_ln!(
+            "chat: [import] {} row(s) could not be read. see GET /hol/chat/import",
+            status.skipped
+        );
+    }
+    Ok(status)
+}
+
+/// the state of the import, with its latest row errors
+pub fn status(db: &Db) -> Result<ImportReport> {
+    let conn = db.pool.get_conn()?;
+    let status = load(&conn)?.unwrap_or_else(ImportStatus::idle);
+    let mut stmt = conn.prepare_cached(
+        "SELECT kind, row, error, window_start, window_end FROM chat_import_errors
+          ORDER BY id DESC
+          LIMIT ?1",
+    )?;
+    let rows = stmt.query_map([ERRORS_SHOWN], |row| {
+        Ok(RowError {
+            kind: row.get(0)?,
+            row: row.get(1)?,
+            error: row.get(2)?,
+            window_start: row.get(3)?,
+            window_end: row.get(4)?,
+        })
+    })?;
+    Ok(ImportReport {
+        progress: status.progress(),
+        status,
+        errors: rows.collect::<rusqlite::Result<Vec<RowError>>>()?,
+    })
+}
+
+/// scry a window and apply it, moving the checkpoint past it
+async fn import_window(ctx: &CallContext, status: &ImportStatus) -> Result<()> {
+    let start = status.checkpoint;
+    let end = start.saturating_add(status.window_ms).min(status.until);
+    let json = ctx
+        .ship
+        .lock()
+        .await
+        .scry("chat-db", &window_path(start, end), "json")
+        .await?;
+    let (dump, errors) = read_window(&json, start, end)?;
+    let rows = dump.tables.paths.len()
+        + dump.tables.peers.len()
+        + dump.tables.messages.len()
+        + dump.del_log.len();
+    let window_ms = next_window(status.window_ms, rows + errors.len());
+    let events = sync::apply_dump_with(&ctx.db, dump, |conn| {
+        record_window(conn, end, window_ms, rows, &errors, get_current_time())
+    })?;
+    events::publish(ctx, events);
+    Ok(())
+}
+
+/// the chat-db scry for the rows received in [start, end)
+pub fn window_path(start: u64, end: u64) -> String {
+    format!("/db/start-ms/{}/end-ms/{}", start, end)
+}
+
+// the rows of a window. rows that cannot be read are returned as errors
+fn read_window(json: &JsonValue, start: u64, end: u64) -> Result<(ChatTables, Vec<RowError>)> {
+    let Some(tables) = json.get("tables") else {
+        bail!("not a chat-db dump: {:.200}", json.to_string());
+    };
+    let mut errors = Vec::new();
+    let window = (start, end);
+    let dump = ChatTables {
+        tables: ChatTable {
+            paths: read_rows("paths", tables.get("paths"), window, &mut errors),
+            messages: read_rows("messages", tables.get("messages"), window, &mut errors),
+            peers: read_rows("peers", tables.get("peers"), window, &mut errors),
+        },
+        del_log: read_rows("del-log", json.get("del-log"), window, &mut errors),
+    };
+    Ok((dump, errors))
+}
+
+fn read_rows<T: DeserializeOwned>(
+    kind: &str,
+    rows: Option<&JsonValue>,
+    (window_start, window_end): (u64, u64),
+    errors: &mut Vec<RowError>,
+) -> Vec<T> {
+    let Some(rows) = rows.and_then(JsonValue::as_array) else {
+        return Vec::new();
+    };
+    rows.iter()
+        .filter_map(|row| match serde_json::from_value(row.clone()) {
+            Ok(row) => Some(row),
+            Err(e) => {
+                errors.push(RowError {
+                    kind: kind.to_string(),
+                    row: row.clone(),
+                    error: e.to_string(),
+                    window_start,
+                    window_end,
+                });
+                None
+            }
+        })
+        .collect()
+}
+
+// the length of the window after one of `window_ms` that held `rows`
+fn next_window(window_ms: u64, rows: usize) -> u64 {
+    if rows > CHUNK_ROWS {
+        (window_ms / 2).max(1)
+    } else if rows < CHUNK_ROWS / 4 {
+        window_ms.saturating_mul(2).min(MAX_WINDOW_MS)
+    } else {
+        window_ms
+    }
+}
+
+// start an import ending at `now`, or resume the unfinished one
+fn begin(db: &Db, now: u64) -> Result<ImportStatus> {
+    let mut conn = db.pool.get_writer()?;
+    let tx = conn.transaction()?;
+    let status = match load(&tx)? {
+        Some(last) if last.state != ImportState::Done => ImportStatus {
+            state: ImportState::Running,
+            until: last.until.max(now),
+            error: None,
+            updated_at: now,
+            ..last
+        },
+        last => {
+            // rows mirrored by the live sync (or before imports were tracked) are in
+            //  already
+            let newest = newest_received(&tx)?;
+            let from = last.map_or(newest, |last| last.checkpoint.max(newest));
+            tx.execute("DELETE FROM chat_import_errors", [])?;
+            ImportStatus {
+                state: ImportState::Running,
+                from,
+                until: now,
+                checkpoint: from,
+                window_ms: FIRST_WINDOW_MS,
+                imported: 0,
+                skipped: 0,
+                error: None,
+                started_at: now,
+                updated_at: now,
+            }
+        }
+    };
+    let status = match status.checkpoint >= status.until {
+        true => ImportStatus {
+            state: ImportState::Done,
+            ..status
+        },
+        false => status,
+    };
+    save(&tx, &status)?;
+    tx.commit()?;
+    Ok(status)
+}
+
+// the latest received-at of the chat tables
+fn newest_received(conn: &Connection) -> Result<u64> {
+    Ok(conn.query_row(
+        "SELECT MAX(
+            (SELECT COALESCE(MAX(received_at), 0) FROM chat_messages),
+            (SELECT COALESCE(MAX(received_at), 0) FROM chat_paths),
+            (SELECT COALESCE(MAX(received_at), 0) FROM chat_peers)
+        )",
+        [],
+        |row| row.get(0),
+    )?)
+}
+
+// move the checkpoint to `end`. runs in the transaction that applies the window
+fn record_window(
+    conn: &Connection,
+    end: u64,
+    window_ms: u64,
+    rows: usize,
+    errors: &[RowError],
+    now: u64,
+) -> Result<()> {
+    conn.prepare_cached(
+        "UPDATE chat_import SET
+                checkpoint = ?1,
+                window_ms = ?2,
+                imported = imported + ?3,
+                skipped = skipped + ?4,
+                state = CASE WHEN ?1 >= until_ms THEN 'done' ELSE 'running' END,
+                updated_at = ?5
+              WHERE id = 1",
+    )?
+    .execute((end, window_ms, rows, errors.len(), now))?;
+    let mut stmt = conn.prepare_cached(
+        "INSERT INTO chat_import_errors (
+                kind,
+                row,
+                error,
+                window_start,
+                window_end,
+                created_at
+              ) VALUES (
+                ?1,
+                ?2,
+                ?3,
+                ?4,
+                ?5,
+                ?6
+              )",
+    )?;
+    for error in errors {
+        stmt.execute((
+            &error.kind,
+            &error.row,
+            &error.error,
+            error.window_start,
+            error.window_end,
+            now,
+        ))?;
+    }
+    Ok(())
+}
+
+fn fail(db: &Db, error: &str, now: u64) -> Result<()> {
+    db.pool.get_writer()?.execute(
+        "UPDATE chat_import SET state = 'failed', error = ?1, updated_at = ?2 WHERE id = 1",
+        (error, now),
+    )?;
+    Ok(())
+}
+
+fn load(conn: &Connection) -> Result<Option<ImportStatus>> {
+    Ok(conn
+        .prepare_cached(
+            "SELECT state, from_ms, until_ms, checkpoint, window_ms, imported, skipped,
+                    error, started_at, updated_at
+              FROM chat_import
+              WHERE id = 1",
+        )?
+        .query_row([], |row| {
+            Ok(ImportStatus {
+                state: ImportState::parse(&row.get::<_, String>(0)?),
+                from: row.get(1)?,
+                until: row.get(2)?,
+                checkpoint: row.get(3)?,
+                window_ms: row.get(4)?,
+                imported: row.get(5)?,
+                skipped: row.get(6)?,
+                error: row.get(7)?,
+                started_at: row.get(8)?,
+                updated_at: row.get(9)?,
+            })
+        })
+        .optional()?)
+}
+
+fn save(conn: &Connection, status: &ImportStatus) -> Result<()> {
+    conn.prepare_cached(
+        "INSERT OR REPLACE INTO chat_import (
+                id,
+                state,
+                from_ms,
+                until_ms,
+                checkpoint,
+                window_ms,
+                imported,
+                skipped,
+                error,
+                started_at,
+                updated_at
+              ) VALUES (
+                1,
+                ?1,
+                ?2,
+                ?3,
+                ?4,
+                ?5,
+                ?6,
+                ?7,
+                ?8,
+                ?9,
+                ?10
+              )",
+    )?
+    .execute((
+        status.state.as_str(),
+        status.from,
+        status.until,
+        status.checkpoint,
+        status.window_ms,
+        status.imported,
+        status.skipped,
+        &status.error,
+        status.started_at,
+        status.updated_at,
+    ))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::api::chat_router;
+    use crate::testing::{run, test_context, test_ship};
+    use serde_json::json;
+
+    const PATH: &str = "/spaces/~zod/chats/0v1";
+    // where the test imports end
+    const NOW: u64 = 1_700_000_000_000;
+
+    fn message(msg_id: &str, received_at: u64) -> JsonValue {
+        json!({
+            "path": PATH,
+            "msg-id": msg_id,
+            "msg-part-id": 0,
+            "content-type": "plain",
+            "content-data": "hello",
+            "reply-to": null,
+            "metadata": {},
+            "sender": "~zod",
+            "created-at": received_at,
+            "updated-at": received_at,
+            "received-at": received_at,
+            "expires-at": null,
+        })
+    }
+
+    fn window(messages: Vec<JsonValue>) -> JsonValue {
+        json!({"tables": {"paths": [], "peers": [], "messages": messages}, "del-log": []})
+    }
+
+    fn scry(start: u64, end: u64) -> String {
+        format!("chat-db{}.json", window_path(start, end))
+    }
+
+    fn ids(ctx: &CallContext) -> Vec<String> {
+        let conn = ctx.db.pool.get_conn().unwrap();
+        let mut stmt = conn
+            .prepare("SELECT msg_id FROM chat_messages ORDER BY msg_id")
+            .unwrap();
+        let rows = stmt.query_map([], |row| row.get(0)).unwrap();
+        rows.map(|row| row.unwrap()).collect()
+    }
+
+    #[test]
+    fn imports_resume_from_their_checkpoint() {
+        run(async {
+            let (eyre, ship) = test_ship().await;
+            let ctx = test_context(ship);
+            // the newest row we have is three days old: the import starts there
+            let from = NOW - 3 * DAY_MS;
+            sync::apply(&ctx.db, &window(vec![message("/~0/~zod", from)])).unwrap();
+
+            // the first window (a day) comes back with a row that cannot be read. the
+            //  second (twice as long, since the first was small) is not there
+            let mut broken = message("/~2/~zod", from + 2);
+            broken.as_object_mut().unwrap().remove("sender");
+            eyre.add_scry(
+                &scry(from, from + DAY_MS),
+                window(vec![message("/~1/~zod", from + 1), broken]),
+            );
+            assert!(super::run(&ctx, NOW).await.is_err());
+            let report = status(&ctx.db).unwrap();
+            assert_eq!(report.status.state, ImportState::Failed);
+            assert_eq!(report.status.checkpoint, from + DAY_MS);
+            assert_eq!((report.status.imported, report.status.skipped), (1, 1));
+            assert!(report.status.error.is_some());
+            assert_eq!(report.progress, 1.0 / 3.0);
+            assert_eq!(report.errors.len(), 1);
+            assert_eq!(report.errors[0].kind, "messages");
+            assert!(report.errors[0].error.contains("sender"));
+            assert_eq!(ids(&ctx), vec!["/~0/~zod", "/~1/~zod"]);
+
+            // the next run picks up where the failed one stopped
+            eyre.add_scry(
+                &scry(from + DAY_MS, NOW),
+                window(vec![message("/~3/~zod", NOW - 1)]),
+            );
+            let done = super::run(&ctx, NOW).await.unwrap();
+            assert_eq!(done.state, ImportState::Done);
+            assert_eq!((done.from, done.checkpoint), (from, NOW));
+            assert_eq!((done.imported, done.skipped), (2, 1));
+            assert_eq!(ids(&ctx), vec!["/~0/~zod", "/~1/~zod", "/~3/~zod"]);
+
+            // ...and the one after that only covers what came in since
+            eyre.add_scry(&scry(NOW, NOW + 1_000), window(vec![]));
+            let next = super::run(&ctx, NOW + 1_000).await.unwrap();
+            assert_eq!((next.from, next.imported), (NOW, 0));
+
+            // the status is served over http
+            let res = warp::test::request()
+                .path("/hol/chat/import")
+                .reply(&chat_router(ctx.clone()))
+                .await;
+            assert_eq!(res.status(), 200);
+            let body: JsonValue = serde_json::from_slice(res.body()).unwrap();
+            assert_eq!(body["state"], "done");
+            assert_eq!(body["checkpoint"], NOW + 1_000);
+            assert_eq!(body["progress"], 1.0);
+            // errors belong to the import that hit them
+            assert_eq!(body["errors"], json!([]));
+        });
+    }
+
+    #[test]
+    fn windows_follow_the_rows_they_hold() {
+        assert_eq!(next_window(DAY_MS, 0), 2 * DAY_MS);
+        assert_eq!(next_window(DAY_MS, CHUNK_ROWS / 2), DAY_MS);
+        assert_eq!(next_window(DAY_MS, CHUNK_ROWS + 1), DAY_MS / 2);
+        assert_eq!(next_window(1, CHUNK_ROWS + 1), 1);
+        assert_eq!(next_window(MAX_WINDOW_MS, 0), MAX_WINDOW_MS);
+    }
+
+    #[test]
+    fn a_response_that_is_not_a_dump_fails_the_window() {
+        assert!(read_window(&json!({"error": "no"}), 0, 1).is_err());
+        let (dump, errors) = read_window(&json!({"tables": {}}), 0, 1).unwrap();
+        assert!(dump.tables.messages.is_empty() && errors.is_empty());
+    }
+}
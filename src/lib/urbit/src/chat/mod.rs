@@ -1,4 +1,55 @@
 pub mod api;
 pub mod core;
-mod data;
-mod types;
+pub mod data;
+pub mod events;
+pub mod expiry;
+pub mod import;
+mod sync;
+pub mod types;
+pub mod write;
+
+use bedrock_db::migrations::Migration;
+
+/// the chat tables. applied by `Db::migrate`
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "chat_messages",
+        sql: include_str!("sql/0001_chat_messages.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "paths_peers_delete_log",
+        sql: include_str!("sql/0002_paths_peers_delete_log.sql"),
+    },
+    Migration {
+        version: 3,
+        name: "chat_messages_order",
+        sql: include_str!("sql/0003_chat_messages_order.sql"),
+    },
+    Migration {
+        version: 4,
+        name: "chat_messages_fts",
+        sql: include_str!("sql/0004_chat_messages_fts.sql"),
+    },
+    Migration {
+        version: 5,
+        name: "chat_messages_reply_to",
+        sql: include_str!("sql/0005_chat_messages_reply_to.sql"),
+    },
+    Migration {
+        version: 6,
+        name: "chat_pending",
+        sql: include_str!("sql/0006_chat_pending.sql"),
+    },
+    Migration {
+        version: 7,
+        name: "chat_messages_expires_at",
+        sql: include_str!("sql/0007_chat_messages_expires_at.sql"),
+    },
+    Migration {
+        version: 8,
+        name: "chat_import",
+        sql: include_str!("sql/0008_chat_import.sql"),
+    },
+];
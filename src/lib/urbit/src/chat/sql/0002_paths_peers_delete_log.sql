@@ -0,0 +1,46 @@
+create table if not exists chat_paths
+(
+    path                    TEXT    NOT NULL PRIMARY KEY,
+    type                    TEXT    NOT NULL,
+    metadata                TEXT,
+    /* json array of pinned message ids */
+    pins                    TEXT,
+    invites                 TEXT    NOT NULL,
+    peers_get_backlog       INTEGER NOT NULL,
+    /* ms. null if messages on the path never expire */
+    max_expires_at_duration INTEGER,
+    created_at              INTEGER NOT NULL,
+    updated_at              INTEGER NOT NULL,
+    received_at             INTEGER NOT NULL
+);
+
+create table if not exists chat_peers
+(
+    path         TEXT    NOT NULL,
+    ship         TEXT    NOT NULL,
+    role         TEXT    NOT NULL,
+    created_at   INTEGER NOT NULL,
+    updated_at   INTEGER NOT NULL,
+    received_at  INTEGER NOT NULL,
+    PRIMARY KEY (path, ship)
+);
+
+/*
+    rows deleted on the ship. kept so that a stale copy of a deleted row (e.g. replayed
+    after a reconnect) is not added back
+*/
+create table if not exists chat_delete_log
+(
+    /* del-paths-row, del-peers-row or del-messages-row */
+    type         TEXT    NOT NULL,
+    path         TEXT    NOT NULL,
+    /* the deleted peer (del-peers-row), otherwise empty */
+    ship         TEXT    NOT NULL DEFAULT '',
+    /* the deleted message (del-messages-row), otherwise empty */
+    msg_id       TEXT    NOT NULL DEFAULT '',
+    /* when the row was deleted (ms since epoch) */
+    timestamp    INTEGER NOT NULL
+);
+
+create unique index if not exists chat_delete_log_uindex
+    on chat_delete_log (type, path, ship, msg_id, timestamp);
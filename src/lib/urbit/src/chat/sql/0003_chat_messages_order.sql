@@ -0,0 +1,6 @@
+/* /hol/chat/messages pages thru messages in this order, across all paths or within one */
+create index if not exists chat_messages_created_at_index
+    on chat_messages (created_at, path, msg_id, msg_part_id);
+
+create index if not exists chat_messages_path_created_at_index
+    on chat_messages (path, created_at, msg_id, msg_part_id);
@@ -0,0 +1,72 @@
+/*
+    give messages a stable id for the search index to point at. implicit rowids can
+    change when the database is vacuumed
+*/
+create table chat_messages_new
+(
+    id           INTEGER PRIMARY KEY,
+    path         TEXT    not null,
+    msg_id       TEXT    NOT NULL,
+    msg_part_id  INTEGER NOT NULL,
+    content_type TEXT,
+    content_data TEXT,
+    reply_to     TEXT,
+    metadata     text,
+    sender       text    NOT NULL,
+    updated_at   INTEGER NOT NULL,
+    created_at   INTEGER NOT NULL,
+    expires_at   INTEGER,
+    received_at  INTEGER NOT NULL
+);
+
+insert into chat_messages_new (path, msg_id, msg_part_id, content_type, content_data, reply_to,
+                               metadata, sender, updated_at, created_at, expires_at, received_at)
+select path, msg_id, msg_part_id, content_type, content_data, reply_to,
+       metadata, sender, updated_at, created_at, expires_at, received_at
+from chat_messages;
+
+drop table chat_messages;
+alter table chat_messages_new rename to chat_messages;
+
+create unique index if not exists chat_messages_path_msg_id_msg_part_id_uindex
+    on chat_messages (path, msg_id, msg_part_id);
+
+create index if not exists chat_messages_created_at_index
+    on chat_messages (created_at, path, msg_id, msg_part_id);
+
+create index if not exists chat_messages_path_created_at_index
+    on chat_messages (path, created_at, msg_id, msg_part_id);
+
+/* full text index of message content. reactions (emoji codes) are left out */
+create virtual table if not exists chat_messages_fts using fts5
+(
+    content_data,
+    content = 'chat_messages',
+    content_rowid = 'id',
+    tokenize = 'unicode61 remove_diacritics 2'
+);
+
+insert into chat_messages_fts (rowid, content_data)
+select id, content_data from chat_messages where content_type is not 'react';
+
+create trigger if not exists chat_messages_fts_insert after insert on chat_messages
+    when new.content_type is not 'react'
+begin
+    insert into chat_messages_fts (rowid, content_data) values (new.id, new.content_data);
+end;
+
+create trigger if not exists chat_messages_fts_delete after delete on chat_messages
+    when old.content_type is not 'react'
+begin
+    insert into chat_messages_fts (chat_messages_fts, rowid, content_data)
+    values ('delete', old.id, old.content_data);
+end;
+
+/* one trigger so that the old content is always removed before the new is added */
+create trigger if not exists chat_messages_fts_update after update on chat_messages
+begin
+    insert into chat_messages_fts (chat_messages_fts, rowid, content_data)
+    select 'delete', old.id, old.content_data where old.content_type is not 'react';
+    insert into chat_messages_fts (rowid, content_data)
+    select new.id, new.content_data where new.content_type is not 'react';
+end;
@@ -0,0 +1,3 @@
+/* replies and reactions are looked up by the message they point at */
+create index if not exists chat_messages_reply_to_index
+    on chat_messages (json_extract(reply_to, '$.msg-id'), path);
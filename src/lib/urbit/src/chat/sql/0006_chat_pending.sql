@@ -0,0 +1,23 @@
+/*
+    writes made through the chat REST api that the ship has not confirmed yet. the
+    change is applied to chat_messages right away; if the ship nacks the poke (or never
+    confirms it) it is rolled back
+*/
+create table if not exists chat_pending
+(
+    /* id of the poke on the sync channel */
+    poke_id      INTEGER NOT NULL PRIMARY KEY,
+    /* send, edit or delete */
+    kind         TEXT    NOT NULL,
+    path         TEXT    NOT NULL,
+    /* the provisional message (send) or the message changed (edit, delete) */
+    msg_id       TEXT    NOT NULL,
+    /* sends: sender, reply-to and parts of the message, to match the ship's copy */
+    fingerprint  TEXT    NOT NULL DEFAULT '',
+    /* edits and deletes: json array of the rows the change replaced */
+    previous     TEXT    NOT NULL DEFAULT '[]',
+    created_at   INTEGER NOT NULL
+);
+
+create index if not exists chat_pending_message_index
+    on chat_pending (path, msg_id);
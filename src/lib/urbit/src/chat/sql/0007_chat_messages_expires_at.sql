@@ -0,0 +1,4 @@
+/* the expiry sweeper looks for messages past their expires-at */
+create index if not exists chat_messages_expires_at_index
+    on chat_messages (expires_at)
+    where expires_at is not null;
@@ -0,0 +1,35 @@
+/*
+    progress of the import of chat-db history (see chat::import). one row; the import
+    resumes from `checkpoint` if the node stops before it is done
+*/
+create table if not exists chat_import
+(
+    id           INTEGER NOT NULL PRIMARY KEY CHECK (id = 1),
+    /* running, done or failed */
+    state        TEXT    NOT NULL,
+    /* rows received (on the ship) from `from_ms` up to `until_ms` are imported */
+    from_ms      INTEGER NOT NULL,
+    until_ms     INTEGER NOT NULL,
+    /* everything received before this is in */
+    checkpoint   INTEGER NOT NULL,
+    /* length of the next window scried (ms) */
+    window_ms    INTEGER NOT NULL,
+    imported     INTEGER NOT NULL DEFAULT 0,
+    skipped      INTEGER NOT NULL DEFAULT 0,
+    error        TEXT,
+    started_at   INTEGER NOT NULL,
+    updated_at   INTEGER NOT NULL
+);
+
+/* rows of the current import that could not be read, and why */
+create table if not exists chat_import_errors
+(
+    id           INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    /* paths, peers, messages or del-log */
+    kind         TEXT    NOT NULL,
+    row          TEXT    NOT NULL,
+    error        TEXT    NOT NULL,
+    window_start INTEGER NOT NULL,
+    window_end   INTEGER NOT NULL,
+    created_at   INTEGER NOT NULL
+);
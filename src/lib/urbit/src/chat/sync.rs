@@ -0,0 +1,684 @@
+//!
+//! live sync of chat-db into the local chat tables
+//!
+//! `core::import_data` brings in the history received since the last import (see
+//!  `import`). after that, the node watches chat-db at `/db` on a channel of its own and
+//!  applies each change as it arrives. applying a change is idempotent: events replayed
+//!  after a reconnect, or changes already covered by the import, leave the tables as
+//!  they are.
+//!
+//! when the stream drops, the node logs in again and resumes the same channel from the
+//!  last event it received. only when the channel is gone on the ship is another one
+//!  opened (and the old one deleted), with an import to catch up.
+//!
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+use anyhow::{bail, Result};
+use crossbeam::channel::RecvTimeoutError;
+use eventsource_threaded::ReceiverSource;
+use rusqlite::Connection;
+use serde_json::Value as JsonValue;
+use tokio::time::{sleep, Duration};
+
+use crate::api::Ship;
+use crate::context::CallContext;
+use crate::db::Db;
+use crate::eyre::{Action, Event};
+use crate::helper::get_current_time;
+
+use super::data;
+use super::events::{self, Changes, ChatEvent};
+use super::types::{ChatTables, DbChange, DbChangeType, DbRow};
+
+use trace::{trace_err_ln, trace_info_ln, trace_warn_ln};
+
+/// id of the `/db` watch on the sync channel
+const WATCH_ID: u64 = 1;
+/// received events are acked once the stream has been idle this long (ms)
+const ACK_IDLE_MS: u64 = 500;
+/// delay between attempts to reconnect to the ship (ms)
+const RETRY_MS: u64 = 3_000;
+/// writes the ship has not confirmed after this long are rolled back (ms)
+const PENDING_TTL_MS: u64 = 60_000;
+/// how often unconfirmed writes are checked for (ms)
+const SWEEP_MS: u64 = 5_000;
+
+/// ids of pokes sent on the sync channel (see `write`)
+static NEXT_POKE_ID: AtomicU64 = AtomicU64::new(WATCH_ID + 1);
+
+/// open the sync channel and apply chat-db changes until the node stops
+pub async fn start(ctx: CallContext) -> Result<()> {
+    // a copy of the node's ship (session and all) so that the sync channel does not
+    //  replace the channel used for devices
+    let mut ship = ctx.ship.lock().await.clone();
+    let receiver = watch(&mut ship).await?;
+    ctx.sync_ship.lock().await.replace(ship);
+    // pending writes from an earlier run keep their ids
+    NEXT_POKE_ID.fetch_max(data::max_pending_poke_id(&ctx.db)? + 1, Ordering::Relaxed);
+    tokio::spawn(listen(ctx, receiver));
+    Ok(())
+}
+
+/// an id for a poke on the sync channel
+pub fn next_poke_id() -> u64 {
+    NEXT_POKE_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// name of the ship (with a leading ~) once the sync channel is open
+pub async fn our_ship(ctx: &CallContext) -> Option<String> {
+    let ship = ctx.sync_ship.lock().await;
+    let name = ship.as_ref()?.ship_name.as_ref()?;
+    Some(format!("~{}", name))
+}
+
+/// poke an app on the sync channel. the ack (or nack) comes back to `listen`
+pub async fn poke(
+    ctx: &CallContext,
+    id: u64,
+    app: &str,
+    mark: &str,
+    json: JsonValue,
+) -> Result<()> {
+    let mut ship = ctx.sync_ship.lock().await;
+    let Some(ship) = ship.as_mut() else {
+        bail!("chat: [sync] the sync channel is not open");
+    };
+    let poke = [Action::Poke {
+        id,
+        ship: ship.ship_name.clone().unwrap_or_default(),
+        app: app.to_string(),
+        mark: mark.to_string(),
+        json,
+    }];
+    ship.post(&poke).await
+}
+
+async fn watch(ship: &mut Ship) -> Result<ReceiverSource> {
+    let receiver = ship.open_channel().await?;
+    subscribe(ship).await?;
+    Ok(receiver)
+}
+
+async fn subscribe(ship: &mut Ship) -> Result<()> {
+    let subscribe = [Action::Subscribe {
+        id: WATCH_ID,
+        ship: ship.ship_name.clone().unwrap_or_default(),
+        app: "chat-db".to_string(),
+        path: "/db".to_string(),
+    }];
+    ship.post(&subscribe).await
+}
+
+async fn resubscribe(ctx: &CallContext) {
+    let mut ship = ctx.sync_ship.lock().await;
+    let Some(ship) = ship.as_mut() else {
+        return;
+    };
+    if let Err(e) = subscribe(ship).await {
+        trace_err_ln!("chat: [sync] resubscribe failed. {}", e);
+    }
+}
+
+async fn ack(ctx: &CallContext, event_id: u64) -> Result<()> {
+    let mut ship = ctx.sync_ship.lock().await;
+    let Some(ship) = ship.as_mut() else {
+        bail!("chat: [sync] the sync channel is not open");
+    };
+    ship.ack(event_id).await
+}
+
+async fn listen(ctx: CallContext, mut receiver: ReceiverSource) {
+    let mut last_event_id: Option<u64> = None;
+    let mut last_ack: Option<u64> = None;
+    let mut last_sweep = Instant::now();
+
+    loop {
+        if last_sweep.elapsed() >= Duration::from_millis(SWEEP_MS) {
+            last_sweep = Instant::now();
+            let before = get_current_time().saturating_sub(PENDING_TTL_MS);
+            match data::expire_pending(&ctx.db, before) {
+                Ok((0, _)) => {}
+                Ok((expired, events)) => {
+                    trace_warn_ln!("chat: [sync] rolled back {} unconfirmed write(s)", expired);
+                    events::publish(&ctx, events);
+                }
+                Err(e) => trace_err_ln!("chat: [sync] failed to expire writes. {}", e),
+            }
+        }
+
+        let msg = tokio::task::block_in_place(|| {
+            receiver.recv_timeout(Duration::from_millis(ACK_IDLE_MS))
+        });
+
+        let event = match msg {
+            Ok(Ok(event)) => event,
+            Err(RecvTimeoutError::Timeout) => {
+                if last_event_id != last_ack {
+                    match ack(&ctx, last_event_id.unwrap()).await {
+                        Ok(_) => last_ack = last_event_id,
+                        Err(e) => trace_err_ln!("chat: [sync] ack failed. {}", e),
+                    }
+                }
+                continue;
+            }
+            Ok(Err(e)) if e.to_string().contains("404") => {
+                trace_warn_ln!("chat: [sync] the sync channel is gone. reopening...");
+                receiver = reopen(&ctx).await;
+                (last_event_id, last_ack) = (None, None);
+                continue;
+            }
+            Ok(Err(e)) => {
+                trace_warn_ln!("chat: [sync] chat-db stream error. {}. resuming...", e);
+                receiver = resume(&ctx, last_event_id).await;
+                continue;
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                trace_warn_ln!("chat: [sync] chat-db stream closed. resuming...");
+                receiver = resume(&ctx, last_event_id).await;
+                continue;
+            }
+        };
+
+        // keep-alive (comment) lines come through as empty events
+        if event.data.is_empty() {
+            continue;
+        }
+        if let Some(event_id) = event.id.as_ref().and_then(|id| id.parse::<u64>().ok()) {
+            last_event_id.replace(event_id);
+        }
+
+        match serde_json::from_str::<Event>(&event.data) {
+            Ok(Event::Fact { id: WATCH_ID, json }) => match apply(&ctx.db, &json) {
+                Ok(events) => events::publish(&ctx, events),
+                Err(e) => trace_err_ln!("chat: [sync] failed to apply change {}. {}", json, e),
+            },
+            Ok(Event::WatchAck { id: WATCH_ID }) => {
+                trace_info_ln!("chat: [sync] watching chat-db");
+            }
+            Ok(Event::WatchNack { id: WATCH_ID, err }) => {
+                trace_err_ln!("chat: [sync] chat-db refused the watch. {}", err);
+                sleep(Duration::from_millis(RETRY_MS)).await;
+                resubscribe(&ctx).await;
+            }
+            Ok(Event::Kick { id: WATCH_ID }) => {
+                trace_warn_ln!("chat: [sync] kicked by chat-db. resubscribing...");
+                resubscribe(&ctx).await;
+            }
+            // a write was refused. the change it made ahead of the ship is undone. acked
+            //  writes are settled when chat-db reports the change
+            Ok(Event::PokeNack { id, err }) => {
+                trace_warn_ln!("chat: [sync] poke {} refused. {}", id, err);
+                match rollback(&ctx.db, id) {
+                    Ok(events) => events::publish(&ctx, events),
+                    Err(e) => trace_err_ln!("chat: [sync] rollback of poke {} failed. {}", id, e),
+                }
+            }
+            Ok(_) => {}
+            Err(e) => trace_err_ln!("chat: [sync] invalid event {}. {}", event.data, e),
+        }
+    }
+}
+
+// a copy of the sync ship to reconnect with, so that writes are not held up (they fail
+//  until the reconnect is done)
+async fn sync_ship(ctx: &CallContext) -> Ship {
+    match ctx.sync_ship.lock().await.clone() {
+        Some(ship) => ship,
+        None => {
+            // the node's channel is not ours to resume, or delete
+            let mut ship = ctx.ship.lock().await.clone();
+            ship.channel_url = None;
+            ship
+        }
+    }
+}
+
+// log in again and reattach to the sync channel after the last event received. eyre
+//  replays the events that came after it, so nothing is missed
+async fn resume(ctx: &CallContext, last_event_id: Option<u64>) -> ReceiverSource {
+    loop {
+        let mut ship = sync_ship(ctx).await;
+        if ship.channel_url.is_none() {
+            return reopen(ctx).await;
+        }
+        let result = match ship.login().await {
+            Ok(_) => ship.resume_channel(last_event_id),
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(receiver) => {
+                ctx.sync_ship.lock().await.replace(ship);
+                return receiver;
+            }
+            Err(e) => {
+                trace_warn_ln!("chat: [sync] resume failed. {}. trying again...", e);
+                sleep(Duration::from_millis(RETRY_MS)).await;
+            }
+        }
+    }
+}
+
+// open a new channel and watch again, once the old one is gone on the ship (it is
+//  deleted, in case it is not). changes made while the stream was down are picked up
+//  by importing everything since the last message we have
+async fn reopen(ctx: &CallContext) -> ReceiverSource {
+    let mut ship = sync_ship(ctx).await;
+    let receiver = loop {
+        let result = match ship.login().await {
+            Ok(_) => {
+                if ship.channel_url.is_some() {
+                    if let Err(e) = ship.discard_channel().await {
+                        trace_warn_ln!("chat: [sync] old channel not deleted. {}", e);
+                    }
+                    ship.channel_url = None;
+                }
+                watch(&mut ship).await
+            }
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(receiver) => {
+                ctx.sync_ship.lock().await.replace(ship);
+                break receiver;
+            }
+            Err(e) => {
+                trace_warn_ln!("chat: [sync] reopen failed. {}. trying again...", e);
+                sleep(Duration::from_millis(RETRY_MS)).await;
+            }
+        }
+    };
+    if let Err(e) = super::core::import_data(ctx).await {
+        trace_err_ln!("chat: [sync] catch up import failed. {}", e);
+    }
+    receiver
+}
+
+/// undo the write made ahead of a poke
+fn rollback(db: &Db, poke_id: u64) -> Result<Vec<ChatEvent>> {
+    let mut conn = db.pool.get_writer()?;
+    let tx = conn.transaction()?;
+    let mut changes = Changes::new();
+    data::rollback_pending(&tx, poke_id, &mut changes)?;
+    let events = changes.events(&tx)?;
+    tx.commit()?;
+    Ok(events)
+}
+
+/// apply a `/db` fact: a list of changes, or a dump of the tables. returns the events
+///  for the messages it changed
+pub fn apply(db: &Db, json: &JsonValue) -> Result<Vec<ChatEvent>> {
+    if json.get("tables").is_some() {
+        return apply_dump(db, serde_json::from_value(json.clone())?);
+    }
+    // one bad change should not hold up the rest
+    let changes: DbChange = serde_json::from_value::<Vec<JsonValue>>(json.clone())?
+        .into_iter()
+        .filter_map(|change| match serde_json::from_value(change.clone()) {
+            Ok(change) => Some(change),
+            Err(e) => {
+                trace_err_ln!("chat: [sync] skipping change {}. {}", change, e);
+                None
+            }
+        })
+        .collect();
+    apply_changes(db, changes)
+}
+
+/// apply a dump of the tables (and the deletes made while it was taken)
+pub fn apply_dump(db: &Db, dump: ChatTables) -> Result<Vec<ChatEvent>> {
+    apply_dump_with(db, dump, |_| Ok(()))
+}
+
+/// `apply_dump`, with `finish` run in the same transaction (e.g. to record how far an
+///  import has got)
+pub fn apply_dump_with<F>(db: &Db, dump: ChatTables, finish: F) -> Result<Vec<ChatEvent>>
+where
+    F: FnOnce(&Connection) -> Result<()>,
+{
+    // deletes first: a row in the dump that was deleted before is a newer copy
+    let tables = dump.tables;
+    let rows = (tables.paths.into_iter().map(DbRow::Paths))
+        .chain(tables.peers.into_iter().map(DbRow::Peers))
+        .chain(tables.messages.into_iter().map(DbRow::Messages))
+        .map(DbChangeType::AddRow);
+    apply_changes_with(db, dump.del_log.into_iter().chain(rows).collect(), finish)
+}
+
+fn apply_changes(db: &Db, changes: DbChange) -> Result<Vec<ChatEvent>> {
+    apply_changes_with(db, changes, |_| Ok(()))
+}
+
+fn apply_changes_with<F>(db: &Db, changes: DbChange, finish: F) -> Result<Vec<ChatEvent>>
+where
+    F: FnOnce(&Connection) -> Result<()>,
+{
+    let mut conn = db.pool.get_writer()?;
+    let tx = conn.transaction()?;
+    let mut touched = Changes::new();
+    // messages added by this batch. the parts of a message may come in separate changes,
+    //  so pending sends are matched once they are all in
+    let mut added: Vec<(String, String)> = Vec::new();
+    for change in changes {
+        match change {
+            DbChangeType::AddRow(DbRow::Paths(row)) => data::save_path(&tx, &row)?,
+            DbChangeType::AddRow(DbRow::Peers(row)) => data::save_peer(&tx, &row)?,
+            DbChangeType::AddRow(DbRow::Messages(msg)) => {
+                touched.touch_row(&tx, &msg)?;
+                data::save_message(&tx, &msg)?;
+                let key = (msg.path, msg.msg_id);
+                if !added.contains(&key) {
+                    added.push(key);
+                }
+            }
+            DbChangeType::UpdMessages { message, .. } => {
+                if let Some(first) = message.first() {
+                    touched.touch_row(&tx, first)?;
+                    data::confirm_pending(&tx, &first.path, &first.msg_id)?;
+                }
+                data::replace_message(&tx, &message)?;
+            }
+            DbChangeType::UpdPathsRow { row, .. } => data::save_path(&tx, &row)?,
+            DbChangeType::DelPathsRow { path, timestamp } => {
+                touched.touch_path(&tx, &path)?;
+                data::delete_path(&tx, &path, timestamp)?;
+            }
+            DbChangeType::DelPeersRow {
+                path,
+                ship,
+                timestamp,
+            } => {
+                data::delete_peer(&tx, &path, &ship, timestamp)?;
+            }
+            DbChangeType::DelMessagesRow {
+                path,
+                msg_id,
+                timestamp,
+            } => {
+                touched.touch(&tx, &path, &msg_id)?;
+                data::delete_message(&tx, &path, &msg_id, timestamp)?;
+                data::confirm_pending(&tx, &path, &msg_id)?;
+            }
+            DbChangeType::Unknown => {}
+        }
+    }
+    for (path, msg_id) in added {
+        data::reconcile_send(&tx, &path, &msg_id, &mut touched)?;
+    }
+    finish(&tx)?;
+    let events = touched.events(&tx)?;
+    tx.commit()?;
+    Ok(events)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{run, test_context, test_db, test_ship};
+    use serde_json::json;
+
+    // chat-db `/db` facts recorded from a ship: a message and a reply are posted, the
+    //  first is edited, the reply is deleted, and the first is reacted to
+    const RECORDED: &str = include_str!("fixtures/db_changes.json");
+
+    // (msg_id, msg_part_id, content_data) of every stored message part
+    fn messages(db: &Db) -> Vec<(String, u64, String)> {
+        let conn = db.pool.get_conn().unwrap();
+        let mut stmt = conn
+            .prepare(
+                "SELECT msg_id, msg_part_id, content_data FROM chat_messages
+                   ORDER BY created_at, msg_id, msg_part_id",
+            )
+            .unwrap();
+        let rows = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap();
+        rows.map(|row| row.unwrap()).collect()
+    }
+
+    fn expected() -> Vec<(String, u64, String)> {
+        vec![
+            (
+                "/~2023.7.7..19.57.10..a94a/~fasnut-famden".to_string(),
+                0,
+                "hello (edited)".to_string(),
+            ),
+            (
+                "/~2023.7.7..19.57.10..a94a/~fasnut-famden".to_string(),
+                1,
+                "https://holium.com".to_string(),
+            ),
+            (
+                "/~2023.7.7..20.57.33..f44a/~tolwer-mogmer".to_string(),
+                0,
+                "2764-fe0f".to_string(),
+            ),
+        ]
+    }
+
+    #[test]
+    fn recorded_changes_replay_idempotently() {
+        let db = test_db();
+        let facts: Vec<JsonValue> = serde_json::from_str(RECORDED).unwrap();
+
+        for fact in &facts {
+            apply(&db, fact).unwrap();
+        }
+        assert_eq!(messages(&db), expected());
+
+        // replaying the whole stream (e.g. after a reconnect) changes nothing...
+        for fact in &facts {
+            apply(&db, fact).unwrap();
+        }
+        assert_eq!(messages(&db), expected());
+
+        // ...and neither does a stale copy of the edited message arriving late
+        apply(&db, &facts[0]).unwrap();
+        assert_eq!(messages(&db), expected());
+
+        // deleting the path removes its messages
+        apply(
+            &db,
+            &json!([{"type": "del-paths-row", "path": "/spaces/~zod/chats/0v1", "timestamp": 1}]),
+        )
+        .unwrap();
+        assert!(messages(&db).is_empty());
+
+        // unknown changes are skipped
+        apply(
+            &db,
+            &json!([{"type": "add-row", "table": "reactions", "row": {}}]),
+        )
+        .unwrap();
+        apply(&db, &json!([{"type": "upd-messages-v2"}])).unwrap();
+    }
+
+    fn path_row(updated_at: u64, title: &str) -> JsonValue {
+        json!({
+            "path": "/spaces/~zod/chats/0v1",
+            "type": "space",
+            "metadata": {"title": title},
+            "pins": [],
+            "invites": "host",
+            "peers-get-backlog": true,
+            "max-expires-at-duration": null,
+            "created-at": 1000,
+            "updated-at": updated_at,
+            "received-at": updated_at,
+        })
+    }
+
+    fn peer_row(ship: &str, role: &str, updated_at: u64) -> JsonValue {
+        json!({
+            "path": "/spaces/~zod/chats/0v1",
+            "ship": ship,
+            "role": role,
+            "created-at": 1000,
+            "updated-at": updated_at,
+            "received-at": updated_at,
+        })
+    }
+
+    #[test]
+    fn paths_and_peers_are_mirrored() {
+        run(async {
+            let db = test_db();
+
+            // a dump: the path, two peers and a peer that has since left
+            apply(
+                &db,
+                &json!({
+                    "tables": {
+                        "paths": [path_row(1000, "forerunners")],
+                        "peers": [peer_row("~zod", "host", 1000), peer_row("~bus", "member", 1000)],
+                        "messages": [],
+                    },
+                    "del-log": [
+                        {"type": "del-peers-row", "path": "/spaces/~zod/chats/0v1", "ship": "~nec", "timestamp": 1500},
+                    ],
+                }),
+            )
+            .unwrap();
+
+            // the path is renamed and pinned; ~bus leaves. a stale copy of ~nec is ignored
+            let mut renamed = path_row(2000, "realm forerunners");
+            renamed["pins"] = json!(["/~2023.7.7..19.57.10..a94a/~fasnut-famden"]);
+            apply(
+                &db,
+                &json!([
+                    {"type": "upd-paths-row", "row": renamed, "old": path_row(1000, "forerunners")},
+                    {"type": "del-peers-row", "path": "/spaces/~zod/chats/0v1", "ship": "~bus", "timestamp": 2000},
+                    {"type": "add-row", "table": "peers", "row": peer_row("~nec", "member", 1200)},
+                    {"type": "add-row", "table": "paths", "row": path_row(1000, "forerunners")},
+                ]),
+            )
+            .unwrap();
+
+            let paths = data::query_paths(&db).await.unwrap();
+            assert_eq!(paths.len(), 1);
+            assert_eq!(paths[0].metadata["title"], "realm forerunners");
+            assert_eq!(paths[0].pins.len(), 1);
+            assert_eq!(paths[0].max_expires_at_duration, None);
+            let peers = data::query_peers(&db, Some("/spaces/~zod/chats/0v1"))
+                .await
+                .unwrap();
+            let ships: Vec<&str> = peers.iter().map(|peer| peer.ship.as_str()).collect();
+            assert_eq!(ships, vec!["~zod"]);
+
+            // ~bus can rejoin later
+            apply(
+                &db,
+                &json!([{"type": "add-row", "table": "peers", "row": peer_row("~bus", "member", 3000)}]),
+            )
+            .unwrap();
+            assert_eq!(data::query_peers(&db, None).await.unwrap().len(), 2);
+
+            // deleting the path takes its peers with it
+            apply(
+                &db,
+                &json!([{"type": "del-paths-row", "path": "/spaces/~zod/chats/0v1", "timestamp": 4000}]),
+            )
+            .unwrap();
+            assert!(data::query_paths(&db).await.unwrap().is_empty());
+            assert!(data::query_peers(&db, None).await.unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    fn changes_are_applied_as_they_arrive() {
+        run(async {
+            let (eyre, ship) = test_ship().await;
+            let ctx = test_context(ship);
+            start(ctx.clone()).await.unwrap();
+
+            let watching = || async {
+                for _ in 0..200 {
+                    if eyre
+                        .channels()
+                        .iter()
+                        .any(|uid| !eyre.subscriptions(uid).is_empty())
+                    {
+                        return true;
+                    }
+                    sleep(Duration::from_millis(50)).await;
+                }
+                false
+            };
+            assert!(watching().await);
+
+            let facts: Vec<JsonValue> = serde_json::from_str(RECORDED).unwrap();
+            for fact in &facts {
+                assert_eq!(eyre.push_fact("chat-db", "/db", fact.clone()), 1);
+            }
+
+            let mut synced = false;
+            for _ in 0..200 {
+                if messages(&ctx.db) == expected() {
+                    synced = true;
+                    break;
+                }
+                sleep(Duration::from_millis(50)).await;
+            }
+            assert!(synced, "{:?}", messages(&ctx.db));
+
+            // the node's own channel was left alone
+            assert!(ctx.ship.lock().await.channel_url.is_none());
+        });
+    }
+
+    #[test]
+    fn reconnects_resume_the_sync_channel() {
+        run(async {
+            let (eyre, ship) = test_ship().await;
+            let ctx = test_context(ship);
+            start(ctx.clone()).await.unwrap();
+            // the channels watching chat-db, once the one(s) expected are there
+            let watching = |not: Option<String>| {
+                let eyre = &eyre;
+                async move {
+                    for _ in 0..200 {
+                        let uids: Vec<String> = eyre
+                            .channels()
+                            .into_iter()
+                            .filter(|uid| !eyre.subscriptions(uid).is_empty())
+                            .collect();
+                        if !uids.is_empty() && uids.iter().all(|uid| Some(uid) != not.as_ref()) {
+                            return uids;
+                        }
+                        sleep(Duration::from_millis(50)).await;
+                    }
+                    panic!("chat-db is not watched");
+                }
+            };
+            let uids = watching(None).await;
+            assert_eq!(uids.len(), 1);
+
+            // changes made while the stream is down come through on the same channel
+            let facts: Vec<JsonValue> = serde_json::from_str(RECORDED).unwrap();
+            let (before, after) = facts.split_at(1);
+            for fact in before {
+                assert_eq!(eyre.push_fact("chat-db", "/db", fact.clone()), 1);
+            }
+            eyre.expire_sessions();
+            for fact in after {
+                assert_eq!(eyre.push_fact("chat-db", "/db", fact.clone()), 1);
+            }
+            for _ in 0..200 {
+                if messages(&ctx.db) == expected() {
+                    break;
+                }
+                sleep(Duration::from_millis(50)).await;
+            }
+            assert_eq!(messages(&ctx.db), expected());
+            assert_eq!(eyre.channels(), uids);
+            assert_eq!(eyre.logins(), 2);
+
+            // a channel that is gone on the ship is replaced, not added to
+            let mut other = ctx.sync_ship.lock().await.clone().unwrap();
+            other.discard_channel().await.unwrap();
+            let reopened = watching(Some(uids[0].clone())).await;
+            assert_eq!(reopened.len(), 1);
+            assert_eq!(eyre.channels(), reopened);
+        });
+    }
+}
@@ -1,11 +1,12 @@
 use serde::{Deserialize, Serialize};
 use serde_json::Value as JsonValue;
 
-#[derive(Debug, Deserialize, Serialize)]
-pub struct ReplyTo {
+/// points at a message: the target of a reply or reaction
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct MsgRef {
     #[serde(rename = "msg-id")]
-    msg_id: String,
-    path: String,
+    pub msg_id: String,
+    pub path: String,
 }
 
 /// derived from chat-db json format
@@ -58,12 +59,290 @@ pub struct ChatMessage {
     pub expires_at: JsonValue, // can be null
 }
 
+/// a chat (dm, group, space channel, ...) and its settings
+// {
+//   "path": "/spaces/~lomder-librun/realm-forerunners/chats/0v2.68end.ets6m.29fgc.ntejl.jbeo7",
+//   "type": "space",
+//   "metadata": {"title": "forerunners"},
+//   "pins": ["/~2023.7.7..19.57.10..a94a/~fasnut-famden"],
+//   "invites": "host",
+//   "peers-get-backlog": true,
+//   "max-expires-at-duration": null,
+//   "created-at": 1688763453954,
+//   "updated-at": 1688763453954,
+//   "received-at": 1688763457727
+// }
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct PathRow {
+    pub path: String,
+    #[serde(rename = "type")]
+    pub path_type: String,
+    pub metadata: JsonValue,
+    /// ids of the pinned messages
+    #[serde(default)]
+    pub pins: Vec<String>,
+    /// who may add peers: host, admin or anyone
+    pub invites: String,
+    #[serde(rename = "peers-get-backlog")]
+    pub peers_get_backlog: bool,
+    /// longest a message on this path may live (ms). null if messages never expire
+    #[serde(rename = "max-expires-at-duration", default)]
+    pub max_expires_at_duration: Option<u64>,
+    #[serde(rename = "created-at")]
+    pub created_at: u64,
+    #[serde(rename = "updated-at")]
+    pub updated_at: u64,
+    #[serde(rename = "received-at")]
+    pub received_at: u64,
+}
+
+/// a ship's membership of a path
+// {
+//   "path": "/spaces/~lomder-librun/realm-forerunners/chats/0v2.68end.ets6m.29fgc.ntejl.jbeo7",
+//   "ship": "~tolwer-mogmer",
+//   "role": "member",
+//   "created-at": 1688763453954,
+//   "updated-at": 1688763453954,
+//   "received-at": 1688763457727
+// }
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct PeerRow {
+    pub path: String,
+    pub ship: String,
+    /// host, admin or member
+    pub role: String,
+    #[serde(rename = "created-at")]
+    pub created_at: u64,
+    #[serde(rename = "updated-at")]
+    pub updated_at: u64,
+    #[serde(rename = "received-at")]
+    pub received_at: u64,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct ChatTable {
+    #[serde(default)]
+    pub paths: Vec<PathRow>,
+    #[serde(default)]
     pub messages: Vec<ChatMessage>,
+    #[serde(default)]
+    pub peers: Vec<PeerRow>,
 }
 
+/// a dump of the chat-db tables (everything, or everything since a point in time),
+///  along with the deletes made in that time
 #[derive(Debug, Deserialize, Serialize)]
 pub struct ChatTables {
     pub tables: ChatTable,
+    /// del-paths-row, del-peers-row and del-messages-row changes
+    #[serde(rename = "del-log", default)]
+    pub del_log: Vec<DbChangeType>,
+}
+
+/// a row added to one of the chat-db tables
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "table", content = "row", rename_all = "kebab-case")]
+pub enum DbRow {
+    Paths(PathRow),
+    Messages(ChatMessage),
+    Peers(PeerRow),
+}
+
+/// a change to the chat-db tables, as delivered on the agent's `/db` watch. a `/db`
+///  fact is a list of changes
+// [
+//   {"type": "add-row", "table": "messages", "row": { ..message part.. }},
+//   {"type": "upd-messages", "msg-id": "/~2023.7.7..20.57.33..f44a/~tolwer-mogmer", "message": [ ..parts.. ]},
+//   {"type": "upd-paths-row", "row": { ..path.. }, "old": { ..path.. }},
+//   {"type": "del-paths-row", "path": "/spaces/..", "timestamp": 1688763457727},
+//   {"type": "del-peers-row", "path": "/spaces/..", "ship": "~zod", "timestamp": 1688763457727},
+//   {"type": "del-messages-row", "path": "/spaces/..", "msg-id": "/~2023..", "timestamp": 1688763457727}
+// ]
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum DbChangeType {
+    AddRow(DbRow),
+    UpdMessages {
+        #[serde(rename = "msg-id")]
+        msg_id: String,
+        message: Vec<ChatMessage>,
+    },
+    UpdPathsRow {
+        row: PathRow,
+        old: PathRow,
+    },
+    DelPathsRow {
+        path: String,
+        timestamp: u64,
+    },
+    DelPeersRow {
+        path: String,
+        ship: String,
+        timestamp: u64,
+    },
+    DelMessagesRow {
+        path: String,
+        #[serde(rename = "msg-id")]
+        msg_id: String,
+        timestamp: u64,
+    },
+    /// changes added to chat-db after this was written
+    #[serde(other)]
+    Unknown,
+}
+
+pub type DbChange = Vec<DbChangeType>;
+
+/// position of a message part in the `created-at` ordering of `/hol/chat/messages`.
+///  parts created in the same millisecond are ordered by path, msg-id and part id
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct MessageCursor {
+    pub created_at: u64,
+    pub path: String,
+    pub msg_id: String,
+    pub msg_part_id: u64,
+}
+
+impl MessageCursor {
+    pub fn of(msg: &ChatMessage) -> MessageCursor {
+        MessageCursor {
+            created_at: msg.created_at,
+            path: msg.path.clone(),
+            msg_id: msg.msg_id.clone(),
+            msg_part_id: msg.msg_part_id,
+        }
+    }
+
+    /// an opaque, url safe token (hex encoded json)
+    pub fn encode(&self) -> String {
+        let json =
+            serde_json::to_string(&(self.created_at, &self.path, &self.msg_id, self.msg_part_id))
+                .unwrap_or_default();
+        json.bytes().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    pub fn decode(token: &str) -> Option<MessageCursor> {
+        let bytes = (0..token.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(token.get(i..i + 2)?, 16).ok())
+            .collect::<Option<Vec<u8>>>()?;
+        let (created_at, path, msg_id, msg_part_id) = serde_json::from_slice(&bytes).ok()?;
+        Some(MessageCursor {
+            created_at,
+            path,
+            msg_id,
+            msg_part_id,
+        })
+    }
+}
+
+/// a page of `/hol/chat/messages`
+#[derive(Debug, Deserialize, Serialize)]
+pub struct MessagePage {
+    pub messages: Vec<ChatMessage>,
+    /// pass as `before` (or `after`, when paging forward) to get the next page. null
+    ///  once there are no more messages
+    pub next: Option<String>,
+}
+
+/// a `/hol/chat/search` hit
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SearchResult {
+    pub message: ChatMessage,
+    /// the matching part of the content, with matched words between <mark> and </mark>
+    pub snippet: String,
+    /// bm25 score. lower is a better match
+    pub rank: f64,
+}
+
+/// a message reassembled from its chat-db parts
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Message {
+    pub id: String,
+    pub path: String,
+    pub sender: String,
+    /// in part order
+    pub parts: Vec<Part>,
+    /// oldest first
+    pub reactions: Vec<Reaction>,
+    pub reply_to: Option<MsgRef>,
+    pub created_at: u64,
+    /// latest edit of any part
+    pub updated_at: u64,
+    pub expires_at: Option<u64>,
+    /// replies to this message (and their replies). only filled in for thread views
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub replies: Vec<Message>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Part {
+    pub id: u64,
+    pub content_type: String,
+    pub content_data: String,
+    pub metadata: JsonValue,
+}
+
+/// a `react` message folded onto its target
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Reaction {
+    /// id of the react message itself
+    pub msg_id: String,
+    pub sender: String,
+    /// the emoji, as a unicode code point string (e.g. 2764-fe0f)
+    pub emoji: String,
+    pub created_at: u64,
+}
+
+/// a page of reassembled messages. see `MessagePage`
+#[derive(Debug, Deserialize, Serialize)]
+pub struct MessageList {
+    pub messages: Vec<Message>,
+    pub next: Option<String>,
+}
+
+/// body of `POST /hol/chat/messages`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct NewMessage {
+    pub path: String,
+    pub parts: Vec<NewPart>,
+    #[serde(default)]
+    pub reply_to: Option<MsgRef>,
+    /// ms until the message expires. messages do not expire by default
+    #[serde(default)]
+    pub expires_in: Option<u64>,
+}
+
+/// a part of a message being sent or edited
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct NewPart {
+    pub content_type: String,
+    pub content_data: String,
+    #[serde(default = "empty_metadata")]
+    pub metadata: JsonValue,
+}
+
+fn empty_metadata() -> JsonValue {
+    JsonValue::Object(Default::default())
+}
+
+/// body of `PATCH /hol/chat/messages`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct EditMessage {
+    pub parts: Vec<NewPart>,
+}
+
+/// body of `POST /hol/chat/reactions`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct NewReaction {
+    pub path: String,
+    /// the message reacted to
+    pub msg_id: String,
+    pub emoji: String,
 }
@@ -0,0 +1,311 @@
+//!
+//! chat writes made through the REST api
+//!
+//! each write is a `realm-chat` poke on the sync channel (see `sync`). the change is
+//!  applied to the chat tables right away, so that clients see it before the ship has
+//!  answered, and recorded in `chat_pending`. the ship confirms it by reporting the same
+//!  change on the chat-db watch; if it refuses the poke, or never confirms it, the change
+//!  is rolled back.
+//!
+//! a sent message is stored under a provisional id (`/~pending.<poke id>/~ship`) until
+//!  the ship's copy, with the id the ship gave it, replaces it.
+//!
+use anyhow::Error;
+use rusqlite::Connection;
+use serde_json::{json, Value as JsonValue};
+
+use crate::context::CallContext;
+use crate::helper::get_current_time;
+
+use super::data::{self, PendingKind};
+use super::events::{self, Changes};
+use super::sync;
+use super::types::{ChatMessage, Message, MsgRef, NewMessage, NewPart, NewReaction};
+
+const APP: &str = "realm-chat";
+const MARK: &str = "chat-action";
+
+#[derive(Debug)]
+pub enum WriteError {
+    /// the sync channel is not open (yet)
+    NotConnected,
+    /// no such message
+    NotFound,
+    /// the message has not been confirmed by the ship, so the ship does not know it
+    Provisional,
+    /// the poke could not be delivered. the change was rolled back
+    Ship(Error),
+    Db(Error),
+}
+
+pub type WriteResult<T> = Result<T, WriteError>;
+
+fn db<E: Into<Error>>(e: E) -> WriteError {
+    WriteError::Db(e.into())
+}
+
+/// send a message
+pub async fn send(ctx: &CallContext, msg: &NewMessage) -> WriteResult<Message> {
+    let sender = sync::our_ship(ctx).await.ok_or(WriteError::NotConnected)?;
+    let poke_id = sync::next_poke_id();
+    let msg_id = format!("/~pending.{}/{}", poke_id, sender);
+    let now = get_current_time();
+
+    let reply_to = serde_json::to_value(&msg.reply_to).map_err(db)?;
+    let expires_at = msg
+        .expires_in
+        // the client picks `expires_in`: keep the sum in the range sqlite can store
+        .map(|ms| json!(now.saturating_add(ms).min(i64::MAX as u64)))
+        .unwrap_or(JsonValue::Null);
+    let parts: Vec<ChatMessage> = msg
+        .parts
+        .iter()
+        .enumerate()
+        .map(|(id, part)| ChatMessage {
+            path: msg.path.clone(),
+            msg_id: msg_id.clone(),
+            msg_part_id: id as u64,
+            content_type: part.content_type.clone(),
+            content_data: part.content_data.clone(),
+            reply_to: reply_to.clone(),
+            metadata: part.metadata.clone(),
+            sender: sender.clone(),
+            created_at: now,
+            updated_at: now,
+            received_at: now,
+            expires_at: expires_at.clone(),
+        })
+        .collect();
+
+    let message = apply(
+        ctx,
+        poke_id,
+        PendingKind::Send,
+        &msg.path,
+        &msg_id,
+        |conn, changes| {
+            if let Some(first) = parts.first() {
+                changes.touch_row(conn, first).map_err(db)?;
+            }
+            for part in &parts {
+                data::save_message(conn, part).map_err(db)?;
+            }
+            Ok(Vec::new())
+        },
+    )?;
+    let action = json!({ "send-message": {
+        "path": msg.path,
+        "fragments": fragments(&msg.parts, &reply_to),
+        "expires-in": msg.expires_in,
+    }});
+    deliver(ctx, poke_id, action).await?;
+    Ok(message)
+}
+
+/// replace the parts of a message
+pub async fn edit(
+    ctx: &CallContext,
+    path: &str,
+    msg_id: &str,
+    parts: &[NewPart],
+) -> WriteResult<Message> {
+    if sync::our_ship(ctx).await.is_none() {
+        return Err(WriteError::NotConnected);
+    }
+    let poke_id = sync::next_poke_id();
+    let mut reply_to = JsonValue::Null;
+    let message = apply(
+        ctx,
+        poke_id,
+        PendingKind::Edit,
+        path,
+        msg_id,
+        |conn, changes| {
+            let previous = existing(conn, path, msg_id)?;
+            changes.touch(conn, path, msg_id).map_err(db)?;
+            let first = &previous[0];
+            reply_to = first.reply_to.clone();
+            // the ship's version of the edit replaces this one, so it keeps the stored
+            //  updated-at rather than the node's clock
+            let updated_at = previous
+                .iter()
+                .map(|part| part.updated_at)
+                .max()
+                .unwrap_or(0);
+            let edited: Vec<ChatMessage> = parts
+                .iter()
+                .enumerate()
+                .map(|(id, part)| ChatMessage {
+                    path: path.to_string(),
+                    msg_id: msg_id.to_string(),
+                    msg_part_id: id as u64,
+                    content_type: part.content_type.clone(),
+                    content_data: part.content_data.clone(),
+                    reply_to: first.reply_to.clone(),
+                    metadata: part.metadata.clone(),
+                    sender: first.sender.clone(),
+                    created_at: first.created_at,
+                    updated_at,
+                    received_at: first.received_at,
+                    expires_at: first.expires_at.clone(),
+                })
+                .collect();
+            data::remove_message(conn, path, msg_id).map_err(db)?;
+            for part in &edited {
+                data::save_message(conn, part).map_err(db)?;
+            }
+            Ok(previous)
+        },
+    )?;
+    let action = json!({ "edit-message": {
+        "msg-id": msg_id,
+        "path": path,
+        "fragments": fragments(parts, &reply_to),
+    }});
+    deliver(ctx, poke_id, action).await?;
+    Ok(message)
+}
+
+/// delete a message
+pub async fn delete(ctx: &CallContext, path: &str, msg_id: &str) -> WriteResult<()> {
+    if sync::our_ship(ctx).await.is_none() {
+        return Err(WriteError::NotConnected);
+    }
+    let poke_id = sync::next_poke_id();
+    remove(ctx, poke_id, path, msg_id)?;
+    let action = json!({ "delete-message": { "path": path, "msg-id": msg_id } });
+    deliver(ctx, poke_id, action).await
+}
+
+/// react to a message. returns the message with the reaction folded on
+pub async fn react(ctx: &CallContext, reaction: &NewReaction) -> WriteResult<Message> {
+    {
+        let conn = ctx.db.pool.get_conn().map_err(db)?;
+        existing(&conn, &reaction.path, &reaction.msg_id)?;
+    }
+    let msg = NewMessage {
+        path: reaction.path.clone(),
+        parts: vec![NewPart {
+            content_type: "react".to_string(),
+            content_data: reaction.emoji.clone(),
+            metadata: json!({}),
+        }],
+        reply_to: Some(MsgRef {
+            msg_id: reaction.msg_id.clone(),
+            path: reaction.path.clone(),
+        }),
+        expires_in: None,
+    };
+    send(ctx, &msg).await?;
+    let conn = ctx.db.pool.get_conn().map_err(db)?;
+    data::load_message(&conn, &reaction.path, &reaction.msg_id)
+        .map_err(db)?
+        .ok_or(WriteError::NotFound)
+}
+
+/// take back our `emoji` reaction to a message
+pub async fn unreact(ctx: &CallContext, path: &str, msg_id: &str, emoji: &str) -> WriteResult<()> {
+    let sender = sync::our_ship(ctx).await.ok_or(WriteError::NotConnected)?;
+    let react_id = {
+        let conn = ctx.db.pool.get_conn().map_err(db)?;
+        data::find_reaction(&conn, path, msg_id, &sender, emoji).map_err(db)?
+    };
+    match react_id {
+        Some(react_id) => delete(ctx, path, &react_id).await,
+        None => Err(WriteError::NotFound),
+    }
+}
+
+// the parts of a message the ship knows about
+fn existing(conn: &Connection, path: &str, msg_id: &str) -> WriteResult<Vec<ChatMessage>> {
+    if data::is_provisional(conn, path, msg_id).map_err(db)? {
+        return Err(WriteError::Provisional);
+    }
+    let parts = data::message_parts(conn, path, msg_id).map_err(db)?;
+    if parts.is_empty() {
+        return Err(WriteError::NotFound);
+    }
+    Ok(parts)
+}
+
+// remove a message ahead of the ship and record it as pending
+fn remove(ctx: &CallContext, poke_id: u64, path: &str, msg_id: &str) -> WriteResult<()> {
+    let mut conn = ctx.db.pool.get_writer().map_err(db)?;
+    let tx = conn.transaction().map_err(db)?;
+    let previous = existing(&tx, path, msg_id)?;
+    let mut changes = Changes::new();
+    changes.touch(&tx, path, msg_id).map_err(db)?;
+    data::remove_message(&tx, path, msg_id).map_err(db)?;
+    let now = get_current_time();
+    data::save_pending(
+        &tx,
+        poke_id,
+        PendingKind::Delete,
+        path,
+        msg_id,
+        &previous,
+        now,
+    )
+    .map_err(db)?;
+    let events = changes.events(&tx).map_err(db)?;
+    tx.commit().map_err(db)?;
+    events::publish(ctx, events);
+    Ok(())
+}
+
+// make a change ahead of the ship and record it as pending. `change` notes the messages
+//  it touches and returns the rows it replaced. returns the message as changed
+fn apply<F>(
+    ctx: &CallContext,
+    poke_id: u64,
+    kind: PendingKind,
+    path: &str,
+    msg_id: &str,
+    change: F,
+) -> WriteResult<Message>
+where
+    F: FnOnce(&Connection, &mut Changes) -> WriteResult<Vec<ChatMessage>>,
+{
+    let mut conn = ctx.db.pool.get_writer().map_err(db)?;
+    let tx = conn.transaction().map_err(db)?;
+    let mut changes = Changes::new();
+    let previous = change(&tx, &mut changes)?;
+    let now = get_current_time();
+    data::save_pending(&tx, poke_id, kind, path, msg_id, &previous, now).map_err(db)?;
+    let message = data::load_message(&tx, path, msg_id)
+        .map_err(db)?
+        .ok_or(WriteError::NotFound)?;
+    let events = changes.events(&tx).map_err(db)?;
+    tx.commit().map_err(db)?;
+    events::publish(ctx, events);
+    Ok(message)
+}
+
+// poke the ship. if the poke cannot be delivered, the change made ahead of it is undone
+async fn deliver(ctx: &CallContext, poke_id: u64, action: JsonValue) -> WriteResult<()> {
+    let Err(e) = sync::poke(ctx, poke_id, APP, MARK, action).await else {
+        return Ok(());
+    };
+    let mut conn = ctx.db.pool.get_writer().map_err(db)?;
+    let tx = conn.transaction().map_err(db)?;
+    let mut changes = Changes::new();
+    data::rollback_pending(&tx, poke_id, &mut changes).map_err(db)?;
+    let events = changes.events(&tx).map_err(db)?;
+    tx.commit().map_err(db)?;
+    events::publish(ctx, events);
+    Err(WriteError::Ship(e))
+}
+
+// realm-chat message fragments: one per part, each with the content keyed by its type
+fn fragments(parts: &[NewPart], reply_to: &JsonValue) -> JsonValue {
+    parts
+        .iter()
+        .map(|part| {
+            json!({
+                "content": { part.content_type.as_str(): part.content_data },
+                "reply-to": reply_to,
+                "metadata": part.metadata,
+            })
+        })
+        .collect()
+}
@@ -1,10 +1,11 @@
 use std::sync::Arc;
 
 use crate::api::Ship;
+use crate::chat::events::ChatEvent;
 use crate::db::Db;
 use crossbeam::channel::{Receiver, Sender};
 use serde_json::Value as JsonValue;
-use tokio::sync::Mutex;
+use tokio::sync::{broadcast, Mutex};
 
 ///
 /// NOTE: We need at least two ship event receivers:
@@ -23,13 +24,26 @@ pub struct NodeContext {
     //
     pub db: Db,
 
-    ///
+    //
     // need Arc::Mutex since cookie expiration retries need to modify internal Ship
     //  state (e.g. update session_auth data in Ship instance). Arc::Mutex allows
     //  mutable references which is necessary for modifying internal Ship instance state.
     //
     pub ship: Arc<Mutex<Ship>>,
 
+    //
+    //  the ship bound to the node's own channel (see chat::sync). it is None until the
+    //  chat sync starts. pokes made on behalf of REST clients go out on this channel so
+    //  that their acks come back to the node instead of to connected devices
+    //
+    pub sync_ship: Arc<Mutex<Option<Ship>>>,
+
+    //
+    //  changes to the chat tables, as events for connected devices (see chat::events).
+    //  create with chat::events::channel()
+    //
+    pub chat_events: broadcast::Sender<ChatEvent>,
+
     //
     //  the unbounded sender/receiver pair defined here are to facilitate the flow
     //  of events coming in from the Urbit ship (EventSource) to the connected
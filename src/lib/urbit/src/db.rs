@@ -1,22 +1,83 @@
 use anyhow::Result;
+use bedrock_db::migrations::migrate;
 use bedrock_db::DbPool;
 use serde_json::Value as JsonValue;
 use std::time::SystemTime;
 
+use crate::eyre::Action;
+use trace::{trace_err_ln, trace_info_ln};
+
 // pub trait DbApi {
 //     fn save_packet(&self, packet: &JsonValue) -> Result<()>;
 // }
 
+/// maps the holon managed id of an action relayed to the ship back to the device
+///  (and the device's own id for the action) so that the ship's response can be routed
+#[derive(Debug, Clone, PartialEq)]
+pub struct MsgEntry {
+    // unique message id managed by the holon
+    pub id: u64,
+    // id of the message originating on the device (e.g. urbit action message id)
+    pub source_id: u64,
+    // id of connected device (key into the DeviceMap)
+    pub device_id: usize,
+    // when the entry is dropped if the ship has not responded (ms since epoch)
+    pub expires_at: u64,
+}
+
+/// a device action that could not be posted to the ship, waiting to be replayed
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutboxEntry {
+    // position in the outbox (assigned by the db)
+    pub id: i64,
+    // the action as it is to be posted to the ship
+    pub action: Action,
+    // id of the device that sent the action
+    pub device_id: usize,
+    // id of the action as sent by the device. None for actions made by the node
+    //  (e.g. upstream subscriptions)
+    pub source_id: Option<u64>,
+    // when the action was queued (ms since epoch)
+    pub queued_at: u64,
+}
+
+/// the node's channel on its ship (see sub.rs), kept so that a restarted node can
+///  resume it instead of opening a new one
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelEntry {
+    // url of the channel
+    pub url: String,
+    // id of the last event acked back to the ship
+    pub last_ack: Option<u64>,
+}
+
 #[derive(Debug)]
 pub struct Db {
     pub pool: DbPool,
 }
 
 impl Db {
+    /// bring the schema up to date. every module that owns tables registers its
+    ///  migrations here
+    pub fn migrate(&self) -> Result<()> {
+        for (module, migrations) in [
+            ("bedrock", bedrock_db::migrations::MIGRATIONS),
+            ("chat", crate::chat::MIGRATIONS),
+            ("ws", crate::ws::MIGRATIONS),
+            ("media", crate::media::MIGRATIONS),
+        ] {
+            let applied = migrate(&self.pool, module, migrations)?;
+            if !applied.is_empty() {
+                trace_info_ln!("db: [migrate] {} migrated to {:?}", module, applied);
+            }
+        }
+        Ok(())
+    }
+
     pub fn save_packet(&self, source: &str, packet: &JsonValue) -> Result<()> {
         let ts = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
         let ts: u128 = ts.as_millis();
-        let conn = self.pool.get_conn()?;
+        let conn = self.pool.get_writer()?;
         let mut stmt = conn.prepare(
             "INSERT INTO packets (
               source,
@@ -31,4 +92,164 @@ impl Db {
         stmt.execute((source, packet, ts as i64))?;
         Ok(())
     }
+
+    pub fn save_message(&self, entry: &MsgEntry) -> Result<()> {
+        let conn = self.pool.get_writer()?;
+        conn.execute(
+            "REPLACE INTO message_store (
+              id,
+              source_id,
+              device_id,
+              expires_at
+            ) VALUES (
+              ?1,
+              ?2,
+              ?3,
+              ?4
+            )",
+            (
+                entry.id as i64,
+                entry.source_id as i64,
+                entry.device_id as i64,
+                entry.expires_at as i64,
+            ),
+        )?;
+        Ok(())
+    }
+
+    pub fn delete_message(&self, id: u64) -> Result<()> {
+        let conn = self.pool.get_writer()?;
+        conn.execute("DELETE FROM message_store WHERE id = ?1", [id as i64])?;
+        Ok(())
+    }
+
+    /// every message still waiting on a ship response
+    pub fn load_messages(&self) -> Result<Vec<MsgEntry>> {
+        let conn = self.pool.get_conn()?;
+        let mut stmt =
+            conn.prepare("SELECT id, source_id, device_id, expires_at FROM message_store")?;
+        let rows = stmt.query_map([], |row| {
+            Ok(MsgEntry {
+                id: row.get::<_, i64>(0)? as u64,
+                source_id: row.get::<_, i64>(1)? as u64,
+                device_id: row.get::<_, i64>(2)? as usize,
+                expires_at: row.get::<_, i64>(3)? as u64,
+            })
+        })?;
+        let mut entries = Vec::new();
+        for row in rows {
+            entries.push(row?);
+        }
+        Ok(entries)
+    }
+
+    /// append an action to the outbox. the entry's id is ignored
+    pub fn queue_action(&self, entry: &OutboxEntry) -> Result<()> {
+        let conn = self.pool.get_writer()?;
+        conn.execute(
+            "INSERT INTO outbox (
+              action,
+              device_id,
+              source_id,
+              queued_at
+            ) VALUES (
+              ?1,
+              ?2,
+              ?3,
+              ?4
+            )",
+            (
+                serde_json::to_string(&entry.action)?,
+                entry.device_id as i64,
+                entry.source_id.map(|id| id as i64),
+                entry.queued_at as i64,
+            ),
+        )?;
+        Ok(())
+    }
+
+    /// every queued action, oldest first. an action that cannot be read back can never
+    ///  be replayed: it is logged and dropped, and the others are still answered
+    pub fn load_outbox(&self) -> Result<Vec<OutboxEntry>> {
+        let mut rows = Vec::new();
+        {
+            let conn = self.pool.get_conn()?;
+            let mut stmt = conn.prepare(
+                "SELECT id, action, device_id, source_id, queued_at FROM outbox ORDER BY id",
+            )?;
+            let results = stmt.query_map([], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, i64>(2)?,
+                    row.get::<_, Option<i64>>(3)?,
+                    row.get::<_, i64>(4)?,
+                ))
+            })?;
+            for row in results {
+                rows.push(row?);
+            }
+        }
+        let mut entries = Vec::new();
+        for (id, action, device_id, source_id, queued_at) in rows {
+            let action = match serde_json::from_str(&action) {
+                Ok(action) => action,
+                Err(e) => {
+                    trace_err_ln!("dropping unreadable outbox entry {}. {}", id, e);
+                    self.delete_outbox_entry(id)?;
+                    continue;
+                }
+            };
+            entries.push(OutboxEntry {
+                id,
+                action,
+                device_id: device_id as usize,
+                source_id: source_id.map(|id| id as u64),
+                queued_at: queued_at as u64,
+            });
+        }
+        Ok(entries)
+    }
+
+    pub fn outbox_len(&self) -> Result<usize> {
+        let conn = self.pool.get_conn()?;
+        let count: i64 = conn.query_row("SELECT COUNT(*) FROM outbox", [], |row| row.get(0))?;
+        Ok(count as usize)
+    }
+
+    pub fn delete_outbox_entry(&self, id: i64) -> Result<()> {
+        let conn = self.pool.get_writer()?;
+        conn.execute("DELETE FROM outbox WHERE id = ?1", [id])?;
+        Ok(())
+    }
+
+    pub fn save_channel(&self, entry: &ChannelEntry) -> Result<()> {
+        let conn = self.pool.get_writer()?;
+        conn.execute(
+            "REPLACE INTO node_channel (
+              id,
+              url,
+              last_ack
+            ) VALUES (
+              1,
+              ?1,
+              ?2
+            )",
+            (entry.url.as_str(), entry.last_ack.map(|id| id as i64)),
+        )?;
+        Ok(())
+    }
+
+    /// the channel the node was last using, if any
+    pub fn load_channel(&self) -> Result<Option<ChannelEntry>> {
+        let conn = self.pool.get_conn()?;
+        let mut stmt = conn.prepare("SELECT url, last_ack FROM node_channel WHERE id = 1")?;
+        let mut rows = stmt.query_map([], |row| {
+            Ok(ChannelEntry {
+                url: row.get(0)?,
+                last_ack: row.get::<_, Option<i64>>(1)?.map(|id| id as u64),
+            })
+        })?;
+        Ok(rows.next().transpose()?)
+    }
 }
@@ -11,6 +11,13 @@ pub enum UrbitAPIError {
     FailedToCreateNewChannel,
     #[error("Failed to create a new subscription.")]
     FailedToCreateNewSubscription,
+    // channel requests must contain at least one action
+    #[error("No channel actions provided.")]
+    EmptyActions,
+    #[error("Invalid channel action. {0}")]
+    InvalidAction(String),
+    #[error("Invalid channel event. {0}")]
+    InvalidEvent(String),
     // TODO add Holium errors
     //
     #[error("{0}")]
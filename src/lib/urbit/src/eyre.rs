@@ -0,0 +1,281 @@
+//!
+//! typed model of the Eyre channel protocol
+//!
+//! @see: https://developers.urbit.org/reference/arvo/eyre/external-api-ref
+//!
+//! actions are sent to the ship as a json array via PUT/POST `/~/channel/{uid}`. events
+//!  come back from the ship over the channel's SSE stream. both validate on deserialize,
+//!  so anything that makes it past `serde_json` is a well formed action/event.
+//!
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+
+use crate::error::{Result, UrbitAPIError};
+
+// @see: https://developers.urbit.org/reference/arvo/eyre/external-api-ref#actions
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "action", rename_all = "lowercase")]
+pub enum Action {
+    Poke {
+        id: u64,
+        ship: String,
+        app: String,
+        mark: String,
+        json: JsonValue,
+    },
+    Subscribe {
+        id: u64,
+        ship: String,
+        app: String,
+        path: String,
+    },
+    Unsubscribe {
+        id: u64,
+        // id of the subscribe action being cancelled
+        subscription: u64,
+    },
+    Ack {
+        id: u64,
+        #[serde(rename = "event-id")]
+        event_id: u64,
+    },
+    Delete {
+        id: u64,
+    },
+}
+
+impl Action {
+    pub fn id(&self) -> u64 {
+        match self {
+            Action::Poke { id, .. }
+            | Action::Subscribe { id, .. }
+            | Action::Unsubscribe { id, .. }
+            | Action::Ack { id, .. }
+            | Action::Delete { id } => *id,
+        }
+    }
+}
+
+/// parse a channel request body (json array of actions). an empty array is an error
+///  since there is nothing to relay to the ship
+pub fn parse_actions(data: &str) -> Result<Vec<Action>> {
+    let actions: Vec<Action> =
+        serde_json::from_str(data).map_err(|e| UrbitAPIError::InvalidAction(e.to_string()))?;
+    if actions.is_empty() {
+        return Err(UrbitAPIError::EmptyActions);
+    }
+    Ok(actions)
+}
+
+// @see: https://developers.urbit.org/reference/arvo/eyre/external-api-ref#responses
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "Response", into = "Response")]
+pub enum Event {
+    PokeAck { id: u64 },
+    PokeNack { id: u64, err: String },
+    WatchAck { id: u64 },
+    WatchNack { id: u64, err: String },
+    Fact { id: u64, json: JsonValue },
+    Kick { id: u64 },
+}
+
+impl Event {
+    /// id of the action (poke/subscribe) this event responds to
+    pub fn id(&self) -> u64 {
+        match self {
+            Event::PokeAck { id }
+            | Event::PokeNack { id, .. }
+            | Event::WatchAck { id }
+            | Event::WatchNack { id, .. }
+            | Event::Fact { id, .. }
+            | Event::Kick { id } => *id,
+        }
+    }
+
+    pub fn set_id(&mut self, new_id: u64) {
+        match self {
+            Event::PokeAck { id }
+            | Event::PokeNack { id, .. }
+            | Event::WatchAck { id }
+            | Event::WatchNack { id, .. }
+            | Event::Fact { id, .. }
+            | Event::Kick { id } => *id = new_id,
+        }
+    }
+}
+
+// wire format of an event. eyre delivers all flavors of response in this one shape,
+//  where the fields present depend on the `response` value
+#[derive(Debug, Deserialize, Serialize)]
+struct Response {
+    id: u64,
+    response: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ok: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    err: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    json: Option<JsonValue>,
+}
+
+impl Response {
+    fn new(id: u64, response: &str) -> Response {
+        Response {
+            id,
+            response: response.to_string(),
+            ok: None,
+            err: None,
+            json: None,
+        }
+    }
+}
+
+impl TryFrom<Response> for Event {
+    type Error = UrbitAPIError;
+
+    fn try_from(resp: Response) -> Result<Event> {
+        let id = resp.id;
+        match (resp.response.as_str(), resp.ok, resp.err, resp.json) {
+            ("poke", Some(_), None, _) => Ok(Event::PokeAck { id }),
+            ("poke", None, Some(err), _) => Ok(Event::PokeNack { id, err }),
+            ("subscribe", Some(_), None, _) => Ok(Event::WatchAck { id }),
+            ("subscribe", None, Some(err), _) => Ok(Event::WatchNack { id, err }),
+            ("diff", _, _, Some(json)) => Ok(Event::Fact { id, json }),
+            ("quit", _, _, _) => Ok(Event::Kick { id }),
+            (response, ..) => Err(UrbitAPIError::InvalidEvent(format!(
+                "unexpected '{}' response for id {}",
+                response, id
+            ))),
+        }
+    }
+}
+
+impl From<Event> for Response {
+    fn from(event: Event) -> Response {
+        match event {
+            Event::PokeAck { id } => Response {
+                ok: Some("ok".to_string()),
+                ..Response::new(id, "poke")
+            },
+            Event::PokeNack { id, err } => Response {
+                err: Some(err),
+                ..Response::new(id, "poke")
+            },
+            Event::WatchAck { id } => Response {
+                ok: Some("ok".to_string()),
+                ..Response::new(id, "subscribe")
+            },
+            Event::WatchNack { id, err } => Response {
+                err: Some(err),
+                ..Response::new(id, "subscribe")
+            },
+            Event::Fact { id, json } => Response {
+                json: Some(json),
+                ..Response::new(id, "diff")
+            },
+            Event::Kick { id } => Response::new(id, "quit"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn actions_round_trip() {
+        let data = json!([
+            {"id": 1, "action": "poke", "ship": "zod", "app": "hood", "mark": "helm-hi", "json": "hi"},
+            {"id": 2, "action": "subscribe", "ship": "zod", "app": "chat-db", "path": "/db"},
+            {"id": 3, "action": "unsubscribe", "subscription": 2},
+            {"id": 4, "action": "ack", "event-id": 7},
+            {"id": 5, "action": "delete"},
+        ]);
+        let actions = parse_actions(&data.to_string()).unwrap();
+        assert_eq!(
+            actions.iter().map(Action::id).collect::<Vec<u64>>(),
+            vec![1, 2, 3, 4, 5]
+        );
+        assert_eq!(actions[3], Action::Ack { id: 4, event_id: 7 });
+        assert_eq!(serde_json::to_value(&actions).unwrap(), data);
+    }
+
+    #[test]
+    fn invalid_actions_are_rejected() {
+        assert!(matches!(
+            parse_actions("[]"),
+            Err(UrbitAPIError::EmptyActions)
+        ));
+        // poke without a mark
+        assert!(matches!(
+            parse_actions(
+                r#"[{"id": 1, "action": "poke", "ship": "zod", "app": "hood", "json": 1}]"#
+            ),
+            Err(UrbitAPIError::InvalidAction(_))
+        ));
+        assert!(matches!(
+            parse_actions(r#"[{"id": 1, "action": "scry"}]"#),
+            Err(UrbitAPIError::InvalidAction(_))
+        ));
+        assert!(matches!(
+            parse_actions(r#"{"id": 1, "action": "delete"}"#),
+            Err(UrbitAPIError::InvalidAction(_))
+        ));
+    }
+
+    #[test]
+    fn events_round_trip() {
+        let cases = [
+            (
+                json!({"id": 1, "response": "poke", "ok": "ok"}),
+                Event::PokeAck { id: 1 },
+            ),
+            (
+                json!({"id": 2, "response": "poke", "err": "crash"}),
+                Event::PokeNack {
+                    id: 2,
+                    err: "crash".to_string(),
+                },
+            ),
+            (
+                json!({"id": 3, "response": "subscribe", "ok": "ok"}),
+                Event::WatchAck { id: 3 },
+            ),
+            (
+                json!({"id": 4, "response": "subscribe", "err": "no"}),
+                Event::WatchNack {
+                    id: 4,
+                    err: "no".to_string(),
+                },
+            ),
+            (
+                json!({"id": 5, "response": "diff", "json": {"a": 1}}),
+                Event::Fact {
+                    id: 5,
+                    json: json!({"a": 1}),
+                },
+            ),
+            (json!({"id": 6, "response": "quit"}), Event::Kick { id: 6 }),
+        ];
+        for (data, event) in cases {
+            assert_eq!(
+                serde_json::from_value::<Event>(data.clone()).unwrap(),
+                event
+            );
+            assert_eq!(serde_json::to_value(&event).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn invalid_events_are_rejected() {
+        for data in [
+            json!({"id": 1, "response": "poke"}),
+            json!({"id": 1, "response": "diff"}),
+            json!({"id": 1, "response": "unknown", "ok": "ok"}),
+            json!({"response": "quit"}),
+        ] {
+            assert!(serde_json::from_value::<Event>(data).is_err());
+        }
+    }
+}
@@ -32,14 +32,14 @@ pub fn index_dec_to_ud(index: &str) -> String {
     let index_split: Vec<&str> = index.split("/").collect();
     let mut udindex = String::new();
     // Handle each segment
-    for i in 0..index_split.len() {
-        if index_split[i].len() > 0 {
-            let mut rev: String = index_split[i].chars().rev().collect();
+    for segment in index_split {
+        if !segment.is_empty() {
+            let mut rev: String = segment.chars().rev().collect();
             let mut out = String::new();
             while rev.len() >= 3 {
                 let chunk: String = rev.drain(..3).collect();
                 out += &chunk;
-                if rev.len() > 0 {
+                if !rev.is_empty() {
                     out += ".";
                 }
             }
@@ -0,0 +1,202 @@
+//!
+//! holon messages: the node's own pub/sub protocol on `/ws`
+//!
+//! eyre actions (json arrays) are relayed to the ship. a json object is a holon message,
+//!  handled by the node itself:
+//!
+//!   {"type": "subscribe", "topic": "chat", "path": "/spaces/~zod/chats/0v1", "id": 1}
+//!   {"type": "unsubscribe", "topic": "chat", "path": "/spaces/~zod/chats/0v1", "id": 2}
+//!
+//! without a `path` the subscription covers every path. `id` is optional and echoed back
+//!  in the reply ({"type": "subscribed"|"unsubscribed", ...}). subscribers then receive
+//!  the topic's events as the node's tables change:
+//!
+//!   {"type": "event", "topic": "chat", "event": "chat.message.added", "path": ..., "message": {...}}
+//!   {"type": "event", "topic": "chat", "event": "chat.message.updated", "path": ..., "message": {...}}
+//!   {"type": "event", "topic": "chat", "event": "chat.message.deleted", "path": ..., "msg-id": ...}
+//!
+//! the events are the node's own schema (see `chat::events`), so they stay the same when
+//!  the ship's marks change.
+//!
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value as JsonValue};
+
+use crate::chat::events::ChatEvent;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Topic {
+    Chat,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum HolonMessage {
+    Subscribe {
+        topic: Topic,
+        path: Option<String>,
+        id: Option<JsonValue>,
+    },
+    Unsubscribe {
+        topic: Topic,
+        path: Option<String>,
+        id: Option<JsonValue>,
+    },
+}
+
+/// parse a holon message. the error says what is wrong with it
+pub fn parse(packet: &JsonValue) -> Result<HolonMessage, String> {
+    serde_json::from_value(packet.clone()).map_err(|e| e.to_string())
+}
+
+/// the reply to a (successful) holon message
+pub fn reply(msg: &HolonMessage) -> JsonValue {
+    let (kind, topic, path, id) = match msg {
+        HolonMessage::Subscribe { topic, path, id } => ("subscribed", topic, path, id),
+        HolonMessage::Unsubscribe { topic, path, id } => ("unsubscribed", topic, path, id),
+    };
+    json!({ "type": kind, "topic": topic, "path": path, "id": id })
+}
+
+/// a chat event as sent to devices
+pub fn chat_event(event: &ChatEvent) -> JsonValue {
+    let mut msg = serde_json::to_value(event).unwrap_or_default();
+    msg["type"] = json!("event");
+    msg["topic"] = json!(Topic::Chat);
+    msg
+}
+
+/// the topics (and paths) each device is subscribed to
+#[derive(Debug, Default)]
+pub struct HolonSubscriptions {
+    // a path of None covers every path of the topic
+    devices: HashMap<usize, HashSet<(Topic, Option<String>)>>,
+}
+
+impl HolonSubscriptions {
+    pub fn new() -> HolonSubscriptions {
+        HolonSubscriptions::default()
+    }
+
+    /// false if the device was already subscribed
+    pub fn subscribe(&mut self, device_id: usize, topic: Topic, path: Option<String>) -> bool {
+        self.devices
+            .entry(device_id)
+            .or_default()
+            .insert((topic, path))
+    }
+
+    /// false if the device was not subscribed
+    pub fn unsubscribe(&mut self, device_id: usize, topic: Topic, path: Option<String>) -> bool {
+        let Some(subscriptions) = self.devices.get_mut(&device_id) else {
+            return false;
+        };
+        let removed = subscriptions.remove(&(topic, path));
+        if subscriptions.is_empty() {
+            self.devices.remove(&device_id);
+        }
+        removed
+    }
+
+    pub fn remove_device(&mut self, device_id: usize) {
+        self.devices.remove(&device_id);
+    }
+
+    /// devices subscribed to the topic on `path` (or on every path)
+    pub fn subscribers(&self, topic: Topic, path: &str) -> Vec<usize> {
+        let mut devices: Vec<usize> = self
+            .devices
+            .iter()
+            .filter(|(_, subscriptions)| {
+                subscriptions.iter().any(|(sub_topic, sub_path)| {
+                    *sub_topic == topic && sub_path.as_deref().is_none_or(|p| p == path)
+                })
+            })
+            .map(|(device_id, _)| *device_id)
+            .collect();
+        devices.sort_unstable();
+        devices
+    }
+
+    /// every device subscribed to the topic, on any path
+    pub fn topic_subscribers(&self, topic: Topic) -> Vec<usize> {
+        let mut devices: Vec<usize> = self
+            .devices
+            .iter()
+            .filter(|(_, subscriptions)| subscriptions.iter().any(|(t, _)| *t == topic))
+            .map(|(device_id, _)| *device_id)
+            .collect();
+        devices.sort_unstable();
+        devices
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn holon_messages_are_parsed() {
+        assert_eq!(
+            parse(&json!({"type": "subscribe", "topic": "chat", "path": "/a", "id": 1})),
+            Ok(HolonMessage::Subscribe {
+                topic: Topic::Chat,
+                path: Some("/a".to_string()),
+                id: Some(json!(1)),
+            })
+        );
+        let msg = parse(&json!({"type": "unsubscribe", "topic": "chat"})).unwrap();
+        assert_eq!(
+            reply(&msg),
+            json!({"type": "unsubscribed", "topic": "chat", "path": null, "id": null})
+        );
+
+        for bad in [
+            json!({"type": "subscribe", "topic": "rooms"}),
+            json!({"type": "publish", "topic": "chat"}),
+            json!({"topic": "chat"}),
+            json!("subscribe"),
+        ] {
+            assert!(parse(&bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn subscribers_match_on_path() {
+        let mut subs = HolonSubscriptions::new();
+        assert!(subs.subscribe(1, Topic::Chat, Some("/a".to_string())));
+        assert!(!subs.subscribe(1, Topic::Chat, Some("/a".to_string())));
+        subs.subscribe(2, Topic::Chat, None);
+        subs.subscribe(3, Topic::Chat, Some("/b".to_string()));
+
+        assert_eq!(subs.subscribers(Topic::Chat, "/a"), vec![1, 2]);
+        assert_eq!(subs.subscribers(Topic::Chat, "/b"), vec![2, 3]);
+        assert_eq!(subs.topic_subscribers(Topic::Chat), vec![1, 2, 3]);
+
+        assert!(subs.unsubscribe(1, Topic::Chat, Some("/a".to_string())));
+        assert!(!subs.unsubscribe(1, Topic::Chat, Some("/a".to_string())));
+        subs.remove_device(2);
+        assert!(subs.subscribers(Topic::Chat, "/a").is_empty());
+        assert_eq!(subs.subscribers(Topic::Chat, "/b"), vec![3]);
+    }
+
+    #[test]
+    fn chat_events_have_a_stable_shape() {
+        let event = ChatEvent::MessageDeleted {
+            path: "/a".to_string(),
+            msg_id: "/~2023.7.7..19.57.10..a94a/~zod".to_string(),
+        };
+        assert_eq!(
+            chat_event(&event),
+            json!({
+                "type": "event",
+                "topic": "chat",
+                "event": "chat.message.deleted",
+                "path": "/a",
+                "msg-id": "/~2023.7.7..19.57.10..a94a/~zod",
+            })
+        );
+    }
+}
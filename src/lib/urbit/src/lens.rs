@@ -22,9 +22,9 @@ pub async fn send_lens_payload(
 
     if resp.status().is_success() {
         let response: String = resp.json().await.unwrap();
-        return Ok(response);
+        Ok(response)
     } else {
-        return Err(Box::new(resp.error_for_status().unwrap_err()));
+        Err(Box::new(resp.error_for_status().unwrap_err()))
     }
 }
 
@@ -42,11 +42,8 @@ pub async fn get_access_code(server_id: String) -> Result<String, Box<dyn Error>
 
     if !code.is_empty() {
         let code_str = code.to_string();
-        return Ok(code_str);
+        Ok(code_str)
     } else {
-        return Err(Box::new(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            "Failed to get access code",
-        )));
+        Err(Box::new(std::io::Error::other("Failed to get access code")))
     }
 }
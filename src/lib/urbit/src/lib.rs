@@ -16,5 +16,12 @@ pub mod process;
 pub mod api;
 pub mod chat;
 pub mod db;
+pub mod eyre;
+pub mod holon;
+pub mod media;
 pub mod sub;
+pub mod subscriptions;
 pub mod ws;
+
+#[cfg(test)]
+mod testing;
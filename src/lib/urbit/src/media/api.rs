@@ -0,0 +1,205 @@
+use std::convert::Infallible;
+use std::fs;
+
+use serde_json::json;
+use warp::http::{header, Response, StatusCode};
+use warp::{reply, Filter, Reply};
+
+use crate::context::CallContext;
+use crate::helper::get_current_time;
+
+use super::cache::MediaCache;
+use super::fetch::{is_image, is_servable};
+
+use trace::trace_err_ln;
+
+/// clients may keep media this long (s). a url's content rarely changes
+const MAX_AGE: u64 = 24 * 60 * 60;
+
+/// `/hol/media/{hash}` and `/hol/media/{hash}/thumbnail`. the node only serves these
+///  to its ship's sessions (see the node's `check_cookie`)
+///
+/// media is served from the node's origin, which is also the ship's: only the media
+///  types the fetcher keeps are served, never sniffed, and anything but a raster image
+///  is sandboxed, so that a peer's file cannot run as a page of the node
+pub fn media_router(
+    ctx: CallContext,
+    cache: MediaCache,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    let with_cache = warp::any().map(move || (ctx.clone(), cache.clone()));
+
+    // /hol/media/{hash}
+    let media_routes = warp::path!("hol" / "media" / String)
+        .and(warp::get())
+        .and(with_cache.clone())
+        .and_then(
+            |hash: String, (context, cache): (CallContext, MediaCache)| async move {
+                handle_media(context, cache, hash, false).await
+            },
+        );
+
+    // /hol/media/{hash}/thumbnail
+    let thumbnail_routes = warp::path!("hol" / "media" / String / "thumbnail")
+        .and(warp::get())
+        .and(with_cache)
+        .and_then(
+            |hash: String, (context, cache): (CallContext, MediaCache)| async move {
+                handle_media(context, cache, hash, true).await
+            },
+        );
+
+    media_routes.or(thumbnail_routes)
+}
+
+async fn handle_media(
+    context: CallContext,
+    cache: MediaCache,
+    hash: String,
+    thumbnail: bool,
+) -> Result<reply::Response, Infallible> {
+    // hashes are hex: anything else cannot name a file in the cache
+    if hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Ok(not_found());
+    }
+    let entry = match cache.lookup(&context.db, &hash, get_current_time()) {
+        Ok(Some(entry)) => entry,
+        Ok(None) => return Ok(not_found()),
+        Err(e) => {
+            trace_err_ln!("media lookup failed. {}", e);
+            return Ok(server_error());
+        }
+    };
+    let (file, content_type) = match (thumbnail, entry.thumbnail_type) {
+        (false, _) => (cache.file(&hash), entry.content_type),
+        (true, Some(content_type)) => (cache.thumbnail_file(&hash), content_type),
+        (true, None) => return Ok(not_found()),
+    };
+    if !is_servable(&content_type) {
+        return Ok(not_found());
+    }
+    match fs::read(&file) {
+        Ok(bytes) => {
+            let mut response = Response::builder()
+                .header(header::X_CONTENT_TYPE_OPTIONS, "nosniff")
+                .header(
+                    header::CACHE_CONTROL,
+                    format!("private, max-age={}", MAX_AGE),
+                );
+            if !is_image(&content_type) {
+                response = response.header(header::CONTENT_SECURITY_POLICY, "sandbox");
+            }
+            Ok(response
+                .header(header::CONTENT_TYPE, content_type)
+                .body(bytes.into())
+                .unwrap_or_else(|_| server_error()))
+        }
+        Err(e) => {
+            trace_err_ln!("cannot read {}. {}", file.display(), e);
+            Ok(not_found())
+        }
+    }
+}
+
+fn not_found() -> reply::Response {
+    reply::with_status(
+        reply::json(&json!({ "error": "not cached" })),
+        StatusCode::NOT_FOUND,
+    )
+    .into_response()
+}
+
+fn server_error() -> reply::Response {
+    reply::with_status(
+        reply::json(&json!({ "error": "database error" })),
+        StatusCode::INTERNAL_SERVER_ERROR,
+    )
+    .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::media::cache::{hash, Thumbnail};
+    use crate::testing::{run, test_cache, test_context, test_ship};
+
+    #[test]
+    fn cached_media_is_served_with_its_content_type() {
+        run(async {
+            let (_eyre, ship) = test_ship().await;
+            let ctx = test_context(ship);
+            let cache = test_cache(1 << 20);
+            let thumbnail = Thumbnail {
+                content_type: "image/jpeg",
+                bytes: b"small".to_vec(),
+            };
+            let image = hash("https://holium.com/a.png");
+            let video = hash("https://holium.com/a.mp4");
+            let svg = hash("https://holium.com/a.svg");
+            cache
+                .store(
+                    &ctx.db,
+                    "https://holium.com/a.png",
+                    "image/png",
+                    b"large",
+                    Some(&thumbnail),
+                    1,
+                )
+                .unwrap();
+            cache
+                .store(
+                    &ctx.db,
+                    "https://holium.com/a.mp4",
+                    "video/mp4",
+                    b"moving",
+                    None,
+                    1,
+                )
+                .unwrap();
+            // cached before only media types were kept
+            cache
+                .store(
+                    &ctx.db,
+                    "https://holium.com/a.svg",
+                    "image/svg+xml",
+                    b"<svg><script/></svg>",
+                    None,
+                    1,
+                )
+                .unwrap();
+            let routes = media_router(ctx.clone(), cache.clone());
+            let get = |path: String| {
+                let routes = routes.clone();
+                async move { warp::test::request().path(&path).reply(&routes).await }
+            };
+
+            let res = get(format!("/hol/media/{}", image)).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(res.headers()["content-type"], "image/png");
+            assert_eq!(res.body().as_ref(), b"large");
+            assert_eq!(res.headers()["x-content-type-options"], "nosniff");
+            assert!(!res.headers().contains_key("content-security-policy"));
+
+            let res = get(format!("/hol/media/{}/thumbnail", image)).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(res.headers()["content-type"], "image/jpeg");
+            assert_eq!(res.body().as_ref(), b"small");
+
+            let res = get(format!("/hol/media/{}", video)).await;
+            assert_eq!(res.headers()["content-type"], "video/mp4");
+            assert_eq!(res.headers()["x-content-type-options"], "nosniff");
+            assert_eq!(res.headers()["content-security-policy"], "sandbox");
+            // no thumbnails for anything but images
+            let res = get(format!("/hol/media/{}/thumbnail", video)).await;
+            assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+            for path in [
+                format!("/hol/media/{}", hash("https://holium.com/b.png")),
+                format!("/hol/media/{}", svg),
+                "/hol/media/..%2F..%2Fdb".to_string(),
+            ] {
+                assert_eq!(get(path).await.status(), StatusCode::NOT_FOUND);
+            }
+            std::fs::remove_dir_all(cache.dir()).unwrap();
+        })
+    }
+}
@@ -0,0 +1,350 @@
+//!
+//! the media files and their `media` rows
+//!
+//! a file is written (under a temporary name, then renamed) before its row, so a `ready`
+//!  row always has its file. evicting or pruning deletes the row first, then the file.
+//!
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::db::Db;
+
+use trace::trace_err_ln;
+
+/// failed fetches are tried again once this long has passed (ms)
+const RETRY_MS: u64 = 60 * 60 * 1000;
+
+#[derive(Debug, Clone)]
+pub struct MediaCache {
+    dir: PathBuf,
+    /// files (and thumbnails) are evicted once they add up to more than this
+    max_bytes: u64,
+    /// larger media is not cached
+    max_item_bytes: u64,
+}
+
+/// a cached url
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct MediaEntry {
+    pub hash: String,
+    pub url: String,
+    pub content_type: String,
+    pub size: u64,
+    pub thumbnail_type: Option<String>,
+    pub fetched_at: u64,
+}
+
+/// a small version of an image
+#[derive(Debug, Clone)]
+pub struct Thumbnail {
+    pub content_type: &'static str,
+    pub bytes: Vec<u8>,
+}
+
+/// the cache key of a url: its sha-256, in hex
+pub fn hash(url: &str) -> String {
+    Sha256::digest(url.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+impl MediaCache {
+    /// a cache in `dir` (created if need be)
+    pub fn new(dir: &Path, max_bytes: u64, max_item_bytes: u64) -> Result<MediaCache> {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("media: [cache] cannot create {}", dir.display()))?;
+        Ok(MediaCache {
+            dir: dir.to_path_buf(),
+            max_bytes,
+            max_item_bytes: max_item_bytes.min(max_bytes),
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn max_item_bytes(&self) -> u64 {
+        self.max_item_bytes
+    }
+
+    pub fn file(&self, hash: &str) -> PathBuf {
+        self.dir.join(hash)
+    }
+
+    pub fn thumbnail_file(&self, hash: &str) -> PathBuf {
+        self.dir.join(format!("{}.thumbnail", hash))
+    }
+
+    /// whether `url` should be fetched: it is not cached, not skipped, and has not
+    ///  failed lately
+    pub fn wants(&self, db: &Db, url: &str, now: u64) -> Result<bool> {
+        let conn = db.pool.get_conn()?;
+        let row: Option<(String, u64)> = conn
+            .query_row(
+                "SELECT state, fetched_at FROM media WHERE hash = ?1",
+                [hash(url)],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        Ok(match row {
+            None => true,
+            Some((state, fetched_at)) => state == "failed" && fetched_at + RETRY_MS <= now,
+        })
+    }
+
+    /// the cached media for `hash`, if there is any. counts as a use of it
+    pub fn lookup(&self, db: &Db, hash: &str, now: u64) -> Result<Option<MediaEntry>> {
+        let conn = db.pool.get_writer()?;
+        let entry = conn
+            .query_row(
+                "SELECT hash, url, content_type, size, thumbnail_type, fetched_at
+                FROM media
+                WHERE hash = ?1 AND state = 'ready'",
+                [hash],
+                |row| {
+                    Ok(MediaEntry {
+                        hash: row.get(0)?,
+                        url: row.get(1)?,
+                        content_type: row.get(2)?,
+                        size: row.get(3)?,
+                        thumbnail_type: row.get(4)?,
+                        fetched_at: row.get(5)?,
+                    })
+                },
+            )
+            .optional()?;
+        if entry.is_some() {
+            conn.execute(
+                "UPDATE media SET last_access = ?2 WHERE hash = ?1",
+                params![hash, now],
+            )?;
+        }
+        Ok(entry)
+    }
+
+    /// keep the media fetched from `url`, then evict whatever no longer fits
+    pub fn store(
+        &self,
+        db: &Db,
+        url: &str,
+        content_type: &str,
+        bytes: &[u8],
+        thumbnail: Option<&Thumbnail>,
+        now: u64,
+    ) -> Result<MediaEntry> {
+        let hash = hash(url);
+        write_file(&self.file(&hash), bytes)?;
+        if let Some(thumbnail) = thumbnail {
+            write_file(&self.thumbnail_file(&hash), &thumbnail.bytes)?;
+        }
+        let conn = db.pool.get_writer()?;
+        conn.execute(
+            "REPLACE INTO media (
+                hash, url, state, content_type, size, thumbnail_type, thumbnail_size, error,
+                fetched_at, last_access
+            ) VALUES (?1, ?2, 'ready', ?3, ?4, ?5, ?6, NULL, ?7, ?7)",
+            params![
+                hash,
+                url,
+                content_type,
+                bytes.len() as u64,
+                thumbnail.map(|t| t.content_type),
+                thumbnail.map_or(0, |t| t.bytes.len() as u64),
+                now,
+            ],
+        )?;
+        self.evict(&conn)?;
+        Ok(MediaEntry {
+            hash,
+            url: url.to_string(),
+            content_type: content_type.to_string(),
+            size: bytes.len() as u64,
+            thumbnail_type: thumbnail.map(|t| t.content_type.to_string()),
+            fetched_at: now,
+        })
+    }
+
+    /// note that `url` is not worth caching (a web page, or too large)
+    pub fn skip(&self, db: &Db, url: &str, reason: &str, now: u64) -> Result<()> {
+        self.give_up(db, url, "skipped", reason, now)
+    }
+
+    /// note that `url` could not be fetched. it is tried again after `RETRY_MS`
+    pub fn fail(&self, db: &Db, url: &str, error: &str, now: u64) -> Result<()> {
+        self.give_up(db, url, "failed", error, now)
+    }
+
+    fn give_up(&self, db: &Db, url: &str, state: &str, error: &str, now: u64) -> Result<()> {
+        let conn = db.pool.get_writer()?;
+        conn.execute(
+            "REPLACE INTO media (hash, url, state, error, fetched_at, last_access)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?5)",
+            params![hash(url), url, state, error, now],
+        )?;
+        Ok(())
+    }
+
+    /// total size of the cached files
+    pub fn size(&self, db: &Db) -> Result<u64> {
+        let conn = db.pool.get_conn()?;
+        total_size(&conn)
+    }
+
+    // drop the least recently used media until the rest fits in `max_bytes`
+    fn evict(&self, conn: &Connection) -> Result<()> {
+        let mut total = total_size(conn)?;
+        if total <= self.max_bytes {
+            return Ok(());
+        }
+        let oldest = {
+            let mut stmt = conn.prepare_cached(
+                "SELECT hash, size + thumbnail_size FROM media
+                WHERE state = 'ready'
+                ORDER BY last_access ASC, fetched_at ASC",
+            )?;
+            let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+            rows.collect::<rusqlite::Result<Vec<(String, u64)>>>()?
+        };
+        for (hash, size) in oldest {
+            if total <= self.max_bytes {
+                break;
+            }
+            self.remove(conn, &hash)?;
+            total -= size;
+        }
+        Ok(())
+    }
+
+    /// drop the media of urls no message holds anymore (their messages were deleted,
+    ///  expired or edited). returns how many urls were dropped
+    pub fn prune(&self, db: &Db) -> Result<usize> {
+        let conn = db.pool.get_writer()?;
+        let orphans = {
+            let mut stmt = conn.prepare_cached(
+                "SELECT hash FROM media
+                WHERE url NOT IN (SELECT content_data FROM chat_messages
+                                  WHERE content_type IN ('image', 'link'))",
+            )?;
+            let rows = stmt.query_map([], |row| row.get(0))?;
+            rows.collect::<rusqlite::Result<Vec<String>>>()?
+        };
+        for hash in &orphans {
+            self.remove(&conn, hash)?;
+        }
+        Ok(orphans.len())
+    }
+
+    // delete the row of `hash`, then its files
+    fn remove(&self, conn: &Connection, hash: &str) -> Result<()> {
+        conn.execute("DELETE FROM media WHERE hash = ?1", [hash])?;
+        for file in [self.file(hash), self.thumbnail_file(hash)] {
+            if let Err(e) = fs::remove_file(&file) {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    trace_err_ln!("media: [cache] cannot remove {}. {}", file.display(), e);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn total_size(conn: &Connection) -> Result<u64> {
+    Ok(conn.query_row(
+        "SELECT COALESCE(SUM(size + thumbnail_size), 0) FROM media WHERE state = 'ready'",
+        [],
+        |row| row.get(0),
+    )?)
+}
+
+// write a file whole: readers never see part of one
+fn write_file(path: &Path, bytes: &[u8]) -> Result<()> {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    let partial = PathBuf::from(partial);
+    fs::write(&partial, bytes)
+        .and_then(|_| fs::rename(&partial, path))
+        .with_context(|| format!("media: [cache] cannot write {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{test_cache, test_db};
+
+    #[test]
+    fn hash_is_sha256_hex() {
+        assert_eq!(
+            hash("https://holium.com/a.png"),
+            format!("{:x}", Sha256::digest(b"https://holium.com/a.png"))
+        );
+        assert_eq!(hash("").len(), 64);
+    }
+
+    #[test]
+    fn least_recently_used_media_is_evicted() {
+        let db = test_db();
+        let cache = test_cache(100);
+        let url = |n: u32| format!("https://holium.com/{}.png", n);
+
+        for n in 0..3 {
+            cache
+                .store(&db, &url(n), "image/png", &[0; 40], None, n as u64)
+                .unwrap();
+            if n == 1 {
+                // using 0 makes 1 the oldest
+                assert!(cache.lookup(&db, &hash(&url(0)), 10).unwrap().is_some());
+            }
+        }
+        assert_eq!(cache.size(&db).unwrap(), 80);
+        assert!(cache.lookup(&db, &hash(&url(1)), 20).unwrap().is_none());
+        assert!(!cache.file(&hash(&url(1))).exists());
+        for n in [0, 2] {
+            let entry = cache.lookup(&db, &hash(&url(n)), 20).unwrap().unwrap();
+            assert_eq!(entry.url, url(n));
+            assert_eq!(fs::read(cache.file(&entry.hash)).unwrap(), vec![0; 40]);
+        }
+        // an evicted url can be fetched again
+        assert!(cache.wants(&db, &url(1), 30).unwrap());
+        assert!(!cache.wants(&db, &url(0), 30).unwrap());
+
+        // thumbnails count too
+        let thumbnail = Thumbnail {
+            content_type: "image/png",
+            bytes: vec![0; 30],
+        };
+        cache
+            .store(&db, &url(3), "image/png", &[0; 10], Some(&thumbnail), 40)
+            .unwrap();
+        assert_eq!(cache.size(&db).unwrap(), 80);
+        assert!(cache.lookup(&db, &hash(&url(0)), 50).unwrap().is_none());
+        fs::remove_dir_all(cache.dir()).unwrap();
+    }
+
+    #[test]
+    fn failed_urls_are_retried_later() {
+        let db = test_db();
+        let cache = test_cache(100);
+        cache
+            .fail(&db, "https://a.io/x.png", "http 500", 0)
+            .unwrap();
+        cache.skip(&db, "https://a.io/", "not media", 0).unwrap();
+        assert!(!cache
+            .wants(&db, "https://a.io/x.png", RETRY_MS - 1)
+            .unwrap());
+        assert!(cache.wants(&db, "https://a.io/x.png", RETRY_MS).unwrap());
+        assert!(!cache.wants(&db, "https://a.io/", RETRY_MS).unwrap());
+        assert!(cache
+            .lookup(&db, &hash("https://a.io/x.png"), 0)
+            .unwrap()
+            .is_none());
+        fs::remove_dir_all(cache.dir()).unwrap();
+    }
+}
@@ -0,0 +1,663 @@
+//!
+//! the background fetcher
+//!
+//! urls are queued as chat events come in, after the urls of the most recent messages
+//!  (`RECENT_URLS`) at start. they are fetched one at a time: the cache is a
+//!  convenience, and should not compete with the node's other traffic.
+//!
+//! once a message is deleted (or expires) or edited, the media no message holds anymore
+//!  is dropped, in the same queue. so is any left over at start.
+//!
+//! the urls come from any chat peer, so the node only fetches from public addresses:
+//!  its own host and networks are out of reach, whether the url names them, a name
+//!  resolves to them or a redirect leads there. (the tests fetch from the loopback
+//!  address, so `start` can be told to fetch from anywhere.)
+//!
+use std::io::Cursor;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use image::{DynamicImage, ImageOutputFormat};
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use reqwest::redirect::Policy;
+use tokio::sync::{broadcast, mpsc};
+use url::{Host, Url};
+use warp::hyper::client::connect::dns::Name;
+
+use crate::chat::data;
+use crate::chat::events::ChatEvent;
+use crate::context::CallContext;
+use crate::db::Db;
+use crate::helper::get_current_time;
+
+use super::cache::{MediaCache, Thumbnail};
+
+use trace::{trace_err_ln, trace_info_ln};
+
+/// urls of recent messages checked at start
+const RECENT_URLS: usize = 500;
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// redirects followed for a url
+const MAX_REDIRECTS: usize = 5;
+
+/// raster images: safe to show inline
+const IMAGE_TYPES: &[&str] = &[
+    "image/avif",
+    "image/bmp",
+    "image/gif",
+    "image/jpeg",
+    "image/png",
+    "image/tiff",
+    "image/webp",
+];
+
+/// the other media that is cached. never shown as a document of the node's origin
+const OTHER_TYPES: &[&str] = &[
+    "application/pdf",
+    "audio/aac",
+    "audio/flac",
+    "audio/mp4",
+    "audio/mpeg",
+    "audio/ogg",
+    "audio/wav",
+    "audio/webm",
+    "video/mp4",
+    "video/ogg",
+    "video/quicktime",
+    "video/webm",
+];
+
+/// thumbnails fit in a square this size (px)
+pub const THUMBNAIL_SIZE: u32 = 256;
+
+// what the fetcher does next
+enum Job {
+    Fetch(String),
+    /// drop the media no message holds anymore
+    Prune,
+}
+
+pub fn start(ctx: CallContext, cache: MediaCache, public_only: bool) {
+    // subscribe before looking at recent messages, so that nothing falls in between
+    let mut events = ctx.chat_events.subscribe();
+    let (queue, mut jobs) = mpsc::unbounded_channel::<Job>();
+    // a sweep deletes many messages at once: one prune covers them all
+    let prune_queued = Arc::new(AtomicBool::new(false));
+
+    let queued = prune_queued.clone();
+    tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(event) => {
+                    for url in media_urls(&event) {
+                        let _ = queue.send(Job::Fetch(url));
+                    }
+                    let drops_media = matches!(
+                        event,
+                        ChatEvent::MessageDeleted { .. } | ChatEvent::MessageUpdated { .. }
+                    );
+                    if drops_media && !queued.swap(true, Ordering::SeqCst) {
+                        let _ = queue.send(Job::Prune);
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    trace_err_ln!("media: [fetch] missed {} chat event(s)", missed);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+
+    tokio::spawn(async move {
+        let allowed: fn(IpAddr) -> bool = match public_only {
+            true => is_public,
+            false => |_| true,
+        };
+        let fetcher = match Fetcher::new(allowed) {
+            Ok(fetcher) => fetcher,
+            Err(e) => {
+                trace_err_ln!("media: [fetch] cannot create http client. {}", e);
+                return;
+            }
+        };
+        prune(&ctx.db, &cache);
+        let recent = ctx
+            .db
+            .pool
+            .get_conn()
+            .and_then(|conn| data::media_urls(&conn, RECENT_URLS));
+        match recent {
+            Ok(recent) => {
+                for url in recent.iter().filter(|url| is_remote(url)) {
+                    fetch(&fetcher, &ctx.db, &cache, url).await;
+                }
+            }
+            Err(e) => trace_err_ln!("media: [fetch] cannot list recent media. {}", e),
+        }
+        while let Some(job) = jobs.recv().await {
+            match job {
+                Job::Fetch(url) => fetch(&fetcher, &ctx.db, &cache, &url).await,
+                Job::Prune => {
+                    prune_queued.store(false, Ordering::SeqCst);
+                    prune(&ctx.db, &cache);
+                }
+            }
+        }
+    });
+}
+
+// the urls of the image and link parts of a new or changed message
+fn media_urls(event: &ChatEvent) -> Vec<String> {
+    match event {
+        ChatEvent::MessageAdded { message, .. } | ChatEvent::MessageUpdated { message, .. } => {
+            message
+                .parts
+                .iter()
+                .filter(|part| part.content_type == "image" || part.content_type == "link")
+                .map(|part| part.content_data.clone())
+                .filter(|url| is_remote(url))
+                .collect()
+        }
+        ChatEvent::MessageDeleted { .. } => Vec::new(),
+    }
+}
+
+fn is_remote(url: &str) -> bool {
+    url.starts_with("https://") || url.starts_with("http://")
+}
+
+// drop the media of urls no message holds anymore
+fn prune(db: &Db, cache: &MediaCache) {
+    if let Err(e) = cache.prune(db) {
+        trace_err_ln!("media: [fetch] cannot drop unused media. {}", e);
+    }
+}
+
+// fetch `url` into the cache, unless it is there already (or not wanted)
+async fn fetch(fetcher: &Fetcher, db: &Db, cache: &MediaCache, url: &str) {
+    match cache.wants(db, url, get_current_time()) {
+        Ok(true) => {}
+        Ok(false) => return,
+        Err(e) => {
+            trace_err_ln!("media: [fetch] cannot check {}. {}", url, e);
+            return;
+        }
+    }
+    let result = match fetcher.download(url, cache.max_item_bytes()).await {
+        Ok(Download::Media {
+            content_type,
+            bytes,
+        }) => {
+            let thumbnail = if content_type.starts_with("image/") {
+                let image = bytes.clone();
+                tokio::task::spawn_blocking(move || thumbnail(&image))
+                    .await
+                    .unwrap_or(None)
+            } else {
+                None
+            };
+            cache
+                .store(
+                    db,
+                    url,
+                    &content_type,
+                    &bytes,
+                    thumbnail.as_ref(),
+                    get_current_time(),
+                )
+                .map(|_| trace_info_ln!("media: [fetch] cached {} ({} bytes)", url, bytes.len()))
+        }
+        Ok(Download::Skipped(reason)) => cache.skip(db, url, &reason, get_current_time()),
+        Err(e) => {
+            trace_err_ln!("media: [fetch] {} failed. {}", url, e);
+            cache.fail(db, url, &e.to_string(), get_current_time())
+        }
+    };
+    if let Err(e) = result {
+        trace_err_ln!("media: [fetch] cannot record {}. {}", url, e);
+    }
+}
+
+enum Download {
+    Media {
+        content_type: String,
+        bytes: Vec<u8>,
+    },
+    /// not worth caching
+    Skipped(String),
+}
+
+struct Fetcher {
+    client: reqwest::Client,
+    /// the addresses that may be fetched from
+    allowed: fn(IpAddr) -> bool,
+}
+
+impl Fetcher {
+    fn new(allowed: fn(IpAddr) -> bool) -> reqwest::Result<Fetcher> {
+        let client = reqwest::Client::builder()
+            .timeout(FETCH_TIMEOUT)
+            .dns_resolver(Arc::new(Resolver { allowed }))
+            .redirect(Policy::custom(move |attempt| {
+                if attempt.previous().len() >= MAX_REDIRECTS {
+                    attempt.error("too many redirects")
+                } else if !is_allowed_url(attempt.url(), allowed) {
+                    attempt.error("redirected to an address that is out of reach")
+                } else {
+                    attempt.follow()
+                }
+            }))
+            .build()?;
+        Ok(Fetcher { client, allowed })
+    }
+
+    async fn download(&self, url: &str, max_bytes: u64) -> Result<Download> {
+        // addresses in the url are not resolved, so the resolver does not see them
+        if !Url::parse(url).is_ok_and(|url| is_allowed_url(&url, self.allowed)) {
+            return Ok(Download::Skipped("address out of reach".to_string()));
+        }
+        let mut response = self.client.get(url).send().await?.error_for_status()?;
+        let too_large = || Download::Skipped(format!("larger than {} bytes", max_bytes));
+        if response.content_length().is_some_and(|len| len > max_bytes) {
+            return Ok(too_large());
+        }
+        let header = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        let mut bytes = Vec::new();
+        while let Some(chunk) = response.chunk().await? {
+            if (bytes.len() + chunk.len()) as u64 > max_bytes {
+                return Ok(too_large());
+            }
+            bytes.extend_from_slice(&chunk);
+        }
+        Ok(match content_type(&bytes, header.as_deref()) {
+            Some(content_type) if is_servable(&content_type) => Download::Media {
+                content_type,
+                bytes,
+            },
+            _ => Download::Skipped("not media".to_string()),
+        })
+    }
+}
+
+/// resolves names to the addresses that may be fetched from only
+struct Resolver {
+    allowed: fn(IpAddr) -> bool,
+}
+
+impl Resolve for Resolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let allowed = self.allowed;
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|addr| allowed(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(format!("{} has no address in reach", name.as_str()).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+// an http(s) url whose host, if it is an address, is allowed. names are left to the
+//  resolver
+fn is_allowed_url(url: &Url, allowed: fn(IpAddr) -> bool) -> bool {
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return false;
+    }
+    match url.host() {
+        Some(Host::Domain(_)) => true,
+        Some(Host::Ipv4(ip)) => allowed(IpAddr::V4(ip)),
+        Some(Host::Ipv6(ip)) => allowed(IpAddr::V6(ip)),
+        None => false,
+    }
+}
+
+/// whether `ip` is reachable from the internet at large, rather than the node's own host
+///  or networks (loopback, private, link-local, ...)
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                // "this network", shared (cgnat), protocol assignments, benchmarking
+                //  and reserved
+                || a == 0
+                || (a == 100 && (64..128).contains(&b))
+                || (a == 192 && b == 0 && c == 0)
+                || (a == 198 && (18..20).contains(&b))
+                || a >= 240)
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_public(IpAddr::V4(ip));
+            }
+            let first = ip.segments()[0];
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_multicast()
+                // unique local, link-local and documentation
+                || (first & 0xfe00) == 0xfc00
+                || (first & 0xffc0) == 0xfe80
+                || (first == 0x2001 && ip.segments()[1] == 0x0db8))
+        }
+    }
+}
+
+/// raster images, which browsers only ever show as images
+pub fn is_image(content_type: &str) -> bool {
+    IMAGE_TYPES.contains(&content_type)
+}
+
+/// the media types that are cached and served
+pub fn is_servable(content_type: &str) -> bool {
+    is_image(content_type) || OTHER_TYPES.contains(&content_type)
+}
+
+/// the content type of fetched media: images are recognized by their content, anything
+///  else goes by what the server said
+pub fn content_type(bytes: &[u8], header: Option<&str>) -> Option<String> {
+    if let Ok(format) = image::guess_format(bytes) {
+        return Some(format.to_mime_type().to_string());
+    }
+    let header = header?.split(';').next()?.trim().to_ascii_lowercase();
+    (!header.is_empty() && header != "application/octet-stream").then_some(header)
+}
+
+/// a copy of an image that fits in `THUMBNAIL_SIZE`: png if the image has transparency,
+///  jpeg otherwise. none if the image cannot be decoded
+pub fn thumbnail(bytes: &[u8]) -> Option<Thumbnail> {
+    let image = image::load_from_memory(bytes).ok()?;
+    let small = image.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE);
+    let mut out = Cursor::new(Vec::new());
+    let content_type = if small.color().has_alpha() {
+        DynamicImage::ImageRgba8(small.to_rgba8())
+            .write_to(&mut out, ImageOutputFormat::Png)
+            .ok()?;
+        "image/png"
+    } else {
+        DynamicImage::ImageRgb8(small.to_rgb8())
+            .write_to(&mut out, ImageOutputFormat::Jpeg(80))
+            .ok()?;
+        "image/jpeg"
+    };
+    Some(Thumbnail {
+        content_type,
+        bytes: out.into_inner(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::events;
+    use crate::chat::types::ChatMessage;
+    use crate::media::cache::{hash, MediaEntry};
+    use crate::testing::{run, test_context, test_ship};
+    use image::{ImageBuffer, Rgb, Rgba};
+    use serde_json::json;
+    use warp::Filter;
+
+    const PATH: &str = "/spaces/~zod/chats/0v1";
+
+    fn png(width: u32, height: u32, alpha: bool) -> Vec<u8> {
+        let image = if alpha {
+            DynamicImage::ImageRgba8(ImageBuffer::from_pixel(
+                width,
+                height,
+                Rgba([0, 0, 255, 128]),
+            ))
+        } else {
+            DynamicImage::ImageRgb8(ImageBuffer::from_pixel(width, height, Rgb([255, 0, 0])))
+        };
+        let mut out = Cursor::new(Vec::new());
+        image.write_to(&mut out, ImageOutputFormat::Png).unwrap();
+        out.into_inner()
+    }
+
+    fn save(db: &Db, msg_id: &str, parts: &[(&str, &str)], at: u64) {
+        let conn = db.pool.get_writer().unwrap();
+        for (id, (content_type, data)) in parts.iter().enumerate() {
+            let row: ChatMessage = serde_json::from_value(json!({
+                "path": PATH,
+                "msg-id": msg_id,
+                "msg-part-id": id,
+                "content-type": content_type,
+                "content-data": data,
+                "reply-to": null,
+                "metadata": {},
+                "sender": "~zod",
+                "created-at": at,
+                "updated-at": at,
+                "received-at": at,
+                "expires-at": null,
+            }))
+            .unwrap();
+            data::save_message(&conn, &row).unwrap();
+        }
+    }
+
+    // the cache entry for `url`, once it is there
+    async fn wait(db: &Db, cache: &MediaCache, url: &str) -> MediaEntry {
+        for _ in 0..100 {
+            if let Some(entry) = cache.lookup(db, &hash(url), 0).unwrap() {
+                return entry;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("{} was not cached", url);
+    }
+
+    #[test]
+    fn content_type_goes_by_the_bytes_first() {
+        let image = png(2, 2, false);
+        assert_eq!(
+            content_type(&image, Some("text/plain")).as_deref(),
+            Some("image/png")
+        );
+        assert_eq!(
+            content_type(b"....ftypisom", Some("Video/MP4; codecs=avc1")).as_deref(),
+            Some("video/mp4")
+        );
+        assert_eq!(content_type(b"<html>", None), None);
+        assert_eq!(content_type(b"?", Some("application/octet-stream")), None);
+    }
+
+    #[test]
+    fn only_public_addresses_are_fetched() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fe80::1",
+            "fd00::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["1.1.1.1", "93.184.216.34", "2606:4700::1111"] {
+            assert!(is_public(ip.parse().unwrap()), "{}", ip);
+        }
+
+        run(async {
+            let files = warp::path!("a.png").map(|| png(2, 2, false));
+            let (addr, server) = warp::serve(files).bind_ephemeral(([127, 0, 0, 1], 0));
+            tokio::spawn(server);
+            let fetcher = Fetcher::new(is_public).unwrap();
+            // an address in the url...
+            let url = format!("http://{}/a.png", addr);
+            assert!(matches!(
+                fetcher.download(&url, 1024).await,
+                Ok(Download::Skipped(_))
+            ));
+            // ...or a name that resolves to one
+            let url = format!("http://localhost:{}/a.png", addr.port());
+            assert!(fetcher.download(&url, 1024).await.is_err());
+
+            // ...or a redirect there, from an address that is in reach (127.0.0.2
+            //  stands in for a public one)
+            let moved = warp::path!("moved").map(move || {
+                warp::redirect::found(
+                    format!("http://{}/a.png", addr)
+                        .parse::<warp::http::Uri>()
+                        .unwrap(),
+                )
+            });
+            let (relay, server) = warp::serve(moved).bind_ephemeral(([127, 0, 0, 2], 0));
+            tokio::spawn(server);
+            let fetcher = Fetcher::new(|ip| ip != "127.0.0.1".parse::<IpAddr>().unwrap()).unwrap();
+            let url = format!("http://{}/moved", relay);
+            let err = fetcher.download(&url, 1024).await.err().unwrap();
+            assert!(format!("{:?}", err).contains("out of reach"), "{:?}", err);
+            let url = format!("http://localhost:{}/moved", relay.port());
+            assert!(fetcher.download(&url, 1024).await.is_err());
+        })
+    }
+
+    #[test]
+    fn thumbnails_fit_in_a_square() {
+        let small = thumbnail(&png(1024, 512, false)).unwrap();
+        assert_eq!(small.content_type, "image/jpeg");
+        let decoded = image::load_from_memory(&small.bytes).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (256, 128));
+
+        // transparency is kept
+        let small = thumbnail(&png(100, 400, true)).unwrap();
+        assert_eq!(small.content_type, "image/png");
+        let decoded = image::load_from_memory(&small.bytes).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (64, 256));
+
+        assert!(thumbnail(b"not an image").is_none());
+    }
+
+    #[test]
+    fn media_of_recent_and_new_messages_is_cached() {
+        run(async {
+            let image = png(600, 300, false);
+            let served = image.clone();
+            let files = warp::path!("a.png")
+                .map(move || served.clone())
+                .or(warp::path!("b.gif").map(|| {
+                    let mut out = Cursor::new(Vec::new());
+                    DynamicImage::ImageRgb8(ImageBuffer::from_pixel(8, 8, Rgb([0, 255, 0])))
+                        .write_to(&mut out, ImageOutputFormat::Gif)
+                        .unwrap();
+                    out.into_inner()
+                }))
+                .or(warp::path!("page").map(|| warp::reply::html("<html>hi</html>")))
+                .or(warp::path!("c.svg")
+                    .map(|| warp::reply::with_header("<svg/>", "content-type", "image/svg+xml")))
+                .or(warp::path!("big").map(|| vec![0u8; 64 * 1024]));
+            let (addr, server) = warp::serve(files).bind_ephemeral(([127, 0, 0, 1], 0));
+            tokio::spawn(server);
+            let url = |name: &str| format!("http://{}/{}", addr, name);
+
+            let (_eyre, ship) = test_ship().await;
+            let ctx = test_context(ship);
+            let dir = std::env::temp_dir().join(format!("holon-media-{}", rand::random::<u64>()));
+            let cache = MediaCache::new(&dir, 1 << 20, 32 * 1024).unwrap();
+            // an older message is picked up at start...
+            save(
+                &ctx.db,
+                "/~1/~zod",
+                &[("image", &url("a.png")), ("link", &url("page"))],
+                1,
+            );
+            // media is served from the loopback address
+            start(ctx.clone(), cache.clone(), false);
+
+            let entry = wait(&ctx.db, &cache, &url("a.png")).await;
+            assert_eq!(entry.content_type, "image/png");
+            assert_eq!(entry.thumbnail_type.as_deref(), Some("image/jpeg"));
+            assert_eq!(std::fs::read(cache.file(&entry.hash)).unwrap(), image);
+            assert!(cache.thumbnail_file(&entry.hash).exists());
+
+            // ...and new ones as they come in
+            save(
+                &ctx.db,
+                "/~2/~zod",
+                &[
+                    ("image", &url("c.svg")),
+                    ("link", &url("big")),
+                    ("image", &url("b.gif")),
+                ],
+                2,
+            );
+            let message = {
+                let conn = ctx.db.pool.get_conn().unwrap();
+                data::load_message(&conn, PATH, "/~2/~zod")
+                    .unwrap()
+                    .unwrap()
+            };
+            events::publish(
+                &ctx,
+                vec![ChatEvent::MessageAdded {
+                    path: PATH.to_string(),
+                    message,
+                }],
+            );
+            assert_eq!(
+                wait(&ctx.db, &cache, &url("b.gif")).await.content_type,
+                "image/gif"
+            );
+
+            // web pages, active content and oversized media are not cached, nor fetched
+            //  again
+            for name in ["page", "big", "c.svg"] {
+                assert!(cache
+                    .lookup(&ctx.db, &hash(&url(name)), 0)
+                    .unwrap()
+                    .is_none());
+                assert!(!cache.wants(&ctx.db, &url(name), u64::MAX / 2).unwrap());
+            }
+
+            // the media of a deleted (or expired) message goes with it
+            let gif = hash(&url("b.gif"));
+            {
+                let conn = ctx.db.pool.get_writer().unwrap();
+                data::delete_message(&conn, PATH, "/~2/~zod", 3).unwrap();
+            }
+            events::publish(
+                &ctx,
+                vec![ChatEvent::MessageDeleted {
+                    path: PATH.to_string(),
+                    msg_id: "/~2/~zod".to_string(),
+                }],
+            );
+            for _ in 0..100 {
+                if !cache.file(&gif).exists() {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+            assert!(!cache.file(&gif).exists());
+            assert!(!cache.thumbnail_file(&gif).exists());
+            assert!(cache.lookup(&ctx.db, &gif, 0).unwrap().is_none());
+            // media still held by a message stays
+            assert!(cache.file(&entry.hash).exists());
+            assert!(cache.lookup(&ctx.db, &entry.hash, 0).unwrap().is_some());
+            std::fs::remove_dir_all(&dir).unwrap();
+        })
+    }
+}
@@ -0,0 +1,38 @@
+//!
+//! media referenced by chat messages, cached by the node
+//!
+//! image and link parts only hold urls, so every client would fetch the media itself,
+//!  over and over. instead the node fetches it once, in the background: as messages
+//!  come in (from the chat event channel), and for the most recent messages when it
+//!  starts. clients get the node's copy at `/hol/media/{hash}`, and a small version of
+//!  images at `/hol/media/{hash}/thumbnail`. `hash` is the sha-256 of the url, in hex.
+//!
+//! files are kept in the node's media folder (`<data root>/media/<id>`) and tracked in
+//!  the `media` table. once the cache grows past its size limit, the least recently
+//!  served files are dropped, and media no message holds anymore (deleted, expired or
+//!  edited away) is dropped right away. only known media types are cached: web pages (links
+//!  that are not media), and anything a browser could run, are not.
+//!
+pub mod api;
+pub mod cache;
+mod fetch;
+
+use bedrock_db::migrations::Migration;
+
+use crate::context::CallContext;
+
+pub use cache::MediaCache;
+
+/// the media table. applied by `Db::migrate`
+pub const MIGRATIONS: &[Migration] = &[Migration {
+    version: 1,
+    name: "media",
+    sql: include_str!("sql/0001_media.sql"),
+}];
+
+/// fetch the media of new (and recent) messages into `cache` until the node stops.
+///  with `public_only`, which the node always sets, media is only fetched from public
+///  addresses (see `fetch`)
+pub fn start(ctx: CallContext, cache: MediaCache, public_only: bool) {
+    fetch::start(ctx, cache, public_only);
+}
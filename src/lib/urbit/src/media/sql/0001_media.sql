@@ -0,0 +1,21 @@
+/*
+    media referenced by chat messages (see `media`). one row per url
+*/
+create table if not exists media
+(
+    /* sha-256 of the url, hex. also the name of the cached file */
+    hash            TEXT    NOT NULL PRIMARY KEY,
+    url             TEXT    NOT NULL,
+    /* ready, skipped (a web page, or too large) or failed (retried later) */
+    state           TEXT    NOT NULL,
+    content_type    TEXT,
+    size            INTEGER NOT NULL DEFAULT 0,
+    /* images only */
+    thumbnail_type  TEXT,
+    thumbnail_size  INTEGER NOT NULL DEFAULT 0,
+    error           TEXT,
+    fetched_at      INTEGER NOT NULL,
+    /* last served (or fetched). the least recently used files are evicted first */
+    last_access     INTEGER NOT NULL
+);
+create index if not exists media_state_last_access_index on media (state, last_access);
@@ -16,10 +16,7 @@ async fn get_pid(server_id: &str) -> Result<String, Box<dyn std::error::Error>>
 
     let pid_str = String::from_utf8(pid.stdout)?;
     if pid_str.is_empty() {
-        return Err(Box::new(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            "Failed to get pid",
-        )));
+        return Err(Box::new(std::io::Error::other("Failed to get pid")));
     }
     Ok(pid_str)
 }
@@ -50,10 +47,7 @@ pub async fn get_pid_and_loopback(
     let loopback_str = String::from_utf8_lossy(&loopback.stdout).to_string();
 
     if loopback_str.is_empty() {
-        return Err(Box::new(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            "Failed to get loopback",
-        )));
+        return Err(Box::new(std::io::Error::other("Failed to get loopback")));
     }
 
     Ok((pid_str, loopback_str))
@@ -92,13 +86,13 @@ pub fn graceful_exit(
 #[cfg(test)]
 mod tests {
     use super::*;
-    use tokio::runtime::Runtime;
 
     fn setup() {
         println!("Boot up a fake zod...");
     }
 
     #[test]
+    #[ignore = "requires a running urbit process for ~zod"]
     fn test_urbit_graceful_exit() {
         setup();
 
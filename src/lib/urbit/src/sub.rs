@@ -7,76 +7,102 @@
 ///   events that come in from the ship are then forwarded to the web socket receiver,
 ///   where they are ultimately delivered to listening devices over websocket.
 ///
+/// events are acked back to the ship in batches so that eyre can drop them from the
+///   channel's buffer. if the session expires, the listener logs in again and resumes
+///   the same channel from the last event it received (Last-Event-ID). either way, a
+///   `ship-reconnected` status is sent to the node once the ship is reachable again.
+///
+/// the channel and its last acked event are saved to the db. a restarted node resumes
+///   that channel, so responses to actions relayed before the restart still come in.
+///
+use crate::api::CHANNEL_OPEN_MSG_ID;
 use crate::context::CallContext;
+use crate::db::ChannelEntry;
+use crate::eyre::Event;
 use anyhow::{bail, Result};
-use serde_json::json;
+use crossbeam::channel::RecvTimeoutError;
+use eventsource_threaded::ReceiverSource;
+use serde_json::{json, Value as JsonValue};
 use tokio::time::{sleep, Duration};
 
 use trace::{trace_err_ln, trace_good_ln, trace_info_ln, trace_warn_ln};
 
-pub async fn start(ctx: CallContext) -> Result<()> {
-    let receiver = ctx.ship.lock().await.open_channel().await;
+/// number of events relayed before they are acked back to the ship
+const ACK_BATCH_SIZE: u64 = 20;
+/// un-acked events are flushed to the ship once the stream has been idle this long (ms)
+const ACK_IDLE_MS: u64 = 500;
+
+// position of the listener in the channel's event stream
+#[derive(Debug, Default)]
+struct Cursor {
+    // id of the last event received from the ship
+    last_event_id: Option<u64>,
+    // id of the last event acked back to the ship
+    last_ack: Option<u64>,
+}
 
-    if receiver.is_err() {
-        bail!("open_channel call failed");
+impl Cursor {
+    // number of received events that have not been acked. eyre event ids are sequential
+    fn pending(&self) -> u64 {
+        match (self.last_event_id, self.last_ack) {
+            (Some(id), Some(ack)) => id.saturating_sub(ack),
+            (Some(id), None) => id + 1,
+            (None, _) => 0,
+        }
     }
+}
 
-    let mut receiver = receiver.unwrap();
+pub async fn start(ctx: CallContext) -> Result<()> {
+    let (mut receiver, mut cursor) = match resume_saved(&ctx).await {
+        Some(resumed) => resumed,
+        None => {
+            let receiver = ctx.ship.lock().await.open_channel().await;
+
+            if receiver.is_err() {
+                bail!("open_channel call failed");
+            }
+
+            let cursor = Cursor::default();
+            save_channel(&ctx, &cursor).await;
+            (receiver.unwrap(), cursor)
+        }
+    };
 
     tokio::spawn(async move {
         loop {
             trace_info_ln!("waiting for ship event...",);
 
-            let msg = receiver.recv();
-
-            if msg.is_err() {
-                trace_err_ln!("event receive error. msg => {:?}", msg.err());
-                continue;
-            }
+            // the EventSource receiver blocks; hand this worker's other tasks off first
+            let msg = tokio::task::block_in_place(|| {
+                receiver.recv_timeout(Duration::from_millis(ACK_IDLE_MS))
+            });
 
-            let msg = msg.unwrap();
+            let msg = match msg {
+                Ok(msg) => msg,
+                Err(RecvTimeoutError::Timeout) => {
+                    // quiet stream. catch up on any events that have not been acked
+                    if cursor.pending() > 0 {
+                        ack(&ctx, &mut cursor).await;
+                    }
+                    continue;
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    trace_err_ln!("event source disconnected. reconnecting...");
+                    receiver = resume(&ctx, &cursor).await;
+                    continue;
+                }
+            };
 
             if msg.is_err() {
                 trace_err_ln!("event request error. msg => {:?}", msg);
 
-                let err = msg.err();
-                if err.unwrap().to_string().contains("403") {
-                    // fire a message to all connected devices letting them know about
-                    //  the disconnection
-                    let msg = json!({
-                      "type": "error",
-                      "target": "broadcast",
-                      "error": "ship-stream-disconnected",
-                    });
-
-                    trace_warn_ln!("forwarding error to devices => {}", msg);
-
-                    let send_result = ctx.sender.send(msg);
-
-                    if send_result.is_err() {
-                        trace_err_ln!("error sending packet => {:?}", send_result);
-                    }
-
-                    receiver = loop {
-                        let mut ship = ctx.ship.lock().await;
-                        let result = ship.login().await;
-                        if result.is_err() {
-                            trace_warn_ln!(
-                            "login call failed attempting to login after token expiration. trying again in 2 seconds..."
-                          );
-                            sleep(Duration::from_millis(3000)).await;
-                        }
-                        let result = ship.open_channel().await;
-                        if result.is_err() {
-                            trace_warn_ln!(
-                              "open_channel call failed attempting to login after token expiration. trying again in 2 seconds..."
-                            );
-                            sleep(Duration::from_millis(3000)).await;
-                        } else {
-                            receiver = result.unwrap();
-                            break receiver;
-                        }
-                    }
+                let err = msg.err().unwrap().to_string();
+                if err.contains("403") {
+                    // session expired. log in again and pick up where the stream left off
+                    receiver = resume(&ctx, &cursor).await;
+                } else if err.contains("404") {
+                    // the channel is gone (along with any events buffered on it)
+                    receiver = reopen(&ctx, &mut cursor).await;
                 }
 
                 continue;
@@ -85,9 +111,23 @@ pub async fn start(ctx: CallContext) -> Result<()> {
             // the deserialized Event from SSE
             let event = msg.unwrap();
 
+            // keep-alive (comment) lines come through as empty events
+            if event.data.is_empty() {
+                continue;
+            }
+
             trace_good_ln!("received event => {}", event);
 
-            let data = serde_json::from_str(&event.data);
+            if let Some(event_id) = event.id.as_ref().and_then(|id| id.parse::<u64>().ok()) {
+                // skip events replayed after a reconnect that have already been relayed
+                if cursor.last_event_id.is_some_and(|last| event_id <= last) {
+                    trace_warn_ln!("skipping duplicate event {}", event_id);
+                    continue;
+                }
+                cursor.last_event_id.replace(event_id);
+            }
+
+            let data = serde_json::from_str::<JsonValue>(&event.data);
 
             if data.is_err() {
                 trace_err_ln!("error deserializing event source message to json");
@@ -99,6 +139,17 @@ pub async fn start(ctx: CallContext) -> Result<()> {
             // log the entire packet to the database
             let _ = ctx.db.save_packet("ship", &data);
 
+            // only well formed channel events are relayed to devices
+            match serde_json::from_value::<Event>(data.clone()) {
+                Err(e) => {
+                    trace_err_ln!("invalid ship event {}. {}", data, e);
+                    continue;
+                }
+                // the channel handshake is replayed when resuming before any other event
+                Ok(event) if event.id() == CHANNEL_OPEN_MSG_ID => continue,
+                Ok(_) => {}
+            }
+
             trace_info_ln!("ship: [listen] sending event to receiver => {}", data);
 
             let send_result = ctx.sender.send(data);
@@ -106,8 +157,397 @@ pub async fn start(ctx: CallContext) -> Result<()> {
             if send_result.is_err() {
                 trace_err_ln!("ship: [listen] error sending packet => {:?}", send_result);
             }
+
+            if cursor.pending() >= ACK_BATCH_SIZE {
+                ack(&ctx, &mut cursor).await;
+            }
         }
     });
 
     Ok(())
 }
+
+// ack everything received so far
+async fn ack(ctx: &CallContext, cursor: &mut Cursor) {
+    let event_id = match cursor.last_event_id {
+        Some(event_id) => event_id,
+        None => return,
+    };
+    let result = ctx.ship.lock().await.ack(event_id).await;
+    if result.is_err() {
+        // not fatal. the next ack covers these events as well
+        trace_err_ln!("ack of event {} failed. {:?}", event_id, result);
+        return;
+    }
+    cursor.last_ack.replace(event_id);
+    save_channel(ctx, cursor).await;
+}
+
+// pick up the channel a previous run was using, from the last event it acked. events
+//  that were not acked (e.g. responses to actions relayed just before the restart) are
+//  replayed. if the ship no longer has the channel, the listener reopens it (see 404)
+async fn resume_saved(ctx: &CallContext) -> Option<(ReceiverSource, Cursor)> {
+    let saved = match ctx.db.load_channel() {
+        Ok(saved) => saved?,
+        Err(e) => {
+            trace_err_ln!("failed to load saved channel. {}", e);
+            return None;
+        }
+    };
+    let mut ship = ctx.ship.lock().await;
+    ship.channel_url.replace(saved.url);
+    match ship.resume_channel(saved.last_ack) {
+        Ok(receiver) => {
+            let cursor = Cursor {
+                last_event_id: saved.last_ack,
+                last_ack: saved.last_ack,
+            };
+            Some((receiver, cursor))
+        }
+        Err(e) => {
+            trace_warn_ln!("failed to resume saved channel. {}", e);
+            ship.channel_url = None;
+            None
+        }
+    }
+}
+
+// remember the channel and how far it has been acked, for the next run
+async fn save_channel(ctx: &CallContext, cursor: &Cursor) {
+    let url = match ctx.ship.lock().await.channel_url.clone() {
+        Some(url) => url,
+        None => return,
+    };
+    let entry = ChannelEntry {
+        url,
+        last_ack: cursor.last_ack,
+    };
+    if let Err(e) = ctx.db.save_channel(&entry) {
+        trace_err_ln!("failed to save channel. {}", e);
+    }
+}
+
+// log in again and reattach to the existing channel from the last event received.
+//  devices see an unbroken stream
+async fn resume(ctx: &CallContext, cursor: &Cursor) -> ReceiverSource {
+    loop {
+        let mut ship = ctx.ship.lock().await;
+        let result = ship.login().await;
+        if result.is_ok() {
+            match ship.resume_channel(cursor.last_event_id) {
+                Ok(receiver) => {
+                    drop(ship);
+                    reconnected(ctx);
+                    return receiver;
+                }
+                Err(e) => trace_warn_ln!("resume_channel call failed. {}", e),
+            }
+        }
+        drop(ship);
+        trace_warn_ln!(
+            "login call failed attempting to resume channel after token expiration. trying again in 3 seconds..."
+        );
+        sleep(Duration::from_millis(3000)).await;
+    }
+}
+
+// open a new channel after the old one was lost on the ship
+async fn reopen(ctx: &CallContext, cursor: &mut Cursor) -> ReceiverSource {
+    // fire a message to all connected devices letting them know about
+    //  the disconnection. events sent while the channel was down are gone
+    let msg = json!({
+      "type": "error",
+      "target": "broadcast",
+      "error": "ship-stream-disconnected",
+    });
+
+    trace_warn_ln!("forwarding error to devices => {}", msg);
+
+    let send_result = ctx.sender.send(msg);
+
+    if send_result.is_err() {
+        trace_err_ln!("error sending packet => {:?}", send_result);
+    }
+
+    let receiver = loop {
+        let mut ship = ctx.ship.lock().await;
+        let result = ship.login().await;
+        if result.is_err() {
+            trace_warn_ln!(
+                "login call failed attempting to reopen channel. trying again in 3 seconds..."
+            );
+            drop(ship);
+            sleep(Duration::from_millis(3000)).await;
+            continue;
+        }
+        match ship.open_channel().await {
+            Ok(receiver) => break receiver,
+            Err(_) => {
+                trace_warn_ln!("open_channel call failed. trying again in 3 seconds...");
+                drop(ship);
+                sleep(Duration::from_millis(3000)).await;
+            }
+        }
+    };
+
+    *cursor = Cursor::default();
+    save_channel(ctx, cursor).await;
+
+    reconnected(ctx);
+
+    receiver
+}
+
+// let the node know the ship is reachable again (e.g. to replay the ws outbox)
+fn reconnected(ctx: &CallContext) {
+    let msg = json!({
+      "type": "status",
+      "target": "node",
+      "status": "ship-reconnected",
+    });
+
+    let send_result = ctx.sender.send(msg);
+
+    if send_result.is_err() {
+        trace_err_ln!("error sending packet => {:?}", send_result);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eyre::Action;
+    use crate::testing::{run, test_context, test_ship, SHIP};
+
+    // wait for the next ship event forwarded by the listener that satisfies `matches`
+    fn next_event(ctx: &CallContext, matches: impl Fn(&JsonValue) -> bool) -> JsonValue {
+        loop {
+            let event = ctx
+                .receiver
+                .recv_timeout(std::time::Duration::from_secs(10))
+                .expect("timed out waiting for ship event");
+            if matches(&event) {
+                return event;
+            }
+        }
+    }
+
+    #[test]
+    fn start_forwards_ship_events() {
+        run(async {
+            let (eyre, ship) = test_ship().await;
+            let ctx = test_context(ship);
+            start(ctx.clone()).await.unwrap();
+
+            let subscribe = [Action::Subscribe {
+                id: 2,
+                ship: SHIP.to_string(),
+                app: "chat-db".to_string(),
+                path: "/db".to_string(),
+            }];
+            ctx.ship.lock().await.post(&subscribe).await.unwrap();
+            let ack = next_event(&ctx, |event| event["id"] == 2);
+            assert_eq!(ack["response"], "subscribe");
+
+            assert_eq!(
+                eyre.push_fact("chat-db", "/db", json!({"hello": "world"})),
+                1
+            );
+            let fact = next_event(&ctx, |event| event["response"] == "diff");
+            assert_eq!(fact["json"], json!({"hello": "world"}));
+
+            // malformed events are logged but not relayed
+            let channel_url = ctx.ship.lock().await.channel_url.clone().unwrap();
+            let uid = channel_url.rsplit('/').next().unwrap();
+            eyre.push_event(uid, json!({"id": 2, "response": "diff"}));
+            eyre.push_fact("chat-db", "/db", json!({"hello": "again"}));
+            let fact = ctx
+                .receiver
+                .recv_timeout(std::time::Duration::from_secs(10))
+                .unwrap();
+            assert_eq!(fact["json"], json!({"hello": "again"}));
+
+            // every ship event is logged to the packets table
+            let count: i64 = ctx
+                .db
+                .pool
+                .get_conn()
+                .unwrap()
+                .query_row(
+                    "SELECT COUNT(*) FROM packets WHERE source = 'ship'",
+                    [],
+                    |row| row.get(0),
+                )
+                .unwrap();
+            assert_eq!(count, 4);
+        });
+    }
+
+    // ship channel uid the listener is attached to
+    async fn channel_uid(ctx: &CallContext) -> String {
+        let channel_url = ctx.ship.lock().await.channel_url.clone().unwrap();
+        channel_url.rsplit('/').next().unwrap().to_string()
+    }
+
+    async fn subscribe(ctx: &CallContext) {
+        let subscribe = [Action::Subscribe {
+            id: 2,
+            ship: SHIP.to_string(),
+            app: "chat-db".to_string(),
+            path: "/db".to_string(),
+        }];
+        ctx.ship.lock().await.post(&subscribe).await.unwrap();
+        next_event(ctx, |event| event["id"] == 2);
+    }
+
+    // poll `check` until it passes or 10 seconds have gone by
+    fn wait_for(check: impl Fn() -> bool) -> bool {
+        for _ in 0..200 {
+            if check() {
+                return true;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(50));
+        }
+        false
+    }
+
+    #[test]
+    fn start_acks_events_in_batches() {
+        run(async {
+            let (eyre, ship) = test_ship().await;
+            let ctx = test_context(ship);
+            start(ctx.clone()).await.unwrap();
+            subscribe(&ctx).await;
+            let uid = channel_uid(&ctx).await;
+
+            let count = ACK_BATCH_SIZE * 2 + 5;
+            for n in 0..count {
+                eyre.push_fact("chat-db", "/db", json!({ "n": n }));
+            }
+            for n in 0..count {
+                let fact = next_event(&ctx, |event| event["response"] == "diff");
+                assert_eq!(fact["json"]["n"], n);
+            }
+
+            // the tail of the batch is acked once the stream goes quiet
+            assert!(wait_for(|| eyre.unacked(&uid) == 0));
+            let acks = eyre
+                .actions(&uid)
+                .iter()
+                .filter(|action| action["action"] == "ack")
+                .count();
+            assert!((2..=5).contains(&acks), "{} acks", acks);
+        });
+    }
+
+    #[test]
+    fn start_resumes_channel_after_session_expiry() {
+        run(async {
+            let (eyre, ship) = test_ship().await;
+            let ctx = test_context(ship);
+            start(ctx.clone()).await.unwrap();
+            subscribe(&ctx).await;
+            let uid = channel_uid(&ctx).await;
+
+            // facts that arrive while the session is expired are buffered on the channel
+            eyre.expire_sessions();
+            for n in 0..3 {
+                eyre.push_fact("chat-db", "/db", json!({ "n": n }));
+            }
+
+            // ...and relayed exactly once, in order, after the listener logs in again
+            let status = next_event(&ctx, |_| true);
+            assert_eq!(status["status"], "ship-reconnected", "{}", status);
+            for n in 0..3 {
+                let event = next_event(&ctx, |_| true);
+                assert_eq!(event["response"], "diff", "{}", event);
+                assert_eq!(event["json"]["n"], n);
+            }
+            assert_eq!(eyre.logins(), 2);
+            assert_eq!(eyre.channels(), vec![uid.clone()]);
+
+            eyre.push_fact("chat-db", "/db", json!({ "n": 3 }));
+            let event = next_event(&ctx, |_| true);
+            assert_eq!(event["json"]["n"], 3);
+            assert!(ctx
+                .receiver
+                .recv_timeout(std::time::Duration::from_millis(500))
+                .is_err());
+        });
+    }
+
+    #[test]
+    fn start_reopens_channel_when_deleted() {
+        run(async {
+            let (eyre, ship) = test_ship().await;
+            let ctx = test_context(ship);
+            start(ctx.clone()).await.unwrap();
+            let uid = channel_uid(&ctx).await;
+
+            let delete = [Action::Delete { id: 9 }];
+            ctx.ship.lock().await.post(&delete).await.unwrap();
+            let err = next_event(&ctx, |event| event["type"] == "error");
+            assert_eq!(err["error"], "ship-stream-disconnected");
+            let status = next_event(&ctx, |event| event["target"] == "node");
+            assert_eq!(status["status"], "ship-reconnected");
+
+            // the listener opens a new channel
+            assert!(wait_for(|| eyre.channels().len() == 1));
+            assert_ne!(eyre.channels(), vec![uid]);
+            let poke = [Action::Poke {
+                id: 3,
+                ship: SHIP.to_string(),
+                app: "hood".to_string(),
+                mark: "helm-hi".to_string(),
+                json: json!("still here"),
+            }];
+            ctx.ship.lock().await.post(&poke).await.unwrap();
+            let ack = next_event(&ctx, |event| event["id"] == 3);
+            assert_eq!(ack["ok"], "ok");
+        });
+    }
+
+    #[test]
+    fn start_resumes_saved_channel() {
+        run(async {
+            // a channel left behind by a previous run, with a response it never received
+            let (eyre, mut ship) = test_ship().await;
+            ship.open_channel().await.unwrap();
+            let url = ship.channel_url.clone().unwrap();
+            let uid = url.rsplit('/').next().unwrap().to_string();
+            let response =
+                eyre.push_event(&uid, json!({"id": 500, "response": "poke", "ok": "ok"}));
+            let ctx = test_context(ship);
+            ctx.db
+                .save_channel(&ChannelEntry {
+                    url: url.clone(),
+                    last_ack: None,
+                })
+                .unwrap();
+
+            start(ctx.clone()).await.unwrap();
+            let event = next_event(&ctx, |_| true);
+            assert_eq!(event["id"], 500, "{}", event);
+            assert_eq!(eyre.channels(), vec![uid.clone()]);
+
+            // the ack is saved for the next run
+            assert!(wait_for(|| {
+                ctx.db
+                    .load_channel()
+                    .unwrap()
+                    .and_then(|saved| saved.last_ack)
+                    == response
+            }));
+
+            // a saved channel the ship no longer has is replaced (and the new one saved)
+            let delete = [Action::Delete { id: 9 }];
+            ctx.ship.lock().await.post(&delete).await.unwrap();
+            let status = next_event(&ctx, |event| event["target"] == "node");
+            assert_eq!(status["status"], "ship-reconnected");
+            assert!(wait_for(|| eyre.channels().len() == 1));
+            let saved = ctx.db.load_channel().unwrap().unwrap();
+            assert_eq!(saved.url.rsplit('/').next().unwrap(), eyre.channels()[0]);
+            assert_ne!(saved.url, url);
+        });
+    }
+}
@@ -0,0 +1,279 @@
+//!
+//! multiplexes device subscriptions onto the node's single ship channel
+//!
+//! devices watching the same app/path share one upstream (Eyre) watch. each device keeps
+//!  its own subscription id; ship events for a watch are fanned out to every subscribed
+//!  device under that device's id. the upstream watch is cancelled when the last device
+//!  leaves.
+//!
+use std::collections::HashMap;
+
+use crate::eyre::Event;
+
+/// a device's subscription: the device and the subscription id it chose
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Subscriber {
+    pub device_id: usize,
+    pub sub_id: u64,
+}
+
+/// outcome of adding a subscriber
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Subscribed {
+    /// first subscriber to this app/path. the caller must subscribe to the ship
+    ///  using this (upstream) watch id
+    New(u64),
+    /// the upstream watch exists but has not been acked yet. the subscriber
+    ///  receives the ack along with everyone else
+    Pending,
+    /// the upstream watch is already live. the caller should ack the subscriber
+    Live,
+}
+
+#[derive(Debug)]
+struct Watch {
+    app: String,
+    path: String,
+    // true once the ship has acked the upstream watch
+    live: bool,
+    subscribers: Vec<Subscriber>,
+}
+
+#[derive(Debug, Default)]
+pub struct SubscriptionManager {
+    // upstream watch id -> watch
+    watches: HashMap<u64, Watch>,
+    // (app, path) -> upstream watch id
+    by_path: HashMap<(String, String), u64>,
+    // subscriber -> upstream watch id
+    by_subscriber: HashMap<Subscriber, u64>,
+}
+
+impl SubscriptionManager {
+    pub fn new() -> SubscriptionManager {
+        SubscriptionManager::default()
+    }
+
+    /// add a subscriber to app/path. `next_id` is only called when a new upstream
+    ///  watch is needed. a subscriber that is already subscribed must be unsubscribed
+    ///  first so that the caller can cancel its upstream watch
+    pub fn subscribe(
+        &mut self,
+        subscriber: Subscriber,
+        app: &str,
+        path: &str,
+        next_id: impl FnOnce() -> u64,
+    ) -> Subscribed {
+        debug_assert!(!self.by_subscriber.contains_key(&subscriber));
+
+        let key = (app.to_string(), path.to_string());
+        if let Some(watch_id) = self.by_path.get(&key) {
+            let watch = self.watches.get_mut(watch_id).unwrap();
+            watch.subscribers.push(subscriber);
+            self.by_subscriber.insert(subscriber, *watch_id);
+            return match watch.live {
+                true => Subscribed::Live,
+                false => Subscribed::Pending,
+            };
+        }
+
+        let watch_id = next_id();
+        self.watches.insert(
+            watch_id,
+            Watch {
+                app: app.to_string(),
+                path: path.to_string(),
+                live: false,
+                subscribers: vec![subscriber],
+            },
+        );
+        self.by_path.insert(key, watch_id);
+        self.by_subscriber.insert(subscriber, watch_id);
+        Subscribed::New(watch_id)
+    }
+
+    /// remove a subscriber. returns the upstream watch id to cancel if it was the
+    ///  watch's last subscriber
+    pub fn unsubscribe(&mut self, subscriber: Subscriber) -> Option<u64> {
+        let watch_id = self.by_subscriber.remove(&subscriber)?;
+        let watch = self.watches.get_mut(&watch_id)?;
+        watch.subscribers.retain(|s| *s != subscriber);
+        if !watch.subscribers.is_empty() {
+            return None;
+        }
+        self.remove_watch(watch_id);
+        Some(watch_id)
+    }
+
+    /// remove every subscription held by a (disconnected) device. returns the
+    ///  upstream watch ids that no longer have any subscribers
+    pub fn remove_device(&mut self, device_id: usize) -> Vec<u64> {
+        let subscribers: Vec<Subscriber> = self
+            .by_subscriber
+            .keys()
+            .filter(|subscriber| subscriber.device_id == device_id)
+            .copied()
+            .collect();
+        let mut cancelled: Vec<u64> = subscribers
+            .into_iter()
+            .filter_map(|subscriber| self.unsubscribe(subscriber))
+            .collect();
+        cancelled.sort();
+        cancelled
+    }
+
+    /// route a ship event to the subscribers of the watch it belongs to, each copy
+    ///  carrying the subscriber's own id. returns None if the event is not for a
+    ///  managed watch
+    pub fn route(&mut self, event: &Event) -> Option<Vec<(Subscriber, Event)>> {
+        let watch_id = event.id();
+        let watch = self.watches.get_mut(&watch_id)?;
+
+        let subscribers = watch.subscribers.clone();
+        match event {
+            Event::WatchAck { .. } => watch.live = true,
+            // the watch is over on the ship. every subscriber is told so
+            Event::WatchNack { .. } | Event::Kick { .. } => self.remove_watch(watch_id),
+            _ => {}
+        }
+
+        Some(
+            subscribers
+                .into_iter()
+                .map(|subscriber| {
+                    let mut event = event.clone();
+                    event.set_id(subscriber.sub_id);
+                    (subscriber, event)
+                })
+                .collect(),
+        )
+    }
+
+    /// forget every watch, e.g. after the channel they were opened on is gone
+    pub fn clear(&mut self) {
+        self.watches.clear();
+        self.by_path.clear();
+        self.by_subscriber.clear();
+    }
+
+    /// number of upstream watches
+    pub fn watch_count(&self) -> usize {
+        self.watches.len()
+    }
+
+    /// number of subscribers sharing the upstream watch on app/path
+    pub fn subscriber_count(&self, app: &str, path: &str) -> usize {
+        self.by_path
+            .get(&(app.to_string(), path.to_string()))
+            .and_then(|watch_id| self.watches.get(watch_id))
+            .map_or(0, |watch| watch.subscribers.len())
+    }
+
+    fn remove_watch(&mut self, watch_id: u64) {
+        if let Some(watch) = self.watches.remove(&watch_id) {
+            self.by_path.remove(&(watch.app, watch.path));
+            for subscriber in watch.subscribers {
+                self.by_subscriber.remove(&subscriber);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn subscriber(device_id: usize, sub_id: u64) -> Subscriber {
+        Subscriber { device_id, sub_id }
+    }
+
+    #[test]
+    fn devices_share_one_watch_per_path() {
+        let mut subs = SubscriptionManager::new();
+        let a = subscriber(1, 10);
+        let b = subscriber(2, 10);
+        let c = subscriber(2, 11);
+
+        assert_eq!(
+            subs.subscribe(a, "chat-db", "/db", || 100),
+            Subscribed::New(100)
+        );
+        assert_eq!(
+            subs.subscribe(b, "chat-db", "/db", || unreachable!()),
+            Subscribed::Pending
+        );
+        assert_eq!(
+            subs.subscribe(c, "spaces", "/updates", || 101),
+            Subscribed::New(101)
+        );
+        assert_eq!(subs.watch_count(), 2);
+        assert_eq!(subs.subscriber_count("chat-db", "/db"), 2);
+
+        // the ack is fanned out; later subscribers are acked locally
+        let acks = subs.route(&Event::WatchAck { id: 100 }).unwrap();
+        assert_eq!(
+            acks,
+            vec![
+                (a, Event::WatchAck { id: 10 }),
+                (b, Event::WatchAck { id: 10 })
+            ]
+        );
+        let d = subscriber(3, 7);
+        assert_eq!(
+            subs.subscribe(d, "chat-db", "/db", || 102),
+            Subscribed::Live
+        );
+
+        let facts = subs
+            .route(&Event::Fact {
+                id: 100,
+                json: json!({"a": 1}),
+            })
+            .unwrap();
+        assert_eq!(facts.len(), 3);
+        assert_eq!(facts[2].0, d);
+        assert_eq!(facts[2].1.id(), 7);
+
+        // events for other actions are not routed
+        assert!(subs.route(&Event::PokeAck { id: 5 }).is_none());
+    }
+
+    #[test]
+    fn watch_is_cancelled_with_its_last_subscriber() {
+        let mut subs = SubscriptionManager::new();
+        let a = subscriber(1, 10);
+        let b = subscriber(2, 20);
+        subs.subscribe(a, "chat-db", "/db", || 100);
+        subs.subscribe(b, "chat-db", "/db", || 101);
+        subs.subscribe(subscriber(2, 21), "spaces", "/updates", || 102);
+
+        assert_eq!(subs.unsubscribe(a), None);
+        assert_eq!(subs.subscriber_count("chat-db", "/db"), 1);
+        assert_eq!(subs.remove_device(2), vec![100, 102]);
+        assert_eq!(subs.watch_count(), 0);
+
+        // a new subscriber starts a new watch
+        assert_eq!(
+            subs.subscribe(a, "chat-db", "/db", || 103),
+            Subscribed::New(103)
+        );
+    }
+
+    #[test]
+    fn kick_ends_the_watch_for_every_subscriber() {
+        let mut subs = SubscriptionManager::new();
+        let a = subscriber(1, 10);
+        let b = subscriber(2, 20);
+        subs.subscribe(a, "chat-db", "/db", || 100);
+        subs.subscribe(b, "chat-db", "/db", || 101);
+
+        let kicks = subs.route(&Event::Kick { id: 100 }).unwrap();
+        assert_eq!(
+            kicks,
+            vec![(a, Event::Kick { id: 10 }), (b, Event::Kick { id: 20 })]
+        );
+        assert_eq!(subs.watch_count(), 0);
+        assert_eq!(subs.unsubscribe(a), None);
+    }
+}
@@ -0,0 +1,74 @@
+//!
+//! shared helpers for tests that run against the eyre-mock ship
+//!
+use std::sync::Arc;
+
+use bedrock_db::DbPool;
+use crossbeam::channel::unbounded;
+use eyre_mock::MockEyre;
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use tokio::sync::Mutex;
+
+use crate::api::Ship;
+use crate::context::{CallContext, NodeContext};
+use crate::db::Db;
+use crate::media::MediaCache;
+
+pub const SHIP: &str = "zod";
+pub const CODE: &str = "lidlut-tabwed-pillex-ridrup";
+
+/// an in-memory database with the node's tables. the pool is limited to a single
+///  connection so that every caller sees the same in-memory database
+pub fn test_db() -> Db {
+    let pool = Pool::builder()
+        .max_size(1)
+        .build(SqliteConnectionManager::memory())
+        .unwrap();
+    let db = Db {
+        pool: DbPool { pool, writer: None },
+    };
+    db.migrate().unwrap();
+    db
+}
+
+/// a media cache in a fresh folder under the temp dir
+pub fn test_cache(max_bytes: u64) -> MediaCache {
+    let dir = std::env::temp_dir().join(format!("holon-media-{}", rand::random::<u64>()));
+    MediaCache::new(&dir, max_bytes, max_bytes).unwrap()
+}
+
+/// start a mock ship and log in to it
+pub async fn test_ship() -> (MockEyre, Ship) {
+    let eyre = MockEyre::start(SHIP, CODE);
+    let ship = Ship::new(eyre.url.as_str(), CODE).await.unwrap();
+    (eyre, ship)
+}
+
+/// a node context bound to the given (logged in) ship
+pub fn test_context(ship: Ship) -> CallContext {
+    let (sender, receiver) = unbounded();
+    NodeContext::to_call_context(NodeContext {
+        db: test_db(),
+        ship: Arc::new(Mutex::new(ship)),
+        sync_ship: Arc::new(Mutex::new(None)),
+        chat_events: crate::chat::events::channel(),
+        sender,
+        receiver,
+    })
+}
+
+/// run a test future on a multi-threaded runtime that is torn down without waiting
+///  on its workers. ship listeners block worker threads on `EventSource` receivers,
+///  which would otherwise hang the runtime's shutdown at the end of each test (or
+///  at a failed assertion)
+pub fn run<F: std::future::Future>(future: F) -> F::Output {
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(16)
+        .enable_all()
+        .build()
+        .unwrap();
+    let output = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| rt.block_on(future)));
+    rt.shutdown_background();
+    output.unwrap_or_else(|e| std::panic::resume_unwind(e))
+}
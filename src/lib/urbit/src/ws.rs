@@ -1,48 +1,88 @@
 use futures_util::{SinkExt, StreamExt, TryFutureExt};
 use lazy_static::lazy_static;
 use reqwest::header::HeaderMap;
-use serde::{Deserialize, Serialize};
-use serde_json::Value as JsonValue;
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
 use std::collections::HashMap;
 use std::sync::{
     atomic::{AtomicU64, AtomicUsize, Ordering},
     Arc,
 };
 use tokio::task::JoinHandle;
+use tokio::time::Duration;
 
-use tokio::sync::RwLock;
+use tokio::sync::{broadcast, Mutex, Notify, RwLock};
 use warp::ws::{Message, WebSocket};
 use warp::Filter;
 
+use bedrock_db::migrations::Migration;
+
+use crate::chat::events::ChatEvent;
 use crate::context::CallContext;
+use crate::db::{MsgEntry, OutboxEntry};
+use crate::error::UrbitAPIError;
+use crate::eyre::{parse_actions, Action, Event};
+use crate::helper::get_current_time;
+use crate::holon::{self, HolonMessage, HolonSubscriptions, Topic};
+use crate::subscriptions::{Subscribed, Subscriber, SubscriptionManager};
 
 use trace::{trace_err_ln, trace_good_ln, trace_info_ln, trace_json_ln, trace_warn_ln};
 
+/// the tables backing the message store and outbox. applied by `Db::migrate`
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "message_store",
+        sql: include_str!("ws/sql/0001_message_store.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "outbox",
+        sql: include_str!("ws/sql/0002_outbox.sql"),
+    },
+    Migration {
+        version: 3,
+        name: "node_channel",
+        sql: include_str!("ws/sql/0003_node_channel.sql"),
+    },
+];
+
 /// global unique device id counter.
 static NEXT_DEVICE_ID: AtomicUsize = AtomicUsize::new(1);
 /// global unique message id counter.
 static NEXT_MESSAGE_ID: AtomicU64 = AtomicU64::new(1);
 
+/// how long a relayed action waits on a ship response before its mapping is dropped
+const MESSAGE_TTL_MS: u64 = 60_000;
+/// how often the ship listener checks for expired mappings
+const MESSAGE_SWEEP_MS: u64 = 1_000;
+/// how often queued actions are retried if no reconnect is signalled in the meantime
+const OUTBOX_RETRY_MS: u64 = 5_000;
+/// how long an action can sit in the outbox before it is failed
+const OUTBOX_TTL_MS: u64 = 10 * 60_000;
+/// how long messages for a device that is away are held for it to reconnect
+const HELD_TTL_MS: u64 = 10 * 60_000;
+
 /// currently connected devices.
 ///
 /// - key is the device id (based on NEXT_DEVICE_ID)
 /// - value is a sender of `warp::ws::Message` which sends messages
-///    across the underlying channel to the websocket device sender
+///   across the underlying channel to the websocket device sender
 type DeviceMap = HashMap<usize, crossbeam::channel::Sender<Message>>;
 type Devices = Arc<RwLock<DeviceMap>>;
 
-#[derive(Debug, Clone)]
-struct MsgEntry {
-    #[allow(dead_code)] // id "not read" warning. disable for now.
-    // unique message id managed by the holon
-    id: u64,
-    // id of the message originating on the device (e.g. urbit action message id)
-    source_id: u64,
-    // id of connected device (key into the DeviceMap)
-    device_id: usize,
+// messages for devices that are not connected (e.g. the response to an action relayed
+//  before a node restart), keyed on device id. each is held until the time given with it
+type HeldMap = HashMap<usize, Vec<(u64, Message)>>;
+
+// GET /ws?device=<id>. a device that reconnects may ask for the id it had before
+#[derive(Debug, Deserialize)]
+struct DeviceQuery {
+    device: Option<usize>,
 }
 
-// maps holong managed message ids to origin message ids and device
+// maps holon managed message ids to origin message ids and device. entries are
+//  mirrored to the db's message_store table so they survive a node restart
 type MsgMap = HashMap<u64, MsgEntry>;
 
 // thread-safe store of the message map
@@ -60,45 +100,20 @@ lazy_static! {
     //
     static ref SHIP_RECEIVER: Arc<RwLock<Option<JoinHandle::<()>>>> = Arc::new(RwLock::new(None));
     static ref MESSAGE_STORE: Arc<RwLock<MsgMap>> = Arc::new(RwLock::new(MsgMap::new()));
+    // device subscriptions multiplexed onto upstream watches on the node's ship channel
+    static ref SUBSCRIPTIONS: Arc<RwLock<SubscriptionManager>> = Arc::new(RwLock::new(SubscriptionManager::new()));
+    // held while posting to the ship or replaying the outbox so that actions reach the
+    //  ship in the order devices sent them
+    static ref OUTBOX_LOCK: Mutex<()> = Mutex::new(());
+    // wakes the outbox worker (e.g. when the ship listener reconnects)
+    static ref OUTBOX_NOTIFY: Notify = Notify::new();
+    // holon message subscriptions (see holon.rs) of connected devices
+    static ref HOLON_SUBSCRIPTIONS: Arc<RwLock<HolonSubscriptions>> = Arc::new(RwLock::new(HolonSubscriptions::new()));
+    // messages waiting for their device to reconnect
+    static ref HELD_MESSAGES: Arc<RwLock<HeldMap>> = Arc::new(RwLock::new(HeldMap::new()));
 
 }
 
-// @see: https://developers.urbit.org/reference/arvo/eyre/external-api-ref#responses
-//
-// handles ship responses which are delivered in a variety of flavors
-#[derive(Debug, Deserialize, Serialize)]
-struct ShipResponse {
-    id: u64,
-    response: String,
-    json: Option<JsonValue>,
-    err: Option<String>,
-    ok: Option<String>,
-}
-
-// @see: https://developers.urbit.org/reference/arvo/eyre/external-api-ref#actions
-//
-// loose representation of a ship action which come in a variety of flavors (e.g. poke, subscribe, etc...)
-//   where some of the fields are missing/present depending on the flavor.
-#[derive(Debug, Deserialize, Serialize)]
-pub struct ShipAction {
-    id: u64,
-    action: String,
-    // poke/subscribe required field
-    ship: Option<String>,
-    // poke/subscribe required field
-    app: Option<String>,
-    // poke required field
-    mark: Option<String>,
-    // poke required field
-    json: Option<JsonValue>,
-    // subscribe required field
-    path: Option<String>,
-    // ack required field
-    // officially field name is "event-id", rename to put it into struct friendly form
-    #[serde(rename = "event-id")]
-    event_id: Option<u64>,
-}
-
 // authorization tokens are required as part of the websocket handshake
 
 // MissingAuthToken is the rejection that is raised when the authorization
@@ -109,6 +124,7 @@ impl warp::reject::Reject for MissingAuthToken {}
 
 // InvalidAuthToken is the rejection that is raised when the authorization
 // token is provided but deemed to be invalid (e.g. expired or improperly formatted, etc.)
+#[allow(dead_code)] // not raised until tokens are validated against the ship
 #[derive(Debug)]
 struct InvalidAuthToken;
 impl warp::reject::Reject for InvalidAuthToken {}
@@ -116,9 +132,19 @@ impl warp::reject::Reject for InvalidAuthToken {}
 pub async fn start(
     context: CallContext,
 ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    // pick up actions relayed by a previous run that are still waiting on the ship
+    restore_messages(&context).await;
+
     // "filterize" our state
 
     let devices = Devices::default();
+
+    // replays actions that could not be posted while the ship was unreachable
+    tokio::spawn(outbox_worker(context.clone(), devices.clone()));
+
+    // forwards changes to the chat tables to subscribed devices
+    tokio::spawn(chat_feed(context.chat_events.subscribe(), devices.clone()));
+
     let devices = warp::any().map(move || devices.clone());
 
     let with_context = warp::any().map(move || context.clone());
@@ -168,26 +194,27 @@ pub async fn start(
                 Ok(context)
             },
         )
+        .and(warp::query::<DeviceQuery>())
         .and(devices)
         .and(warp::ws())
-        .map(|context: CallContext, devices: Devices, ws: warp::ws::Ws| {
-            // This will call our function if the handshake succeeds.
-            ws.on_upgrade(move |socket| device_connected(socket, devices, context.clone()))
-        });
+        .map(
+            |context: CallContext, query: DeviceQuery, devices: Devices, ws: warp::ws::Ws| {
+                // This will call our function if the handshake succeeds.
+                ws.on_upgrade(move |socket| {
+                    device_connected(socket, query.device, devices, context.clone())
+                })
+            },
+        );
 
     handler
 }
 
 async fn device_connected(
     ws: WebSocket,
+    requested_id: Option<usize>,
     devices: Devices,
     context: CallContext, /*ship_event_receiver: ShipReceiver*/
 ) {
-    // use a counter to assign a new unique ID for this device.
-    let my_id = NEXT_DEVICE_ID.fetch_add(1, Ordering::Relaxed);
-
-    trace_good_ln!("new chat user: {}", my_id);
-
     // Split the socket into a sender and receive of messages.
     let (mut device_ws_tx, mut device_ws_rx) = ws.split();
 
@@ -200,7 +227,7 @@ async fn device_connected(
     // spawn a task to listen for messages to send to transmit to connected devices
     tokio::task::spawn(async move {
         trace_info_ln!("waiting for outgoing messages...");
-        while let Ok(message) = rx.recv() {
+        while let Ok(message) = tokio::task::block_in_place(|| rx.recv()) {
             trace_info_ln!("sending message to device...");
             device_ws_tx
                 .send(message)
@@ -212,7 +239,23 @@ async fn device_connected(
     });
 
     // Save the sender in our list of connected devices.
-    devices.write().await.insert(my_id, tx);
+    let my_id = register_device(requested_id, tx.clone(), &devices).await;
+
+    trace_good_ln!("new chat user: {}", my_id);
+
+    // tell the device its id, so that it can ask for it again when it reconnects...
+    let hello = json!({
+      "type": "status",
+      "status": "connected",
+      "device": my_id,
+    });
+    let _ = tx.send(Message::text(hello.to_string()));
+    // ...and hand over anything that came in for it while it was away
+    if let Some(held) = HELD_MESSAGES.write().await.remove(&my_id) {
+        for (_, msg) in held {
+            let _ = tx.send(msg);
+        }
+    }
 
     // one and only one ship listener per holon process
     if SHIP_RECEIVER.read().await.is_none() {
@@ -225,9 +268,21 @@ async fn device_connected(
         let handle = tokio::task::spawn(async move {
             trace_info_ln!("waiting for ship event...");
 
-            while let Ok(result) = ship_rx_context.receiver.recv() {
-                trace_info_ln!("received event from ship => [{}, {}]", my_id, result);
-                on_ship_message(my_id, result, &ship_rx_devices).await;
+            loop {
+                let result = tokio::task::block_in_place(|| {
+                    ship_rx_context
+                        .receiver
+                        .recv_timeout(Duration::from_millis(MESSAGE_SWEEP_MS))
+                });
+                match result {
+                    Ok(result) => {
+                        trace_info_ln!("received event from ship => [{}, {}]", my_id, result);
+                        on_ship_message(my_id, result, &ship_rx_context, &ship_rx_devices).await;
+                    }
+                    Err(crossbeam::channel::RecvTimeoutError::Timeout) => {}
+                    Err(crossbeam::channel::RecvTimeoutError::Disconnected) => break,
+                }
+                expire_messages(get_current_time(), &ship_rx_context, &ship_rx_devices).await;
             }
         });
 
@@ -249,7 +304,44 @@ async fn device_connected(
     }
     // device_ws_rx stream will keep processing as long as the device stays
     // connected. Once they disconnect, then...
-    on_device_disconnected(my_id, &devices).await;
+    on_device_disconnected(my_id, &context, &devices).await;
+}
+
+// add a device to the connected devices. it gets the id it asked for if that id was
+//  handed out before (in this run, or a run whose messages were restored) and is not
+//  in use. otherwise it gets a new one
+async fn register_device(
+    requested_id: Option<usize>,
+    tx: crossbeam::channel::Sender<Message>,
+    devices: &Devices,
+) -> usize {
+    let mut devices = devices.write().await;
+    let my_id = match requested_id {
+        Some(id)
+            if (1..NEXT_DEVICE_ID.load(Ordering::Relaxed)).contains(&id)
+                && !devices.contains_key(&id) =>
+        {
+            id
+        }
+        _ => NEXT_DEVICE_ID.fetch_add(1, Ordering::Relaxed),
+    };
+    devices.insert(my_id, tx);
+    my_id
+}
+
+// send a message to a device, or hold it for the device to reconnect
+async fn send_or_hold(device_id: usize, msg: Message, devices: &Devices) {
+    if let Some(tx) = find_device_tx(device_id, devices).await {
+        let _ = tx.send(msg);
+        return;
+    }
+    trace_warn_ln!("device {} not connected. holding message", device_id);
+    HELD_MESSAGES
+        .write()
+        .await
+        .entry(device_id)
+        .or_default()
+        .push((get_current_time() + HELD_TTL_MS, msg));
 }
 
 async fn on_device_message(my_id: usize, msg: Message, context: &CallContext, devices: &Devices) {
@@ -290,42 +382,67 @@ async fn on_device_message(my_id: usize, msg: Message, context: &CallContext, de
         // the flow should follow:
         //   device -[req]-> node -[req]-> ship -[resp]-> node -[resp]-> device
 
-        // leverage serde_json deserialization strictly for the validation of the message
-        //  if nothing else
-        let actions: serde_json::Result<Vec<ShipAction>> = serde_json::from_str(msg);
+        let actions = match parse_actions(msg) {
+            Ok(actions) => actions,
+            Err(e) => {
+                trace_err_ln!("invalid action array: {}. {}", msg, e);
+                send_device_error(my_id, devices, &e).await;
+                return;
+            }
+        };
 
-        if actions.is_err() {
-            trace_err_ln!("error deserializing message to action array: {}", msg);
-            return;
+        // subscriptions are multiplexed onto shared upstream watches; everything else is
+        //   relayed to the ship as is
+        let mut relay: Vec<Action> = Vec::new();
+        for action in actions {
+            match action {
+                Action::Subscribe { .. } | Action::Unsubscribe { .. } => {
+                    on_device_subscription(my_id, action, devices, &mut relay).await;
+                }
+                // the node acks ship events itself and owns the channel. letting a device
+                //  ack or delete it would affect every other device
+                Action::Ack { .. } | Action::Delete { .. } => {
+                    trace_warn_ln!("ignoring {:?} from device {}", action, my_id);
+                }
+                Action::Poke {
+                    id,
+                    ship,
+                    app,
+                    mark,
+                    json,
+                } => {
+                    // device ids are only unique per device. the ship sees the holon's id
+                    //  and responses are mapped back to the device's id
+                    //
+                    // to prevent orphaned messages (ship post that succeeds but MESSAGE_STORE persist fails),
+                    //   add the holon id <-> urbit id message map entry first. that way if the ship post
+                    //   below fails, it may orphan the mapping entry but the message post can still be retried on error
+                    let entry = MsgEntry {
+                        id: NEXT_MESSAGE_ID.fetch_add(1, Ordering::Relaxed),
+                        source_id: id,
+                        device_id: my_id,
+                        expires_at: get_current_time() + MESSAGE_TTL_MS,
+                    };
+                    store_message(context, &entry).await;
+                    relay.push(Action::Poke {
+                        id: entry.id,
+                        ship,
+                        app,
+                        mark,
+                        json,
+                    });
+                }
+            }
         }
 
-        let actions = actions.unwrap();
-
-        // to prevent orphaned messages (ship post that succeeds but MESSAGE_STORE persist fails),
-        //   add the holon id <-> urbit id message map entry first. that way if the ship post
-        //   below fails, it may orphan the mapping entry but the message post can still be retried on error
-        let msg_id = NEXT_MESSAGE_ID.fetch_add(1, Ordering::Relaxed);
-        MESSAGE_STORE.write().await.insert(
-            actions[0].id,
-            MsgEntry {
-                id: msg_id,
-                source_id: actions[0].id,
-                device_id: my_id,
-            },
-        );
+        if relay.is_empty() {
+            return;
+        }
 
         trace_info_ln!("relaying actions payload");
-        trace_json_ln!(&packet);
-
-        let result = context.ship.lock().await.post(&packet).await;
-
-        if result.is_err() {
-            // an error here is a big deal. print to holon std out...
-            trace_err_ln!("proxy.post call failed. {:?}", result);
+        trace_json_ln!(&json!(relay));
 
-            // ...and send error response to connected device over socket
-            return;
-        }
+        relay_actions(my_id, relay, context, devices).await;
 
         // no more to do. eventually a response to ship requests will come back thru the
         //   SHIP_RECEIVER where it will be delivered to the device from whence it originated
@@ -333,24 +450,8 @@ async fn on_device_message(my_id: usize, msg: Message, context: &CallContext, de
     } else {
         // holon message
         // this is json object message; therefore it's destination is meant for holon
-        let value: JsonValue = serde_json::from_value(packet).unwrap();
-
-        // for now, echo holon messages back to calling device (no use case for holon
-        //  messages at the time of writing)
         //  holon message flow: device - [req] -> holon -> [res] -> device
-
-        // send the proxy post response back to the originating device over websocket
-        let tx = devices.read();
-        let tx = tx.await;
-        let tx = tx.get(&my_id);
-        {
-            if tx.is_none() {
-                trace_err_ln!("ws: [device_message] error attempting to read device {} from list of connected devices", my_id);
-                return;
-            }
-            let tx = tx.unwrap();
-            let _ = tx.send(Message::text(value.to_string()));
-        }
+        on_holon_message(my_id, &packet, devices).await;
     }
 
     ////////////////////////////////////////////////////////
@@ -368,31 +469,330 @@ async fn on_device_message(my_id: usize, msg: Message, context: &CallContext, de
     ///////////////////////////////////////////////////////
 }
 
+// post actions to the ship. if the ship cannot be reached (or earlier actions are still
+//  waiting on it), the actions are queued in the outbox and replayed in order later
+async fn relay_actions(my_id: usize, relay: Vec<Action>, context: &CallContext, devices: &Devices) {
+    let _outbox = OUTBOX_LOCK.lock().await;
+
+    // never jump ahead of queued actions
+    if context.db.outbox_len().unwrap_or(0) == 0 {
+        let result = context.ship.lock().await.post(&relay).await;
+        if result.is_ok() {
+            return;
+        }
+        // an error here is a big deal. print to holon std out...
+        trace_err_ln!("proxy.post call failed. queueing actions. {:?}", result);
+    }
+
+    // ...and let the device know its actions are on hold
+    queue_actions(my_id, relay, context, devices).await;
+}
+
+async fn queue_actions(my_id: usize, relay: Vec<Action>, context: &CallContext, devices: &Devices) {
+    let now = get_current_time();
+    for action in relay {
+        // the message mapping is created again (with a fresh ttl) on delivery
+        let source_id = match find_msg_entry(action.id()).await {
+            Some(entry) => {
+                remove_message(context, entry.id).await;
+                Some(entry.source_id)
+            }
+            None => None,
+        };
+        let entry = OutboxEntry {
+            id: 0,
+            action,
+            device_id: my_id,
+            source_id,
+            queued_at: now,
+        };
+        let status = match context.db.queue_action(&entry) {
+            Ok(_) => "queued",
+            Err(e) => {
+                trace_err_ln!("failed to queue action {}. {}", entry.action.id(), e);
+                "failed"
+            }
+        };
+        if let Some(source_id) = source_id {
+            send_device_status(my_id, source_id, status, devices).await;
+        }
+    }
+}
+
+async fn outbox_worker(context: CallContext, devices: Devices) {
+    loop {
+        // replay as soon as the ship is back, and every so often in case that was missed
+        let _ = tokio::time::timeout(
+            Duration::from_millis(OUTBOX_RETRY_MS),
+            OUTBOX_NOTIFY.notified(),
+        )
+        .await;
+        flush_outbox(&context, &devices).await;
+    }
+}
+
+// replay the outbox. everything is posted in one batch (in queue order); actions that
+//  have been waiting too long are failed instead
+async fn flush_outbox(context: &CallContext, devices: &Devices) {
+    let _outbox = OUTBOX_LOCK.lock().await;
+
+    let entries = match context.db.load_outbox() {
+        Ok(entries) => entries,
+        Err(e) => {
+            trace_err_ln!("failed to load outbox. {}", e);
+            return;
+        }
+    };
+
+    let now = get_current_time();
+    let mut pending = Vec::new();
+    for entry in entries {
+        if entry.queued_at + OUTBOX_TTL_MS <= now {
+            trace_warn_ln!("giving up on queued action {}", entry.action.id());
+            finish_outbox_entry(&entry, "failed", context, devices).await;
+        } else {
+            pending.push(entry);
+        }
+    }
+
+    if pending.is_empty() {
+        return;
+    }
+
+    // map responses back to devices before the ship has a chance to send them
+    for entry in &pending {
+        if let Some(source_id) = entry.source_id {
+            let msg = MsgEntry {
+                id: entry.action.id(),
+                source_id,
+                device_id: entry.device_id,
+                expires_at: now + MESSAGE_TTL_MS,
+            };
+            store_message(context, &msg).await;
+        }
+    }
+
+    let actions: Vec<Action> = pending.iter().map(|entry| entry.action.clone()).collect();
+    let result = context.ship.lock().await.post(&actions).await;
+
+    if result.is_err() {
+        trace_warn_ln!(
+            "ship still unreachable. {} action(s) remain queued. {:?}",
+            pending.len(),
+            result
+        );
+        for entry in pending.iter().filter(|entry| entry.source_id.is_some()) {
+            remove_message(context, entry.action.id()).await;
+        }
+        return;
+    }
+
+    for entry in &pending {
+        finish_outbox_entry(entry, "delivered", context, devices).await;
+    }
+}
+
+async fn finish_outbox_entry(
+    entry: &OutboxEntry,
+    status: &str,
+    context: &CallContext,
+    devices: &Devices,
+) {
+    if let Err(e) = context.db.delete_outbox_entry(entry.id) {
+        trace_err_ln!("failed to delete outbox entry {}. {}", entry.id, e);
+    }
+    if let Some(source_id) = entry.source_id {
+        send_device_status(entry.device_id, source_id, status, devices).await;
+    }
+}
+
+// add/remove a device subscription. upstream subscribe/unsubscribe actions that are
+//  needed as a result are appended to `relay`
+async fn on_device_subscription(
+    my_id: usize,
+    action: Action,
+    devices: &Devices,
+    relay: &mut Vec<Action>,
+) {
+    let next_id = || NEXT_MESSAGE_ID.fetch_add(1, Ordering::Relaxed);
+    let mut subscriptions = SUBSCRIPTIONS.write().await;
+    match action {
+        Action::Subscribe {
+            id,
+            ship,
+            app,
+            path,
+        } => {
+            let subscriber = Subscriber {
+                device_id: my_id,
+                sub_id: id,
+            };
+            // a device re-using a subscription id replaces its old subscription
+            if let Some(watch_id) = subscriptions.unsubscribe(subscriber) {
+                relay.push(Action::Unsubscribe {
+                    id: next_id(),
+                    subscription: watch_id,
+                });
+            }
+            match subscriptions.subscribe(subscriber, &app, &path, next_id) {
+                Subscribed::New(watch_id) => relay.push(Action::Subscribe {
+                    id: watch_id,
+                    ship,
+                    app,
+                    path,
+                }),
+                Subscribed::Pending => {}
+                Subscribed::Live => {
+                    drop(subscriptions);
+                    send_device_event(my_id, devices, &Event::WatchAck { id }).await;
+                }
+            }
+        }
+        Action::Unsubscribe { subscription, .. } => {
+            let subscriber = Subscriber {
+                device_id: my_id,
+                sub_id: subscription,
+            };
+            if let Some(watch_id) = subscriptions.unsubscribe(subscriber) {
+                relay.push(Action::Unsubscribe {
+                    id: next_id(),
+                    subscription: watch_id,
+                });
+            }
+        }
+        _ => {}
+    }
+}
+
 // msg_id - the holon managed message id
 async fn find_msg_entry(msg_id: u64) -> Option<MsgEntry> {
     let lock = MESSAGE_STORE.read().await;
     let entry = lock.get(&msg_id);
-    if entry.is_none() {
-        return None;
-    }
+    entry?;
     let entry = entry.unwrap();
     Some(entry.clone())
 }
 
+async fn store_message(context: &CallContext, entry: &MsgEntry) {
+    MESSAGE_STORE.write().await.insert(entry.id, entry.clone());
+    if let Err(e) = context.db.save_message(entry) {
+        trace_err_ln!("failed to persist message {}. {}", entry.id, e);
+    }
+}
+
+async fn remove_message(context: &CallContext, msg_id: u64) {
+    MESSAGE_STORE.write().await.remove(&msg_id);
+    if let Err(e) = context.db.delete_message(msg_id) {
+        trace_err_ln!("failed to delete message {}. {}", msg_id, e);
+    }
+}
+
+// load the mappings persisted by a previous run. the counters are moved past the
+//  restored ids, and the ids of the actions still queued, so that new messages and
+//  devices cannot be mistaken for old ones. the node's channel (and the watches made
+//  on it) outlives a restart, so message ids also start from the clock: ids used by a
+//  previous run are never used again on it
+async fn restore_messages(context: &CallContext) {
+    NEXT_MESSAGE_ID.fetch_max(get_current_time(), Ordering::Relaxed);
+
+    let entries = match context.db.load_messages() {
+        Ok(entries) => entries,
+        Err(e) => {
+            trace_err_ln!("failed to load persisted messages. {}", e);
+            Vec::new()
+        }
+    };
+    let mut store = MESSAGE_STORE.write().await;
+    store.clear();
+    for entry in entries {
+        NEXT_MESSAGE_ID.fetch_max(entry.id + 1, Ordering::Relaxed);
+        NEXT_DEVICE_ID.fetch_max(entry.device_id + 1, Ordering::Relaxed);
+        store.insert(entry.id, entry);
+    }
+    drop(store);
+
+    let _outbox = OUTBOX_LOCK.lock().await;
+    let queued = match context.db.load_outbox() {
+        Ok(queued) => queued,
+        Err(e) => {
+            trace_err_ln!("failed to load outbox. {}", e);
+            Vec::new()
+        }
+    };
+    for entry in queued {
+        // the node's own watches were made for the devices of the previous run, on its
+        //  channel. they are made again as devices subscribe
+        if entry.source_id.is_none() {
+            if let Err(e) = context.db.delete_outbox_entry(entry.id) {
+                trace_err_ln!("failed to drop queued action {}. {}", entry.action.id(), e);
+            }
+            continue;
+        }
+        NEXT_MESSAGE_ID.fetch_max(entry.action.id() + 1, Ordering::Relaxed);
+        NEXT_DEVICE_ID.fetch_max(entry.device_id + 1, Ordering::Relaxed);
+    }
+}
+
+// drop the mappings of actions the ship never responded to, telling the device
+//  (now, or once it reconnects) that no response is coming. messages held for too
+//  long are dropped as well
+async fn expire_messages(now: u64, context: &CallContext, devices: &Devices) {
+    HELD_MESSAGES.write().await.retain(|_, held| {
+        held.retain(|(until, _)| *until > now);
+        !held.is_empty()
+    });
+
+    let expired: Vec<MsgEntry> = MESSAGE_STORE
+        .read()
+        .await
+        .values()
+        .filter(|entry| entry.expires_at <= now)
+        .cloned()
+        .collect();
+    for entry in expired {
+        trace_warn_ln!("message {} expired without a ship response", entry.id);
+        remove_message(context, entry.id).await;
+        let msg = json!({
+          "type": "error",
+          "error": "message-expired",
+          "id": entry.source_id,
+        });
+        send_or_hold(entry.device_id, Message::text(msg.to_string()), devices).await;
+    }
+}
+
 async fn find_device_tx(
     device_id: usize,
     devices: &Devices,
 ) -> Option<crossbeam::channel::Sender<Message>> {
     let lock = devices.read().await;
     let tx = lock.get(&device_id);
-    if tx.is_none() {
-        return None;
-    }
+    tx?;
     Some(tx.unwrap().clone())
 }
 
-async fn on_ship_message(_my_id: usize, msg: JsonValue, devices: &Devices) {
-    let data = serde_json::from_value::<ShipResponse>(msg.clone());
+async fn on_ship_message(my_id: usize, msg: JsonValue, context: &CallContext, devices: &Devices) {
+    // messages from the ship listener meant for the node itself
+    if msg["target"] == "node" {
+        if msg["status"] == "ship-reconnected" {
+            OUTBOX_NOTIFY.notify_one();
+        }
+        return;
+    }
+
+    // messages from the ship listener itself (e.g. ship-stream-disconnected) go to everyone
+    if msg["target"] == "broadcast" {
+        if msg["error"] == "ship-stream-disconnected" {
+            // upstream watches were lost along with the channel. devices must resubscribe
+            SUBSCRIPTIONS.write().await.clear();
+        }
+        for tx in devices.read().await.values() {
+            let _ = tx.send(Message::text(msg.to_string()));
+        }
+        return;
+    }
+
+    let data = serde_json::from_value::<Event>(msg.clone());
     if data.is_err() {
         trace_err_ln!(
             "error deserializing ship event => {:?}",
@@ -402,51 +802,204 @@ async fn on_ship_message(_my_id: usize, msg: JsonValue, devices: &Devices) {
     }
 
     let mut data = data.unwrap();
+
+    // events for a shared watch go to each of its subscribers under their own ids
+    let routed = SUBSCRIPTIONS.write().await.route(&data);
+    if let Some(routed) = routed {
+        for (subscriber, event) in routed {
+            send_device_event(subscriber.device_id, devices, &event).await;
+        }
+        return;
+    }
+
     // note the id coming from the ship will be the holon managed message
     // id. use it to find the corresponding MsgStore entry which provides
     // the originating message id (e.g. urbit message id)
-    let entry = find_msg_entry(data.id).await;
+    let entry = find_msg_entry(data.id()).await;
 
     if entry.is_none() {
-        trace_err_ln!("message {} not found", data.id);
+        trace_err_ln!("message {} not found", data.id());
+        // e.g. a watch made by a previous run on the resumed channel. nobody is listening
+        if let Event::Fact { id, .. } = data {
+            let relay = vec![Action::Unsubscribe {
+                id: NEXT_MESSAGE_ID.fetch_add(1, Ordering::Relaxed),
+                subscription: id,
+            }];
+            relay_actions(my_id, relay, context, devices).await;
+        }
         return;
     }
 
     let entry = entry.unwrap();
-    let tx = find_device_tx(entry.device_id, devices).await;
 
-    if tx.is_none() {
-        trace_err_ln!("device {} not found", entry.device_id);
+    // a poke gets exactly one response. anything else keeps the mapping alive
+    if matches!(
+        data,
+        Event::PokeAck { .. }
+            | Event::PokeNack { .. }
+            | Event::WatchNack { .. }
+            | Event::Kick { .. }
+    ) {
+        remove_message(context, entry.id).await;
     }
 
     // override the outgoing message's id field with the original message id
-    data.id = entry.source_id;
+    data.set_id(entry.source_id);
 
-    if data.err.is_some() {
+    if matches!(data, Event::PokeNack { .. } | Event::WatchNack { .. }) {
         trace_err_ln!("error in ship response {:?}", data);
     }
 
-    let result = serde_json::to_string::<ShipResponse>(&data);
+    let result = serde_json::to_string::<Event>(&data);
 
     if result.is_err() {
         trace_err_ln!("error serializing message {:?}", data);
+        return;
+    }
+
+    // the device may have disconnected, or the message was relayed before a restart
+    send_or_hold(entry.device_id, Message::text(result.unwrap()), devices).await;
+}
+
+async fn send_device_event(device_id: usize, devices: &Devices, event: &Event) {
+    let result = serde_json::to_string::<Event>(event);
+    if result.is_err() {
+        trace_err_ln!("error serializing message {:?}", event);
+        return;
+    }
+    if let Some(tx) = find_device_tx(device_id, devices).await {
+        let _ = tx.send(Message::text(result.unwrap()));
+    }
+}
+
+// tell a device what became of one of its actions (queued, delivered or failed)
+async fn send_device_status(device_id: usize, source_id: u64, status: &str, devices: &Devices) {
+    let msg = json!({
+      "type": "status",
+      "status": status,
+      "id": source_id,
+    });
+    if let Some(tx) = find_device_tx(device_id, devices).await {
+        let _ = tx.send(Message::text(msg.to_string()));
+    }
+}
+
+// reply to a device whose message could not be relayed to the ship
+async fn send_device_error(device_id: usize, devices: &Devices, err: &UrbitAPIError) {
+    let code = match err {
+        UrbitAPIError::EmptyActions => "empty-actions",
+        UrbitAPIError::InvalidAction(_) => "invalid-action",
+        _ => "error",
+    };
+    let msg = json!({
+      "type": "error",
+      "error": code,
+      "message": err.to_string(),
+    });
+    if let Some(tx) = find_device_tx(device_id, devices).await {
+        let _ = tx.send(Message::text(msg.to_string()));
+    }
+}
+
+// subscribe/unsubscribe a device to node events
+async fn on_holon_message(my_id: usize, packet: &JsonValue, devices: &Devices) {
+    let msg = match holon::parse(packet) {
+        Ok(msg) => msg,
+        Err(e) => {
+            trace_err_ln!("invalid holon message: {}. {}", packet, e);
+            let msg = json!({
+              "type": "error",
+              "error": "invalid-holon-message",
+              "message": e,
+            });
+            if let Some(tx) = find_device_tx(my_id, devices).await {
+                let _ = tx.send(Message::text(msg.to_string()));
+            }
+            return;
+        }
+    };
+    {
+        let mut subscriptions = HOLON_SUBSCRIPTIONS.write().await;
+        match &msg {
+            HolonMessage::Subscribe { topic, path, .. } => {
+                subscriptions.subscribe(my_id, *topic, path.clone());
+            }
+            HolonMessage::Unsubscribe { topic, path, .. } => {
+                subscriptions.unsubscribe(my_id, *topic, path.clone());
+            }
+        }
+    }
+    if let Some(tx) = find_device_tx(my_id, devices).await {
+        let _ = tx.send(Message::text(holon::reply(&msg).to_string()));
     }
+}
 
-    let tx = tx.unwrap();
-    if let Err(_disconnected) = tx.send(Message::text(result.unwrap())) {
-        // The tx is disconnected, our `user_disconnected` code
-        // should be happening in another task, nothing more to
-        // do here.
+// send each chat event to the devices subscribed to its path
+async fn chat_feed(mut events: broadcast::Receiver<ChatEvent>, devices: Devices) {
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            // too far behind. subscribers are told so that they can catch up over REST
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                trace_warn_ln!("chat feed fell behind. {} event(s) dropped", missed);
+                let msg = json!({
+                  "type": "error",
+                  "error": "events-dropped",
+                  "topic": Topic::Chat,
+                  "missed": missed,
+                });
+                let subscribers = HOLON_SUBSCRIPTIONS
+                    .read()
+                    .await
+                    .topic_subscribers(Topic::Chat);
+                for device_id in subscribers {
+                    if let Some(tx) = find_device_tx(device_id, &devices).await {
+                        let _ = tx.send(Message::text(msg.to_string()));
+                    }
+                }
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+        let subscribers = HOLON_SUBSCRIPTIONS
+            .read()
+            .await
+            .subscribers(Topic::Chat, event.path());
+        if subscribers.is_empty() {
+            continue;
+        }
+        let msg = holon::chat_event(&event).to_string();
+        for device_id in subscribers {
+            if let Some(tx) = find_device_tx(device_id, &devices).await {
+                let _ = tx.send(Message::text(msg.clone()));
+            }
+        }
     }
 }
 
-async fn on_device_disconnected(my_id: usize, devices: &Devices) {
+async fn on_device_disconnected(my_id: usize, context: &CallContext, devices: &Devices) {
     trace_good_ln!("removing device {}...", my_id);
 
     // stream closed up, so remove from the device list
     devices.write().await.remove(&my_id);
+    HOLON_SUBSCRIPTIONS.write().await.remove_device(my_id);
+
+    // ...and cancel any upstream watches only this device was using
+    let relay: Vec<Action> = SUBSCRIPTIONS
+        .write()
+        .await
+        .remove_device(my_id)
+        .into_iter()
+        .map(|watch_id| Action::Unsubscribe {
+            id: NEXT_MESSAGE_ID.fetch_add(1, Ordering::Relaxed),
+            subscription: watch_id,
+        })
+        .collect();
+    if !relay.is_empty() {
+        relay_actions(my_id, relay, context, devices).await;
+    }
 
-    if devices.read().await.len() == 0 {
+    if devices.read().await.is_empty() {
         trace_warn_ln!("no more connected devices. stopping ship listener...");
 
         // kill the current ship receiver thread
@@ -461,140 +1014,539 @@ async fn on_device_disconnected(my_id: usize, devices: &Devices) {
 
 #[cfg(test)]
 mod tests {
-    // use crate::trace::{ctraceln, trace_jsonln};
-    use futures_util::{SinkExt, StreamExt};
-    use rand::Rng;
+    use super::*;
+    use crate::testing::{run, test_context, test_ship, SHIP};
+    use eyre_mock::MockEyre;
     use serde_json::json;
-    use std::sync::atomic::{AtomicUsize, Ordering};
-    use trace::{trace_err_ln, trace_good_ln, trace_info_ln};
-    // use termcolor::Color;
-    use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
-    use tokio::time::{sleep, Duration};
+    use std::net::SocketAddr;
+    use std::sync::Mutex;
+    use tokio::time::{timeout, Duration};
     use tokio_tungstenite::{
         connect_async,
-        tungstenite::{client::IntoClientRequest, connect, Message},
+        tungstenite::{client::IntoClientRequest, Message},
     };
-    static NEXT_MSG_ID: AtomicUsize = AtomicUsize::new(0);
-    static SENT_COUNT: AtomicUsize = AtomicUsize::new(0);
-    static RECD_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+    // the ship listener (SHIP_RECEIVER) is process wide, so tests that connect
+    //  devices must take turns
+    static SERIAL: Mutex<()> = Mutex::new(());
+
+    // start a mock ship, subscribe to it and serve this node's websocket on an
+    //  ephemeral port. returns the address of the node
+    async fn start_node() -> (MockEyre, SocketAddr, CallContext) {
+        // a listener left behind by a previous test is bound to that test's context
+        if let Some(handle) = SHIP_RECEIVER.write().await.take() {
+            handle.abort();
+        }
+        SUBSCRIPTIONS.write().await.clear();
+        let (eyre, ship) = test_ship().await;
+        let ctx = test_context(ship);
+        crate::sub::start(ctx.clone()).await.unwrap();
+        let (addr, server) =
+            warp::serve(start(ctx.clone()).await).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        (eyre, addr, ctx)
+    }
+
+    type Socket = tokio_tungstenite::WebSocketStream<
+        tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
+    >;
+
+    fn ws_request(
+        addr: SocketAddr,
+        cookie: Option<String>,
+    ) -> tokio_tungstenite::tungstenite::handshake::client::Request {
+        device_request(addr, cookie, None)
+    }
+
+    fn device_request(
+        addr: SocketAddr,
+        cookie: Option<String>,
+        device: Option<usize>,
+    ) -> tokio_tungstenite::tungstenite::handshake::client::Request {
+        let url = match device {
+            Some(device) => format!("ws://{}/ws?device={}", addr, device),
+            None => format!("ws://{}/ws", addr),
+        };
+        let mut request = url.into_client_request().unwrap();
+        if let Some(cookie) = cookie {
+            request
+                .headers_mut()
+                .insert("cookie", cookie.parse().unwrap());
+        }
+        request
+    }
+
+    fn poke(id: u64) -> Message {
+        Message::text(
+            json!([{
+              "id": id,
+              "ship": SHIP,
+              "action": "poke",
+              "app": "hood",
+              "mark": "helm-hi",
+              "json": format!("test message {}", id)
+            }])
+            .to_string(),
+        )
+    }
+
+    fn watch(id: u64) -> Message {
+        Message::text(
+            json!([{
+              "id": id,
+              "ship": SHIP,
+              "action": "subscribe",
+              "app": "chat-db",
+              "path": "/db"
+            }])
+            .to_string(),
+        )
+    }
+
+    async fn next_json(socket: &mut Socket) -> JsonValue {
+        let msg = timeout(Duration::from_secs(10), socket.next())
+            .await
+            .expect("timed out waiting for message")
+            .unwrap()
+            .unwrap();
+        serde_json::from_str(msg.to_text().unwrap()).unwrap()
+    }
+
+    // connect a device (asking for the given id) and read the id it was given
+    async fn connect_device(
+        addr: SocketAddr,
+        eyre: &MockEyre,
+        device: Option<usize>,
+    ) -> (Socket, usize) {
+        let (mut socket, _) = connect_async(device_request(addr, eyre.cookie(), device))
+            .await
+            .unwrap();
+        let hello = next_json(&mut socket).await;
+        assert_eq!(hello["status"], "connected", "{}", hello);
+        let device = hello["device"].as_u64().unwrap() as usize;
+        (socket, device)
+    }
+
+    // poll `check` until it passes or 10 seconds have gone by
+    async fn wait_for(check: impl Fn() -> bool) -> bool {
+        for _ in 0..200 {
+            if check() {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        false
+    }
+
     #[test]
-    // connect to this node's websocket server (for receiving events from a ship)
+    // connect to this node's websocket server and relay a poke to the ship
     fn can_ws_connect() {
-        trace_info_ln!("connecting to node websocket...");
-        let mut request = "ws://127.0.0.1:3030/ws".into_client_request().unwrap();
-        let headers = request.headers_mut();
-        headers.insert("cookie", "urbauth-~ralbes-mislec-lodlev-migdev=0v6.s58oo.vp1c4.e4fg8.peu65.mols9; Path=/; Max-Age=604800".parse().unwrap());
-        let (mut socket, _response) = connect(request).unwrap();
-        loop {
-            trace_info_ln!("waiting for ship events...");
-            let msg = socket.read_message().expect("Error reading message");
-            trace_info_ln!("received: {}", msg);
-        }
+        let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+        run(async {
+            let (eyre, addr, _) = start_node().await;
+
+            // devices must present the ship's session cookie
+            assert!(connect_async(ws_request(addr, None)).await.is_err());
+
+            let (mut socket, _) = connect_device(addr, &eyre, None).await;
+            socket.send(poke(1)).await.unwrap();
+
+            let msg = timeout(Duration::from_secs(10), socket.next())
+                .await
+                .expect("timed out waiting for poke ack")
+                .unwrap()
+                .unwrap();
+            let ack: JsonValue = serde_json::from_str(msg.to_text().unwrap()).unwrap();
+            assert_eq!(ack["id"], 1);
+            assert_eq!(ack["response"], "poke");
+            assert_eq!(ack["ok"], "ok");
+        });
+    }
+
+    #[test]
+    fn invalid_actions_are_reported_to_device() {
+        let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+        run(async {
+            let (eyre, addr, _) = start_node().await;
+            let (mut socket, _) = connect_device(addr, &eyre, None).await;
+
+            for (payload, code) in [
+                ("[]", "empty-actions"),
+                (r#"[{"id": 2, "action": "poke"}]"#, "invalid-action"),
+            ] {
+                socket.send(Message::text(payload)).await.unwrap();
+                let msg = timeout(Duration::from_secs(10), socket.next())
+                    .await
+                    .expect("timed out waiting for error reply")
+                    .unwrap()
+                    .unwrap();
+                let reply: JsonValue = serde_json::from_str(msg.to_text().unwrap()).unwrap();
+                assert_eq!(reply["type"], "error");
+                assert_eq!(reply["error"], code);
+            }
+
+            // the connection survives and later actions are still relayed
+            socket.send(poke(3)).await.unwrap();
+            let msg = timeout(Duration::from_secs(10), socket.next())
+                .await
+                .expect("timed out waiting for poke ack")
+                .unwrap()
+                .unwrap();
+            let ack: JsonValue = serde_json::from_str(msg.to_text().unwrap()).unwrap();
+            assert_eq!(ack["id"], 3);
+        });
     }
 
     ///
     /// test_ws_multi_connect - open NUM_WS_CONNECTIONS websocket client connections
-    ///   and succeed only if 8 unique sends are transmitted and 8 unique receives are
-    ///   read.
+    ///   and succeed only if each device receives the ack for its own poke.
     ///
-    ///  prereqs: you must start the main process which will start the websocket server and
-    ///    serve out the ws routes.
-    ///
-    #[tokio::test]
-    async fn test_ws_multi_connect() {
-        const NUM_WS_CONNECTIONS: usize = 8;
-        let (tx, mut rx): (UnboundedSender<String>, UnboundedReceiver<String>) =
-            unbounded_channel();
-
-        trace_info_ln!("starting writer...");
-        // tokio::task::spawn(async move {
-        for i in 0..NUM_WS_CONNECTIONS {
-            let mut rng: rand::rngs::StdRng = rand::SeedableRng::from_entropy();
-
-            let mut request = "ws://127.0.0.1:3030/ws".into_client_request().unwrap();
-            let headers = request.headers_mut();
-            headers.insert("cookie", "urbauth-~ralbes-mislec-lodlev-migdev=0v6.s58oo.vp1c4.e4fg8.peu65.mols9; Path=/; Max-Age=604800".parse().unwrap());
-
-            let (socket, _) = connect_async(request).await.unwrap();
-
-            let (mut itx, mut irx) = socket.split();
-            let tx1 = tx.clone();
-            let tx2 = tx.clone();
-
-            tokio::task::spawn(async move {
-                trace_info_ln!("[thread-{}] - write started", i);
-                // loop {
-                let secs = rng.gen_range(0..10);
-                sleep(Duration::from_secs(secs)).await;
-                let msg_id = NEXT_MSG_ID.fetch_add(1, Ordering::Relaxed);
-                let msg = json!([{
-                  "id": msg_id,
-                  "ship":"ralbes-mislec-lodlev-migdev",
-                  "action":"poke",
-                  "app": "helm",
-                  "mark": "helm-hi",
-                  "json": format!("test message {}", msg_id)
-                }]);
-                trace_info_ln!("[thread-{}]: sending message...", i); // [thread-{}]: {}", i, msg.to_string());
-                let _ = itx.send(Message::text(msg.to_string())).await;
-                SENT_COUNT.fetch_add(1, Ordering::Relaxed);
-                let _ = tx1.send("".to_string());
-                // }
-            });
-            tokio::task::spawn(async move {
-                trace_info_ln!("[thread-{}] - read started", i);
-                loop {
-                    let msg = irx.next().await;
-                    if msg.is_none() {
-                        trace_err_ln!("[thread-{}]: received message [no data]", i);
-                    } else {
-                        let msg = msg.unwrap();
-
-                        if msg.is_err() {
-                            trace_err_ln!("[thread-{}]: received message [error]", i);
-                        }
-
-                        let msg = msg.unwrap();
-
-                        trace_info_ln!("[thread-{}]: received message {}", i, msg);
-                        let msg = msg.to_string();
-
-                        let res = serde_json::from_str::<Vec<super::ShipAction>>(&msg);
-
-                        if res.is_err() {
-                            trace_err_ln!(
-                                "[thread-{}]: received message [error deserializing message]",
-                                i
-                            );
-                        }
-
-                        let actions = res.unwrap();
-
-                        for i in 0..actions.len() {
-                            trace_info_ln!(
-                                "[thread-{}]: received actions - {:?}",
-                                i,
-                                actions[i].json
-                            );
-                        }
-
-                        RECD_COUNT.fetch_add(1, Ordering::Relaxed);
-                        let _ = tx2.send("".to_string());
-                    }
-                }
-            });
-        }
+    #[test]
+    fn test_ws_multi_connect() {
+        const NUM_WS_CONNECTIONS: u64 = 8;
+        let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+        run(async {
+            let (eyre, addr, _) = start_node().await;
+
+            let mut sockets = Vec::new();
+            for _ in 0..NUM_WS_CONNECTIONS {
+                let (socket, _) = connect_device(addr, &eyre, None).await;
+                sockets.push(socket);
+            }
 
-        trace_info_ln!("waiting for eof...");
-        loop {
-            let msg = rx.recv().await;
-            let sent_count = SENT_COUNT.fetch_add(0, Ordering::Relaxed);
-            let recd_count = RECD_COUNT.fetch_add(0, Ordering::Relaxed);
-            trace_info_ln!("waiting for eof [{}, {}]...", sent_count, recd_count);
-            if sent_count == NUM_WS_CONNECTIONS && recd_count == NUM_WS_CONNECTIONS {
-                trace_good_ln!("eot {:?}", msg);
-                break;
+            // message ids must be unique across devices (and tests)
+            for (i, socket) in sockets.iter_mut().enumerate() {
+                socket.send(poke(100 + i as u64)).await.unwrap();
             }
-        }
+
+            for (i, socket) in sockets.iter_mut().enumerate() {
+                let msg = timeout(Duration::from_secs(10), socket.next())
+                    .await
+                    .expect("timed out waiting for poke ack")
+                    .unwrap()
+                    .unwrap();
+                let ack: JsonValue = serde_json::from_str(msg.to_text().unwrap()).unwrap();
+                assert_eq!(ack["id"], 100 + i as u64);
+                assert_eq!(ack["ok"], "ok");
+            }
+        });
+    }
+
+    #[test]
+    fn devices_share_upstream_watches() {
+        let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+        run(async {
+            let (eyre, addr, _) = start_node().await;
+            let uid = eyre.channels()[0].clone();
+
+            let (mut a, _) = connect_device(addr, &eyre, None).await;
+            let (mut b, _) = connect_device(addr, &eyre, None).await;
+
+            // same app/path, different subscription ids. one watch on the ship
+            a.send(watch(7)).await.unwrap();
+            let ack = next_json(&mut a).await;
+            assert_eq!(
+                (ack["id"].as_u64(), ack["ok"].as_str()),
+                (Some(7), Some("ok"))
+            );
+            b.send(watch(9)).await.unwrap();
+            let ack = next_json(&mut b).await;
+            assert_eq!(
+                (ack["id"].as_u64(), ack["ok"].as_str()),
+                (Some(9), Some("ok"))
+            );
+            assert_eq!(eyre.subscriptions(&uid).len(), 1);
+
+            // facts reach each device under its own subscription id
+            assert_eq!(eyre.push_fact("chat-db", "/db", json!({"n": 1})), 1);
+            for (socket, id) in [(&mut a, 7), (&mut b, 9)] {
+                let fact = next_json(socket).await;
+                assert_eq!(fact["id"], id);
+                assert_eq!(fact["response"], "diff");
+                assert_eq!(fact["json"], json!({"n": 1}));
+            }
+
+            // the watch outlives all but its last subscriber
+            a.send(Message::text(
+                json!([{"id": 8, "action": "unsubscribe", "subscription": 7}]).to_string(),
+            ))
+            .await
+            .unwrap();
+            assert_eq!(eyre.push_fact("chat-db", "/db", json!({"n": 2})), 1);
+            assert_eq!(next_json(&mut b).await["json"], json!({"n": 2}));
+            assert_eq!(eyre.subscriptions(&uid).len(), 1);
+
+            b.close(None).await.unwrap();
+            assert!(wait_for(|| eyre.subscriptions(&uid).is_empty()).await);
+        });
+    }
+
+    #[test]
+    fn chat_events_reach_subscribed_devices() {
+        let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+        run(async {
+            let (eyre, addr, ctx) = start_node().await;
+            let (mut a, _) = connect_device(addr, &eyre, None).await;
+            let (mut b, _) = connect_device(addr, &eyre, None).await;
+            let deleted = |path: &str, msg_id: &str| ChatEvent::MessageDeleted {
+                path: path.to_string(),
+                msg_id: msg_id.to_string(),
+            };
+
+            // a follows one path, b every path
+            a.send(Message::text(
+                json!({"type": "subscribe", "topic": "chat", "path": "/a", "id": 1}).to_string(),
+            ))
+            .await
+            .unwrap();
+            assert_eq!(
+                next_json(&mut a).await,
+                json!({"type": "subscribed", "topic": "chat", "path": "/a", "id": 1})
+            );
+            b.send(Message::text(
+                json!({"type": "subscribe", "topic": "chat"}).to_string(),
+            ))
+            .await
+            .unwrap();
+            assert_eq!(next_json(&mut b).await["type"], "subscribed");
+
+            crate::chat::events::publish(&ctx, vec![deleted("/b", "/1"), deleted("/a", "/2")]);
+            let event = next_json(&mut a).await;
+            assert_eq!(
+                event,
+                json!({
+                  "type": "event",
+                  "topic": "chat",
+                  "event": "chat.message.deleted",
+                  "path": "/a",
+                  "msg-id": "/2",
+                })
+            );
+            assert_eq!(next_json(&mut b).await["msg-id"], "/1");
+            assert_eq!(next_json(&mut b).await["msg-id"], "/2");
+
+            // after unsubscribing, a hears nothing more
+            a.send(Message::text(
+                json!({"type": "unsubscribe", "topic": "chat", "path": "/a"}).to_string(),
+            ))
+            .await
+            .unwrap();
+            assert_eq!(next_json(&mut a).await["type"], "unsubscribed");
+            crate::chat::events::publish(&ctx, vec![deleted("/a", "/3")]);
+            assert_eq!(next_json(&mut b).await["msg-id"], "/3");
+
+            // objects that are not holon messages are errors, not echoes
+            a.send(Message::text(json!({"type": "publish"}).to_string()))
+                .await
+                .unwrap();
+            let error = next_json(&mut a).await;
+            assert_eq!(error["type"], "error");
+            assert_eq!(error["error"], "invalid-holon-message");
+        });
+    }
+
+    #[test]
+    fn device_ids_are_rewritten_per_message() {
+        let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+        run(async {
+            let (eyre, addr, ctx) = start_node().await;
+            let uid = eyre.channels()[0].clone();
+
+            let mut sockets = Vec::new();
+            for _ in 0..2 {
+                let (socket, _) = connect_device(addr, &eyre, None).await;
+                sockets.push(socket);
+            }
+
+            // both devices use the same id. each gets its own ack back
+            for socket in sockets.iter_mut() {
+                socket.send(poke(1)).await.unwrap();
+                let ack = next_json(socket).await;
+                assert_eq!(ack["id"], 1);
+                assert_eq!(ack["ok"], "ok");
+            }
+
+            // ...while the ship saw two different ids
+            let ids: Vec<JsonValue> = eyre
+                .actions(&uid)
+                .into_iter()
+                .filter(|action| action["json"] == "test message 1")
+                .map(|action| action["id"].clone())
+                .collect();
+            assert_eq!(ids.len(), 2);
+            assert_ne!(ids[0], ids[1]);
+            assert_ne!(ids[0], 1);
+
+            // answered messages are forgotten
+            assert!(MESSAGE_STORE.read().await.is_empty());
+            assert!(ctx.db.load_messages().unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    fn responses_reach_devices_after_restart() {
+        let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+        run(async {
+            let (eyre, addr, ctx) = start_node().await;
+            let uid = eyre.channels()[0].clone();
+
+            // mappings left behind by a previous run, for a device that has not reconnected yet
+            let now = get_current_time();
+            for (id, source_id, expires_at) in [(500, 1, now + MESSAGE_TTL_MS), (501, 2, now)] {
+                ctx.db
+                    .save_message(&MsgEntry {
+                        id,
+                        source_id,
+                        device_id: 999,
+                        expires_at,
+                    })
+                    .unwrap();
+            }
+            restore_messages(&ctx).await;
+            assert_eq!(MESSAGE_STORE.read().await.len(), 2);
+            assert!(NEXT_MESSAGE_ID.load(Ordering::Relaxed) >= now);
+            assert!(NEXT_DEVICE_ID.load(Ordering::Relaxed) > 999);
+
+            // ids that were never handed out are not given to devices that ask for them
+            let (_other, other_id) = connect_device(addr, &eyre, Some(5000)).await;
+            assert_ne!(other_id, 5000);
+
+            // the late response and the expired entry are held for the device...
+            eyre.push_event(&uid, json!({"id": 500, "response": "poke", "ok": "ok"}));
+            assert!(wait_for(|| ctx.db.load_messages().unwrap().is_empty()).await);
+            assert!(MESSAGE_STORE.read().await.is_empty());
+
+            // ...which gets them once it is back under its old id
+            let (mut socket, device_id) = connect_device(addr, &eyre, Some(999)).await;
+            assert_eq!(device_id, 999);
+            let mut held = [next_json(&mut socket).await, next_json(&mut socket).await];
+            held.sort_by_key(|msg| msg["id"].as_u64());
+            assert_eq!(held[0]["id"], 1);
+            assert_eq!(held[0]["response"], "poke");
+            assert_eq!(held[0]["ok"], "ok");
+            assert_eq!(
+                held[1],
+                json!({"type": "error", "error": "message-expired", "id": 2})
+            );
+
+            // a connected device's id is not given out again
+            let (_socket, device_id) = connect_device(addr, &eyre, Some(999)).await;
+            assert_ne!(device_id, 999);
+        });
+    }
+
+    #[test]
+    fn queued_actions_keep_their_ids_after_restart() {
+        let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+        run(async {
+            // no node running yet: nothing replays the outbox meanwhile
+            let (_eyre, ship) = test_ship().await;
+            let ctx = test_context(ship);
+
+            // actions queued by a previous run: a device's poke, a watch of the node's
+            //  own, and one that cannot be read back
+            let queued_at = get_current_time();
+            let poke = OutboxEntry {
+                id: 0,
+                action: Action::Poke {
+                    id: 700,
+                    ship: "zod".to_string(),
+                    app: "hood".to_string(),
+                    mark: "helm-hi".to_string(),
+                    json: json!("hi"),
+                },
+                device_id: 800,
+                source_id: Some(1),
+                queued_at,
+            };
+            let watch = OutboxEntry {
+                id: 0,
+                action: Action::Subscribe {
+                    id: 701,
+                    ship: "zod".to_string(),
+                    app: "chat-db".to_string(),
+                    path: "/db".to_string(),
+                },
+                device_id: 801,
+                source_id: None,
+                queued_at,
+            };
+            ctx.db.queue_action(&poke).unwrap();
+            ctx.db.queue_action(&watch).unwrap();
+            ctx.db
+                .pool
+                .get_writer()
+                .unwrap()
+                .execute(
+                    "INSERT INTO outbox (action, device_id, source_id, queued_at)
+                     VALUES ('{\"action\": \"nope\"}', 802, 3, ?1)",
+                    [queued_at as i64],
+                )
+                .unwrap();
+
+            restore_messages(&ctx).await;
+            assert!(NEXT_MESSAGE_ID.load(Ordering::Relaxed) > 700);
+            assert!(NEXT_DEVICE_ID.load(Ordering::Relaxed) > 800);
+            // the watch is not replayed, and the unreadable action does not hold up the rest
+            let outbox = ctx.db.load_outbox().unwrap();
+            assert_eq!(outbox.len(), 1);
+            assert_eq!(outbox[0].action, poke.action);
+            assert_eq!(ctx.db.outbox_len().unwrap(), 1);
+        });
+    }
+
+    #[test]
+    fn undeliverable_actions_are_queued_and_replayed() {
+        let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+        run(async {
+            let (eyre, addr, ctx) = start_node().await;
+            let uid = eyre.channels()[0].clone();
+            let (mut socket, _) = connect_device(addr, &eyre, None).await;
+
+            eyre.fail_next("/~/channel", 502);
+            socket.send(poke(1)).await.unwrap();
+            let status = next_json(&mut socket).await;
+            assert_eq!(
+                status,
+                json!({"type": "status", "status": "queued", "id": 1})
+            );
+
+            // later actions queue up behind it, even though the ship is back
+            socket.send(poke(2)).await.unwrap();
+            let status = next_json(&mut socket).await;
+            assert_eq!(
+                status,
+                json!({"type": "status", "status": "queued", "id": 2})
+            );
+            assert_eq!(ctx.db.outbox_len().unwrap(), 2);
+
+            // the ship listener reconnecting triggers the replay
+            ctx.sender
+                .send(json!({"type": "status", "target": "node", "status": "ship-reconnected"}))
+                .unwrap();
+            let mut replies = Vec::new();
+            for _ in 0..4 {
+                replies.push(next_json(&mut socket).await);
+            }
+            for id in [1, 2] {
+                assert!(
+                    replies.contains(&json!({"type": "status", "status": "delivered", "id": id}))
+                );
+                assert!(replies.contains(&json!({"id": id, "response": "poke", "ok": "ok"})));
+            }
+            assert_eq!(ctx.db.outbox_len().unwrap(), 0);
+
+            // ...in the order they were sent
+            let pokes: Vec<JsonValue> = eyre
+                .actions(&uid)
+                .into_iter()
+                .filter(|action| action["mark"] == "helm-hi")
+                .map(|action| action["json"].clone())
+                .collect();
+            assert_eq!(
+                pokes[pokes.len() - 2..],
+                [json!("test message 1"), json!("test message 2")]
+            );
+        });
     }
 }
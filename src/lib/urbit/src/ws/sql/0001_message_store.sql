@@ -0,0 +1,11 @@
+create table if not exists message_store
+(
+    /* holon managed id the action was relayed to the ship under */
+    id           INTEGER PRIMARY KEY,
+    /* id of the action as sent by the device */
+    source_id    INTEGER NOT NULL,
+    /* id of the device that sent the action */
+    device_id    INTEGER NOT NULL,
+    /* when the mapping is dropped if the ship has not responded (ms since epoch) */
+    expires_at   INTEGER NOT NULL
+);
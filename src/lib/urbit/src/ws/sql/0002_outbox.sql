@@ -0,0 +1,16 @@
+create table if not exists outbox
+(
+    /* auto increment ROWID. actions are replayed in this order */
+    id           INTEGER PRIMARY KEY,
+    /*
+        the action (with its holon managed id) as it is to be posted to the ship
+        @see: https://developers.urbit.org/reference/arvo/eyre/external-api-ref#actions
+    */
+    action       TEXT    NOT NULL,
+    /* id of the device that sent the action */
+    device_id    INTEGER NOT NULL,
+    /* id of the action as sent by the device. null for actions made by the node */
+    source_id    INTEGER,
+    /* when the action was queued (ms since epoch) */
+    queued_at    INTEGER NOT NULL
+);
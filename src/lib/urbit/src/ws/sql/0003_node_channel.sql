@@ -0,0 +1,9 @@
+create table if not exists node_channel
+(
+    /* the node has one channel on its ship. this is its only row */
+    id           INTEGER PRIMARY KEY CHECK (id = 1),
+    /* url of the channel (e.g. http://localhost/~/channel/<uid>) */
+    url          TEXT    NOT NULL,
+    /* id of the last event acked back to the ship. null until the first ack */
+    last_ack     INTEGER
+);